@@ -0,0 +1,43 @@
+//! Ticks per second of a hot loop, with and without `Cpu::decode_cache`.
+//!
+//! Run with `cargo bench --bench decode`.
+
+extern crate dcpu;
+
+use std::time::Instant;
+
+use dcpu::cpu::Cpu;
+use dcpu::encodings::*;
+use dcpu::types::{BasicOp, Register};
+
+const TICKS: u64 = 10000000;
+
+fn ticks_per_second(cache: bool) -> f64 {
+    // Sums the numbers from 0xffff down to 1, forever.
+    let program = [basic(BasicOp::SET, reg(Register::B), NEXT),
+                   0xffff,
+                   basic(BasicOp::ADD, reg(Register::A), reg(Register::B)),
+                   basic(BasicOp::SUB, reg(Register::B), lit(1)),
+                   basic(BasicOp::IFN, reg(Register::B), lit(0)),
+                   basic(BasicOp::SET, PC, lit(2)),
+                   basic(BasicOp::SET, PC, lit(0))];
+    let mut cpu = Cpu::default();
+    cpu.load(&program, 0);
+    if !cache {
+        cpu.decode_cache = None;
+    }
+    let start = Instant::now();
+    for _ in 0..TICKS {
+        cpu.tick(&mut []).unwrap();
+    }
+    let elapsed = start.elapsed();
+    TICKS as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9)
+}
+
+fn main() {
+    let without = ticks_per_second(false);
+    let with = ticks_per_second(true);
+    println!("without the decode cache: {:.0} ticks/s", without);
+    println!("with the decode cache:    {:.0} ticks/s", with);
+    println!("speedup: {:.2}x", with / without);
+}
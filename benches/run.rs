@@ -0,0 +1,77 @@
+//! Ticks per second of a computer with a clock, from the plain `tick` loop
+//! to the blocks run with `Computer::run`.
+//!
+//! Run with `cargo bench --bench run`.
+
+extern crate dcpu;
+
+use std::time::Instant;
+
+use dcpu::blocks::BlockCache;
+use dcpu::computer::Computer;
+use dcpu::cpu::Cpu;
+#[cfg(feature = "devices-clock")]
+use dcpu::device::clock::Clock;
+use dcpu::encodings::*;
+use dcpu::types::{BasicOp, Register};
+
+const TICKS: u64 = 10000000;
+
+#[derive(Debug, Copy, Clone)]
+enum Mode {
+    Decode,
+    Cached,
+    Blocks,
+}
+
+fn ticks_per_second(mode: Mode) -> f64 {
+    // Sums the products of the numbers from 0xffff down to 1, forever.
+    let program = [basic(BasicOp::SET, reg(Register::B), NEXT),
+                   0xffff,
+                   basic(BasicOp::SET, reg(Register::C), reg(Register::B)),
+                   basic(BasicOp::MUL, reg(Register::C), reg(Register::B)),
+                   basic(BasicOp::ADD, reg(Register::A), reg(Register::C)),
+                   basic(BasicOp::SUB, reg(Register::B), lit(1)),
+                   basic(BasicOp::IFN, reg(Register::B), lit(0)),
+                   basic(BasicOp::SET, PC, lit(2)),
+                   basic(BasicOp::SET, PC, lit(0))];
+    let mut cpu = Cpu::default();
+    cpu.load(&program, 0);
+    match mode {
+        Mode::Decode => cpu.decode_cache = None,
+        Mode::Cached => (),
+        Mode::Blocks => cpu.blocks = Some(Box::new(BlockCache::new())),
+    }
+    let mut computer = Computer::new(cpu);
+    add_clock(&mut computer);
+    let start = Instant::now();
+    match mode {
+        Mode::Decode | Mode::Cached => {
+            for _ in 0..TICKS {
+                computer.tick().unwrap();
+            }
+        }
+        Mode::Blocks => {
+            computer.run(TICKS).unwrap();
+        }
+    }
+    let elapsed = start.elapsed();
+    TICKS as f64 / (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9)
+}
+
+#[cfg(feature = "devices-clock")]
+fn add_clock(computer: &mut Computer) {
+    computer.add_device(Box::new(Clock::new()));
+}
+
+#[cfg(not(feature = "devices-clock"))]
+fn add_clock(_: &mut Computer) {}
+
+fn main() {
+    let decode = ticks_per_second(Mode::Decode);
+    println!("{:?}: {:.0} ticks/s", Mode::Decode, decode);
+    for &mode in &[Mode::Cached, Mode::Blocks] {
+        let speed = ticks_per_second(mode);
+        println!("{:?}: {:.0} ticks/s, {:.2}x", mode, speed, speed / decode);
+    }
+}
@@ -0,0 +1,21 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate dcpu;
+
+use dcpu::iterators::U16ToInstruction;
+use dcpu::types::Instruction;
+
+fuzz_target!(|data: &[u8]| {
+    let words = data.chunks(2)
+                    .map(|c| c[0] as u16 | (*c.get(1).unwrap_or(&0) as u16) << 8)
+                    .collect::<Vec<_>>();
+
+    for w in words.windows(3) {
+        if let Ok((size, i)) = Instruction::decode(&[w[0], w[1], w[2]]) {
+            let mut encoded = [0; 3];
+            assert!(i.encode(&mut encoded) <= size);
+        }
+    }
+    assert!(U16ToInstruction::chain(words.iter().cloned()).count() <= words.len());
+});
@@ -0,0 +1,16 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate dcpu;
+
+use std::str;
+
+use dcpu::types::Instruction;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(s) = str::from_utf8(data) {
+        if s.len() < 256 {
+            let _ = s.parse::<Instruction>();
+        }
+    }
+});
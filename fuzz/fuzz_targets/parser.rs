@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate dcpu;
+
+use dcpu::assembler::parser;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parser::parse_with_limits(data, &parser::Limits::default());
+});
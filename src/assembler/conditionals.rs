@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+
+use assembler::linker::Error;
+use assembler::types::*;
+
+struct Block {
+    /// Whether the items of the current branch are kept.
+    active: bool,
+    /// Whether the enclosing block is active.
+    parent_active: bool,
+    /// Whether a branch of this block has already been taken.
+    taken: bool,
+    in_else: bool,
+    /// Index of the `.if`.
+    start: usize,
+}
+
+/// Keeps only the taken branches of the `.if`/`.ifdef`/`.ifndef` blocks.
+///
+/// Conditions can use `defines` and the constants declared before them whose
+/// value doesn't depend on a label. This must be done after the macro
+/// expansion, so macro bodies can contain conditional blocks.
+pub fn evaluate(ast: &[ParsedItem],
+                defines: &HashMap<String, u16>)
+                -> Result<Vec<ParsedItem>, Error> {
+    evaluate_with_positions(ast, &vec![(); ast.len()], defines)
+        .map(|(items, _)| items)
+        .map_err(Error::without_location)
+}
+
+/// Same as `evaluate`, keeping the position of the remaining items, see
+/// `macros::expand_with_positions`. The errors are wrapped in `Error::At`.
+pub fn evaluate_with_positions<P: Copy>(ast: &[ParsedItem],
+                                        positions: &[P],
+                                        defines: &HashMap<String, u16>)
+                                        -> Result<(Vec<ParsedItem>, Vec<P>), Error> {
+    let mut values = defines.clone();
+    let mut declared = defines.keys().cloned().collect::<HashSet<_>>();
+    let mut blocks: Vec<Block> = vec![];
+    let mut items = vec![];
+    let mut kept_positions = vec![];
+
+    for (i, (item, &position)) in ast.iter().zip(positions).enumerate() {
+        let active = blocks.last().map_or(true, |b| b.active);
+        let condition = match *item {
+            ParsedItem::Directive(Directive::If(_)) if !active => Some(false),
+            ParsedItem::Directive(Directive::If(ref e)) => {
+                let value = try!(e.solve(&values, &HashMap::new())
+                                  .map_err(|e| Error::At(i, Box::new(e))));
+                Some(value != 0)
+            }
+            ParsedItem::Directive(Directive::IfDef(ref s)) => Some(declared.contains(s)),
+            ParsedItem::Directive(Directive::IfNDef(ref s)) => Some(!declared.contains(s)),
+            _ => None,
+        };
+        if let Some(condition) = condition {
+            blocks.push(Block {
+                active: active && condition,
+                parent_active: active,
+                taken: condition,
+                in_else: false,
+                start: i,
+            });
+            continue;
+        }
+
+        match *item {
+            ParsedItem::Directive(Directive::Else) => {
+                match blocks.last_mut() {
+                    Some(ref mut b) if !b.in_else => {
+                        b.active = b.parent_active && !b.taken;
+                        b.in_else = true;
+                    }
+                    _ => {
+                        let e = Error::UnbalancedConditional(Directive::Else);
+                        return Err(Error::At(i, Box::new(e)));
+                    }
+                }
+            }
+            ParsedItem::Directive(Directive::EndIf) => {
+                if blocks.pop().is_none() {
+                    let e = Error::UnbalancedConditional(Directive::EndIf);
+                    return Err(Error::At(i, Box::new(e)));
+                }
+            }
+            _ if !active => (),
+            ref i => {
+                if let ParsedItem::ConstDecl(ref name, ref e) = *i {
+                    declared.insert(name.clone());
+                    if let Ok(value) = e.solve(&values, &HashMap::new()) {
+                        values.insert(name.clone(), value);
+                    }
+                }
+                items.push(i.clone());
+                kept_positions.push(position);
+            }
+        }
+    }
+
+    if let Some(b) = blocks.pop() {
+        return Err(Error::At(b.start, Box::new(Error::UnbalancedConditional(Directive::EndIf))));
+    }
+    Ok((items, kept_positions))
+}
+
+#[cfg(test)]
+#[test]
+fn test_evaluate() {
+    use nom::IResult;
+
+    use assembler::parser;
+
+    let ast = match parser::parse(".equ SIZE, 2\n\
+                                   .ifdef DEBUG\n\
+                                   SET A, 1\n\
+                                   .if SIZE - 2\n\
+                                   SET A, 2\n\
+                                   .else\n\
+                                   SET A, 3\n\
+                                   .endif\n\
+                                   .else\n\
+                                   SET A, 4\n\
+                                   .endif\n"
+                                      .as_bytes()) {
+        IResult::Done(_, ast) => ast,
+        _ => panic!(),
+    };
+    let values = |defines: &HashMap<String, u16>| {
+        evaluate(&ast, defines)
+            .unwrap()
+            .into_iter()
+            .filter_map(|i| match i {
+                ParsedItem::ParsedInstruction(ParsedInstruction::BasicOp(_, _,
+                    ParsedValue::Litteral(Expression::Num(n)))) => Some(u16::from(n)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut defines = HashMap::new();
+    assert_eq!(values(&defines), vec![4]);
+    defines.insert("DEBUG".into(), 1);
+    assert_eq!(values(&defines), vec![1, 3]);
+
+    assert!(evaluate(&ast[..ast.len() - 1], &defines).is_err());
+}
@@ -0,0 +1,210 @@
+//! Syntaxes of the other DCPU assemblers, rewritten to the one of the parser
+//! before parsing.
+
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Words which are directives after a `.` or a `#`.
+const DIRECTIVES: [&'static str; 26] = ["dat", "datp", "byte", "word", "short", "org", "fill",
+                                        "reserve", "maxcycles", "proc", "globl", "global",
+                                        "text", "data", "bss", "if", "ifdef", "ifndef", "else",
+                                        "endif", "macro", "endmacro", "equ", "define",
+                                        "include", "incbin"];
+
+/// Syntax accepted by the assembler.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dialect {
+    /// Everything the other dialects accept.
+    Permissive,
+    /// The syntax of Notch's examples: `:label`, `dat` without a dot,
+    /// decimal and hexadecimal numbers and no directive.
+    Notch,
+    /// The 0x10c-standards syntax: directives starting with `.` or `#`,
+    /// `label:` or `:label`, `dat` with or without a dot and `0b` binary
+    /// numbers, but not `0o` octal ones.
+    Standard,
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect::Permissive
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Dialect, ()> {
+        match s {
+            "permissive" => Ok(Dialect::Permissive),
+            "notch" => Ok(Dialect::Notch),
+            "standard" => Ok(Dialect::Standard),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Construct not allowed by the dialect, with its line starting at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// `.` or `#` directive.
+    Directive(usize, String),
+    /// `label:` instead of `:label`.
+    LabelSuffix(usize, String),
+    /// Number prefix, `0b` or `0o`.
+    NumberPrefix(usize, &'static str),
+}
+
+impl Error {
+    pub fn line(&self) -> usize {
+        match *self {
+            Error::Directive(l, _) |
+            Error::LabelSuffix(l, _) |
+            Error::NumberPrefix(l, _) => l,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Directive(l, ref d) => write!(f, "line {}: directive {} not allowed", l, d),
+            Error::LabelSuffix(l, ref label) => {
+                write!(f, "line {}: label {}: should be written :{}", l, label, label)
+            }
+            Error::NumberPrefix(l, p) => write!(f, "line {}: {} numbers not allowed", l, p),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Directive(..) => "directive not allowed by the dialect",
+            Error::LabelSuffix(..) => "label syntax not allowed by the dialect",
+            Error::NumberPrefix(..) => "number not allowed by the dialect",
+        }
+    }
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Length of the identifier at the start of `s`.
+fn ident_len(s: &str) -> usize {
+    s.find(|c| !is_ident(c)).unwrap_or(s.len())
+}
+
+/// Rewrites `text` from `dialect` to the syntax of the parser, keeping the
+/// lines where they are.
+pub fn translate(text: &str, dialect: Dialect) -> Result<String, Error> {
+    let mut res = String::with_capacity(text.len());
+    for (i, line) in text.split('\n').enumerate() {
+        if i != 0 {
+            res.push('\n');
+        }
+        try!(translate_line(line, i + 1, dialect, &mut res));
+    }
+    Ok(res)
+}
+
+fn translate_line(line: &str,
+                  n: usize,
+                  dialect: Dialect,
+                  res: &mut String)
+                  -> Result<(), Error> {
+    let indent = line.len() - line.trim_left().len();
+    res.push_str(&line[..indent]);
+    let mut code = &line[indent..];
+
+    // `:label`, possibly followed by an instruction.
+    if code.starts_with(':') {
+        let len = 1 + ident_len(&code[1..]);
+        res.push_str(&code[..len]);
+        let rest = &code[len..];
+        let spaces = rest.len() - rest.trim_left().len();
+        res.push_str(&rest[..spaces]);
+        code = &rest[spaces..];
+    }
+
+    let len = ident_len(code);
+    let word = &code[..len];
+    let after = code[len..].chars().next();
+    if code.starts_with('#') || code.starts_with('.') {
+        let name = &code[1..1 + ident_len(&code[1..])];
+        if DIRECTIVES.contains(&name) {
+            if dialect == Dialect::Notch {
+                return Err(Error::Directive(n, code[..1 + name.len()].into()));
+            }
+            res.push('.');
+            code = &code[1..];
+        }
+    } else if word.to_lowercase() == "dat" && after.map_or(false, char::is_whitespace) {
+        res.push_str(".dat");
+        code = &code[len..];
+    } else if len != 0 && after == Some(':') && dialect == Dialect::Notch {
+        return Err(Error::LabelSuffix(n, word.into()));
+    }
+
+    try!(check_numbers(code, n, dialect));
+    res.push_str(code);
+    Ok(())
+}
+
+/// Checks the number prefixes outside of the strings and the comment.
+fn check_numbers(code: &str, n: usize, dialect: Dialect) -> Result<(), Error> {
+    let forbidden: &[&'static str] = match dialect {
+        Dialect::Permissive => return Ok(()),
+        Dialect::Notch => &["0b", "0o"],
+        Dialect::Standard => &["0o"],
+    };
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = ' ';
+    for (i, c) in code.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => (),
+            None if c == ';' => break,
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if !is_ident(previous) => {
+                for &prefix in forbidden {
+                    let number = code[i..].starts_with(prefix) &&
+                                 code[i + 2..].chars().next().map_or(false, |c| c.is_digit(10));
+                    if number {
+                        return Err(Error::NumberPrefix(n, prefix));
+                    }
+                }
+            }
+            None => (),
+        }
+        previous = c;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[test]
+fn test_translate() {
+    let notch = ":loop SET A, 1\n  dat 1, \"0b1\" ; 0o7\n:data DAT 0x10\n";
+    assert_eq!(translate(notch, Dialect::Notch),
+               Ok(":loop SET A, 1\n  .dat 1, \"0b1\" ; 0o7\n:data .dat 0x10\n".into()));
+    assert_eq!(translate("loop: SET A, 1\n", Dialect::Notch),
+               Err(Error::LabelSuffix(1, "loop".into())));
+    assert_eq!(translate("SET A, 1\n.org 4\n", Dialect::Notch),
+               Err(Error::Directive(2, ".org".into())));
+    assert_eq!(translate("SET A, 0b11\n", Dialect::Notch),
+               Err(Error::NumberPrefix(1, "0b")));
+
+    let standard = "#macro inc(r)\nADD r, 1\n#endmacro\n.loop:\nSET A, 0b11\n";
+    assert_eq!(translate(standard, Dialect::Standard),
+               Ok(".macro inc(r)\nADD r, 1\n.endmacro\n.loop:\nSET A, 0b11\n".into()));
+    assert_eq!(translate("SET A, 0o7\n", Dialect::Standard),
+               Err(Error::NumberPrefix(1, "0o")));
+    assert_eq!(translate("SET A, 0o7\n", Dialect::Permissive),
+               Ok("SET A, 0o7\n".into()));
+}
@@ -0,0 +1,354 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use nom::IResult;
+
+use assembler::dialect::{self, Dialect};
+use assembler::parser;
+use assembler::types::{DatItem, Directive, ParsedItem};
+use preprocessor;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Included path and file containing the `.include`.
+    NotFound(String, PathBuf),
+    Io(PathBuf, io::Error),
+    /// Chain of includes, starting and ending with the same file.
+    Cycle(Vec<PathBuf>),
+    Preprocessor(PathBuf),
+    /// File, line, column and text which couldn't be parsed.
+    Syntax(PathBuf, usize, usize, String),
+    /// File and error, with the line translated to the original file.
+    Limits(PathBuf, parser::Error),
+    /// File and error, with the line translated to the original file.
+    Dialect(PathBuf, dialect::Error),
+    /// File of a `.incbin`, end of the requested bytes and size of the file.
+    OutOfFile(PathBuf, usize, usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound(ref path, ref from) => {
+                write!(f, "{}: cannot find included file \"{}\"", from.display(), path)
+            }
+            Error::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            Error::Cycle(ref chain) => {
+                try!(write!(f, "include cycle: "));
+                for (i, p) in chain.iter().enumerate() {
+                    if i != 0 {
+                        try!(write!(f, " -> "));
+                    }
+                    try!(write!(f, "{}", p.display()));
+                }
+                Ok(())
+            }
+            Error::Preprocessor(ref path) => {
+                write!(f, "{}: preprocessor failed", path.display())
+            }
+            Error::Syntax(ref path, 0, _, _) => write!(f, "{}: syntax error", path.display()),
+            Error::Syntax(ref path, line, column, ref text) => {
+                write!(f, "{}:{}:{}: syntax error at \"{}\"", path.display(), line, column, text)
+            }
+            Error::Limits(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            Error::Dialect(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            Error::OutOfFile(ref path, end, size) => {
+                write!(f, "{}: byte {} requested, but the file is {} bytes long",
+                       path.display(), end, size)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound(..) => "included file not found",
+            Error::Io(_, ref e) => e.description(),
+            Error::Cycle(_) => "include cycle",
+            Error::Preprocessor(_) => "preprocessor failed",
+            Error::Syntax(..) => "syntax error",
+            Error::Limits(_, ref e) => error::Error::description(e),
+            Error::Dialect(_, ref e) => error::Error::description(e),
+            Error::OutOfFile(..) => "incbin past the end of the file",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(_, ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Where an item comes from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Position {
+    /// Index in `Program::files`.
+    pub file: usize,
+    /// Starting at 1, 0 if unknown.
+    pub line: usize,
+    /// Starting at 1, in the preprocessed line if cpp is used.
+    pub column: usize,
+}
+
+/// Items of a file with its includes replaced by their content.
+#[derive(Debug)]
+pub struct Program {
+    pub items: Vec<ParsedItem>,
+    /// Every file read, the main one first.
+    pub files: Vec<PathBuf>,
+    /// Position of each item.
+    pub positions: Vec<Position>,
+}
+
+impl Program {
+    /// File the `i`th item comes from.
+    pub fn file_of(&self, i: usize) -> &Path {
+        &self.files[self.positions[i].file]
+    }
+}
+
+/// Reads and parses assembly files, resolving `.include`s and `.incbin`s.
+///
+/// Relative includes are looked up in the directory of the including file,
+/// then in each of the search paths in order.
+#[derive(Debug, Default)]
+pub struct Loader {
+    pub search_paths: Vec<PathBuf>,
+    /// Run each file through cpp before parsing it.
+    pub preprocess: bool,
+    /// Checked for each file after preprocessing.
+    pub limits: parser::Limits,
+    /// Syntax of the files, translated after preprocessing.
+    pub dialect: Dialect,
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader::default()
+    }
+
+    pub fn load(&self, path: &Path) -> Result<Program, Error> {
+        let asm = try!(read(path));
+        self.load_str(&asm, path)
+    }
+
+    /// Same as `load` but with an already read file. `path` is used to name
+    /// it and to resolve its includes.
+    pub fn load_str(&self, asm: &str, path: &Path) -> Result<Program, Error> {
+        let mut program = Program {
+            items: vec![],
+            files: vec![],
+            positions: vec![],
+        };
+        try!(self.load_into(asm, path, &mut vec![], &mut program));
+        Ok(program)
+    }
+
+    fn load_into(&self,
+                 asm: &str,
+                 path: &Path,
+                 stack: &mut Vec<PathBuf>,
+                 program: &mut Program)
+                 -> Result<(), Error> {
+        let id = path.canonicalize().unwrap_or(path.to_path_buf());
+        if stack.contains(&id) {
+            let mut chain = stack.clone();
+            chain.push(id);
+            return Err(Error::Cycle(chain));
+        }
+
+        // Line of `asm` for each line of the parsed text.
+        let (text, lines) = if self.preprocess {
+            // cpp resolves `#include`s itself, with the same lookup order.
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let dirs = Some(dir).into_iter()
+                .chain(self.search_paths.iter().map(|p| p.as_path()))
+                .collect::<Vec<_>>();
+            try!(preprocessor::preprocess_with_lines(asm, &dirs)
+                     .ok_or(Error::Preprocessor(path.to_path_buf())))
+        } else {
+            (asm.to_string(), (1..asm.lines().count() + 1).collect())
+        };
+        let text = try!(dialect::translate(&text, self.dialect).map_err(|e| {
+            let line = lines.get(e.line() - 1).cloned().unwrap_or(0);
+            let e = match e {
+                dialect::Error::Directive(_, d) => dialect::Error::Directive(line, d),
+                dialect::Error::LabelSuffix(_, l) => dialect::Error::LabelSuffix(line, l),
+                dialect::Error::NumberPrefix(_, p) => dialect::Error::NumberPrefix(line, p),
+            };
+            Error::Dialect(path.to_path_buf(), e)
+        }));
+        let line_at = |offset: usize| {
+            let l = text[..offset].matches('\n').count();
+            lines.get(l).cloned().unwrap_or(0)
+        };
+        let column_at = |offset: usize| {
+            offset - text[..offset].rfind('\n').map_or(0, |n| n + 1) + 1
+        };
+        try!(parser::check_limits(text.as_bytes(), &self.limits).map_err(|e| {
+            let line = |l: usize| lines.get(l - 1).cloned().unwrap_or(0);
+            let e = match e {
+                parser::Error::TooDeep(l) => parser::Error::TooDeep(line(l)),
+                parser::Error::UnterminatedString(l) => {
+                    parser::Error::UnterminatedString(line(l))
+                }
+                e => e,
+            };
+            Error::Limits(path.to_path_buf(), e)
+        }));
+        let items = match parser::parse_located(text.as_bytes()) {
+            IResult::Done(i, o) if i.is_empty() => o,
+            IResult::Done(i, _) => {
+                let offset = text.len() - i.len();
+                let text = String::from_utf8_lossy(i).lines().next().unwrap_or("").into();
+                return Err(Error::Syntax(path.to_path_buf(),
+                                         line_at(offset),
+                                         column_at(offset),
+                                         text));
+            }
+            _ => return Err(Error::Syntax(path.to_path_buf(), 0, 0, "".into())),
+        };
+
+        let file = program.files.len();
+        program.files.push(path.to_path_buf());
+        stack.push(id);
+        for (offset, item) in items {
+            let item = match item {
+                ParsedItem::Include(ref included) => {
+                    let included_path = try!(self.resolve(included, path));
+                    let asm = try!(read(&included_path));
+                    try!(self.load_into(&asm, &included_path, stack, program));
+                    continue;
+                }
+                ParsedItem::IncBin(ref included, start, length, packing) => {
+                    let included_path = try!(self.resolve(included, path));
+                    let mut bytes = vec![];
+                    try!(File::open(&included_path)
+                             .and_then(|mut f| f.read_to_end(&mut bytes))
+                             .map_err(|e| Error::Io(included_path.clone(), e)));
+                    let end = length.map_or(bytes.len(), |l| start + l);
+                    if start > bytes.len() || end > bytes.len() {
+                        return Err(Error::OutOfFile(included_path, end, bytes.len()));
+                    }
+                    let words = packing.pack(&bytes[start..end]);
+                    ParsedItem::Directive(Directive::Dat(words.into_iter()
+                                                              .map(DatItem::N)
+                                                              .collect()))
+                }
+                item => item,
+            };
+            program.items.push(item);
+            program.positions.push(Position {
+                file: file,
+                line: line_at(offset),
+                column: column_at(offset),
+            });
+        }
+        stack.pop();
+        Ok(())
+    }
+
+    fn resolve(&self, included: &str, from: &Path) -> Result<PathBuf, Error> {
+        let included = Path::new(included);
+        if included.is_absolute() {
+            return Ok(included.to_path_buf());
+        }
+
+        let dir = from.parent().unwrap_or(Path::new(""));
+        let candidates = Some(dir).into_iter().chain(self.search_paths.iter().map(|p| p.as_path()));
+        for dir in candidates {
+            let candidate = dir.join(included);
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+        Err(Error::NotFound(included.display().to_string(), from.to_path_buf()))
+    }
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    let mut asm = String::new();
+    try!(File::open(path)
+             .and_then(|mut f| f.read_to_string(&mut asm))
+             .map_err(|e| Error::Io(path.to_path_buf(), e)));
+    Ok(asm)
+}
+
+#[cfg(test)]
+#[test]
+fn test_include() {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    let dir = env::temp_dir().join("dcpu_test_include");
+    fs::create_dir_all(dir.join("lib")).unwrap();
+    let write = |name: &str, content: &str| {
+        File::create(dir.join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    };
+    write("main.dasm", ".include \"a.dasm\"\n\nSET A, 1\n");
+    write("lib/a.dasm", "SET B, 1\n");
+    write("cycle.dasm", ".include \"cycle.dasm\"\n");
+    write("lib/font.bin", "ABC");
+    write("font.dasm", ".incbin \"font.bin\"\n.incbin \"font.bin\", 1, 1, byte\n");
+
+    let mut loader = Loader::new();
+    assert!(match loader.load(&dir.join("main.dasm")) {
+        Err(Error::NotFound(..)) => true,
+        _ => false,
+    });
+
+    loader.search_paths.push(dir.join("lib"));
+    let program = loader.load(&dir.join("main.dasm")).unwrap();
+    assert_eq!(program.items.len(), 2);
+    assert_eq!(program.file_of(0), dir.join("lib/a.dasm").as_path());
+    assert_eq!(program.file_of(1), dir.join("main.dasm").as_path());
+    assert_eq!(program.positions[1].line, 3);
+    assert_eq!(program.positions[1].column, 1);
+
+    assert!(match loader.load_str("SET A, 1\n  @@@\n", Path::new("bad.dasm")) {
+        Err(Error::Syntax(_, 2, 3, ref text)) => text == "@@@",
+        _ => false,
+    });
+
+    let program = loader.load(&dir.join("font.dasm")).unwrap();
+    assert_eq!(program.items,
+               vec![ParsedItem::Directive(Directive::Dat(vec![DatItem::N(0x4241),
+                                                              DatItem::N(0x43)])),
+                    ParsedItem::Directive(Directive::Dat(vec![DatItem::N(0x42)]))]);
+    assert!(match loader.load_str(".incbin \"font.bin\", 2, 2\n", &dir.join("main.dasm")) {
+        Err(Error::OutOfFile(_, 4, 3)) => true,
+        _ => false,
+    });
+
+    loader.dialect = Dialect::Notch;
+    let program = loader.load_str(":start SET A, 1\n  dat 2\n", Path::new("notch.dasm")).unwrap();
+    assert_eq!(program.items.len(), 3);
+    assert_eq!(program.positions[2].line, 2);
+    assert!(match loader.load_str("SET A, 1\nstart: SET A, 1\n", Path::new("notch.dasm")) {
+        Err(Error::Dialect(_, dialect::Error::LabelSuffix(2, _))) => true,
+        _ => false,
+    });
+    loader.dialect = Dialect::Permissive;
+
+    write("cpp.dasm", "#include \"a.dasm\"\nSET A, 1\n");
+    loader.preprocess = true;
+    let program = loader.load(&dir.join("cpp.dasm")).unwrap();
+    assert_eq!(program.items.len(), 2);
+    assert_eq!(program.positions[1].line, 2);
+    loader.preprocess = false;
+
+    assert!(match loader.load(&dir.join("cycle.dasm")) {
+        Err(Error::Cycle(ref chain)) => chain.len() == 2,
+        _ => false,
+    });
+}
@@ -1,6 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
 
+use assembler::peephole::{self, Rewrite};
 use assembler::types::*;
+use encodings::PATCH_POINT;
+use flow;
+use symbols::Symbols;
+use types::Region;
 
 #[derive(Debug)]
 pub enum Error {
@@ -9,21 +16,423 @@ pub enum Error {
     DuplicatedLabel(String),
     DuplicatedLocalLabel(String),
     LocalBeforeGlobal(String),
+    UnknownMacro(String),
+    DuplicatedMacro(String),
+    RecursiveMacro(String),
+    /// Macro name, expected and given number of arguments.
+    MacroArgs(String, usize, usize),
+    /// Text that couldn't be parsed after substitution.
+    MacroSyntax(String),
+    /// Error in the expansion of the given macro invocation.
+    InMacro(String, Box<Error>),
+    /// `.include` or `.incbin` left in the AST, see `include::Loader`.
+    UnresolvedInclude(String),
+    /// Constant defined in terms of itself.
+    RecursiveConstant(String),
+    /// Conditional directive without its `.if` or `.endif`, or left in the
+    /// AST given to the linker.
+    UnbalancedConditional(Directive),
+    DivisionByZero(Expression),
+    /// Expression which isn't a label plus a constant, see
+    /// `object::assemble`.
+    NotRelocatable(Expression),
+    /// Error in the given object, see `object::link`.
+    InObject(String, Box<Error>),
+    /// `.org` in an object, whose address is only known once linked.
+    OrgInObject,
+    /// Relocation past the end of the code of an object.
+    BadRelocation(u16),
+    /// Budget of a `.maxcycles` and the most cycles the function can take.
+    TooManyCycles(u16, u64),
+    /// Instruction or initialized data in the `.bss` section.
+    InitializedBss,
+    /// Parts of the binary placed on the same addresses, by `.org`s or
+    /// the section addresses of `Options`.
+    Overlap(Region, Region),
+    /// Part of the binary going past the end of the memory, its start and
+    /// number of words.
+    PastEnd(u16, usize),
+    /// Instruction or directive of this crate, rejected by
+    /// `Options::strict`.
+    Extension(String),
+    /// Error caused by the given item of the AST, see `link_located`.
+    At(usize, Box<Error>),
+    /// Every error found by the linker when there are several of them.
+    Many(Vec<Error>),
 }
 
+impl Error {
+    /// Removes the `At` wrappers, if any.
+    pub fn without_location(self) -> Error {
+        match self {
+            Error::At(_, e) => *e,
+            Error::Many(errors) => {
+                Error::Many(errors.into_iter().map(Error::without_location).collect())
+            }
+            e => e,
+        }
+    }
+
+    /// The errors in a `Many`, else this one.
+    pub fn into_vec(self) -> Vec<Error> {
+        match self {
+            Error::Many(errors) => errors,
+            e => vec![e],
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownLabel(ref l) => write!(f, "unknown label \"{}\"", l),
+            Error::UnknownLocalLabel(ref l) => write!(f, "unknown local label \".{}\"", l),
+            Error::DuplicatedLabel(ref l) => write!(f, "label \"{}\" is already defined", l),
+            Error::DuplicatedLocalLabel(ref l) => {
+                write!(f, "local label \".{}\" is already defined", l)
+            }
+            Error::LocalBeforeGlobal(ref l) => {
+                write!(f, "local label \".{}\" before any global label", l)
+            }
+            Error::UnknownMacro(ref m) => write!(f, "unknown macro \"{}\"", m),
+            Error::DuplicatedMacro(ref m) => write!(f, "macro \"{}\" is already defined", m),
+            Error::RecursiveMacro(ref m) => write!(f, "macro \"{}\" expands to itself", m),
+            Error::MacroArgs(ref m, expected, given) => {
+                write!(f, "macro \"{}\" takes {} arguments, {} given", m, expected, given)
+            }
+            Error::MacroSyntax(ref text) => {
+                write!(f, "syntax error after macro substitution: \"{}\"", text)
+            }
+            Error::InMacro(ref m, ref e) => write!(f, "in macro \"{}\": {}", m, e),
+            Error::UnresolvedInclude(ref path) => write!(f, "unresolved include of \"{}\"", path),
+            Error::RecursiveConstant(ref c) => write!(f, "constant \"{}\" depends on itself", c),
+            Error::UnbalancedConditional(ref d) => {
+                let name = match *d {
+                    Directive::If(_) => ".if",
+                    Directive::IfDef(_) => ".ifdef",
+                    Directive::IfNDef(_) => ".ifndef",
+                    Directive::Else => ".else",
+                    Directive::EndIf => ".endif",
+                    _ => "directive",
+                };
+                write!(f, "unbalanced {}", name)
+            }
+            Error::DivisionByZero(ref e) => write!(f, "division by zero in {:?}", e),
+            Error::NotRelocatable(ref e) => {
+                write!(f, "{:?} isn't a label plus a constant, it can't be relocated", e)
+            }
+            Error::InObject(ref o, ref e) => write!(f, "in object {}: {}", o, e),
+            Error::OrgInObject => write!(f, ".org in a relocatable object"),
+            Error::BadRelocation(addr) => {
+                write!(f, "relocation at 0x{:04x}, past the end of the code", addr)
+            }
+            Error::TooManyCycles(max, cycles) => {
+                write!(f, "takes up to {} cycles, more than the {} of its .maxcycles", cycles, max)
+            }
+            Error::InitializedBss => write!(f, "instruction or initialized data in .bss"),
+            Error::Overlap(a, b) => {
+                write!(f,
+                       "0x{:04x}-0x{:04x} overlaps 0x{:04x}-0x{:04x}",
+                       a.first,
+                       a.last,
+                       b.first,
+                       b.last)
+            }
+            Error::PastEnd(start, len) => {
+                write!(f,
+                       "0x{:04x}-0x{:04x} goes past the end of the memory",
+                       start,
+                       start as usize + len - 1)
+            }
+            Error::Extension(ref e) => {
+                write!(f, "{} is an extension, not allowed in strict mode", e)
+            }
+            Error::At(i, ref e) => write!(f, "item {}: {}", i, e),
+            Error::Many(ref errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i != 0 {
+                        try!(writeln!(f, ""));
+                    }
+                    try!(write!(f, "{}", e));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::UnknownLabel(_) => "unknown label",
+            Error::UnknownLocalLabel(_) => "unknown local label",
+            Error::DuplicatedLabel(_) => "duplicated label",
+            Error::DuplicatedLocalLabel(_) => "duplicated local label",
+            Error::LocalBeforeGlobal(_) => "local label before any global label",
+            Error::UnknownMacro(_) => "unknown macro",
+            Error::DuplicatedMacro(_) => "duplicated macro",
+            Error::RecursiveMacro(_) => "recursive macro",
+            Error::MacroArgs(..) => "wrong number of macro arguments",
+            Error::MacroSyntax(_) => "syntax error after macro substitution",
+            Error::InMacro(_, ref e) |
+            Error::InObject(_, ref e) |
+            Error::At(_, ref e) => e.description(),
+            Error::UnresolvedInclude(_) => "unresolved include",
+            Error::RecursiveConstant(_) => "recursive constant",
+            Error::UnbalancedConditional(_) => "unbalanced conditional",
+            Error::DivisionByZero(_) => "division by zero",
+            Error::NotRelocatable(_) => "expression can't be relocated",
+            Error::OrgInObject => ".org in a relocatable object",
+            Error::BadRelocation(_) => "relocation past the end of the code",
+            Error::TooManyCycles(..) => "too many cycles",
+            Error::InitializedBss => "initialized data in .bss",
+            Error::Overlap(..) => "overlapping code",
+            Error::PastEnd(..) => "code past the end of the memory",
+            Error::Extension(_) => "extension in strict mode",
+            Error::Many(_) => "several errors",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::InMacro(_, ref e) |
+            Error::InObject(_, ref e) |
+            Error::At(_, ref e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// `errors` as one error, which must not be empty.
+fn many(mut errors: Vec<Error>) -> Error {
+    if errors.len() == 1 {
+        errors.pop().unwrap()
+    } else {
+        Error::Many(errors)
+    }
+}
+
+/// Unwraps the result of the `n`th item, else adds its error to `errors` and
+/// skips to the next item.
+macro_rules! or_skip {
+    ($errors:ident, $n:expr, $res:expr) => {
+        match $res {
+            Ok(v) => v,
+            Err(e) => {
+                $errors.push(Error::At($n, Box::new(e)));
+                continue;
+            }
+        }
+    }
+}
+
+/// Macros must have been expanded with `macros::expand` and conditional
+/// blocks evaluated with `conditionals::evaluate` beforehand.
 pub fn link(ast: &[ParsedItem]) -> Result<Vec<u16>, Error> {
+    link_with_regions(ast).map(|(bin, _)| bin)
+}
+
+/// Same as `link`, but also returns the regions of the binary containing
+/// instructions. Everything else (`.dat`, `.reserve`, the gaps) is data.
+pub fn link_with_regions(ast: &[ParsedItem]) -> Result<(Vec<u16>, Vec<Region>), Error> {
+    link_detailed(ast).map(|l| (l.bin, l.regions))
+}
+
+/// Same as `link`, but also returns the final address of every label. Local
+/// labels are named `global.local`.
+pub fn link_with_symbols(ast: &[ParsedItem]) -> Result<(Vec<u16>, Symbols), Error> {
+    link_detailed(ast).map(|l| (l.bin, l.symbols))
+}
+
+/// Result of `link_detailed`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linked {
+    pub bin: Vec<u16>,
+    /// See `link_with_regions`.
+    pub regions: Vec<Region>,
+    /// Address of each item of the AST.
+    pub addresses: Vec<u16>,
+    /// Number of words taken by each item of the AST. Those of the `.bss`
+    /// section aren't in `bin`.
+    pub sizes: Vec<u16>,
+    /// See `link_with_symbols`.
+    pub symbols: Symbols,
+    /// Words removed by `Options::optimize`.
+    pub saved_words: u16,
+    /// Address of each sled added by `Options::patch_points`.
+    pub patch_points: Vec<u16>,
+}
+
+pub fn link_detailed(ast: &[ParsedItem]) -> Result<Linked, Error> {
+    link_located(ast).map_err(Error::without_location)
+}
 
-    let mut bin = Vec::new();
-    let (mut globals, mut locals) = try!(extract_labels(ast));
-    let mut last_global = None;
+/// Same as `link_detailed`, but the errors caused by an item are wrapped in
+/// `Error::At` with its index.
+///
+/// The linker doesn't stop at the first error: all the unknown and
+/// duplicated labels are reported together in an `Error::Many`.
+pub fn link_located(ast: &[ParsedItem]) -> Result<Linked, Error> {
+    link_with_options(ast, &Options::default())
+}
+
+/// Settings of `link_with_options`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Encode the literals from -1 to 30 given as `a` in the instruction
+    /// word. Without it, they take an extra word like the other literals, so
+    /// the size of the instructions doesn't depend on the value of the
+    /// labels.
+    pub short_literals: bool,
+    /// Apply the peephole optimizations of `peephole::optimize`.
+    pub optimize: bool,
+    /// Address of the `.text` section.
+    pub text_base: u16,
+    /// Address of the `.data` section, right after `.text` if `None`.
+    pub data_base: Option<u16>,
+    /// Address of the `.bss` section, right after `.data` if `None`.
+    pub bss_base: Option<u16>,
+    /// Put an `encodings::PATCH_POINT` at each `.proc`, for the emulator
+    /// to trace the calls to the functions, see `patch`.
+    pub patch_points: bool,
+    /// Reject the opcodes and directives specific to this crate, so the
+    /// program behaves the same with any DCPU-16 1.7 implementation.
+    pub strict: bool,
+}
+
+impl Options {
+    /// Address of each section given their length, in the order of
+    /// `SECTIONS`.
+    fn bases(&self, lengths: &[usize; 3]) -> [u16; 3] {
+        let text = self.text_base;
+        let data = self.data_base.unwrap_or(text.wrapping_add(lengths[0] as u16));
+        let bss = self.bss_base.unwrap_or(data.wrapping_add(lengths[1] as u16));
+        [text, data, bss]
+    }
+}
+
+const SECTIONS: [Section; 3] = [Section::Text, Section::Data, Section::BSS];
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            short_literals: true,
+            optimize: false,
+            text_base: 0,
+            data_base: None,
+            bss_base: None,
+            patch_points: false,
+            strict: false,
+        }
+    }
+}
+
+/// Same as `link_located`, with non-default settings.
+pub fn link_with_options(ast: &[ParsedItem], options: &Options) -> Result<Linked, Error> {
+    let ast = &resolve_numeric_labels(ast)[..];
+    let mut segments = Vec::new();
+    let mut lengths = [0; 3];
+    let mut bases = options.bases(&lengths);
+    let mut regions = Vec::new();
+    let mut addresses = Vec::new();
+    let mut item_segments = Vec::new();
+    let mut errors = vec![];
+    let (mut globals, mut locals) = collect_labels(ast, &mut errors);
+    check_constants_into(ast, &mut errors);
+    if options.strict {
+        check_strict_into(ast, &mut errors);
+    }
+    let mut budgets = Vec::new();
+    let rewrites = if options.optimize {
+        peephole::optimize(ast)
+    } else {
+        HashMap::new()
+    };
+    let mut saved_words = 0;
+    let mut patch_points = vec![];
     let mut changed = true;
 
     while changed {
         changed = false;
-        let mut index = 0u16;
-        for item in ast {
+        segments.clear();
+        segments.extend(SECTIONS.iter().map(|&s| Segment::new(s, None)));
+        let mut current = [0, 1, 2];
+        regions.clear();
+        addresses.clear();
+        item_segments.clear();
+        budgets.clear();
+        patch_points.clear();
+        saved_words = 0;
+        let mut last_global = None;
+        let mut section = Section::Text;
+        for (n, item) in ast.iter().enumerate() {
+            if let ParsedItem::Directive(ref d) = *item {
+                section = d.section().unwrap_or(section);
+            }
+            if section == Section::BSS {
+                match *item {
+                    ParsedItem::ParsedInstruction(_) |
+                    ParsedItem::Directive(Directive::Dat(_)) |
+                    ParsedItem::Directive(Directive::DatP(_, _)) |
+                    ParsedItem::Directive(Directive::Fill(_, _)) => {
+                        errors.push(Error::At(n, Box::new(Error::InitializedBss)));
+                        continue;
+                    }
+                    _ => (),
+                }
+            }
+            globals.insert(HERE.into(), segments[current[section as usize]].end(&bases));
+            if let ParsedItem::Directive(Directive::Org(ref e)) = *item {
+                let addr = match last_global {
+                    Some(ref s) => or_skip!(errors, n, e.solve(&globals, &locals[*s])),
+                    None => or_skip!(errors, n, e.solve(&globals, &HashMap::new())),
+                };
+                segments.push(Segment::new(section, Some(addr)));
+                current[section as usize] = segments.len() - 1;
+            }
+            let segment = &mut segments[current[section as usize]];
+            let mut index = segment.end(&bases);
+            addresses.push(index);
+            item_segments.push(current[section as usize]);
+            globals.insert(HERE.into(), index);
+            let bin = &mut segment.words;
             match *item {
-                ParsedItem::Directive(ref d) => index += d.append_to(&mut bin),
+                ParsedItem::Directive(Directive::Org(_)) => (),
+                ParsedItem::Directive(Directive::MaxCycles(ref e)) => {
+                    let max = match last_global {
+                        Some(ref s) => or_skip!(errors, n, e.solve(&globals, &locals[*s])),
+                        None => or_skip!(errors, n, e.solve(&globals, &HashMap::new())),
+                    };
+                    budgets.push((n, index, max));
+                }
+                ParsedItem::Directive(Directive::Proc(_)) if options.patch_points => {
+                    bin.extend(&PATCH_POINT);
+                    patch_points.push(index);
+                    add_to_regions(&mut regions,
+                                   index,
+                                   index.wrapping_add(PATCH_POINT.len() as u16 - 1));
+                }
+                ParsedItem::Directive(ref d) => {
+                    let size = match last_global {
+                        Some(ref s) => {
+                            or_skip!(errors, n, d.append_to(bin, &globals, &locals[*s]))
+                        }
+                        None => or_skip!(errors, n, d.append_to(bin, &globals, &HashMap::new())),
+                    };
+                    // Past the end of the memory, rejected by `lay_out`.
+                    index = index.wrapping_add(size);
+                }
+                ParsedItem::ConstDecl(ref name, ref e) => {
+                    let value = match last_global {
+                        Some(ref s) => or_skip!(errors, n, e.solve(&globals, &locals[*s])),
+                        None => or_skip!(errors, n, e.solve(&globals, &HashMap::new())),
+                    };
+                    let ptr = globals.get_mut(name).unwrap();
+                    if *ptr != value {
+                        *ptr = value;
+                        changed = true;
+                    }
+                }
                 ParsedItem::LabelDecl(ref s) => {
                     let ptr = globals.get_mut(s).unwrap();
                     if *ptr != index {
@@ -33,57 +442,304 @@ pub fn link(ast: &[ParsedItem]) -> Result<Vec<u16>, Error> {
                     last_global = Some(s);
                 }
                 ParsedItem::LocalLabelDecl(ref s) => {
-                    let ptr = locals.get_mut(*last_global.as_ref().unwrap())
-                                    .unwrap()
-                                    .get_mut(s)
-                                    .unwrap();
+                    // Missing after a `LocalBeforeGlobal`.
+                    let ptr = match last_global.and_then(|g| locals.get_mut(g)) {
+                        Some(locals) => locals.get_mut(s).unwrap(),
+                        None => continue,
+                    };
                     if *ptr != index {
                         changed = true;
                         *ptr = index;
                     }
                 }
                 ParsedItem::ParsedInstruction(ref i) => {
-                    let solved = match last_global {
-                        Some(ref s) => try!(i.solve(&globals, locals.get(*s).unwrap())),
-                        None => try!(i.solve(&globals, &HashMap::new())),
-                    };
-                    bin.extend(&[0xbeaf; 3]);
-                    index += solved.encode(&mut bin[index as usize..]);
-                    bin.truncate(index as usize);
+                    let empty = HashMap::new();
+                    let locals = last_global.map_or(&empty, |s| &locals[s]);
+                    let solved = or_skip!(errors, n, i.solve(&globals, locals));
+                    let mut words = [0; 3];
+                    let mut size = solved.encode_with(&mut words, options.short_literals);
+                    match rewrites.get(&n) {
+                        Some(&Rewrite::Remove) => {
+                            saved_words += size;
+                            continue;
+                        }
+                        Some(&Rewrite::Replace(ref i)) => {
+                            let solved = or_skip!(errors, n, i.solve(&globals, locals));
+                            let new_size = solved.encode_with(&mut words,
+                                                              options.short_literals);
+                            saved_words += size - new_size;
+                            size = new_size;
+                        }
+                        None => (),
+                    }
+                    bin.extend(&words[..size as usize]);
+                    let start = index;
+                    index = index.wrapping_add(size as u16);
+                    add_to_regions(&mut regions, start, index.wrapping_sub(1));
+                }
+                ParsedItem::MacroCall(ref name, _) => {
+                    errors.push(Error::At(n, Box::new(Error::UnknownMacro(name.clone()))))
+                }
+                ParsedItem::Include(ref path) |
+                ParsedItem::IncBin(ref path, _, _, _) => {
+                    errors.push(Error::At(n, Box::new(Error::UnresolvedInclude(path.clone()))))
                 }
                 _ => (),
             }
         }
+        // Later passes would only repeat them.
+        if !errors.is_empty() {
+            return Err(many(errors));
+        }
+
+        let new_lengths = [segments[0].words.len(),
+                           segments[1].words.len(),
+                           segments[2].words.len()];
+        if new_lengths != lengths {
+            lengths = new_lengths;
+            bases = options.bases(&lengths);
+            changed = true;
+        }
     }
 
+    let bin = try!(lay_out(&segments, &bases, &item_segments));
+    regions.sort_by_key(|r| r.first);
+    let mut merged = Vec::new();
+    for r in regions {
+        add_to_regions(&mut merged, r.first, r.last);
+    }
+    let regions = merged;
+
+    // Walking backward, the next item of each segment gives the size.
+    let mut sizes = vec![0; ast.len()];
+    let mut ends = segments.iter().map(|s| s.end(&bases)).collect::<Vec<_>>();
+    for i in (0..ast.len()).rev() {
+        let s = item_segments[i];
+        sizes[i] = ends[s].wrapping_sub(addresses[i]);
+        ends[s] = addresses[i];
+    }
+
+    for &(n, entry, max) in budgets.iter() {
+        match flow::max_cycles(&bin, entry) {
+            Some(cycles) if cycles > max as u64 => {
+                errors.push(Error::At(n, Box::new(Error::TooManyCycles(max, cycles))))
+            }
+            _ => (),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(many(errors));
+    }
+
+    let mut symbols = Symbols::new();
+    for item in ast {
+        if let ParsedItem::LabelDecl(ref s) = *item {
+            symbols.insert(s.clone(), globals[s]);
+            for (l, &addr) in locals[s].iter() {
+                symbols.insert(format!("{}.{}", s, l), addr);
+            }
+        }
+    }
+
+    Ok(Linked {
+        bin: bin,
+        regions: regions,
+        addresses: addresses,
+        sizes: sizes,
+        symbols: symbols,
+        saved_words: saved_words,
+        patch_points: patch_points,
+    })
+}
+
+/// Words following each other in a section.
+struct Segment {
+    section: Section,
+    /// Set by a `.org`, else the segment starts at the base of the section.
+    start: Option<u16>,
+    words: Vec<u16>,
+}
+
+impl Segment {
+    fn new(section: Section, start: Option<u16>) -> Segment {
+        Segment {
+            section: section,
+            start: start,
+            words: vec![],
+        }
+    }
+
+    fn start(&self, bases: &[u16; 3]) -> u16 {
+        self.start.unwrap_or(bases[self.section as usize])
+    }
+
+    /// Address of the next word.
+    fn end(&self, bases: &[u16; 3]) -> u16 {
+        self.start(bases).wrapping_add(self.words.len() as u16)
+    }
+}
+
+/// Copies the segments of `.text` and `.data` at their address, checking
+/// that no segments overlap. `item_segments` is the index of the segment of
+/// each item, an overlap being reported at the first item of both segments.
+fn lay_out(segments: &[Segment],
+           bases: &[u16; 3],
+           item_segments: &[usize])
+           -> Result<Vec<u16>, Error> {
+    let range = |s: usize| {
+        let start = segments[s].start(bases) as usize;
+        (start, start + segments[s].words.len())
+    };
+    let mut placed = (0..segments.len())
+                         .filter(|&s| !segments[s].words.is_empty())
+                         .collect::<Vec<_>>();
+    placed.sort_by_key(|&s| range(s));
+    // The section directive or the `.org` starting the segment.
+    let at = |s: usize, e: Error| {
+        match item_segments.iter().position(|&i| i == s) {
+            Some(n) => Error::At(n, Box::new(e)),
+            None => e,
+        }
+    };
+    if let Some(&s) = placed.iter().find(|&&s| range(s).1 > 0x10000) {
+        let (start, end) = range(s);
+        return Err(at(s, Error::PastEnd(start as u16, end - start)));
+    }
+    for pair in placed.windows(2) {
+        let ((a_start, a_end), (b_start, b_end)) = (range(pair[0]), range(pair[1]));
+        if b_start < a_end {
+            let region = |start: usize, end: usize| {
+                Region {
+                    first: start as u16,
+                    last: (end - 1) as u16,
+                }
+            };
+            let (a, b) = (region(a_start, a_end), region(b_start, b_end));
+            return Err(Error::Many(vec![at(pair[0], Error::Overlap(a, b)),
+                                        at(pair[1], Error::Overlap(b, a))]));
+        }
+    }
+
+    let mut bin = vec![];
+    for &s in placed.iter().filter(|&&s| segments[s].section != Section::BSS) {
+        let (start, end) = range(s);
+        if bin.len() < end {
+            bin.resize(end, 0);
+        }
+        bin[start..end].copy_from_slice(&segments[s].words);
+    }
     Ok(bin)
 }
 
-fn extract_labels
+fn add_to_regions(regions: &mut Vec<Region>, first: u16, last: u16) {
+    if let Some(r) = regions.last_mut() {
+        if r.last.wrapping_add(1) == first {
+            r.last = last;
+            return;
+        }
+    }
+    regions.push(Region {
+        first: first,
+        last: last,
+    });
+}
+
+/// Replaces each numeric label (`1:`) by a constant holding its address,
+/// and the references to them (`1b`, `1f`) by the name of that constant.
+/// The references without a matching label are left as is and fail to
+/// solve.
+pub fn resolve_numeric_labels(ast: &[ParsedItem]) -> Vec<ParsedItem> {
+    let mut definitions = HashMap::new();
+    for (i, item) in ast.iter().enumerate() {
+        if let ParsedItem::NumericLabelDecl(n) = *item {
+            definitions.entry(n).or_insert_with(Vec::new).push(i);
+        }
+    }
+    let name = |n: u16, k: usize| format!("{}{}{}", n, HERE, k);
+
+    ast.iter()
+       .enumerate()
+       .map(|(i, item)| {
+           if let ParsedItem::NumericLabelDecl(n) = *item {
+               let k = definitions[&n].iter().position(|&d| d == i).unwrap();
+               return ParsedItem::ConstDecl(name(n, k), Expression::Here);
+           }
+           item.map_expressions(&|e| {
+               let (n, k) = match *e {
+                   Expression::Backward(n) => {
+                       let before = definitions.get(&n).map_or(0, |d| {
+                           d.iter().take_while(|&&d| d < i).count()
+                       });
+                       (n, before.checked_sub(1))
+                   }
+                   Expression::Forward(n) => {
+                       let k = definitions.get(&n).and_then(|d| d.iter().position(|&d| d > i));
+                       (n, k)
+                   }
+                   _ => return None,
+               };
+               k.map(|k| Expression::Label(name(n, k)))
+           })
+       })
+       .collect()
+}
+
+/// Declared global and local labels and constants, with 0 as value.
+pub fn extract_labels
+    (ast: &[ParsedItem])
+     -> Result<(HashMap<String, u16>, HashMap<String, HashMap<String, u16>>), Error> {
+    extract_labels_located(ast).map_err(Error::without_location)
+}
+
+fn extract_labels_located
     (ast: &[ParsedItem])
      -> Result<(HashMap<String, u16>, HashMap<String, HashMap<String, u16>>), Error> {
+    let mut errors = vec![];
+    let labels = collect_labels(ast, &mut errors);
+    if errors.is_empty() {
+        Ok(labels)
+    } else {
+        Err(many(errors))
+    }
+}
+
+/// Same as `extract_labels_located`, adding the errors to `errors`. Only the
+/// first declaration of a duplicated label is kept.
+fn collect_labels(ast: &[ParsedItem],
+                  errors: &mut Vec<Error>)
+                  -> (HashMap<String, u16>, HashMap<String, HashMap<String, u16>>) {
     let mut prev_label = None;
     let mut globals = HashMap::new();
     let mut locals = HashMap::new();
 
-    for item in ast.iter() {
+    for (i, item) in ast.iter().enumerate() {
         match *item {
             ParsedItem::LabelDecl(ref s) => {
                 prev_label = Some(s.clone());
                 if globals.contains_key(s) {
-                    return Err(Error::DuplicatedLabel(s.clone()));
+                    errors.push(Error::At(i, Box::new(Error::DuplicatedLabel(s.clone()))));
                 } else {
                     globals.insert(s.clone(), 0);
                     locals.insert(s.clone(), HashMap::new());
                 }
             }
-            ParsedItem::LocalLabelDecl(ref s) => {
-                if prev_label.is_none() {
-                    return Err(Error::LocalBeforeGlobal(s.clone()));
+            ParsedItem::ConstDecl(ref s, _) => {
+                if globals.contains_key(s) {
+                    errors.push(Error::At(i, Box::new(Error::DuplicatedLabel(s.clone()))));
+                } else {
+                    globals.insert(s.clone(), 0);
                 }
-                let locals = locals.get_mut(prev_label.as_ref().unwrap()).unwrap();
+            }
+            ParsedItem::LocalLabelDecl(ref s) => {
+                let locals = match prev_label {
+                    Some(ref l) => locals.get_mut(l).unwrap(),
+                    None => {
+                        errors.push(Error::At(i, Box::new(Error::LocalBeforeGlobal(s.clone()))));
+                        continue;
+                    }
+                };
                 if locals.contains_key(s) {
-                    return Err(Error::DuplicatedLocalLabel(s.clone()));
+                    errors.push(Error::At(i, Box::new(Error::DuplicatedLocalLabel(s.clone()))));
                 } else {
                     locals.insert(s.clone(), 0);
                 }
@@ -92,5 +748,265 @@ fn extract_labels
         }
     }
 
-    Ok((globals, locals))
+    (globals, locals)
+}
+
+/// Constants can use constants defined later, as long as there is no cycle.
+pub fn check_constants(ast: &[ParsedItem]) -> Result<(), Error> {
+    check_constants_located(ast).map_err(Error::without_location)
+}
+
+fn check_constants_located(ast: &[ParsedItem]) -> Result<(), Error> {
+    let mut errors = vec![];
+    check_constants_into(ast, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(many(errors))
+    }
+}
+
+fn check_strict_into(ast: &[ParsedItem], errors: &mut Vec<Error>) {
+    for (i, item) in ast.iter().enumerate() {
+        let extension = match *item {
+            ParsedItem::ParsedInstruction(ParsedInstruction::SpecialOp(op, _))
+                if op.is_extension() => op.mnemonic().into(),
+            ParsedItem::Directive(Directive::DatP(..)) => ".datp".into(),
+            ParsedItem::Directive(Directive::MaxCycles(_)) => ".maxcycles".into(),
+            ParsedItem::Directive(Directive::Proc(_)) => ".proc".into(),
+            ParsedItem::Directive(ref d) if d.section().is_some() => "section".into(),
+            _ => continue,
+        };
+        errors.push(Error::At(i, Box::new(Error::Extension(extension))));
+    }
+}
+
+fn check_constants_into(ast: &[ParsedItem], errors: &mut Vec<Error>) {
+    let constants = ast.iter()
+                       .filter_map(|i| match *i {
+                           ParsedItem::ConstDecl(ref name, ref e) => Some((name.as_str(), e)),
+                           _ => None,
+                       })
+                       .collect::<HashMap<_, _>>();
+    let mut in_cycle = HashSet::new();
+    for (i, item) in ast.iter().enumerate() {
+        if let ParsedItem::ConstDecl(ref name, _) = *item {
+            if in_cycle.contains(name.as_str()) {
+                continue;
+            }
+            let mut stack = vec![];
+            if let Err(e) = check_constant(name, &constants, &mut stack) {
+                // Reported once per cycle.
+                in_cycle.extend(stack);
+                errors.push(Error::At(i, Box::new(e)));
+            }
+        }
+    }
+}
+
+fn check_constant<'a>(name: &'a str,
+                      constants: &HashMap<&'a str, &'a Expression>,
+                      stack: &mut Vec<&'a str>)
+                      -> Result<(), Error> {
+    if stack.contains(&name) {
+        return Err(Error::RecursiveConstant(name.into()));
+    }
+    stack.push(name);
+    for label in constants[name].labels() {
+        if constants.contains_key(label) {
+            try!(check_constant(label, constants, stack));
+        }
+    }
+    stack.pop();
+    Ok(())
+}
+
+/// AST of `s`, which must be valid.
+#[cfg(test)]
+fn parse_ok(s: &str) -> Vec<ParsedItem> {
+    use nom::IResult;
+
+    use assembler::parser;
+
+    match parser::parse(s.as_bytes()) {
+        IResult::Done(_, ast) => ast,
+        _ => panic!("{}", s),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_regions() {
+    use types::{BasicOp, Register};
+
+    let set = ParsedItem::ParsedInstruction(
+        ParsedInstruction::BasicOp(BasicOp::SET,
+                                   ParsedValue::Reg(Register::A),
+                                   ParsedValue::Reg(Register::B)));
+    let ast = vec![set.clone(),
+                   set.clone(),
+                   ParsedItem::Directive(Directive::Dat(vec![DatItem::N(1)])),
+                   set];
+    let (bin, regions) = link_with_regions(&ast).unwrap();
+    assert_eq!(bin.len(), 4);
+    assert_eq!(regions,
+               vec![Region { first: 0, last: 1 }, Region { first: 3, last: 3 }]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_constants() {
+    let link_str = |s: &str| link(&parse_ok(s));
+    assert_eq!(link_str(".equ SIZE, WIDTH * 2\n.equ WIDTH, 3\n.dat SIZE\n.org WIDTH\n.dat end\nend:")
+                   .unwrap(),
+               vec![6, 0, 0, 4]);
+    assert!(match link_str(".equ A, B\n.equ B, A + 1") {
+        Err(Error::RecursiveConstant(_)) => true,
+        _ => false,
+    });
+}
+
+#[cfg(test)]
+#[test]
+fn test_symbols() {
+    let ast = parse_ok(".equ SIZE, 2\nSET A, 1\nmain:\n.dat 1\n.loop:\nSET PC, .loop\n");
+    let (_, symbols) = link_with_symbols(&ast).unwrap();
+    assert_eq!(symbols.to_string(), "main 0x0001\nmain.loop 0x0002\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_short_literals() {
+    let ast = parse_ok("SET PC, end\nSET A, 0x100\nend:\n");
+    assert_eq!(link(&ast).unwrap(), vec![0x9381, 0x7c01, 0x0100]);
+    let long = Options { short_literals: false, ..Options::default() };
+    assert_eq!(link_with_options(&ast, &long).unwrap().bin,
+               vec![0x7f81, 0x0004, 0x7c01, 0x0100]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_optimize() {
+    let ast = parse_ok("SET A, 0x100\nSET PC, end\nend:\nSET PC, end\n");
+    let options = Options { optimize: true, ..Options::default() };
+    let linked = link_with_options(&ast, &options).unwrap();
+    assert_eq!(linked.bin, vec![0x7c01, 0x0100, 0x8f81]);
+    assert_eq!(linked.saved_words, 1);
+    assert_eq!(linked.addresses, vec![0, 2, 2, 2]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_sections() {
+    let ast = parse_ok(".data\ncount:\n.dat 5\n.bss\nbuf:\n.reserve 4\n.text\nSET A, [count]\n\
+                        SET B, buf\n");
+    let linked = link_detailed(&ast).unwrap();
+    assert_eq!(linked.bin, vec![0x7801, 3, 0x9421, 5]);
+    assert_eq!(linked.addresses, vec![3, 3, 3, 4, 4, 4, 0, 0, 2]);
+    assert_eq!(linked.sizes, vec![0, 0, 1, 0, 0, 4, 0, 2, 1]);
+    assert_eq!(linked.regions,
+               vec![Region {
+                        first: 0,
+                        last: 2,
+                    }]);
+
+    let placed = Options { data_base: Some(0x10), bss_base: Some(0x20), ..Options::default() };
+    let bin = link_with_options(&ast, &placed).unwrap().bin;
+    assert_eq!(bin.len(), 0x11);
+    assert_eq!(&bin[..4], &[0x7801, 0x10, 0x7c21, 0x20]);
+    assert_eq!(bin[0x10], 5);
+
+    let overlapping = Options { data_base: Some(1), ..Options::default() };
+    // At the .text and the .data.
+    let errors = link_with_options(&ast, &overlapping).err().unwrap().into_vec();
+    let (text, data) = (Region { first: 0, last: 2 }, Region { first: 1, last: 1 });
+    assert_eq!(errors.len(), 2);
+    match (&errors[0], &errors[1]) {
+        (&Error::At(6, ref a), &Error::At(0, ref b)) => {
+            match (&**a, &**b) {
+                (&Error::Overlap(a1, a2), &Error::Overlap(b1, b2)) => {
+                    assert_eq!((a1, a2, b1, b2), (text, data, data, text))
+                }
+                e => panic!("{:?}", e),
+            }
+        }
+        e => panic!("{:?}", e),
+    }
+    match link_detailed(&parse_ok(".bss\n.dat 1\n")) {
+        Err(Error::InitializedBss) => (),
+        e => panic!("{:?}", e),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_org() {
+    let link_str = |s: &str| link_detailed(&parse_ok(s));
+    let linked = link_str("SET PC, end\n.org 0x10\nend:\n.dat 1\n.org 4\n.dat 2, $\n").unwrap();
+    assert_eq!(linked.bin,
+               vec![0xc781, 0, 0, 0, 2, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!(linked.addresses, vec![0, 0x10, 0x10, 0x10, 4, 4]);
+    let errors = link_str(".dat 1, 2\n.org 1\n.dat 3\n").err().unwrap().into_vec();
+    let (first, org) = (Region { first: 0, last: 1 }, Region { first: 1, last: 1 });
+    assert_eq!(errors.len(), 2);
+    match (&errors[0], &errors[1]) {
+        (&Error::Overlap(a1, a2), &Error::Overlap(b1, b2)) => {
+            assert_eq!((a1, a2, b1, b2), (first, org, org, first))
+        }
+        e => panic!("{:?}", e),
+    }
+    match link_located(&parse_ok("SET A, 1\n.org 0xfffe\n.dat 1, 2, 3\n")) {
+        Err(Error::At(1, ref e)) => {
+            assert_eq!(e.to_string(), "0xfffe-0x10000 goes past the end of the memory")
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_many_errors() {
+    let ast = parse_ok(".loop:\nSET A, foo\nx:\nx:\nSET B, bar\n");
+    let errors = link_located(&ast)
+                     .unwrap_err()
+                     .into_vec()
+                     .into_iter()
+                     .map(|e| match e {
+                         Error::At(i, e) => (i, format!("{:?}", e)),
+                         e => panic!("{:?}", e),
+                     })
+                     .collect::<Vec<_>>();
+    assert_eq!(errors,
+               vec![(0, "LocalBeforeGlobal(\"loop\")".into()),
+                    (3, "DuplicatedLabel(\"x\")".into()),
+                    (1, "UnknownLabel(\"foo\")".into()),
+                    (4, "UnknownLabel(\"bar\")".into())]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_patch_points() {
+    use encodings::NOP;
+
+    let ast = parse_ok("JSR f\nf:\n.proc\nSET A, 1\nSET PC, POP\n");
+    let options = Options { patch_points: true, ..Options::default() };
+    let linked = link_with_options(&ast, &options).unwrap();
+    assert_eq!(linked.bin, vec![0x8820, NOP, NOP, 0x8801, 0x6381]);
+    assert_eq!(linked.patch_points, vec![1]);
+    assert_eq!(linked.symbols.describe(1), "f");
+    assert_eq!(link_detailed(&ast).unwrap().bin, vec![0x8820, 0x8801, 0x6381]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_strict() {
+    let ast = parse_ok("f:\n.proc\nSET A, 1\nHLT 0\n.data\n.dat 1\n");
+    assert!(link_with_options(&ast, &Options::default()).is_ok());
+    let options = Options { strict: true, ..Options::default() };
+    let errors = link_with_options(&ast, &options).unwrap_err().into_vec();
+    let errors = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(errors,
+               vec!["item 1: .proc is an extension, not allowed in strict mode",
+                    "item 3: HLT is an extension, not allowed in strict mode",
+                    "item 4: section is an extension, not allowed in strict mode"]);
 }
@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use assembler::include::Position;
+use assembler::linker::Linked;
+use assembler::types::*;
+
+/// Words shown on a line of the listing.
+const WORDS_PER_LINE: usize = 3;
+
+/// Content of a source line: words, and the ones of the `.fill`s and
+/// `.reserve`s too long to be shown, as `<n words>`.
+enum Cell {
+    Word(u16),
+    Elided(usize),
+}
+
+/// Returns each line of `sources` (name and content of `Program::files`)
+/// preceded by the address and the words emitted for it.
+///
+/// `ast`, `positions` and `linked` are the output of
+/// `macros::expand_with_positions` or `conditionals::evaluate_with_positions`
+/// and `linker::link_detailed`. The words of a macro invocation are listed on
+/// its line, the `.fill`s and `.reserve`s longer than a line as `<n words>`.
+pub fn listing(sources: &[(String, String)],
+               ast: &[ParsedItem],
+               positions: &[Position],
+               linked: &Linked)
+               -> String {
+    let mut lines = BTreeMap::new();
+    for (i, (item, pos)) in ast.iter().zip(positions).enumerate() {
+        let start = linked.addresses[i];
+        let end = start as usize + linked.sizes[i] as usize;
+        let words = &linked.bin[start as usize..end];
+        let cells = match *item {
+            ParsedItem::Comment(_) => continue,
+            ParsedItem::Directive(Directive::Fill(_, _)) |
+            ParsedItem::Directive(Directive::Reserve(_)) if words.len() > WORDS_PER_LINE => {
+                vec![Cell::Elided(words.len())]
+            }
+            _ => words.iter().map(|&w| Cell::Word(w)).collect(),
+        };
+        if pos.line == 0 {
+            continue;
+        }
+        lines.entry((pos.file, pos.line))
+             .or_insert((start, vec![]))
+             .1
+             .extend(cells);
+    }
+
+    let mut res = String::new();
+    for (file, &(ref name, ref source)) in sources.iter().enumerate() {
+        writeln!(res, "; {}", name).unwrap();
+        for (n, text) in source.lines().enumerate() {
+            let (addr, cells) = match lines.get(&(file, n + 1)) {
+                Some(&(addr, ref cells)) => (addr, &cells[..]),
+                None => {
+                    writeln!(res, "{:21}{}", "", text).unwrap();
+                    continue;
+                }
+            };
+            let mut rows = rows(addr, cells).into_iter();
+            let (addr, first) = rows.next().unwrap_or((addr, String::new()));
+            writeln!(res, "{:04x}  {:15}{}", addr, first, text).unwrap();
+            for (addr, row) in rows {
+                writeln!(res, "{:04x}  {}", addr, row).unwrap();
+            }
+        }
+    }
+    res
+}
+
+/// `cells` starting at `addr`, split in rows of at most `WORDS_PER_LINE`
+/// words, with their address.
+fn rows(mut addr: u16, cells: &[Cell]) -> Vec<(u16, String)> {
+    let mut rows = vec![];
+    let mut words = vec![];
+    for cell in cells {
+        match *cell {
+            Cell::Word(w) => {
+                words.push(format!("{:04x}", w));
+                if words.len() == WORDS_PER_LINE {
+                    rows.push((addr, words.join(" ")));
+                    addr = addr.wrapping_add(words.len() as u16);
+                    words.clear();
+                }
+            }
+            Cell::Elided(n) => {
+                if !words.is_empty() {
+                    rows.push((addr, words.join(" ")));
+                    addr = addr.wrapping_add(words.len() as u16);
+                    words.clear();
+                }
+                rows.push((addr, format!("<{} words>", n)));
+                addr = addr.wrapping_add(n as u16);
+            }
+        }
+    }
+    if !words.is_empty() {
+        rows.push((addr, words.join(" ")));
+    }
+    rows
+}
+
+#[cfg(test)]
+#[test]
+fn test_listing() {
+    use std::path::Path;
+
+    use assembler::{include, linker, macros};
+
+    let source = "; start\n\
+                  SET A, 0x1000\n\
+                  :loop\n\
+                  .dat 1, 2, 3, 4\n\
+                  SET PC, loop\n\
+                  .fill 7, 2\n\
+                  .reserve 0x100\n";
+    let program = include::Loader::default().load_str(source, Path::new("main.dasm")).unwrap();
+    let (ast, positions) = macros::expand_with_positions(&program.items, &program.positions)
+                               .unwrap();
+    let linked = linker::link_detailed(&ast).unwrap();
+    assert_eq!(listing(&[("main.dasm".into(), source.into())], &ast, &positions, &linked),
+               "; main.dasm\n\
+               \x20                    ; start\n\
+               0000  7c01 1000      SET A, 0x1000\n\
+               0002                 :loop\n\
+               0002  0001 0002 0003 .dat 1, 2, 3, 4\n\
+               0005  0004\n\
+               0006  8f81           SET PC, loop\n\
+               0007  0007 0007      .fill 7, 2\n\
+               0009  <256 words>    .reserve 0x100\n");
+}
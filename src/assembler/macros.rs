@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::str;
+
+use nom::IResult;
+
+use assembler::linker::Error;
+use assembler::parser;
+use assembler::types::*;
+
+/// Replaces the macro invocations by their body. The declarations are
+/// removed.
+pub fn expand(ast: &[ParsedItem]) -> Result<Vec<ParsedItem>, Error> {
+    expand_with_positions(ast, &vec![(); ast.len()])
+        .map(|(items, _)| items)
+        .map_err(Error::without_location)
+}
+
+/// Same as `expand`, with a position (e.g. `include::Position`) for each
+/// item. The items of an expansion get the position of the invocation, the
+/// errors are wrapped in `Error::At` with the index of the invocation.
+pub fn expand_with_positions<P: Copy>(ast: &[ParsedItem],
+                                      positions: &[P])
+                                      -> Result<(Vec<ParsedItem>, Vec<P>), Error> {
+    let mut macros = HashMap::new();
+    for (i, item) in ast.iter().enumerate() {
+        if let ParsedItem::MacroDecl(ref m) = *item {
+            if macros.insert(m.name.clone(), m).is_some() {
+                return Err(Error::At(i, Box::new(Error::DuplicatedMacro(m.name.clone()))));
+            }
+        }
+    }
+
+    let mut expanded = vec![];
+    let mut expanded_positions = vec![];
+    for (i, &position) in positions.iter().enumerate().take(ast.len()) {
+        try!(expand_into(&ast[i..i + 1], &macros, &mut vec![], &mut expanded)
+                 .map_err(|e| Error::At(i, Box::new(e))));
+        expanded_positions.resize(expanded.len(), position);
+    }
+    Ok((expanded, expanded_positions))
+}
+
+fn expand_into<'a>(ast: &[ParsedItem],
+                   macros: &HashMap<String, &'a Macro>,
+                   stack: &mut Vec<&'a str>,
+                   output: &mut Vec<ParsedItem>)
+                   -> Result<(), Error> {
+    for item in ast {
+        match *item {
+            ParsedItem::MacroDecl(_) => (),
+            ParsedItem::MacroCall(ref name, ref args) => {
+                let m = match macros.get(name) {
+                    Some(m) => *m,
+                    None => return Err(Error::UnknownMacro(name.clone())),
+                };
+                try!(expand_call(m, args, macros, stack, output).map_err(|e| {
+                    Error::InMacro(format!("{}({})", name, args.join(", ")), Box::new(e))
+                }));
+            }
+            ref i => output.push(i.clone()),
+        }
+    }
+    Ok(())
+}
+
+fn expand_call<'a>(m: &'a Macro,
+                   args: &[String],
+                   macros: &HashMap<String, &'a Macro>,
+                   stack: &mut Vec<&'a str>,
+                   output: &mut Vec<ParsedItem>)
+                   -> Result<(), Error> {
+    if stack.contains(&m.name.as_str()) {
+        return Err(Error::RecursiveMacro(m.name.clone()));
+    }
+    if args.len() != m.args.len() {
+        return Err(Error::MacroArgs(m.name.clone(), m.args.len(), args.len()));
+    }
+
+    let body = substitute(&m.body, &m.args, args);
+    if parser::check_limits(body.as_bytes(), &parser::Limits::default()).is_err() {
+        return Err(Error::MacroSyntax(body));
+    }
+    let items = match parser::parse(body.as_bytes()) {
+        IResult::Done(i, ref o) if i.is_empty() => o.clone(),
+        IResult::Done(i, _) => {
+            return Err(Error::MacroSyntax(String::from_utf8_lossy(i).into_owned()))
+        }
+        _ => return Err(Error::MacroSyntax(body.clone())),
+    };
+
+    stack.push(&m.name);
+    let res = expand_into(&items, macros, stack, output);
+    stack.pop();
+    res
+}
+
+/// Replaces each identifier of `text` found in `params` by the corresponding
+/// value of `args`.
+fn substitute(text: &str, params: &[String], args: &[String]) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut ident = String::new();
+    for c in text.chars().chain(Some('\n')) {
+        if c.is_alphanumeric() || c == '_' {
+            ident.push(c);
+            continue;
+        }
+        if !ident.is_empty() {
+            match params.iter().position(|p| *p == ident) {
+                Some(idx) => res.push_str(&args[idx]),
+                None => res.push_str(&ident),
+            }
+            ident.clear();
+        }
+        res.push(c);
+    }
+    res.pop();
+    res
+}
+
+#[cfg(test)]
+#[test]
+fn test_expand() {
+    use types::{BasicOp, Register};
+
+    let m = Macro {
+        name: "inc".into(),
+        args: vec!["reg".into(), "n".into()],
+        body: "\nADD reg, n\n".into(),
+    };
+    let ast = vec![ParsedItem::MacroDecl(m),
+                   ParsedItem::MacroCall("inc".into(), vec!["A".into(), "2".into()])];
+    assert_eq!(expand(&ast).unwrap(),
+               vec![ParsedItem::ParsedInstruction(
+                   ParsedInstruction::BasicOp(BasicOp::ADD,
+                                              ParsedValue::Reg(Register::A),
+                                              ParsedValue::Litteral(Num::U(2).into())))]);
+}
@@ -1,3 +1,305 @@
+pub mod conditionals;
+pub mod dialect;
+pub mod include;
 pub mod linker;
+pub mod listing;
+pub mod macros;
+pub mod object;
 pub mod parser;
+pub mod peephole;
 pub mod types;
+pub mod warnings;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use debug_info::{self, DebugInfo};
+use self::types::ParsedItem;
+
+/// Any error of the assembler, so its steps compose with `try!`.
+#[derive(Debug)]
+pub enum Error {
+    Include(include::Error),
+    Link(linker::Error),
+    /// File, line and column of the item causing the error.
+    At(PathBuf, usize, usize, linker::Error),
+    Many(Vec<Error>),
+}
+
+impl Error {
+    /// Replaces the item indices of `e` by their place in `files`.
+    pub fn locate(e: linker::Error, positions: &[include::Position], files: &[PathBuf]) -> Error {
+        let mut errors = e.into_vec()
+                          .into_iter()
+                          .map(|e| {
+                              match e {
+                                  linker::Error::At(i, e) if positions[i].line != 0 => {
+                                      let pos = positions[i];
+                                      Error::At(files[pos.file].clone(), pos.line, pos.column, *e)
+                                  }
+                                  e => Error::Link(e.without_location()),
+                              }
+                          })
+                          .collect::<Vec<_>>();
+        if errors.len() == 1 {
+            errors.pop().unwrap()
+        } else {
+            Error::Many(errors)
+        }
+    }
+}
+
+impl From<include::Error> for Error {
+    fn from(e: include::Error) -> Error {
+        Error::Include(e)
+    }
+}
+
+impl From<linker::Error> for Error {
+    fn from(e: linker::Error) -> Error {
+        Error::Link(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Include(ref e) => e.fmt(f),
+            Error::Link(ref e) => e.fmt(f),
+            Error::At(ref file, line, column, ref e) => {
+                write!(f, "{}:{}:{}: {}", file.display(), line, column, e)
+            }
+            Error::Many(ref errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i != 0 {
+                        try!(writeln!(f, ""));
+                    }
+                    try!(write!(f, "{}", e));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Include(ref e) => e.description(),
+            Error::Link(ref e) |
+            Error::At(_, _, _, ref e) => e.description(),
+            Error::Many(_) => "several errors",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Include(ref e) => Some(e),
+            Error::Link(ref e) |
+            Error::At(_, _, _, ref e) => Some(e),
+            Error::Many(_) => None,
+        }
+    }
+}
+
+/// Problem found by `assemble_str`. `line` and `column` start at 1, 0 if
+/// unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// Errors of `assemble_str`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+/// One `line:column: message` per line.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for d in self.0.iter() {
+            try!(writeln!(f, "{}:{}: {}", d.line, d.column, d.message));
+        }
+        Ok(())
+    }
+}
+
+/// Assembles a program without the preprocessor nor any file access, so
+/// `.include`s fail.
+pub fn assemble_str(asm: &str) -> Result<Vec<u16>, Diagnostics> {
+    let diagnostic = |pos: Option<include::Position>, message: String| {
+        let (line, column) = pos.map_or((0, 0), |p| (p.line, p.column));
+        Diagnostics(vec![Diagnostic {
+                             line: line,
+                             column: column,
+                             message: message,
+                         }])
+    };
+
+    let program = match include::Loader::new().load_str(asm, Path::new("<input>")) {
+        Ok(program) => program,
+        Err(include::Error::Syntax(_, line, column, text)) => {
+            let pos = include::Position {
+                file: 0,
+                line: line,
+                column: column,
+            };
+            return Err(diagnostic(Some(pos), format!("syntax error at \"{}\"", text)));
+        }
+        Err(e) => return Err(diagnostic(None, e.to_string())),
+    };
+    let locate = |e: linker::Error, positions: &[include::Position]| {
+        Diagnostics(e.into_vec()
+                     .into_iter()
+                     .flat_map(|e| {
+                         match e {
+                             linker::Error::At(i, e) => {
+                                 diagnostic(Some(positions[i]), e.to_string()).0
+                             }
+                             e => diagnostic(None, e.to_string()).0,
+                         }
+                     })
+                     .collect())
+    };
+    let (ast, positions) = try!(macros::expand_with_positions(&program.items,
+                                                              &program.positions)
+                                    .map_err(|e| locate(e, &program.positions)));
+    let (ast, positions) = try!(conditionals::evaluate_with_positions(&ast,
+                                                                      &positions,
+                                                                      &HashMap::new())
+                                    .map_err(|e| locate(e, &positions)));
+    linker::link_located(&ast)
+        .map(|linked| linked.bin)
+        .map_err(|e| locate(e, &positions))
+}
+
+/// Assembles the file at `path` and the ones it includes, with the default
+/// settings of the linker.
+pub fn assemble_file(loader: &include::Loader, path: &Path) -> Result<Vec<u16>, Error> {
+    assemble_file_with_debug_info(loader, path).map(|(bin, _)| bin)
+}
+
+/// Like `assemble_file`, also returning the source line of each
+/// instruction and the labels, as written by `assembler --debug-info`.
+pub fn assemble_file_with_debug_info(loader: &include::Loader,
+                                     path: &Path)
+                                     -> Result<(Vec<u16>, DebugInfo), Error> {
+    let program = try!(loader.load(path));
+    let files = &program.files;
+    let (ast, positions) = try!(macros::expand_with_positions(&program.items,
+                                                              &program.positions)
+                                    .map_err(|e| Error::locate(e, &program.positions, files)));
+    let (ast, positions) = try!(conditionals::evaluate_with_positions(&ast,
+                                                                      &positions,
+                                                                      &HashMap::new())
+                                    .map_err(|e| Error::locate(e, &positions, files)));
+    let linked = try!(linker::link_located(&ast).map_err(|e| Error::locate(e, &positions, files)));
+    let info = debug_info(files, &ast, &positions, &linked);
+    Ok((linked.bin, info))
+}
+
+/// Source line of each instruction of `ast`, linked as `linked`, and the
+/// labels.
+pub fn debug_info(files: &[PathBuf],
+                  ast: &[ParsedItem],
+                  positions: &[include::Position],
+                  linked: &linker::Linked)
+                  -> DebugInfo {
+    let mut info = DebugInfo::new();
+    info.set_symbols(linked.symbols.clone());
+    for file in files {
+        info.add_file(file.display().to_string());
+    }
+    for ((item, pos), &addr) in ast.iter().zip(positions).zip(&linked.addresses) {
+        if let ParsedItem::ParsedInstruction(_) = *item {
+            // Defined on the command line.
+            if pos.line != 0 {
+                info.add_line(addr,
+                              debug_info::Line {
+                                  file: pos.file,
+                                  line: pos.line,
+                              });
+            }
+        }
+    }
+    info
+}
+
+#[cfg(test)]
+#[test]
+fn test_error() {
+    let positions = [include::Position {
+                         file: 0,
+                         line: 3,
+                         column: 5,
+                     }];
+    let e = linker::Error::At(0, Box::new(linker::Error::UnknownLabel("foo".into())));
+    let e = Error::locate(e, &positions, &[PathBuf::from("a.dasm")]);
+    assert_eq!(e.to_string(), "a.dasm:3:5: unknown label \"foo\"");
+    let e: Error = linker::Error::Many(vec![linker::Error::OrgInObject,
+                                            linker::Error::InitializedBss])
+                       .into();
+    assert_eq!(e.to_string(),
+               ".org in a relocatable object\ninstruction or initialized data in .bss");
+}
+
+#[cfg(test)]
+#[test]
+fn test_assemble_str() {
+    assert_eq!(assemble_str("SET A, 1\nSET PC, 0x1000\n"),
+               Ok(vec![0x8801, 0x7f81, 0x1000]));
+    assert_eq!(assemble_str(".fill 7, 2\n.reserve 1\n.dat 1\n"), Ok(vec![7, 7, 0, 1]));
+    assert_eq!(assemble_str(".datp \"abc\"\n.datp \"ab\", zero\n.datp \"a\", length\n"),
+               Ok(vec![0x6162, 0x6300, 0x6162, 0, 1, 0x6100]));
+    assert_eq!(assemble_str("start:\nSET PC, $\n.dat $ - start, $\n.equ size, $\n.dat size\n"),
+               Ok(vec![0x8781, 1, 1, 3]));
+    assert_eq!(assemble_str("1:\nSET A, 1\nIFE A, 2\nSET PC, 1f\nSET PC, 1b\n1:\nSET PC, 1b\n"),
+               Ok(vec![0x8801, 0x8c12, 0x9781, 0x8781, 0x9781]));
+    assert_eq!(assemble_str("SET PC, 2f\n"),
+               Err(Diagnostics(vec![Diagnostic {
+                                        line: 1,
+                                        column: 1,
+                                        message: "unknown label \"2f\"".into(),
+                                    }])));
+    assert!(assemble_str(".maxcycles 3\nADD A, 1\nSET PC, POP\n").is_ok());
+    assert_eq!(assemble_str(".maxcycles 2\nADD A, 1\nSET PC, POP\n"),
+               Err(Diagnostics(vec![Diagnostic {
+                                        line: 1,
+                                        column: 1,
+                                        message: "takes up to 3 cycles, more than the 2 of \
+                                                  its .maxcycles"
+                                                     .into(),
+                                    }])));
+    assert_eq!(assemble_str("SET A, 1\n  SET B, foo\n"),
+               Err(Diagnostics(vec![Diagnostic {
+                                        line: 2,
+                                        column: 3,
+                                        message: "unknown label \"foo\"".into(),
+                                    }])));
+}
+
+#[cfg(test)]
+#[test]
+fn test_assemble_file() {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    let dir = env::temp_dir().join("dcpu_test_assemble_file");
+    fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, content: &str| {
+        File::create(dir.join(name)).unwrap().write_all(content.as_bytes()).unwrap();
+    };
+    write("main.dasm", ".include \"lib.dasm\"\nSET A, 1\n");
+    write("lib.dasm", "SET B, 1\nSET C, foo\n");
+
+    let e = assemble_file(&include::Loader::new(), &dir.join("main.dasm")).unwrap_err();
+    assert_eq!(e.to_string(),
+               format!("{}:2:1: unknown label \"foo\"", dir.join("lib.dasm").display()));
+    write("lib.dasm", "SET B, 1\n");
+    assert_eq!(assemble_file(&include::Loader::new(), &dir.join("main.dasm")).unwrap(),
+               [0x8821, 0x8801]);
+}
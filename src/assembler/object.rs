@@ -0,0 +1,677 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use assembler::linker::{self, Error};
+use assembler::types::*;
+use types::{Instruction, ParseError};
+
+/// Added to the labels to find out which words depend on them. Being odd,
+/// only a coefficient of 1 keeps it unchanged (modulo 2^16).
+const TRIAL_OFFSET: u16 = 0x1001;
+
+/// What is added to a word when the object is placed in the final binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// Address of the object.
+    Base,
+    /// Address of a global label of another object.
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub target: Target,
+}
+
+/// Label defined by an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: u16,
+    /// Listed in a `.globl`, so usable by other objects.
+    pub global: bool,
+}
+
+/// Code assembled as if it started at address 0, with what is needed to move
+/// it and to resolve its references to other objects.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub code: Vec<u16>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+}
+
+/// Labels of the current pass, plus the same with the labels moved by
+/// `TRIAL_OFFSET`.
+struct Scope {
+    globals: HashMap<String, u16>,
+    locals: HashMap<String, HashMap<String, u16>>,
+    trial_globals: HashMap<String, u16>,
+    trial_locals: HashMap<String, HashMap<String, u16>>,
+    externals: HashSet<String>,
+}
+
+impl Scope {
+    /// Value of `e` in the object, and what must be added to it when
+    /// linking.
+    fn solve(&self,
+             e: &Expression,
+             last_global: Option<&String>)
+             -> Result<(u16, Vec<Target>), Error> {
+        let empty = HashMap::new();
+        let (locals, trial_locals) = match last_global {
+            Some(s) => (&self.locals[s], &self.trial_locals[s]),
+            None => (&empty, &empty),
+        };
+
+        let value = try!(e.solve(&self.globals, locals));
+        let mut targets = vec![];
+        match try!(e.solve(&self.trial_globals, trial_locals)).wrapping_sub(value) {
+            0 => (),
+            TRIAL_OFFSET => targets.push(Target::Base),
+            _ => return Err(Error::NotRelocatable(e.clone())),
+        }
+        let mut externals = e.labels();
+        externals.retain(|l| self.externals.contains(*l));
+        externals.dedup();
+        for external in externals {
+            let mut globals = self.globals.clone();
+            globals.insert(external.into(), TRIAL_OFFSET);
+            match try!(e.solve(&globals, locals)).wrapping_sub(value) {
+                0 => (),
+                TRIAL_OFFSET => targets.push(Target::Symbol(external.into())),
+                _ => return Err(Error::NotRelocatable(e.clone())),
+            }
+        }
+        Ok((value, targets))
+    }
+}
+
+/// Same as `linker::link`, but the labels not defined in `ast` are left to
+/// be resolved by `link` with the global labels of other objects.
+///
+/// Expressions using labels must be of the form `label + constant`, since
+/// only the address of the label will be added when linking. Instructions
+/// using labels don't use the short literal form.
+pub fn assemble(ast: &[ParsedItem]) -> Result<Object, Error> {
+    let ast = &linker::resolve_numeric_labels(ast)[..];
+    let (globals, locals) = try!(linker::extract_labels(ast));
+    try!(linker::check_constants(ast));
+
+    let mut exported = HashSet::new();
+    let mut constants = HashSet::new();
+    let mut externals = HashSet::new();
+    {
+        let mut use_expression = |e: &Expression| {
+            for l in e.labels() {
+                if !globals.contains_key(l) {
+                    externals.insert(l.to_string());
+                }
+            }
+        };
+        for item in ast {
+            match *item {
+                ParsedItem::Directive(Directive::Global(ref labels)) => {
+                    exported.extend(labels.iter().cloned());
+                }
+                ParsedItem::Directive(Directive::Org(ref e)) |
+                ParsedItem::Directive(Directive::Reserve(ref e)) |
+                ParsedItem::Directive(Directive::MaxCycles(ref e)) => use_expression(e),
+                ParsedItem::Directive(Directive::Fill(ref v, ref n)) => {
+                    use_expression(v);
+                    use_expression(n);
+                }
+                ParsedItem::Directive(Directive::Dat(ref items)) => {
+                    for i in items {
+                        if let DatItem::E(ref e) = *i {
+                            use_expression(e);
+                        }
+                    }
+                }
+                ParsedItem::ConstDecl(ref name, ref e) => {
+                    constants.insert(name.clone());
+                    use_expression(e);
+                }
+                ParsedItem::ParsedInstruction(ref i) => {
+                    let (b, a) = match *i {
+                        ParsedInstruction::BasicOp(_, ref b, ref a) => (Some(b), a),
+                        ParsedInstruction::SpecialOp(_, ref a) => (None, a),
+                    };
+                    for v in b.into_iter().chain(Some(a)) {
+                        if let Some(e) = v.expression() {
+                            use_expression(e);
+                        }
+                    }
+                }
+                ParsedItem::MacroCall(ref name, _) => {
+                    return Err(Error::UnknownMacro(name.clone()))
+                }
+                ParsedItem::Include(ref path) |
+                ParsedItem::IncBin(ref path, _, _, _) => {
+                    return Err(Error::UnresolvedInclude(path.clone()))
+                }
+                _ => (),
+            }
+        }
+    }
+    for item in ast {
+        if let ParsedItem::ConstDecl(_, ref e) = *item {
+            if e.labels().iter().any(|l| externals.contains(*l)) {
+                return Err(Error::NotRelocatable(e.clone()));
+            }
+        }
+    }
+
+    let mut scope = Scope {
+        globals: globals,
+        locals: locals,
+        trial_globals: HashMap::new(),
+        trial_locals: HashMap::new(),
+        externals: externals,
+    };
+    for e in scope.externals.iter() {
+        scope.globals.insert(e.clone(), 0);
+    }
+    let mut object = Object::default();
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+        object.code.clear();
+        object.relocations.clear();
+        scope.trial_globals = HashMap::new();
+        for (l, &v) in scope.globals.iter() {
+            if constants.contains(l) || scope.externals.contains(l) {
+                scope.trial_globals.insert(l.clone(), v);
+            } else {
+                scope.trial_globals.insert(l.clone(), v.wrapping_add(TRIAL_OFFSET));
+            }
+        }
+        scope.trial_locals = HashMap::new();
+        for (g, locals) in scope.locals.iter() {
+            let moved = locals.iter().map(|(l, &v)| (l.clone(), v.wrapping_add(TRIAL_OFFSET)));
+            scope.trial_locals.insert(g.clone(), moved.collect());
+        }
+        let mut last_global = None;
+        let mut index = 0u16;
+        for item in ast {
+            scope.globals.insert(HERE.into(), index);
+            scope.trial_globals.insert(HERE.into(), index.wrapping_add(TRIAL_OFFSET));
+            match *item {
+                ParsedItem::Directive(Directive::Org(_)) => return Err(Error::OrgInObject),
+                ParsedItem::Directive(Directive::Reserve(ref e)) => {
+                    let (n, targets) = try!(scope.solve(e, last_global));
+                    if !targets.is_empty() {
+                        return Err(Error::NotRelocatable(e.clone()));
+                    }
+                    let len = object.code.len();
+                    object.code.resize(len + n as usize, 0);
+                    index = index.wrapping_add(n);
+                }
+                ParsedItem::Directive(Directive::Fill(ref v, ref e)) => {
+                    let (n, targets) = try!(scope.solve(e, last_global));
+                    if !targets.is_empty() {
+                        return Err(Error::NotRelocatable(e.clone()));
+                    }
+                    let (v, targets) = try!(scope.solve(v, last_global));
+                    for _ in 0..n {
+                        add_relocations(&mut object, index, targets.clone());
+                        object.code.push(v);
+                        index = index.wrapping_add(1);
+                    }
+                }
+                ParsedItem::Directive(Directive::Dat(ref items)) => {
+                    for i in items {
+                        if let DatItem::E(ref e) = *i {
+                            let (n, targets) = try!(scope.solve(e, last_global));
+                            add_relocations(&mut object, index, targets);
+                            object.code.push(n);
+                            index = index.wrapping_add(1);
+                        } else {
+                            let d = Directive::Dat(vec![i.clone()]);
+                            let empty = HashMap::new();
+                            index = index.wrapping_add(try!(d.append_to(&mut object.code,
+                                                                        &empty,
+                                                                        &empty)));
+                        }
+                    }
+                }
+                ParsedItem::Directive(ref d) => {
+                    let empty = HashMap::new();
+                    index = index.wrapping_add(try!(d.append_to(&mut object.code,
+                                                                &empty,
+                                                                &empty)));
+                }
+                ParsedItem::LabelDecl(ref s) => {
+                    let ptr = scope.globals.get_mut(s).unwrap();
+                    if *ptr != index {
+                        *ptr = index;
+                        changed = true;
+                    }
+                    last_global = Some(s);
+                }
+                ParsedItem::LocalLabelDecl(ref s) => {
+                    let ptr = scope.locals
+                                   .get_mut(*last_global.as_ref().unwrap())
+                                   .unwrap()
+                                   .get_mut(s)
+                                   .unwrap();
+                    if *ptr != index {
+                        *ptr = index;
+                        changed = true;
+                    }
+                }
+                ParsedItem::ConstDecl(ref name, ref e) => {
+                    let empty = HashMap::new();
+                    let (locals, trial_locals) = match last_global {
+                        Some(s) => (&scope.locals[s], &scope.trial_locals[s]),
+                        None => (&empty, &empty),
+                    };
+                    let value = try!(e.solve(&scope.globals, locals));
+                    let trial = try!(e.solve(&scope.trial_globals, trial_locals));
+                    scope.trial_globals.insert(name.clone(), trial);
+                    let ptr = scope.globals.get_mut(name).unwrap();
+                    if *ptr != value {
+                        *ptr = value;
+                        changed = true;
+                    }
+                }
+                ParsedItem::ParsedInstruction(ref i) => {
+                    let (b, a) = match *i {
+                        ParsedInstruction::BasicOp(_, ref b, ref a) => (Some(b), a),
+                        ParsedInstruction::SpecialOp(_, ref a) => (None, a),
+                    };
+                    let mut a_targets = vec![];
+                    if let Some(e) = a.expression() {
+                        a_targets = try!(scope.solve(e, last_global)).1;
+                    }
+                    let mut b_targets = vec![];
+                    if let Some(e) = b.and_then(|b| b.expression()) {
+                        b_targets = try!(scope.solve(e, last_global)).1;
+                    }
+
+                    let empty = HashMap::new();
+                    let locals = match last_global {
+                        Some(s) => &scope.locals[s],
+                        None => &empty,
+                    };
+                    let solved = try!(i.solve(&scope.globals, locals));
+                    let short_literals = a_targets.is_empty() && b_targets.is_empty();
+                    let mut words = [0; 3];
+                    let size = solved.encode_with(&mut words, short_literals);
+
+                    let a_size = match solved {
+                        Instruction::BasicOp(_, _, a) |
+                        Instruction::SpecialOp(_, a) => {
+                            if a.encode(short_literals).1.is_some() { 1 } else { 0 }
+                        }
+                    };
+                    add_relocations(&mut object, index.wrapping_add(1), a_targets);
+                    add_relocations(&mut object, index.wrapping_add(1 + a_size), b_targets);
+                    object.code.extend(&words[..size as usize]);
+                    index = index.wrapping_add(size);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    object.symbols = ast.iter()
+                        .filter_map(|i| match *i {
+                            ParsedItem::LabelDecl(ref s) => {
+                                Some(Symbol {
+                                    name: s.clone(),
+                                    offset: scope.globals[s],
+                                    global: exported.contains(s),
+                                })
+                            }
+                            _ => None,
+                        })
+                        .collect();
+    Ok(object)
+}
+
+fn add_relocations(object: &mut Object, offset: u16, targets: Vec<Target>) {
+    for t in targets {
+        object.relocations.push(Relocation {
+            offset: offset,
+            target: t,
+        });
+    }
+}
+
+impl Object {
+    /// Renames the global symbols in `names` to `prefix` followed by their
+    /// name, both where they are defined and where they are used.
+    pub fn prefix(&mut self, prefix: &str, names: &HashSet<String>) {
+        for s in self.symbols.iter_mut().filter(|s| s.global && names.contains(&s.name)) {
+            s.name = format!("{}{}", prefix, s.name);
+        }
+        for r in self.relocations.iter_mut() {
+            if let Target::Symbol(ref mut s) = r.target {
+                if names.contains(s) {
+                    *s = format!("{}{}", prefix, s);
+                }
+            }
+        }
+    }
+}
+
+/// Global symbols defined by `objects`.
+///
+/// Passing them to `Object::prefix` for each object of a library renames the
+/// symbols of the library and the references of its objects to them, so two
+/// libraries defining the same symbol can be linked together. The other
+/// objects must use the prefixed names.
+pub fn global_symbols<'a, I: IntoIterator<Item = &'a Object>>(objects: I) -> HashSet<String> {
+    objects.into_iter()
+           .flat_map(|o| o.symbols.iter())
+           .filter(|s| s.global)
+           .map(|s| s.name.clone())
+           .collect()
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LinkOptions {
+    /// Like `ld --wrap`: the references to these symbols go to
+    /// `__wrap_symbol` instead, and the ones to `__real_symbol` to the
+    /// symbol itself.
+    pub wrap: Vec<String>,
+}
+
+/// Global symbol of the binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provided {
+    pub name: String,
+    pub address: u16,
+    /// Name of the object defining it.
+    pub object: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linked {
+    pub bin: Vec<u16>,
+    /// Sorted by address.
+    pub symbols: Vec<Provided>,
+}
+
+/// Places the objects one after the other and resolves their references to
+/// each other. The names are only used in the errors.
+pub fn link(objects: &[(String, Object)]) -> Result<Vec<u16>, Error> {
+    link_with_options(objects, &LinkOptions::default()).map(|l| l.bin)
+}
+
+/// Same as `link`, also telling which object defines each symbol.
+pub fn link_with_options(objects: &[(String, Object)],
+                         options: &LinkOptions)
+                         -> Result<Linked, Error> {
+    let mut bases = vec![];
+    let mut symbols = HashMap::new();
+    let mut provided = vec![];
+    let mut base = 0u16;
+    for &(ref name, ref object) in objects {
+        bases.push(base);
+        for s in object.symbols.iter().filter(|s| s.global) {
+            if symbols.insert(s.name.clone(), base.wrapping_add(s.offset)).is_some() {
+                return Err(Error::InObject(name.clone(),
+                                           Box::new(Error::DuplicatedLabel(s.name.clone()))));
+            }
+            provided.push(Provided {
+                name: s.name.clone(),
+                address: base.wrapping_add(s.offset),
+                object: name.clone(),
+            });
+        }
+        base = base.wrapping_add(object.code.len() as u16);
+    }
+    provided.sort_by_key(|p| p.address);
+    let resolve = |s: &str| -> String {
+        if options.wrap.iter().any(|w| w == s) {
+            format!("__wrap_{}", s)
+        } else if s.starts_with("__real_") && options.wrap.iter().any(|w| *w == s[7..]) {
+            s[7..].into()
+        } else {
+            s.into()
+        }
+    };
+
+    let mut bin = vec![];
+    for (&(ref name, ref object), &base) in objects.iter().zip(bases.iter()) {
+        let start = bin.len();
+        bin.extend(object.code.iter().cloned());
+        for r in object.relocations.iter() {
+            let value = match r.target {
+                Target::Base => base,
+                Target::Symbol(ref s) => {
+                    let s = resolve(s);
+                    match symbols.get(&s) {
+                        Some(&addr) => addr,
+                        None => {
+                            return Err(Error::InObject(name.clone(),
+                                                       Box::new(Error::UnknownLabel(s))))
+                        }
+                    }
+                }
+            };
+            match bin.get_mut(start + r.offset as usize) {
+                Some(word) => *word = word.wrapping_add(value),
+                None => {
+                    return Err(Error::InObject(name.clone(),
+                                               Box::new(Error::BadRelocation(r.offset))))
+                }
+            }
+        }
+    }
+    Ok(Linked {
+        bin: bin,
+        symbols: provided,
+    })
+}
+
+/// Line based:
+///
+/// ```text
+/// code 0x7c01 0x0000
+/// symbol main 0x0000 global
+/// reloc 0x0001 base
+/// reloc 0x0003 symbol putc
+/// ```
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.code.chunks(8) {
+            try!(write!(f, "code"));
+            for w in chunk {
+                try!(write!(f, " 0x{:04x}", w));
+            }
+            try!(writeln!(f, ""));
+        }
+        for s in self.symbols.iter() {
+            try!(writeln!(f,
+                          "symbol {} 0x{:04x} {}",
+                          s.name,
+                          s.offset,
+                          if s.global { "global" } else { "local" }));
+        }
+        for r in self.relocations.iter() {
+            match r.target {
+                Target::Base => try!(writeln!(f, "reloc 0x{:04x} base", r.offset)),
+                Target::Symbol(ref s) => {
+                    try!(writeln!(f, "reloc 0x{:04x} symbol {}", r.offset, s))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_hex(s: &str) -> Result<u16, ParseError> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).map_err(|_| ParseError::Object)
+    } else {
+        Err(ParseError::Object)
+    }
+}
+
+impl FromStr for Object {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Object, ParseError> {
+        let mut object = Object::default();
+        for line in s.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => (),
+                Some("code") => {
+                    for w in words {
+                        object.code.push(try!(parse_hex(w)));
+                    }
+                }
+                Some("symbol") => {
+                    let name = words.next();
+                    let offset = words.next().map(parse_hex);
+                    let global = match words.next() {
+                        Some("global") => Some(true),
+                        Some("local") => Some(false),
+                        _ => None,
+                    };
+                    match (name, offset, global, words.next()) {
+                        (Some(name), Some(Ok(offset)), Some(global), None) => {
+                            object.symbols.push(Symbol {
+                                name: name.into(),
+                                offset: offset,
+                                global: global,
+                            })
+                        }
+                        _ => return Err(ParseError::Object),
+                    }
+                }
+                Some("reloc") => {
+                    let offset = try!(words.next().map_or(Err(ParseError::Object), parse_hex));
+                    let target = match (words.next(), words.next(), words.next()) {
+                        (Some("base"), None, None) => Target::Base,
+                        (Some("symbol"), Some(name), None) => Target::Symbol(name.into()),
+                        _ => return Err(ParseError::Object),
+                    };
+                    object.relocations.push(Relocation {
+                        offset: offset,
+                        target: target,
+                    })
+                }
+                _ => return Err(ParseError::Object),
+            }
+        }
+        Ok(object)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_objects() {
+    use nom::IResult;
+
+    use assembler::parser;
+    use encodings::*;
+    use types::{BasicOp, Register, SpecialOp};
+
+    let assemble_str = |s: &str| match parser::parse(s.as_bytes()) {
+        IResult::Done(_, ast) => assemble(&ast).unwrap(),
+        _ => panic!(),
+    };
+    let main = assemble_str(".globl main\nmain:\nJSR putc\nSET PC, main + 1\n.dat end\nend:\n");
+    let lib = assemble_str(".globl putc\nSET A, 1\nputc:\nSET PC, POP\n");
+    assert_eq!(main.to_string().parse::<Object>(), Ok(main.clone()));
+
+    let objects = vec![("main".to_string(), main), ("lib".to_string(), lib.clone())];
+    // JSR putc, SET PC, main + 1, .dat end, SET A, 1, SET PC, POP
+    assert_eq!(link(&objects).unwrap(),
+               vec![special(SpecialOp::JSR, NEXT),
+                    0x0006,
+                    basic(BasicOp::SET, PC, NEXT),
+                    0x0001,
+                    0x0005,
+                    basic(BasicOp::SET, reg(Register::A), lit(1)),
+                    RET]);
+    // The short literal form is still used without labels.
+    assert_eq!(lib.code.len(), 2);
+
+    let objects = vec![("lib".to_string(), lib.clone()), ("lib2".to_string(), lib)];
+    assert!(match link(&objects) {
+        Err(Error::InObject(ref o, ref e)) => {
+            o == "lib2" &&
+            match **e {
+                Error::DuplicatedLabel(ref l) => l == "putc",
+                _ => false,
+            }
+        }
+        _ => false,
+    });
+    let objects = vec![("main".to_string(), assemble_str("JSR putc\n"))];
+    assert!(match link(&objects) {
+        Err(Error::InObject(_, ref e)) => {
+            match **e {
+                Error::UnknownLabel(ref l) => l == "putc",
+                _ => false,
+            }
+        }
+        _ => false,
+    });
+
+    let ast = match parser::parse("SET A, 2 * foo\n".as_bytes()) {
+        IResult::Done(_, ast) => ast,
+        _ => panic!(),
+    };
+    assert!(match assemble(&ast) {
+        Err(Error::NotRelocatable(_)) => true,
+        _ => false,
+    });
+}
+
+#[cfg(test)]
+#[test]
+fn test_link_options() {
+    use nom::IResult;
+
+    use assembler::parser;
+    use encodings::*;
+    use types::{BasicOp, SpecialOp};
+
+    let assemble_str = |s: &str| match parser::parse(s.as_bytes()) {
+        IResult::Done(_, ast) => assemble(&ast).unwrap(),
+        _ => panic!(),
+    };
+    let main = assemble_str(".globl main\nmain:\nJSR memcpy\nJSR fast_memcpy\n");
+    let wrapper = assemble_str(".globl __wrap_memcpy\n__wrap_memcpy:\nSET PC, __real_memcpy\n");
+    let a = assemble_str(".globl memcpy\nmemcpy:\nSET PC, POP\n");
+    let mut b = assemble_str(".globl memcpy\nmemcpy:\nSET PC, POP\n");
+    let names = global_symbols(&[b.clone()]);
+    b.prefix("fast_", &names);
+    assert_eq!(b.symbols[0].name, "fast_memcpy");
+
+    let objects = vec![("main".to_string(), main),
+                       ("wrapper".to_string(), wrapper),
+                       ("a".to_string(), a),
+                       ("b".to_string(), b)];
+    let options = LinkOptions { wrap: vec!["memcpy".into()] };
+    let linked = link_with_options(&objects, &options).unwrap();
+    // JSR __wrap_memcpy, JSR fast_memcpy, SET PC, memcpy, SET PC, POP, SET PC, POP
+    assert_eq!(linked.bin,
+               vec![special(SpecialOp::JSR, NEXT),
+                    4,
+                    special(SpecialOp::JSR, NEXT),
+                    7,
+                    basic(BasicOp::SET, PC, NEXT),
+                    6,
+                    RET,
+                    RET]);
+    let provided = linked.symbols
+                         .iter()
+                         .map(|p| (&p.name[..], p.address, &p.object[..]))
+                         .collect::<Vec<_>>();
+    assert_eq!(provided,
+               vec![("main", 0, "main"),
+                    ("__wrap_memcpy", 4, "wrapper"),
+                    ("memcpy", 6, "a"),
+                    ("fast_memcpy", 7, "b")]);
+}
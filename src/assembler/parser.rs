@@ -1,10 +1,14 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::str;
 use std::str::FromStr;
 
 use nom::*;
 
+use assembler::linker;
 use assembler::types::*;
-use types::{BasicOp, SpecialOp, Register};
+use types::{BasicOp, SpecialOp, Register, Instruction, ParseError};
 
 fn bytes_to_type<I: FromStr>(i: &[u8]) -> Result<I, ()> {
     str::from_utf8(i)
@@ -155,6 +159,7 @@ named!(value<ParsedValue>,
             ),
             ParsedValue::Pick
         ) |
+        map!(tag!("PEEK"), |_| ParsedValue::Peek) |
         map!(tag!("SP"), |_| ParsedValue::SP) |
         map!(tag!("PC"), |_| ParsedValue::PC) |
         map!(tag!("EX"), |_| ParsedValue::EX)
@@ -186,6 +191,14 @@ named!(label_decl<ParsedItem>,
     )
 );
 
+named!(numeric_label_decl<ParsedItem>,
+    chain!(
+        n: map_res!(map_res!(digit, str::from_utf8), u16::from_str) ~
+        char!(':'),
+        || ParsedItem::NumericLabelDecl(n)
+    )
+);
+
 named!(local_label_decl<ParsedItem>,
     chain!(
         opt!(char!(':')) ~
@@ -195,15 +208,33 @@ named!(local_label_decl<ParsedItem>,
     )
 );
 
+// `1b` or `1f`, but not `0b1`.
+named!(numeric_label_ref<Expression>,
+    map_res!(
+        recognize!(chain!(digit ~ one_of!("bf") ~ opt!(complete!(alphanumeric)), || ())),
+        |s: &[u8]| {
+            let (&direction, n) = s.split_last().unwrap();
+            match str::from_utf8(n).ok().and_then(|n| n.parse().ok()) {
+                Some(n) if direction == b'b' => Ok(Expression::Backward(n)),
+                Some(n) => Ok(Expression::Forward(n)),
+                None => Err(()),
+            }
+        }
+    )
+);
+
 named!(simple_expression<Expression>,
     alt_complete!(
+        numeric_label_ref |
         map!(number, Expression::Num) |
+        map!(char_literal, |c| Expression::Num(Num::U(c))) |
         map!(raw_label, Expression::Label) |
-        map!(raw_local_label, Expression::LocalLabel)
+        map!(raw_local_label, Expression::LocalLabel) |
+        map!(char!('$'), |_| Expression::Here)
     )
 );
 
-named!(expression<Expression>,
+named!(atom<Expression>,
     alt_complete!(
         chain!(char!('(') ~
                multispace? ~
@@ -211,52 +242,62 @@ named!(expression<Expression>,
                multispace? ~
                char!(')'),
                || e) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               char!('+') ~
-               multispace? ~
-               e2: expression,
-               || Expression::Add(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               char!('-') ~
-               multispace? ~
-               e2: expression,
-               || Expression::Sub(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               char!('*') ~
-               multispace? ~
-               e2: expression,
-               || Expression::Mul(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               char!('/') ~
-               multispace? ~
-               e2: expression,
-               || Expression::Div(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               tag!(">>") ~
-               multispace? ~
-               e2: expression,
-               || Expression::Shr(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
-               multispace? ~
-               tag!("<<") ~
-               multispace? ~
-               e2: expression,
-               || Expression::Shl(Box::new(e1), Box::new(e2))) |
-        chain!(e1: simple_expression ~
+        simple_expression
+    )
+);
+
+named!(unary<Expression>,
+    alt_complete!(
+        numeric_label_ref |
+        map!(number, Expression::Num) |
+        chain!(char!('-') ~
                multispace? ~
-               char!('%') ~
+               e: unary,
+               || Expression::Neg(Box::new(e))) |
+        chain!(char!('~') ~
                multispace? ~
-               e2: expression,
-               || Expression::Mod(Box::new(e1), Box::new(e2))) |
-        simple_expression
+               e: unary,
+               || Expression::Not(Box::new(e))) |
+        atom
     )
 );
 
+/// Parser of a left-associative level of binary operators, with `$operand`
+/// as the operands. Longer operators must come first.
+macro_rules! binary_level {
+    ($name:ident, $operand:ident, $($op:expr => $variant:path),*) => {
+        named!($name<Expression>,
+            chain!(
+                first: $operand ~
+                rest: many0!(complete!(chain!(
+                    multispace? ~
+                    op: alt!($(map!(tag!($op), |_| $variant as BinaryOp))|*) ~
+                    multispace? ~
+                    e: $operand,
+                    || (op, e)
+                ))),
+                || rest.into_iter().fold(first, |l, (op, r)| op(Box::new(l), Box::new(r)))
+            )
+        );
+    }
+}
+
+binary_level!(product, unary,
+              "*" => Expression::Mul, "/" => Expression::Div, "%" => Expression::Mod);
+binary_level!(sum, product, "+" => Expression::Add, "-" => Expression::Sub);
+binary_level!(shift, sum, "<<" => Expression::Shl, ">>" => Expression::Shr);
+binary_level!(comparison, shift,
+              "<=" => Expression::Le, ">=" => Expression::Ge,
+              "<" => Expression::Lt, ">" => Expression::Gt);
+binary_level!(equality, comparison, "==" => Expression::Eq, "!=" => Expression::Ne);
+binary_level!(bit_and, equality, "&" => Expression::And);
+binary_level!(bit_xor, bit_and, "^" => Expression::Xor);
+binary_level!(bit_or, bit_xor, "|" => Expression::Or);
+
+// Expression with the precedence of C, from the loosest: `|`, `^`, `&`,
+// `== !=`, `< <= > >=`, `<< >>`, `+ -`, `* / %` then the unary `-` and `~`.
+named!(expression<Expression>, call!(bit_or));
+
 named!(a_value<ParsedValue>,
     alt_complete!(
         map!(tag!("POP"), |_| ParsedValue::Push) |
@@ -273,33 +314,124 @@ named!(b_value<ParsedValue>,
     )
 );
 
+// Character after a backslash in a string or a character literal.
+named!(escape<char>,
+    alt_complete!(
+        map!(char!('n'), |_| '\n') |
+        map!(char!('r'), |_| '\r') |
+        map!(char!('t'), |_| '\t') |
+        map!(char!('0'), |_| '\0') |
+        chain!(char!('x') ~
+               n: map_res!(map_res!(take!(2), str::from_utf8),
+                           |n| u8::from_str_radix(n, 16)),
+               || n as char) |
+        one_of!("\\\"'")
+    )
+);
+
 named!(string<String>,
-    map_res!(
-        delimited!(tag!("\""), recognize!(many0!(none_of!("\""))), tag!("\"")),
-        bytes_to_type
+    delimited!(
+        char!('"'),
+        map!(many0!(alt_complete!(preceded!(char!('\\'), escape) | none_of!("\\\""))),
+             |chars: Vec<char>| chars.into_iter().collect()),
+        char!('"')
     )
 );
 
+named!(char_literal<u16>,
+    delimited!(
+        char!('\''),
+        map!(alt_complete!(preceded!(char!('\\'), escape) | none_of!("\\'")),
+             |c| c as u16),
+        char!('\'')
+    )
+);
+
+named!(dat_literal<DatItem>,
+    alt_complete!(map!(string, From::from) |
+                  map!(number, From::from) |
+                  map!(char_literal, DatItem::N))
+);
+
+named!(dat_item<DatItem>,
+    alt_complete!(map!(string, From::from) |
+                  map!(expression, From::from))
+);
+
+named!(dat_literals< Vec<DatItem> >,
+    chain!(ns: separated_nonempty_list!(space, dat_literal) ~
+           peek!(line_ending),
+           || ns)
+);
+
+named!(dat_items< Vec<DatItem> >,
+    separated_list!(comma, dat_item)
+);
+
+// Items are separated by commas, or by spaces if they are all literals:
+// `.dat 1 -2` is two words but `.dat 1 - 2` is one.
 named!(dir_dat<Directive>,
     chain!(alt_complete!(tag!("dat") | tag!("byte")| tag!("word") | tag!("short")) ~
            space ~
-           ns: separated_list!(space,
-                               alt_complete!(map!(number, From::from) |
-                                             map!(string, From::from))),
+           ns: alt_complete!(dat_literals | dat_items),
            || Directive::Dat(ns))
 );
 
+named!(string_format<StringFormat>,
+    alt_complete!(map!(tag!("zero"), |_| StringFormat::ZeroTerminated) |
+                  map!(tag!("length"), |_| StringFormat::LengthPrefixed))
+);
+
+named!(dir_datp<Directive>,
+    chain!(tag!("datp") ~
+           space ~
+           s: string ~
+           format: opt!(complete!(preceded!(comma, string_format))),
+           || Directive::DatP(s, format.unwrap_or(StringFormat::Plain)))
+);
+
 named!(dir_org<Directive>,
     chain!(tag!("org") ~
            space ~
-           n: number,
-           || Directive::Org(n.into()))
+           e: expression,
+           || Directive::Org(e))
+);
+
+named!(dir_fill<Directive>,
+    chain!(tag!("fill") ~
+           space ~
+           v: expression ~
+           comma ~
+           n: expression,
+           || Directive::Fill(v, n))
+);
+
+named!(dir_reserve<Directive>,
+    chain!(tag!("reserve") ~
+           space ~
+           n: expression,
+           || Directive::Reserve(n))
+);
+
+named!(dir_maxcycles<Directive>,
+    chain!(tag!("maxcycles") ~
+           space ~
+           n: expression,
+           || Directive::MaxCycles(n))
+);
+
+named!(dir_proc<Directive>,
+    chain!(tag!("proc") ~
+           regs: opt!(complete!(preceded!(space, separated_list!(comma, register)))),
+           || Directive::Proc(regs.unwrap_or(vec![])))
 );
 
 named!(dir_global<Directive>,
-    chain!(tag!("globl") ~
+    chain!(alt_complete!(tag!("globl") | tag!("global")) ~
+           space? ~
+           labels: separated_list!(alt_complete!(comma | space), raw_label) ~
            many0!(none_of!("\n")),
-           || Directive::Global)
+           || Directive::Global(labels))
 );
 
 named!(dir_text<Directive>,
@@ -308,40 +440,331 @@ named!(dir_text<Directive>,
            || Directive::Text)
 );
 
+named!(dir_data<Directive>,
+    chain!(tag!("data") ~
+           many0!(none_of!("\n")),
+           || Directive::Data)
+);
+
 named!(dir_bss<Directive>,
     chain!(tag!("bss") ~
            many0!(none_of!("\n")),
            || Directive::BSS)
 );
 
+named!(dir_ifdef<Directive>,
+    chain!(tag!("ifdef") ~
+           space ~
+           l: raw_label,
+           || Directive::IfDef(l))
+);
+
+named!(dir_ifndef<Directive>,
+    chain!(tag!("ifndef") ~
+           space ~
+           l: raw_label,
+           || Directive::IfNDef(l))
+);
+
+named!(dir_if<Directive>,
+    chain!(tag!("if") ~
+           space ~
+           e: expression,
+           || Directive::If(e))
+);
+
 named!(directive<Directive>,
     chain!(char!('.') ~
-           d: alt_complete!(dir_dat |
+           d: alt_complete!(dir_datp |
+                            dir_data |
+                            dir_dat |
                             dir_org |
+                            dir_fill |
+                            dir_reserve |
+                            dir_maxcycles |
+                            dir_proc |
                             dir_global |
                             dir_text |
-                            dir_bss) ~
+                            dir_bss |
+                            dir_ifdef |
+                            dir_ifndef |
+                            dir_if |
+                            map!(tag!("else"), |_| Directive::Else) |
+                            map!(tag!("endif"), |_| Directive::EndIf)) ~
            peek!(line_ending),
            || d)
 );
 
+named!(comma,
+    delimited!(opt!(space), tag!(","), opt!(space))
+);
+
+named!(macro_decl<ParsedItem>,
+    chain!(tag!(".macro") ~
+           space ~
+           name: raw_label ~
+           opt!(space) ~
+           char!('(') ~
+           opt!(space) ~
+           args: separated_list!(comma, raw_label) ~
+           opt!(space) ~
+           char!(')') ~
+           body: map_res!(take_until_and_consume!(".endmacro"), bytes_to_type),
+           || ParsedItem::MacroDecl(Macro {
+               name: name,
+               args: args,
+               body: body,
+           }))
+);
+
+named!(const_decl<ParsedItem>,
+    chain!(alt_complete!(tag!(".equ") | tag!(".define")) ~
+           space ~
+           name: raw_label ~
+           alt_complete!(comma | space) ~
+           e: expression,
+           || ParsedItem::ConstDecl(name, e))
+);
+
+named!(include<ParsedItem>,
+    chain!(alt_complete!(tag!(".include") | tag!("#include")) ~
+           space ~
+           path: string,
+           || ParsedItem::Include(path))
+);
+
+named!(file_offset<usize>,
+    map_res!(
+        alt_complete!(hex_num | octal_num | bin_num | num),
+        |(n, base)| usize::from_str_radix(n, base)
+    )
+);
+
+named!(packing<Packing>,
+    alt_complete!(map!(tag!("byte"), |_| Packing::Byte) |
+                  map!(tag!("word"), |_| Packing::Word))
+);
+
+named!(incbin<ParsedItem>,
+    chain!(tag!(".incbin") ~
+           space ~
+           path: string ~
+           offset: opt!(complete!(preceded!(comma, file_offset))) ~
+           length: opt!(complete!(preceded!(comma, file_offset))) ~
+           packing: opt!(complete!(preceded!(comma, packing))),
+           || {
+               ParsedItem::IncBin(path,
+                                  offset.unwrap_or(0),
+                                  length,
+                                  packing.unwrap_or(Packing::Word))
+           })
+);
+
+named!(macro_arg<String>,
+    map!(
+        map_res!(recognize!(many1!(none_of!(",)\n"))), str::from_utf8),
+        |s: &str| s.trim().into()
+    )
+);
+
+named!(macro_call<ParsedItem>,
+    chain!(name: raw_label ~
+           char!('(') ~
+           opt!(space) ~
+           args: separated_list!(comma, macro_arg) ~
+           opt!(space) ~
+           char!(')'),
+           || ParsedItem::MacroCall(name, args))
+);
+
+named!(item<ParsedItem>,
+    alt_complete!(
+        macro_decl |
+        include |
+        incbin |
+        const_decl |
+        map!(directive, ParsedItem::Directive) |
+        map!(instruction,
+             ParsedItem::ParsedInstruction) |
+        comment |
+        macro_call |
+        label_decl |
+        local_label_decl |
+        numeric_label_decl
+    )
+);
+
 named!(pub parse< Vec<ParsedItem> >,
     delimited!(
         opt!(multispace),
-        separated_list!(multispace,
-                        alt_complete!(
-                            map!(directive, ParsedItem::Directive) |
-                            map!(instruction,
-                                 ParsedItem::ParsedInstruction) |
-                            comment |
-                            label_decl |
-                            local_label_decl
-                        )
-        ),
+        separated_list!(multispace, item),
         opt!(multispace)
     )
 );
 
+/// Item with the length of the input left before it.
+fn located_item(i: &[u8]) -> IResult<&[u8], (usize, ParsedItem)> {
+    match item(i) {
+        IResult::Done(rest, o) => IResult::Done(rest, (i.len(), o)),
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+    }
+}
+
+named!(located_items< Vec<(usize, ParsedItem)> >,
+    delimited!(
+        opt!(multispace),
+        separated_list!(multispace, located_item),
+        opt!(multispace)
+    )
+);
+
+/// Same as `parse`, but also returns the offset in `i` of each item.
+pub fn parse_located(i: &[u8]) -> IResult<&[u8], Vec<(usize, ParsedItem)>> {
+    match located_items(i) {
+        IResult::Done(rest, items) => {
+            let items = items.into_iter()
+                             .map(|(left, item)| (i.len() - left, item))
+                             .collect();
+            IResult::Done(rest, items)
+        }
+        IResult::Error(e) => IResult::Error(e),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+    }
+}
+
+/// Bounds on the input accepted by `parse_with_limits`. The parser is
+/// recursive, so deeply nested expressions would overflow the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Size of the input in bytes.
+    pub max_len: usize,
+    /// Nesting of an expression, counting both the parentheses and the
+    /// operators.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_len: 1 << 20,
+            max_depth: 64,
+        }
+    }
+}
+
+/// Errors of `parse_with_limits`. Lines start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Length of the input.
+    TooLong(usize),
+    TooDeep(usize),
+    UnterminatedString(usize),
+    /// Line of the first item which couldn't be parsed.
+    Syntax(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::TooLong(len) => write!(f, "input too long ({} bytes)", len),
+            Error::TooDeep(line) => write!(f, "line {}: expression nested too deeply", line),
+            Error::UnterminatedString(line) => write!(f, "line {}: unterminated string", line),
+            Error::Syntax(line) => write!(f, "line {}: syntax error", line),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::TooLong(_) => "input too long",
+            Error::TooDeep(_) => "expression nested too deeply",
+            Error::UnterminatedString(_) => "unterminated string",
+            Error::Syntax(_) => "syntax error",
+        }
+    }
+}
+
+/// Checks that `i` can be given to `parse` or `parse_located` without
+/// exhausting the stack.
+pub fn check_limits(i: &[u8], limits: &Limits) -> Result<(), Error> {
+    if i.len() > limits.max_len {
+        return Err(Error::TooLong(i.len()));
+    }
+    for (n, line) in i.split(|&c| c == b'\n').enumerate() {
+        let mut in_string = false;
+        let mut in_char = false;
+        let mut escaped = false;
+        let mut nesting = 0usize;
+        let mut depth = 0usize;
+        for &c in line {
+            match c {
+                _ if escaped => escaped = false,
+                b'\\' if in_string || in_char => escaped = true,
+                b'"' if !in_char => in_string = !in_string,
+                b'\'' if !in_string => in_char = !in_char,
+                _ if in_string || in_char => (),
+                b';' => break,
+                b'(' => {
+                    nesting += 1;
+                    depth += 1;
+                }
+                b')' => nesting = nesting.saturating_sub(1),
+                b'+' | b'-' | b'*' | b'/' | b'%' | b'<' | b'>' | b'&' | b'|' | b'^' |
+                b'~' | b'=' | b'!' => depth += 1,
+                _ => (),
+            }
+            if depth > limits.max_depth || nesting > limits.max_depth {
+                return Err(Error::TooDeep(n + 1));
+            }
+        }
+        if in_string {
+            return Err(Error::UnterminatedString(n + 1));
+        }
+    }
+    Ok(())
+}
+
+/// Same as `parse`, but rejects the inputs which don't fit in `limits`
+/// instead of possibly overflowing the stack, and requires the whole input
+/// to be parsed.
+pub fn parse_with_limits(i: &[u8], limits: &Limits) -> Result<Vec<ParsedItem>, Error> {
+    try!(check_limits(i, limits));
+    match parse(i) {
+        IResult::Done(rest, o) if rest.is_empty() => Ok(o),
+        IResult::Done(rest, _) => {
+            let line = i[..i.len() - rest.len()].iter().filter(|&&c| c == b'\n').count();
+            Err(Error::Syntax(line + 1))
+        }
+        _ => Err(Error::Syntax(1)),
+    }
+}
+
+impl FromStr for ParsedInstruction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<ParsedInstruction, ParseError> {
+        match instruction(s.trim().as_bytes()) {
+            IResult::Done(i, o) if i.len() == 0 => Ok(o),
+            _ => Err(ParseError::Instruction),
+        }
+    }
+}
+
+/// Parses a single instruction. Labels are not allowed.
+impl FromStr for Instruction {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Instruction, ParseError> {
+        let parsed: ParsedInstruction = try!(s.parse());
+        parsed.solve(&HashMap::new(), &HashMap::new()).map_err(|e| match e {
+            linker::Error::UnknownLabel(l) |
+            linker::Error::UnknownLocalLabel(l) => ParseError::UnknownLabel(l),
+            _ => ParseError::Instruction,
+        })
+    }
+}
 
 #[cfg(test)]
 const EMPTY: &'static [u8] = &[];
@@ -394,6 +817,28 @@ fn test_expression() {
     assert_eq!(expression("(1)".as_bytes()),
                IResult::Done(EMPTY,
                              Expression::Num(Num::U(1))));
+
+    let n = |n| Box::new(Expression::Num(Num::U(n)));
+    assert_eq!(expression("1 - 2 - 3".as_bytes()),
+               IResult::Done(EMPTY, Expression::Sub(Box::new(Expression::Sub(n(1), n(2))), n(3))));
+    assert_eq!(expression("1 + 2 * 3 == 7".as_bytes()),
+               IResult::Done(EMPTY,
+                             Expression::Eq(Box::new(Expression::Add(n(1),
+                                                                     Box::new(Expression::Mul(n(2), n(3))))),
+                                            n(7))));
+    assert_eq!(expression("(label + 3) & 0xFFF0".as_bytes()),
+               IResult::Done(EMPTY,
+                             Expression::And(Box::new(Expression::Add(Box::new(Expression::Label("label".into())),
+                                                                      n(3))),
+                                             n(0xfff0))));
+    assert_eq!(expression("~-(1)".as_bytes()),
+               IResult::Done(EMPTY,
+                             Expression::Not(Box::new(Expression::Neg(n(1))))));
+    let forward = Box::new(Expression::Forward(1));
+    assert_eq!(expression("1f + 0b1 - 12b".as_bytes()),
+               IResult::Done(EMPTY,
+                             Expression::Sub(Box::new(Expression::Add(forward, n(1))),
+                                             Box::new(Expression::Backward(12)))));
 }
 
 #[cfg(test)]
@@ -404,4 +849,116 @@ fn test_directive() {
                IResult::Done(nl,
                              Directive::Dat(vec!(DatItem::N(1),
                                                  DatItem::N(2)))));
+    assert_eq!(directive(".dat 1 -2\n".as_bytes()),
+               IResult::Done(nl,
+                             Directive::Dat(vec!(DatItem::N(0x0001),
+                                                 DatItem::N(0xfffe)))));
+    assert_eq!(directive(".dat 1 - 2, 'a'\n".as_bytes()),
+               IResult::Done(nl,
+                             Directive::Dat(vec!(DatItem::E(Expression::Sub(
+                                                     Box::new(Expression::Num(Num::U(1))),
+                                                     Box::new(Expression::Num(Num::U(2))))),
+                                                 DatItem::N(0x61)))));
+    assert_eq!(directive(".datp \"abc\", zero\n".as_bytes()),
+               IResult::Done(nl, Directive::DatP("abc".into(), StringFormat::ZeroTerminated)));
+    assert_eq!(directive(".fill 0xFFFF, 16\n".as_bytes()),
+               IResult::Done(nl,
+                             Directive::Fill(Expression::Num(Num::U(0xffff)),
+                                             Expression::Num(Num::U(16)))));
+    assert_eq!(directive(".reserve 256\n".as_bytes()),
+               IResult::Done(nl, Directive::Reserve(Expression::Num(Num::U(256)))));
+    assert_eq!(directive(".proc A, x\n".as_bytes()),
+               IResult::Done(nl, Directive::Proc(vec![Register::A, Register::X])));
+    assert_eq!(directive(".proc\n".as_bytes()), IResult::Done(nl, Directive::Proc(vec![])));
+}
+
+#[cfg(test)]
+#[test]
+fn test_escapes() {
+    assert_eq!(string("\"a\\n\\0\\xFF\\\"\\\\\"".as_bytes()),
+               IResult::Done(EMPTY, "a\n\0\u{ff}\"\\".into()));
+    assert_eq!(expression("'A' + '\\''".as_bytes()),
+               IResult::Done(EMPTY,
+                             Expression::Add(Box::new(Expression::Num(Num::U(0x41))),
+                                             Box::new(Expression::Num(Num::U(0x27))))));
+    assert_eq!(check_limits("SET A, ';' ; \"\n.dat \"\\\"\"".as_bytes(), &Limits::default()),
+               Ok(()));
+}
+
+#[cfg(test)]
+#[test]
+fn test_from_str() {
+    use types::Value;
+
+    assert_eq!("SET A, 0x30".parse(),
+               Ok(Instruction::BasicOp(BasicOp::SET,
+                                       Value::Reg(Register::A),
+                                       Value::Litteral(0x30))));
+    assert_eq!("SET A, foo".parse::<Instruction>(),
+               Err(ParseError::UnknownLabel("foo".into())));
+    assert_eq!("SET A,".parse::<Instruction>(), Err(ParseError::Instruction));
+    assert_eq!("SET A, (0x1234 + 3) & 0xFFF0 | (2 < 1) ^ -(~0)".parse(),
+               Ok(Instruction::BasicOp(BasicOp::SET,
+                                       Value::Reg(Register::A),
+                                       Value::Litteral(0x1231))));
+}
+
+#[cfg(test)]
+#[test]
+fn test_macro() {
+    assert_eq!(parse(".macro inc(reg, n)\nADD reg, n\n.endmacro\ninc(A, 1 + 2)".as_bytes()),
+               IResult::Done(EMPTY,
+                             vec![ParsedItem::MacroDecl(Macro {
+                                      name: "inc".into(),
+                                      args: vec!["reg".into(), "n".into()],
+                                      body: "\nADD reg, n\n".into(),
+                                  }),
+                                  ParsedItem::MacroCall("inc".into(),
+                                                        vec!["A".into(), "1 + 2".into()])]));
+}
+
+#[cfg(test)]
+#[test]
+fn test_include() {
+    assert_eq!(parse(".include \"lib.dasm\"\n#include \"lem.dasm\"".as_bytes()),
+               IResult::Done(EMPTY,
+                             vec![ParsedItem::Include("lib.dasm".into()),
+                                  ParsedItem::Include("lem.dasm".into())]));
+    assert_eq!(incbin(".incbin \"font.bin\"".as_bytes()),
+               IResult::Done(EMPTY,
+                             ParsedItem::IncBin("font.bin".into(), 0, None, Packing::Word)));
+    assert_eq!(incbin(".incbin \"font.bin\", 0x10, 256, byte".as_bytes()),
+               IResult::Done(EMPTY,
+                             ParsedItem::IncBin("font.bin".into(), 16, Some(256), Packing::Byte)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_const_decl() {
+    assert_eq!(parse(".equ WIDTH, 32\n.define SIZE WIDTH * 12".as_bytes()),
+               IResult::Done(EMPTY,
+                             vec![ParsedItem::ConstDecl("WIDTH".into(),
+                                                        Expression::Num(Num::U(32))),
+                                  ParsedItem::ConstDecl("SIZE".into(),
+                                                        Expression::Mul(
+                                      Box::new(Expression::Label("WIDTH".into())),
+                                      Box::new(Expression::Num(Num::U(12)))))]));
+}
+
+#[cfg(test)]
+#[test]
+fn test_limits() {
+    let limits = Limits::default();
+    let deep = format!("SET A, 1\nSET A, {}1{}", "(".repeat(1000), ")".repeat(1000));
+    assert_eq!(parse_with_limits(deep.as_bytes(), &limits), Err(Error::TooDeep(2)));
+    let long = format!("SET A, 1{}", "+1".repeat(1000));
+    assert_eq!(parse_with_limits(long.as_bytes(), &limits), Err(Error::TooDeep(1)));
+    assert_eq!(parse_with_limits(".dat \"foo".as_bytes(), &limits),
+               Err(Error::UnterminatedString(1)));
+    assert!(parse_with_limits(".dat \"a;(((\"\n; \"\n".as_bytes(), &limits).is_ok());
+    assert_eq!(parse_with_limits("SET A, 0x10000".as_bytes(), &limits),
+               Err(Error::Syntax(1)));
+    assert_eq!(parse_with_limits("SET A, 1".as_bytes(),
+                                 &Limits { max_len: 4, ..limits }),
+               Err(Error::TooLong(8)));
 }
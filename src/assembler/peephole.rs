@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use assembler::types::*;
+use types::BasicOp;
+
+/// Change of an instruction found by `optimize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rewrite {
+    Remove,
+    Replace(ParsedInstruction),
+}
+
+/// Finds the instructions of `ast` which can be removed or simplified, by
+/// index:
+///
+/// - `SET PC, label` right before `label`,
+/// - `ADD x, 0` and `SUB x, 0`, which only clear EX, when the next
+///   instructions set EX again before anything reads it,
+/// - `SET PUSH, x` followed by `SET y, POP`, replaced by `SET y, x`.
+///
+/// The instruction after a conditional is never changed, so the
+/// conditional still skips the same code.
+pub fn optimize(ast: &[ParsedItem]) -> HashMap<usize, Rewrite> {
+    let mut rewrites = HashMap::new();
+    let mut after_if = false;
+    let mut n = 0;
+    while n < ast.len() {
+        let i = match ast[n] {
+            ParsedItem::ParsedInstruction(ref i) => i,
+            _ => {
+                n += 1;
+                continue;
+            }
+        };
+        if after_if {
+            after_if = is_if(i);
+            n += 1;
+            continue;
+        }
+        after_if = is_if(i);
+
+        match *i {
+            ParsedInstruction::BasicOp(BasicOp::SET, ParsedValue::PC, ParsedValue::Litteral(ref e))
+                if jumps_to_next(e, &ast[n + 1..]) => {
+                rewrites.insert(n, Rewrite::Remove);
+            }
+            ParsedInstruction::BasicOp(BasicOp::ADD, ref b, ParsedValue::Litteral(ref e)) |
+            ParsedInstruction::BasicOp(BasicOp::SUB, ref b, ParsedValue::Litteral(ref e))
+                if is_zero(e) && is_plain(b) && ex_is_dead(&ast[n + 1..]) => {
+                rewrites.insert(n, Rewrite::Remove);
+            }
+            ParsedInstruction::BasicOp(BasicOp::SET, ParsedValue::Push, ref pushed)
+                if is_plain(pushed) && *pushed != ParsedValue::PC => {
+                if let Some(&ParsedItem::ParsedInstruction(ParsedInstruction::BasicOp(
+                        BasicOp::SET, ref popped, ParsedValue::Push))) = ast.get(n + 1) {
+                    if is_plain(popped) {
+                        if popped == pushed {
+                            rewrites.insert(n, Rewrite::Remove);
+                        } else {
+                            let set = ParsedInstruction::BasicOp(BasicOp::SET,
+                                                                 popped.clone(),
+                                                                 pushed.clone());
+                            rewrites.insert(n, Rewrite::Replace(set));
+                        }
+                        rewrites.insert(n + 1, Rewrite::Remove);
+                        after_if = false;
+                        n += 1;
+                    }
+                }
+            }
+            _ => (),
+        }
+        n += 1;
+    }
+    rewrites
+}
+
+fn is_if(i: &ParsedInstruction) -> bool {
+    match *i {
+        ParsedInstruction::BasicOp(op, _, _) => op.is_if(),
+        ParsedInstruction::SpecialOp(_, _) => false,
+    }
+}
+
+fn is_zero(e: &Expression) -> bool {
+    e.is_constant() && e.solve(&HashMap::new(), &HashMap::new()).ok() == Some(0)
+}
+
+/// Whether reading or writing `v` has no side effect on the stack.
+fn is_plain(v: &ParsedValue) -> bool {
+    match *v {
+        ParsedValue::Push | ParsedValue::Peek | ParsedValue::Pick(_) | ParsedValue::SP => false,
+        _ => true,
+    }
+}
+
+/// Whether EX is written before being read by the straight-line code at
+/// the start of `rest`, stopping at jumps, conditionals and data.
+fn ex_is_dead(rest: &[ParsedItem]) -> bool {
+    for item in rest {
+        let (op, b, a) = match *item {
+            ParsedItem::ParsedInstruction(ParsedInstruction::BasicOp(op, ref b, ref a)) => {
+                (op, b, a)
+            }
+            ParsedItem::LabelDecl(_) |
+            ParsedItem::LocalLabelDecl(_) |
+            ParsedItem::NumericLabelDecl(_) |
+            ParsedItem::Comment(_) => continue,
+            _ => return false,
+        };
+        if op.is_if() || *b == ParsedValue::PC || *a == ParsedValue::EX {
+            return false;
+        }
+        match op {
+            BasicOp::SET if *b == ParsedValue::EX => return true,
+            _ if *b == ParsedValue::EX => return false,
+            BasicOp::ADD | BasicOp::SUB | BasicOp::MUL | BasicOp::MLI | BasicOp::DIV |
+            BasicOp::DVI | BasicOp::SHR | BasicOp::ASR | BasicOp::SHL => return true,
+            BasicOp::ADX | BasicOp::SBX => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+/// Whether `target` is one of the labels declared at the start of `rest`.
+fn jumps_to_next(target: &Expression, rest: &[ParsedItem]) -> bool {
+    let mut same_scope = true;
+    for item in rest {
+        match (item, target) {
+            (&ParsedItem::LabelDecl(ref l), &Expression::Label(ref t)) if l == t => return true,
+            (&ParsedItem::LocalLabelDecl(ref l), &Expression::LocalLabel(ref t))
+                if same_scope && l == t => return true,
+            (&ParsedItem::LabelDecl(_), _) => same_scope = false,
+            (&ParsedItem::LocalLabelDecl(_), _) |
+            (&ParsedItem::Comment(_), _) => (),
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+#[test]
+fn test_optimize() {
+    use nom::IResult;
+
+    use assembler::parser;
+    use types::Register;
+
+    let ast = match parser::parse("SET PC, next\n\
+                                   next:\n\
+                                   ADD A, 0\n\
+                                   MUL B, 2\n\
+                                   IFE A, 1\n\
+                                   SUB A, 0\n\
+                                   SET PUSH, A\n\
+                                   SET B, POP\n\
+                                   SET PUSH, [A]\n\
+                                   SET [A], POP\n\
+                                   ADD A, 0\n\
+                                   ADX B, 0\n\
+                                   SET PC, next\n"
+                                      .as_bytes()) {
+        IResult::Done(_, ast) => ast,
+        _ => panic!(),
+    };
+    let mut rewrites = optimize(&ast).into_iter().collect::<Vec<_>>();
+    rewrites.sort_by_key(|&(n, _)| n);
+    let set_b_a = ParsedInstruction::BasicOp(BasicOp::SET,
+                                             ParsedValue::Reg(Register::B),
+                                             ParsedValue::Reg(Register::A));
+    assert_eq!(rewrites,
+               vec![(0, Rewrite::Remove),
+                    (2, Rewrite::Remove),
+                    (6, Rewrite::Replace(set_b_a)),
+                    (7, Rewrite::Remove),
+                    (8, Rewrite::Remove),
+                    (9, Rewrite::Remove)]);
+}
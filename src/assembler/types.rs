@@ -7,9 +7,42 @@ use assembler::linker::Error;
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Directive {
     Dat(Vec<DatItem>),
-    Org(u16),
-    Global,
+    /// String with two characters per word, see `StringFormat::pack`.
+    DatP(String, StringFormat),
+    /// Absolute address of the next item.
+    Org(Expression),
+    /// Value repeated count times.
+    Fill(Expression, Expression),
+    /// Number of words left zeroed.
+    Reserve(Expression),
+    /// Most cycles the function starting here may take, checked by the
+    /// linker.
+    MaxCycles(Expression),
+    /// Registers holding the arguments of the function starting here, see
+    /// `warnings::check`.
+    Proc(Vec<Register>),
+    /// Labels exported by an object, see `object::assemble`.
+    Global(Vec<String>),
+    /// Start of a section, see `Section`.
     Text,
+    Data,
+    BSS,
+    /// Conditional assembly, see `conditionals::evaluate`.
+    If(Expression),
+    IfDef(String),
+    IfNDef(String),
+    Else,
+    EndIf,
+}
+
+/// Part of the program laid out separately by the linker, see
+/// `linker::Options`. Items before the first section directive are in
+/// `Text`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    Data,
+    /// Only takes space, it must only contain labels and `.reserve`s.
     BSS,
 }
 
@@ -17,37 +50,77 @@ pub enum Directive {
 pub enum DatItem {
     S(String),
     N(u16),
+    E(Expression),
 }
 
 impl Directive {
-    pub fn append_to(&self, bin: &mut Vec<u16>) -> u16 {
+    /// Section started by this directive.
+    pub fn section(&self) -> Option<Section> {
         match *self {
+            Directive::Text => Some(Section::Text),
+            Directive::Data => Some(Section::Data),
+            Directive::BSS => Some(Section::BSS),
+            _ => None,
+        }
+    }
+
+    pub fn append_to(&self,
+                     bin: &mut Vec<u16>,
+                     globals: &HashMap<String, u16>,
+                     locals: &HashMap<String, u16>)
+                     -> Result<u16, Error> {
+        Ok(match *self {
             Directive::Dat(ref v) => {
                 let mut i = 0;
                 for x in v.iter() {
                     i += match *x {
                         DatItem::S(ref s) => {
-                            let it = s.bytes().chain(iter::once(0));
-                            let size = it.size_hint().0;
-                            assert!(size == it.size_hint().1.unwrap());
-                            bin.extend(it.map(|x| x as u16));
-                            size
+                            let l = bin.len();
+                            bin.extend(s.chars().chain(iter::once('\0')).map(|c| c as u16));
+                            bin.len() - l
                         }
                         DatItem::N(n) => {
                             bin.push(n);
                             1
                         }
+                        DatItem::E(ref e) => {
+                            bin.push(try!(e.solve(globals, locals)));
+                            1
+                        }
                     }
                 }
                 i as u16
             }
-            Directive::Org(n) => {
+            Directive::DatP(ref s, format) => {
+                let words = format.pack(s);
+                bin.extend(&words);
+                words.len() as u16
+            }
+            Directive::Fill(ref v, ref n) => {
+                let (v, n) = (try!(v.solve(globals, locals)), try!(n.solve(globals, locals)));
+                bin.extend(iter::repeat(v).take(n as usize));
+                n
+            }
+            Directive::Reserve(ref n) => {
+                let n = try!(n.solve(globals, locals));
                 let l = bin.len();
                 bin.resize(l + (n as usize), 0);
                 n
             }
-            Directive::Global | Directive::Text | Directive::BSS => 0,
-        }
+            // Moves to another address, see `linker::link_with_options`.
+            Directive::Org(_) |
+            Directive::Global(_) |
+            Directive::Text |
+            Directive::Data |
+            Directive::BSS |
+            Directive::MaxCycles(_) |
+            Directive::Proc(_) => 0,
+            Directive::If(_) |
+            Directive::IfDef(_) |
+            Directive::IfNDef(_) |
+            Directive::Else |
+            Directive::EndIf => return Err(Error::UnbalancedConditional(self.clone())),
+        })
     }
 }
 
@@ -63,13 +136,133 @@ impl From<Num> for DatItem {
     }
 }
 
+impl From<Expression> for DatItem {
+    fn from(e: Expression) -> DatItem {
+        match e {
+            Expression::Num(n) => n.into(),
+            e => DatItem::E(e),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ParsedItem {
     Directive(Directive),
     LabelDecl(String),
     LocalLabelDecl(String),
+    /// `1:`, see `linker::resolve_numeric_labels`.
+    NumericLabelDecl(u16),
     ParsedInstruction(ParsedInstruction),
     Comment(String),
+    MacroDecl(Macro),
+    /// `.equ name, value` or `.define name value`. Constants share the
+    /// namespace of the global labels.
+    ConstDecl(String, Expression),
+    /// Name and arguments.
+    MacroCall(String, Vec<String>),
+    /// `.include "file"`, replaced by the items of the file by
+    /// `include::Loader`.
+    Include(String),
+    /// `.incbin "file", offset, length, packing`, replaced by a `.dat` of
+    /// the bytes of the file by `include::Loader`. Without a length, the
+    /// file is read until its end.
+    IncBin(String, usize, Option<usize>, Packing),
+}
+
+impl ParsedItem {
+    /// Copy of the item with `f` applied to its expressions, see
+    /// `Expression::map`.
+    pub fn map_expressions(&self, f: &Fn(&Expression) -> Option<Expression>) -> ParsedItem {
+        let map_directive = |d: &Directive| {
+            match *d {
+                Directive::Dat(ref items) => {
+                    Directive::Dat(items.iter()
+                                        .map(|i| match *i {
+                                            DatItem::E(ref e) => DatItem::E(e.map(f)),
+                                            ref i => i.clone(),
+                                        })
+                                        .collect())
+                }
+                Directive::Org(ref e) => Directive::Org(e.map(f)),
+                Directive::Fill(ref v, ref n) => Directive::Fill(v.map(f), n.map(f)),
+                Directive::Reserve(ref e) => Directive::Reserve(e.map(f)),
+                Directive::MaxCycles(ref e) => Directive::MaxCycles(e.map(f)),
+                Directive::If(ref e) => Directive::If(e.map(f)),
+                ref d => d.clone(),
+            }
+        };
+        match *self {
+            ParsedItem::Directive(ref d) => ParsedItem::Directive(map_directive(d)),
+            ParsedItem::ParsedInstruction(ParsedInstruction::BasicOp(op, ref b, ref a)) => {
+                ParsedItem::ParsedInstruction(ParsedInstruction::BasicOp(op, b.map(f), a.map(f)))
+            }
+            ParsedItem::ParsedInstruction(ParsedInstruction::SpecialOp(op, ref a)) => {
+                ParsedItem::ParsedInstruction(ParsedInstruction::SpecialOp(op, a.map(f)))
+            }
+            ParsedItem::ConstDecl(ref name, ref e) => ParsedItem::ConstDecl(name.clone(), e.map(f)),
+            ref i => i.clone(),
+        }
+    }
+}
+
+/// How `.datp` delimits its string.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StringFormat {
+    Plain,
+    /// Followed by a 0 character.
+    ZeroTerminated,
+    /// Preceded by a word holding the number of characters.
+    LengthPrefixed,
+}
+
+impl StringFormat {
+    /// Packs the characters of `s` two per word, the first one in the high
+    /// byte. An odd character is padded with 0.
+    pub fn pack(&self, s: &str) -> Vec<u16> {
+        let mut bytes = s.chars().map(|c| c as u8).collect::<Vec<_>>();
+        let mut words = vec![];
+        match *self {
+            StringFormat::Plain => (),
+            StringFormat::ZeroTerminated => bytes.push(0),
+            StringFormat::LengthPrefixed => words.push(bytes.len() as u16),
+        }
+        words.extend(bytes.chunks(2)
+                          .map(|c| (c[0] as u16) << 8 | c.get(1).map_or(0, |&b| b as u16)));
+        words
+    }
+}
+
+/// How `.incbin` stores the bytes of a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Packing {
+    /// One byte per word.
+    Byte,
+    /// Two bytes per word, little endian like the binaries of the assembler.
+    Word,
+}
+
+impl Packing {
+    pub fn pack(&self, bytes: &[u8]) -> Vec<u16> {
+        match *self {
+            Packing::Byte => bytes.iter().map(|&b| b as u16).collect(),
+            Packing::Word => {
+                bytes.chunks(2)
+                     .map(|c| c[0] as u16 | c.get(1).map_or(0, |&b| (b as u16) << 8))
+                     .collect()
+            }
+        }
+    }
+}
+
+/// `.macro name(args) body .endmacro`
+///
+/// The body is kept as text and parsed after the arguments have been
+/// substituted, so arguments can be anything: registers, expressions...
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Macro {
+    pub name: String,
+    pub args: Vec<String>,
+    pub body: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -112,6 +305,26 @@ pub enum ParsedValue {
 }
 
 impl ParsedValue {
+    pub fn expression(&self) -> Option<&Expression> {
+        match *self {
+            ParsedValue::AtRegPlus(_, ref e) |
+            ParsedValue::Pick(ref e) |
+            ParsedValue::AtAddr(ref e) |
+            ParsedValue::Litteral(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn map(&self, f: &Fn(&Expression) -> Option<Expression>) -> ParsedValue {
+        match *self {
+            ParsedValue::AtRegPlus(r, ref e) => ParsedValue::AtRegPlus(r, e.map(f)),
+            ParsedValue::Pick(ref e) => ParsedValue::Pick(e.map(f)),
+            ParsedValue::AtAddr(ref e) => ParsedValue::AtAddr(e.map(f)),
+            ParsedValue::Litteral(ref e) => ParsedValue::Litteral(e.map(f)),
+            ref v => v.clone(),
+        }
+    }
+
     fn solve(&self,
              globals: &HashMap<String, u16>,
              locals: &HashMap<String, u16>)
@@ -134,10 +347,23 @@ impl ParsedValue {
     }
 }
 
+/// Constructor of a binary `Expression`.
+pub type BinaryOp = fn(Box<Expression>, Box<Expression>) -> Expression;
+
+/// Name under which the linker passes the address of the current item to
+/// `Expression::solve`, in the globals.
+pub const HERE: &'static str = "$";
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Expression {
     Label(String),
     LocalLabel(String),
+    /// `$`, the address of the current instruction or directive.
+    Here,
+    /// `1b`, the closest `1:` before, see `linker::resolve_numeric_labels`.
+    Backward(u16),
+    /// `1f`, the closest `1:` after.
+    Forward(u16),
     Num(Num),
     Add(Box<Expression>, Box<Expression>),
     Sub(Box<Expression>, Box<Expression>),
@@ -146,10 +372,23 @@ pub enum Expression {
     Shr(Box<Expression>, Box<Expression>),
     Shl(Box<Expression>, Box<Expression>),
     Mod(Box<Expression>, Box<Expression>),
+    Neg(Box<Expression>),
+    /// Bitwise not.
+    Not(Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Xor(Box<Expression>, Box<Expression>),
+    /// The comparisons are unsigned and give 1 if true, 0 else.
+    Eq(Box<Expression>, Box<Expression>),
+    Ne(Box<Expression>, Box<Expression>),
+    Lt(Box<Expression>, Box<Expression>),
+    Le(Box<Expression>, Box<Expression>),
+    Gt(Box<Expression>, Box<Expression>),
+    Ge(Box<Expression>, Box<Expression>),
 }
 
 impl Expression {
-    fn solve(&self,
+    pub fn solve(&self,
              globals: &HashMap<String, u16>,
              locals: &HashMap<String, u16>)
              -> Result<u16, Error> {
@@ -166,6 +405,11 @@ impl Expression {
                     None => Err(Error::UnknownLocalLabel(s.clone())),
                 }
             }
+            Expression::Here => {
+                globals.get(HERE).cloned().ok_or(Error::UnknownLabel(HERE.into()))
+            }
+            Expression::Backward(n) => Err(Error::UnknownLabel(format!("{}b", n))),
+            Expression::Forward(n) => Err(Error::UnknownLabel(format!("{}f", n))),
             Expression::Num(n) => Ok(n.into()),
             Expression::Add(ref l, ref r) => {
                 Ok(try!(l.solve(globals, locals)).wrapping_add(try!(r.solve(globals, locals))))
@@ -177,16 +421,148 @@ impl Expression {
                 Ok(try!(l.solve(globals, locals)).wrapping_mul(try!(r.solve(globals, locals))))
             }
             Expression::Div(ref l, ref r) => {
-                Ok(try!(l.solve(globals, locals)).wrapping_div(try!(r.solve(globals, locals))))
+                try!(l.solve(globals, locals))
+                    .checked_div(try!(r.solve(globals, locals)))
+                    .ok_or(Error::DivisionByZero(self.clone()))
             }
             Expression::Shr(ref l, ref r) => {
-                Ok(try!(l.solve(globals, locals)) >> try!(r.solve(globals, locals)))
+                let (l, r) = (try!(l.solve(globals, locals)), try!(r.solve(globals, locals)));
+                Ok(l.checked_shr(r as u32).unwrap_or(0))
             }
             Expression::Shl(ref l, ref r) => {
-                Ok(try!(l.solve(globals, locals)) << try!(r.solve(globals, locals)))
+                let (l, r) = (try!(l.solve(globals, locals)), try!(r.solve(globals, locals)));
+                Ok(l.checked_shl(r as u32).unwrap_or(0))
             }
             Expression::Mod(ref l, ref r) => {
-                Ok(try!(l.solve(globals, locals)) % try!(r.solve(globals, locals)))
+                try!(l.solve(globals, locals))
+                    .checked_rem(try!(r.solve(globals, locals)))
+                    .ok_or(Error::DivisionByZero(self.clone()))
+            }
+            Expression::Neg(ref e) => Ok(try!(e.solve(globals, locals)).wrapping_neg()),
+            Expression::Not(ref e) => Ok(!try!(e.solve(globals, locals))),
+            Expression::And(ref l, ref r) => {
+                Ok(try!(l.solve(globals, locals)) & try!(r.solve(globals, locals)))
+            }
+            Expression::Or(ref l, ref r) => {
+                Ok(try!(l.solve(globals, locals)) | try!(r.solve(globals, locals)))
+            }
+            Expression::Xor(ref l, ref r) => {
+                Ok(try!(l.solve(globals, locals)) ^ try!(r.solve(globals, locals)))
+            }
+            Expression::Eq(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) == try!(r.solve(globals, locals))) as u16)
+            }
+            Expression::Ne(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) != try!(r.solve(globals, locals))) as u16)
+            }
+            Expression::Lt(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) < try!(r.solve(globals, locals))) as u16)
+            }
+            Expression::Le(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) <= try!(r.solve(globals, locals))) as u16)
+            }
+            Expression::Gt(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) > try!(r.solve(globals, locals))) as u16)
+            }
+            Expression::Ge(ref l, ref r) => {
+                Ok((try!(l.solve(globals, locals)) >= try!(r.solve(globals, locals))) as u16)
+            }
+        }
+    }
+
+    /// Whether the expression is made of numbers only.
+    pub fn is_constant(&self) -> bool {
+        match *self {
+            Expression::Label(_) |
+            Expression::LocalLabel(_) |
+            Expression::Here |
+            Expression::Backward(_) |
+            Expression::Forward(_) => false,
+            Expression::Num(_) => true,
+            Expression::Neg(ref e) | Expression::Not(ref e) => e.is_constant(),
+            Expression::Add(ref l, ref r) |
+            Expression::Sub(ref l, ref r) |
+            Expression::Mul(ref l, ref r) |
+            Expression::Div(ref l, ref r) |
+            Expression::Shr(ref l, ref r) |
+            Expression::Shl(ref l, ref r) |
+            Expression::Mod(ref l, ref r) |
+            Expression::And(ref l, ref r) |
+            Expression::Or(ref l, ref r) |
+            Expression::Xor(ref l, ref r) |
+            Expression::Eq(ref l, ref r) |
+            Expression::Ne(ref l, ref r) |
+            Expression::Lt(ref l, ref r) |
+            Expression::Le(ref l, ref r) |
+            Expression::Gt(ref l, ref r) |
+            Expression::Ge(ref l, ref r) => l.is_constant() && r.is_constant(),
+        }
+    }
+
+    /// Copy of the expression where the leaves (labels, numbers...) for
+    /// which `f` returns something are replaced.
+    pub fn map(&self, f: &Fn(&Expression) -> Option<Expression>) -> Expression {
+        let bin = |l: &Expression, r: &Expression, op: BinaryOp| {
+            op(Box::new(l.map(f)), Box::new(r.map(f)))
+        };
+        match *self {
+            Expression::Label(_) |
+            Expression::LocalLabel(_) |
+            Expression::Here |
+            Expression::Backward(_) |
+            Expression::Forward(_) |
+            Expression::Num(_) => f(self).unwrap_or_else(|| self.clone()),
+            Expression::Neg(ref e) => Expression::Neg(Box::new(e.map(f))),
+            Expression::Not(ref e) => Expression::Not(Box::new(e.map(f))),
+            Expression::Add(ref l, ref r) => bin(l, r, Expression::Add),
+            Expression::Sub(ref l, ref r) => bin(l, r, Expression::Sub),
+            Expression::Mul(ref l, ref r) => bin(l, r, Expression::Mul),
+            Expression::Div(ref l, ref r) => bin(l, r, Expression::Div),
+            Expression::Shr(ref l, ref r) => bin(l, r, Expression::Shr),
+            Expression::Shl(ref l, ref r) => bin(l, r, Expression::Shl),
+            Expression::Mod(ref l, ref r) => bin(l, r, Expression::Mod),
+            Expression::And(ref l, ref r) => bin(l, r, Expression::And),
+            Expression::Or(ref l, ref r) => bin(l, r, Expression::Or),
+            Expression::Xor(ref l, ref r) => bin(l, r, Expression::Xor),
+            Expression::Eq(ref l, ref r) => bin(l, r, Expression::Eq),
+            Expression::Ne(ref l, ref r) => bin(l, r, Expression::Ne),
+            Expression::Lt(ref l, ref r) => bin(l, r, Expression::Lt),
+            Expression::Le(ref l, ref r) => bin(l, r, Expression::Le),
+            Expression::Gt(ref l, ref r) => bin(l, r, Expression::Gt),
+            Expression::Ge(ref l, ref r) => bin(l, r, Expression::Ge),
+        }
+    }
+
+    /// Global labels and constants used by the expression.
+    pub fn labels(&self) -> Vec<&str> {
+        match *self {
+            Expression::Label(ref s) => vec![s],
+            Expression::LocalLabel(_) |
+            Expression::Here |
+            Expression::Backward(_) |
+            Expression::Forward(_) |
+            Expression::Num(_) => vec![],
+            Expression::Neg(ref e) |
+            Expression::Not(ref e) => e.labels(),
+            Expression::Add(ref l, ref r) |
+            Expression::Sub(ref l, ref r) |
+            Expression::Mul(ref l, ref r) |
+            Expression::Div(ref l, ref r) |
+            Expression::Shr(ref l, ref r) |
+            Expression::Shl(ref l, ref r) |
+            Expression::Mod(ref l, ref r) |
+            Expression::And(ref l, ref r) |
+            Expression::Or(ref l, ref r) |
+            Expression::Xor(ref l, ref r) |
+            Expression::Eq(ref l, ref r) |
+            Expression::Ne(ref l, ref r) |
+            Expression::Lt(ref l, ref r) |
+            Expression::Le(ref l, ref r) |
+            Expression::Gt(ref l, ref r) |
+            Expression::Ge(ref l, ref r) => {
+                let mut labels = l.labels();
+                labels.extend(r.labels());
+                labels
             }
         }
     }
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use assembler::linker::Linked;
+use assembler::types::*;
+use encodings::PATCH_POINT;
+use flow;
+use types::{BasicOp, Register, SpecialOp};
+
+/// Names the parser reads as values, so labels named like them are confusing.
+const RESERVED: &'static [&'static str] = &["A", "B", "C", "X", "Y", "Z", "I", "J", "SP", "PC",
+                                             "EX", "PUSH", "POP", "PEEK", "PICK"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// Label never used in an expression nor exported with `.globl`.
+    UnusedLabel(String),
+    /// Label or constant named like a register or a stack value.
+    RegisterName(String),
+    /// Number of words of a `.dat`, more than the whole memory.
+    DataTooLong(usize),
+    /// Target of a `SET PC` or `JSR` outside of the code regions.
+    JumpToData(u16),
+    /// Expression and its value before truncation to 16 bits.
+    Truncated(Expression, i64),
+    /// Address of a function with a `.maxcycles` whose duration can't be
+    /// computed.
+    UnknownCycles(u16),
+    /// Register read before being written in a function declared with
+    /// `.proc`, which isn't one of its arguments.
+    UndefinedRegister(Register),
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnusedLabel(ref l) => write!(f, "unused label {}", l),
+            Warning::RegisterName(ref l) => write!(f, "label {} is named like a register", l),
+            Warning::DataTooLong(n) => write!(f, ".dat of {} words doesn't fit in memory", n),
+            Warning::JumpToData(addr) => write!(f, "jump to data at 0x{:04x}", addr),
+            Warning::Truncated(ref e, v) => {
+                write!(f, "{:?} is {}, truncated to 0x{:04x}", e, v, v as u16)
+            }
+            Warning::UnknownCycles(addr) => {
+                write!(f, "cycles of the function at 0x{:04x} can't be computed", addr)
+            }
+            Warning::UndefinedRegister(r) => write!(f, "{:?} may be read before being set", r),
+        }
+    }
+}
+
+/// Looks for suspicious constructs in a program linked with
+/// `linker::link_detailed`. Returns the warnings with the index of the item
+/// causing them.
+pub fn check(ast: &[ParsedItem], linked: &Linked) -> Vec<(usize, Warning)> {
+    let mut warnings = vec![];
+
+    let mut globals = HashMap::new();
+    let mut locals = HashMap::new();
+    let mut used = HashSet::new();
+    let mut used_locals = HashSet::new();
+    let mut last_global = None;
+    for item in ast {
+        let mut expressions = vec![];
+        match *item {
+            ParsedItem::LabelDecl(ref s) => {
+                last_global = Some(s.clone());
+                globals.insert(s.clone(), linked.symbols.address(s).unwrap_or(0));
+                locals.insert(s.clone(), HashMap::new());
+            }
+            ParsedItem::LocalLabelDecl(ref s) => {
+                if let Some(ref g) = last_global {
+                    let addr = linked.symbols.address(&format!("{}.{}", g, s)).unwrap_or(0);
+                    locals.get_mut(g).unwrap().insert(s.clone(), addr);
+                }
+            }
+            ParsedItem::Directive(Directive::Global(ref labels)) => {
+                used.extend(labels.iter().cloned());
+            }
+            ParsedItem::Directive(Directive::Org(ref e)) |
+            ParsedItem::Directive(Directive::Reserve(ref e)) |
+            ParsedItem::Directive(Directive::MaxCycles(ref e)) |
+            ParsedItem::ConstDecl(_, ref e) => expressions.push(e),
+            ParsedItem::Directive(Directive::Fill(ref v, ref n)) => {
+                expressions.push(v);
+                expressions.push(n);
+            }
+            ParsedItem::Directive(Directive::Dat(ref items)) => {
+                for i in items {
+                    if let DatItem::E(ref e) = *i {
+                        expressions.push(e);
+                    }
+                }
+            }
+            ParsedItem::ParsedInstruction(ref i) => {
+                let (b, a) = match *i {
+                    ParsedInstruction::BasicOp(_, ref b, ref a) => (Some(b), a),
+                    ParsedInstruction::SpecialOp(_, ref a) => (None, a),
+                };
+                expressions.extend(b.and_then(|b| b.expression()));
+                expressions.extend(a.expression());
+            }
+            _ => (),
+        }
+        for e in expressions {
+            used_names(e, &mut used, &mut used_locals, last_global.as_ref());
+        }
+    }
+
+    // Constants, possibly defined after their use.
+    let constants = ast.iter()
+                       .filter_map(|i| match *i {
+                           ParsedItem::ConstDecl(ref name, ref e) => Some((name, e)),
+                           _ => None,
+                       })
+                       .collect::<Vec<_>>();
+    for _ in 0..constants.len() {
+        for &(name, e) in constants.iter() {
+            if let Ok(v) = e.solve(&globals, &HashMap::new()) {
+                globals.insert(name.clone(), v);
+            }
+        }
+    }
+
+    let empty = HashMap::new();
+    let mut last_global = None;
+    for (n, item) in ast.iter().enumerate() {
+        let mut expressions = vec![];
+        let mut name = None;
+        match *item {
+            ParsedItem::LabelDecl(ref s) => {
+                last_global = Some(s);
+                if !used.contains(s) {
+                    warnings.push((n, Warning::UnusedLabel(s.clone())));
+                }
+                name = Some(s);
+            }
+            ParsedItem::LocalLabelDecl(ref s) => {
+                let full = format!("{}.{}", last_global.map_or("", |g| g.as_str()), s);
+                if !used_locals.contains(&full) {
+                    warnings.push((n, Warning::UnusedLabel(full)));
+                }
+                name = Some(s);
+            }
+            ParsedItem::ConstDecl(ref s, ref e) => {
+                name = Some(s);
+                expressions.push(e);
+            }
+            ParsedItem::Directive(Directive::Org(ref e)) |
+            ParsedItem::Directive(Directive::Reserve(ref e)) => expressions.push(e),
+            ParsedItem::Directive(Directive::MaxCycles(ref e)) => {
+                expressions.push(e);
+                let entry = linked.addresses[n];
+                if flow::max_cycles(&linked.bin, entry).is_none() {
+                    warnings.push((n, Warning::UnknownCycles(entry)));
+                }
+            }
+            ParsedItem::Directive(Directive::Proc(ref inputs)) => {
+                // The `SET A, A` of the sled would count as reads of A.
+                let mut entry = linked.addresses[n];
+                if linked.patch_points.contains(&entry) {
+                    entry += PATCH_POINT.len() as u16;
+                }
+                for (addr, r) in flow::undefined_reads(&linked.bin, entry, inputs) {
+                    let item = (0..ast.len()).find(|&i| {
+                        linked.addresses[i] == addr &&
+                        match ast[i] {
+                            ParsedItem::ParsedInstruction(_) => true,
+                            _ => false,
+                        }
+                    });
+                    warnings.push((item.unwrap_or(n), Warning::UndefinedRegister(r)));
+                }
+            }
+            ParsedItem::Directive(Directive::Fill(ref v, ref n)) => {
+                expressions.push(v);
+                expressions.push(n);
+            }
+            ParsedItem::Directive(Directive::Dat(ref items)) => {
+                let mut size = 0;
+                for i in items {
+                    size += match *i {
+                        DatItem::S(ref s) => s.chars().count() + 1,
+                        DatItem::N(_) => 1,
+                        DatItem::E(ref e) => {
+                            expressions.push(e);
+                            1
+                        }
+                    };
+                }
+                if size > 0x10000 {
+                    warnings.push((n, Warning::DataTooLong(size)));
+                }
+            }
+            ParsedItem::Directive(Directive::DatP(ref s, _)) => {
+                let size = s.chars().count() / 2 + 2;
+                if size > 0x10000 {
+                    warnings.push((n, Warning::DataTooLong(size)));
+                }
+            }
+            ParsedItem::ParsedInstruction(ref i) => {
+                let (b, a) = match *i {
+                    ParsedInstruction::BasicOp(_, ref b, ref a) => (Some(b), a),
+                    ParsedInstruction::SpecialOp(_, ref a) => (None, a),
+                };
+                expressions.extend(b.and_then(|b| b.expression()));
+                expressions.extend(a.expression());
+
+                let target = match *i {
+                    ParsedInstruction::BasicOp(BasicOp::SET,
+                                               ParsedValue::PC,
+                                               ParsedValue::Litteral(ref e)) |
+                    ParsedInstruction::SpecialOp(SpecialOp::JSR, ParsedValue::Litteral(ref e)) => {
+                        Some(e)
+                    }
+                    _ => None,
+                };
+                let locals = last_global.map_or(&empty, |g| &locals[g]);
+                if let Some(e) = target {
+                    match e.solve(&globals, locals) {
+                        Ok(addr) if !e.is_constant() &&
+                                    !linked.regions.iter().any(|r| r.contains(addr)) => {
+                            warnings.push((n, Warning::JumpToData(addr)))
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        if let Some(name) = name {
+            if RESERVED.contains(&name.to_uppercase().as_str()) {
+                warnings.push((n, Warning::RegisterName(name.clone())));
+            }
+        }
+        let locals = last_global.map_or(&empty, |g| &locals[g]);
+        globals.insert(HERE.into(), linked.addresses[n]);
+        for e in expressions {
+            match wide(e, &globals, locals) {
+                Some(v) if v < -0x8000 || v > 0xffff => {
+                    warnings.push((n, Warning::Truncated(e.clone(), v)))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    warnings.sort_by_key(|&(n, _)| n);
+    warnings
+}
+
+fn used_names(e: &Expression,
+              globals: &mut HashSet<String>,
+              locals: &mut HashSet<String>,
+              last_global: Option<&String>) {
+    match *e {
+        Expression::Label(ref s) => {
+            globals.insert(s.clone());
+        }
+        Expression::LocalLabel(ref s) => {
+            if let Some(g) = last_global {
+                locals.insert(format!("{}.{}", g, s));
+            }
+        }
+        Expression::Here |
+        Expression::Backward(_) |
+        Expression::Forward(_) |
+        Expression::Num(_) => (),
+        Expression::Neg(ref e) |
+        Expression::Not(ref e) => used_names(e, globals, locals, last_global),
+        Expression::Add(ref l, ref r) |
+        Expression::Sub(ref l, ref r) |
+        Expression::Mul(ref l, ref r) |
+        Expression::Div(ref l, ref r) |
+        Expression::Shr(ref l, ref r) |
+        Expression::Shl(ref l, ref r) |
+        Expression::Mod(ref l, ref r) |
+        Expression::And(ref l, ref r) |
+        Expression::Or(ref l, ref r) |
+        Expression::Xor(ref l, ref r) |
+        Expression::Eq(ref l, ref r) |
+        Expression::Ne(ref l, ref r) |
+        Expression::Lt(ref l, ref r) |
+        Expression::Le(ref l, ref r) |
+        Expression::Gt(ref l, ref r) |
+        Expression::Ge(ref l, ref r) => {
+            used_names(l, globals, locals, last_global);
+            used_names(r, globals, locals, last_global);
+        }
+    }
+}
+
+/// Value of `e` without wrapping, `None` if it can't be computed.
+fn wide(e: &Expression,
+        globals: &HashMap<String, u16>,
+        locals: &HashMap<String, u16>)
+        -> Option<i64> {
+    let operands = |l: &Expression, r: &Expression| {
+        match (wide(l, globals, locals), wide(r, globals, locals)) {
+            (Some(l), Some(r)) => Some((l, r)),
+            _ => None,
+        }
+    };
+    // Like `Expression::solve`, on the truncated values.
+    let compare = |l: &Expression, r: &Expression, f: fn(u16, u16) -> bool| {
+        operands(l, r).map(|(l, r)| f(l as u16, r as u16) as i64)
+    };
+    match *e {
+        Expression::Label(ref s) => globals.get(s).map(|&v| v as i64),
+        Expression::LocalLabel(ref s) => locals.get(s).map(|&v| v as i64),
+        Expression::Here => globals.get(HERE).map(|&v| v as i64),
+        Expression::Backward(_) | Expression::Forward(_) => None,
+        Expression::Num(Num::U(n)) => Some(n as i64),
+        Expression::Num(Num::I(n)) => Some(n as i64),
+        Expression::Add(ref l, ref r) => operands(l, r).map(|(l, r)| l + r),
+        Expression::Sub(ref l, ref r) => operands(l, r).map(|(l, r)| l - r),
+        Expression::Mul(ref l, ref r) => operands(l, r).and_then(|(l, r)| l.checked_mul(r)),
+        Expression::Div(ref l, ref r) => operands(l, r).and_then(|(l, r)| l.checked_div(r)),
+        Expression::Mod(ref l, ref r) => operands(l, r).and_then(|(l, r)| l.checked_rem(r)),
+        Expression::Shr(ref l, ref r) => {
+            operands(l, r).map(|(l, r)| if r < 0 || r >= 64 { 0 } else { l >> r })
+        }
+        Expression::Shl(ref l, ref r) => {
+            operands(l, r).and_then(|(l, r)| if r < 0 || r >= 48 { None } else { Some(l << r) })
+        }
+        Expression::Neg(ref e) => wide(e, globals, locals).map(|v| -v),
+        Expression::Not(ref e) => wide(e, globals, locals).map(|v| !v),
+        Expression::And(ref l, ref r) => operands(l, r).map(|(l, r)| l & r),
+        Expression::Or(ref l, ref r) => operands(l, r).map(|(l, r)| l | r),
+        Expression::Xor(ref l, ref r) => operands(l, r).map(|(l, r)| l ^ r),
+        Expression::Eq(ref l, ref r) => compare(l, r, |l, r| l == r),
+        Expression::Ne(ref l, ref r) => compare(l, r, |l, r| l != r),
+        Expression::Lt(ref l, ref r) => compare(l, r, |l, r| l < r),
+        Expression::Le(ref l, ref r) => compare(l, r, |l, r| l <= r),
+        Expression::Gt(ref l, ref r) => compare(l, r, |l, r| l > r),
+        Expression::Ge(ref l, ref r) => compare(l, r, |l, r| l >= r),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_check() {
+    use nom::IResult;
+
+    use assembler::{linker, parser};
+
+    let ast = match parser::parse(".equ BIG, 0x8000 * 4\n\
+                                   main:\n\
+                                   SET A, BIG\n\
+                                   JSR table\n\
+                                   SET PC, main\n\
+                                   unused:\n\
+                                   table:\n\
+                                   .dat 1\n\
+                                   pc:\n"
+                                      .as_bytes()) {
+        IResult::Done(_, ast) => ast,
+        _ => panic!(),
+    };
+    let linked = linker::link_detailed(&ast).unwrap();
+    let warnings = check(&ast, &linked).into_iter().map(|(_, w)| w).collect::<Vec<_>>();
+    assert_eq!(warnings,
+               vec![Warning::Truncated(Expression::Mul(Box::new(Num::U(0x8000).into()),
+                                                       Box::new(Num::U(4).into())),
+                                       0x20000),
+                    Warning::JumpToData(3),
+                    Warning::UnusedLabel("unused".into()),
+                    Warning::UnusedLabel("pc".into()),
+                    Warning::RegisterName("pc".into())]);
+}
@@ -8,35 +8,205 @@ extern crate simplelog;
 #[macro_use]
 mod utils;
 
-use std::io::{Read, Write};
-use std::str;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
-use byteorder::WriteBytesExt;
 use docopt::Docopt;
-use nom::IResult::*;
 
-use dcpu::assembler::{linker, parser};
+use dcpu::assembler::{self, conditionals, include, linker, listing, macros, object, warnings};
+use dcpu::assembler::dialect::Dialect;
+use dcpu::assembler::types::{Expression, Num, ParsedItem};
+use dcpu::formats::{self, Format};
+use dcpu::types::Region;
+use rustc_serialize::json;
+use utils::OutputFormat;
 
 const USAGE: &'static str = "
 Usage:
-  assembler [--no-cpp] [--ast] [--hex] [<file>] [-o <file>]
+  assembler [--no-cpp] [--dialect <name>] [--strict] [--ast] [-c] [--hex | --format <format>] [--deny-warnings] [--no-short-literals] [-O] [--text <addr>] [--data <addr>] [--bss <addr>] [-I <dir>]... [-D <define>]... [--regions <file>] [--debug-info <file>] [--listing <file>] [--symbols <file>] [--patch-points <file>] [--output <format>] [<file>] [-o <file>]
   assembler (--help | --version)
 
 Options:
-  --no-cpp      Disable gcc preprocessor pass.
-  --ast         Show the file AST.
-  --hex         Show in hexadecimal instead of binary.
-  <file>        File to use instead of stdin.
-  -o <file>     File to use instead of stdout.
-  -h --help     Show this screen.
-  --version     Show version.
+  --no-cpp           Disable gcc preprocessor pass.
+  --dialect <name>   Syntax of the source: permissive, notch (:label, dat,
+                     no directive) or standard (0x10c-standards, #macro or
+                     .macro). cpp rejects the # directives, use --no-cpp
+                     with them. [default: permissive]
+  --strict           Reject the opcodes and directives specific to this
+                     assembler and emulator (LOG, BRK, HLT, SLP, .datp,
+                     .maxcycles, .proc and the sections), so the program
+                     runs the same on any DCPU-16 1.7. Implies the
+                     standard dialect instead of permissive. Ignored
+                     with -c.
+  --ast              Show the file AST.
+  -c                 Output a relocatable object to give to the linker.
+  --hex              Same as --format hex, show the binary in
+                     hexadecimal.
+  --format <format>  Format of the binary: le, be, hex or ihex (see
+                     dcpu::formats). [default: le]
+  --deny-warnings    Fail if there are warnings.
+  --no-short-literals
+                     Always put the literals in a word after the
+                     instruction, even the small ones which fit in it.
+  -O                 Remove the useless instructions, like the jumps to
+                     the next instruction.
+  --text <addr>      Address of the .text section, 0 by default.
+  --data <addr>      Address of the .data section, right after .text by
+                     default.
+  --bss <addr>       Address of the .bss section, right after .data by
+                     default.
+  -I <dir>           Add a directory to the .include search path.
+  -D <define>        Define a constant, as NAME or NAME=value. They can be
+                     used in expressions and .if/.ifdef conditions.
+  --regions <file>   Write the code regions of the binary to this file.
+  --debug-info <file>
+                     Write the source line of each instruction and the
+                     labels to this file.
+  --listing <file>   Write the source with the address and words of each
+                     line to this file.
+  --symbols <file>   Write the address of each label to this file, one
+                     \"label 0xaddr\" per line, local labels as
+                     \"global.local\".
+  --patch-points <file>
+                     Put a 2 words NOP sled at the start of each .proc,
+                     which the emulator can patch to trace the calls, and
+                     write their addresses to this file, one
+                     \"label 0xaddr\" per line.
+  --output <format>  Output format, text or json. With json, the words,
+                     code regions and diagnostics (errors and warnings,
+                     with their file, line, column and severity) are
+                     written as a JSON object. [default: text]
+  <file>             File to use instead of stdin.
+  -o <file>          File to use instead of stdout.
+  -h --help          Show this screen.
+  --version          Show version.
 ";
 
+#[derive(RustcEncodable)]
+struct JsonRegion {
+    first: u16,
+    last: u16,
+}
+
+#[derive(RustcEncodable)]
+struct JsonOutput {
+    words: Vec<u16>,
+    regions: Vec<JsonRegion>,
+    /// The warnings.
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[derive(RustcEncodable)]
+struct JsonError {
+    /// The errors, and the warnings found before them.
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Error or warning, `file` being empty and `line` and `column` 0 if
+/// unknown.
+#[derive(RustcEncodable)]
+struct Diagnostic {
+    file: String,
+    line: usize,
+    column: usize,
+    /// error or warning.
+    severity: String,
+    message: String,
+}
+
+impl Diagnostic {
+    fn error(message: String) -> Diagnostic {
+        Diagnostic {
+            file: String::new(),
+            line: 0,
+            column: 0,
+            severity: "error".into(),
+            message: message,
+        }
+    }
+
+    fn at(file: &Path, line: usize, column: usize, severity: &str, message: String) -> Diagnostic {
+        Diagnostic {
+            file: file.display().to_string(),
+            line: line,
+            column: column,
+            severity: severity.into(),
+            message: message,
+        }
+    }
+
+    /// `file:line:column: message` followed by the source line, if the
+    /// position is known.
+    fn describe(&self, stdin: &str) -> String {
+        let message = match self.severity.as_str() {
+            "warning" => format!("warning: {}", self.message),
+            _ if self.line == 0 => format!("Error: {}", self.message),
+            _ => self.message.clone(),
+        };
+        if self.line == 0 {
+            return message;
+        }
+        let mut res = format!("{}:{}:{}: {}", self.file, self.line, self.column, message);
+        let text = read_source(Path::new(&self.file), stdin)
+                       .ok()
+                       .and_then(|s| s.lines().nth(self.line - 1).map(String::from));
+        if let Some(text) = text {
+            res.push_str(&format!("\n{}\n{:>2$}", text, "^", self.column));
+        }
+        res
+    }
+}
+
+/// Writes the diagnostics, as JSON or to stderr, and exits.
+macro_rules! fail_with {
+    ( $format:expr, $diagnostics:expr, $stdin:expr ) => (
+        {
+            let diagnostics = $diagnostics;
+            if $format == OutputFormat::Json {
+                println!("{}", json::encode(&JsonError { diagnostics: diagnostics }).unwrap());
+            } else {
+                let mut stderr = io::stderr();
+                for d in diagnostics.iter() {
+                    writeln!(stderr, "{}", d.describe($stdin)).unwrap();
+                }
+            }
+            return 1;
+        }
+    )
+}
+
+macro_rules! fail {
+    ( $format:expr, $($x:expr),* ) => (
+        fail_with!($format, vec![Diagnostic::error(format!($($x),*))], "")
+    )
+}
+
+#[allow(non_snake_case)]
 #[derive(Debug, RustcDecodable)]
 struct Args {
     flag_no_cpp: bool,
+    flag_dialect: String,
+    flag_strict: bool,
     flag_ast: bool,
+    flag_c: bool,
     flag_hex: bool,
+    flag_format: String,
+    flag_deny_warnings: bool,
+    flag_no_short_literals: bool,
+    flag_O: bool,
+    flag_text: Option<String>,
+    flag_data: Option<String>,
+    flag_bss: Option<String>,
+    flag_I: Vec<String>,
+    flag_D: Vec<String>,
+    flag_regions: Option<String>,
+    flag_debug_info: Option<String>,
+    flag_listing: Option<String>,
+    flag_symbols: Option<String>,
+    flag_patch_points: Option<String>,
+    flag_output: utils::OutputFormat,
     arg_file: Option<String>,
     flag_o: Option<String>,
 }
@@ -48,51 +218,250 @@ fn main_ret() -> i32 {
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
 
-    let asm = {
-        let mut asm = String::new();
-        let mut input = utils::get_input(args.arg_file);
-        input.read_to_string(&mut asm).unwrap();
-        asm
+    let dialect = match args.flag_dialect.parse() {
+        Ok(Dialect::Permissive) if args.flag_strict => Dialect::Standard,
+        Ok(d) => d,
+        Err(()) => fail!(args.flag_output, "Invalid dialect: {}", args.flag_dialect),
     };
-
-    let preprocessed = {
-        if args.flag_no_cpp {
-            asm
-        } else {
-            dcpu::preprocessor::preprocess(&asm).unwrap()
+    let format = match args.flag_format.parse() {
+        Ok(_) if args.flag_hex => Format::Hex,
+        Ok(f) => f,
+        Err(e) => fail!(args.flag_output, "{}", e),
+    };
+    let loader = include::Loader {
+        search_paths: args.flag_I.iter().map(PathBuf::from).collect(),
+        preprocess: !args.flag_no_cpp,
+        dialect: dialect,
+        ..include::Loader::default()
+    };
+    let mut stdin = String::new();
+    let program = match args.arg_file {
+        Some(ref path) => loader.load(Path::new(path)),
+        None => {
+            utils::get_input(None).read_to_string(&mut stdin).unwrap();
+            loader.load_str(&stdin, Path::new("<stdin>"))
+        }
+    };
+    let program = match program {
+        Ok(program) => program,
+        Err(include::Error::Syntax(ref file, line, column, ref text)) if line != 0 => {
+            let message = format!("syntax error at \"{}\"", text);
+            fail_with!(args.flag_output,
+                       vec![Diagnostic::at(file, line, column, "error", message)],
+                       &stdin)
         }
+        Err(e) => fail!(args.flag_output, "{}", e)
     };
-    let parsed = parser::parse(&preprocessed.as_bytes());
-    let ast = match parsed {
-        Done(ref i, ref o) if i.len() == 0 => o,
-        Done(ref i, _) => die!(1, "Unknown: \"{}\"", str::from_utf8(i).unwrap().lines().next().unwrap()),
-        e => die!(1, "Error: {:?}", e)
+    let (ast, positions) = match macros::expand_with_positions(&program.items,
+                                                               &program.positions) {
+        Ok(v) => v,
+        Err(e) => {
+            fail_with!(args.flag_output,
+                       link_diagnostics(e, &program.positions, &program.files),
+                       &stdin)
+        }
     };
 
+    let mut defines = HashMap::new();
+    for d in args.flag_D.iter() {
+        let mut parts = d.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let value = match parts.next().map(parse_num) {
+            Some(Some(v)) => v,
+            Some(None) => fail!(args.flag_output, "Invalid value: {}", d),
+            None => 1,
+        };
+        defines.insert(name.to_string(), value);
+    }
+    let (mut ast, mut positions) = match conditionals::evaluate_with_positions(&ast,
+                                                                               &positions,
+                                                                               &defines) {
+        Ok(v) => v,
+        Err(e) => {
+            fail_with!(args.flag_output,
+                       link_diagnostics(e, &positions, &program.files),
+                       &stdin)
+        }
+    };
+    for (name, &value) in defines.iter() {
+        ast.insert(0, ParsedItem::ConstDecl(name.clone(), Expression::Num(Num::U(value))));
+        positions.insert(0,
+                         include::Position {
+                             file: 0,
+                             line: 0,
+                             column: 0,
+                         });
+    }
+
     if args.flag_ast {
         die!(0, "{:?}", ast);
     }
 
-    let bin = match linker::link(ast) {
+    if args.flag_c {
+        let object = match object::assemble(&ast) {
+            Ok(v) => v,
+            Err(e) => fail!(args.flag_output, "{}", e)
+        };
+        write!(utils::get_output(args.flag_o), "{}", object).unwrap();
+        return 0;
+    }
+
+    let mut bases = [None; 3];
+    for (base, flag) in bases.iter_mut().zip(&[&args.flag_text, &args.flag_data, &args.flag_bss]) {
+        if let Some(ref addr) = **flag {
+            match parse_num(addr) {
+                Some(v) => *base = Some(v),
+                None => fail!(args.flag_output, "Invalid address: {}", addr),
+            }
+        }
+    }
+    let options = linker::Options {
+        short_literals: !args.flag_no_short_literals,
+        optimize: args.flag_O,
+        text_base: bases[0].unwrap_or(0),
+        data_base: bases[1],
+        bss_base: bases[2],
+        patch_points: args.flag_patch_points.is_some(),
+        strict: args.flag_strict,
+    };
+    let linked = match linker::link_with_options(&ast, &options) {
         Ok(v) => v,
-        Err(e) => die!(1, "Error: {:?}", e)
+        Err(e) => {
+            fail_with!(args.flag_output,
+                       link_diagnostics(e, &positions, &program.files),
+                       &stdin)
+        }
     };
+    if args.flag_O {
+        writeln!(io::stderr(), "optimizations saved {} words", linked.saved_words).unwrap();
+    }
 
-    let mut output = utils::get_output(args.flag_o);
+    let mut warnings = warnings::check(&ast, &linked)
+                           .into_iter()
+                           .map(|(i, w)| {
+                               let pos = positions[i];
+                               let file = if pos.line == 0 {
+                                   Path::new("")
+                               } else {
+                                   program.files[pos.file].as_path()
+                               };
+                               Diagnostic::at(file, pos.line, pos.column, "warning", w.to_string())
+                           })
+                           .collect::<Vec<_>>();
+    if args.flag_output == OutputFormat::Text {
+        for w in warnings.iter() {
+            writeln!(io::stderr(), "{}", w.describe(&stdin)).unwrap();
+        }
+    }
+    if args.flag_deny_warnings && !warnings.is_empty() {
+        let error = Diagnostic::error(format!("{} warnings", warnings.len()));
+        // Already written to stderr.
+        if args.flag_output == OutputFormat::Text {
+            fail_with!(args.flag_output, vec![error], &stdin);
+        }
+        warnings.push(error);
+        fail_with!(args.flag_output, warnings, &stdin);
+    }
+
+    if let Some(path) = args.flag_debug_info {
+        let info = assembler::debug_info(&program.files, &ast, &positions, &linked);
+        let mut output = utils::get_output(Some(path));
+        write!(output, "{}", info).unwrap();
+    }
 
-    if args.flag_hex {
-        for n in bin {
-            writeln!(output, "0x{:x}", n).unwrap();
+    if let Some(path) = args.flag_listing {
+        let mut sources = vec![];
+        for file in program.files.iter() {
+            match read_source(file, &stdin) {
+                Ok(source) => sources.push((file.display().to_string(), source)),
+                Err(e) => fail!(args.flag_output, "{}: {}", file.display(), e),
+            }
         }
-    } else {
-        for n in bin {
-            output.write_u16::<byteorder::LittleEndian>(n).unwrap();
+        let mut output = utils::get_output(Some(path));
+        write!(output,
+               "{}",
+               listing::listing(&sources, &ast, &positions, &linked))
+            .unwrap();
+    }
+
+    if let Some(path) = args.flag_symbols {
+        let mut output = utils::get_output(Some(path));
+        write!(output, "{}", linked.symbols).unwrap();
+    }
+
+    if let Some(path) = args.flag_patch_points {
+        let mut output = utils::get_output(Some(path));
+        for &addr in linked.patch_points.iter() {
+            writeln!(output, "{} 0x{:04x}", linked.symbols.describe(addr), addr).unwrap();
         }
     }
 
+    if let Some(path) = args.flag_regions {
+        let mut output = utils::get_output(Some(path));
+        for r in linked.regions.iter() {
+            writeln!(output, "{}", r).unwrap();
+        }
+    }
+
+    let (bin, regions) = (linked.bin, linked.regions);
+    let mut output = utils::get_output(args.flag_o);
+
+    if args.flag_output == OutputFormat::Json {
+        let out = JsonOutput {
+            diagnostics: warnings,
+            words: bin,
+            regions: regions.iter()
+                            .map(|&Region { first, last }| JsonRegion {
+                                first: first,
+                                last: last,
+                            })
+                            .collect(),
+        };
+        writeln!(output, "{}", json::encode(&out).unwrap()).unwrap();
+    } else {
+        formats::write(&bin, format, &mut output).unwrap();
+    }
+
     return 0;
 }
 
+/// Reads a file of `Program::files`, `stdin` being the content of `<stdin>`.
+fn read_source(file: &Path, stdin: &str) -> io::Result<String> {
+    if file == Path::new("<stdin>") {
+        return Ok(stdin.into());
+    }
+    let mut source = String::new();
+    try!(File::open(file).and_then(|mut f| f.read_to_string(&mut source)));
+    Ok(source)
+}
+
+/// Each error of `e`, at the item causing it if known.
+fn link_diagnostics(e: linker::Error,
+                    positions: &[include::Position],
+                    files: &[PathBuf])
+                    -> Vec<Diagnostic> {
+    e.into_vec()
+     .into_iter()
+     .map(|e| {
+         match e {
+             linker::Error::At(i, e) if positions[i].line != 0 => {
+                 let pos = positions[i];
+                 Diagnostic::at(&files[pos.file], pos.line, pos.column, "error", e.to_string())
+             }
+             e => Diagnostic::error(e.without_location().to_string()),
+         }
+     })
+     .collect()
+}
+
+fn parse_num(s: &str) -> Option<u16> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
 fn main() {
     std::process::exit(main_ret());
 }
@@ -0,0 +1,151 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::io::{Read, Write};
+
+use docopt::Docopt;
+use rustc_serialize::json;
+
+use dcpu::flow::CallGraph;
+use dcpu::symbols::Symbols;
+
+const USAGE: &'static str = "
+Usage:
+  callgraph [--symbols <file>] [--calls <file>] [-e <entry>]... [--reach <function>] [--output <format>] [<file>] [-o <file>]
+  callgraph (--help | --version)
+
+Options:
+  --symbols <file>    Name the functions after the labels of this file (see
+                      assembler --symbols).
+  --calls <file>      Calls which can't be found statically, like the `JSR`s
+                      to registers. One `caller callee` per line.
+  -e <entry>          Function from which to look for the calls, 0 if none
+                      is given.
+  --reach <function>  List the functions from which this one can be called
+                      instead.
+  --output <format>   Output format, dot or json. [default: dot]
+  <file>              File to use instead of stdin.
+  -o <file>           File to use instead of stdout.
+  -h, --help          Show this message.
+  --version           Show the version of callgraph.
+
+Functions are labels, `label+offset` or addresses.
+";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, RustcDecodable)]
+enum Format {
+    Dot,
+    Json,
+}
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_symbols: Option<String>,
+    flag_calls: Option<String>,
+    flag_e: Vec<String>,
+    flag_reach: Option<String>,
+    flag_output: Format,
+    arg_file: Option<String>,
+    flag_o: Option<String>,
+}
+
+#[derive(RustcEncodable)]
+struct JsonFunction {
+    address: u16,
+    name: String,
+    calls: Vec<String>,
+    indirect: bool,
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let symbols = match args.flag_symbols {
+        Some(path) => {
+            let mut text = String::new();
+            utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+            match text.parse() {
+                Ok(symbols) => symbols,
+                Err(e) => die!(1, "Invalid symbols {}: {:?}", path, e),
+            }
+        }
+        None => Symbols::new(),
+    };
+
+    let mut entries = vec![];
+    for e in args.flag_e.iter() {
+        match symbols.resolve(e) {
+            Some(addr) => entries.push(addr),
+            None => die!(1, "Unknown function {}", e),
+        }
+    }
+    if entries.is_empty() {
+        entries.push(0);
+    }
+
+    let mut extra_calls = vec![];
+    if let Some(path) = args.flag_calls {
+        let mut text = String::new();
+        utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let mut functions = line.split_whitespace().map(|f| symbols.resolve(f));
+            match (functions.next(), functions.next(), functions.next()) {
+                (Some(Some(caller)), Some(Some(callee)), None) => {
+                    extra_calls.push((caller, callee))
+                }
+                _ => die!(1, "Invalid call in {}: {}", path, line),
+            }
+        }
+    }
+
+    let words = utils::IterU16 { input: utils::get_input(args.arg_file) }.collect::<Vec<_>>();
+    let graph = CallGraph::build(&words, &entries, &extra_calls);
+
+    let mut output = utils::get_output(args.flag_o);
+    if let Some(target) = args.flag_reach {
+        let target = match symbols.resolve(&target) {
+            Some(addr) => addr,
+            None => die!(1, "Unknown function {}", target),
+        };
+        for caller in graph.callers(target) {
+            writeln!(output, "{}", symbols.describe(caller)).unwrap();
+        }
+        return 0;
+    }
+
+    match args.flag_output {
+        Format::Dot => write!(output, "{}", graph.to_dot(&symbols)).unwrap(),
+        Format::Json => {
+            let functions = graph.calls
+                                 .iter()
+                                 .map(|(&address, callees)| {
+                                     JsonFunction {
+                                         address: address,
+                                         name: symbols.describe(address),
+                                         calls: callees.iter()
+                                                       .map(|&c| symbols.describe(c))
+                                                       .collect(),
+                                         indirect: graph.indirect.contains(&address),
+                                     }
+                                 })
+                                 .collect::<Vec<_>>();
+            writeln!(output, "{}", json::encode(&functions).unwrap()).unwrap();
+        }
+    }
+
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
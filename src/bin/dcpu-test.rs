@@ -0,0 +1,144 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use docopt::Docopt;
+
+use dcpu::assembler;
+use dcpu::assembler::include::Loader;
+use dcpu::computer::Computer;
+use dcpu::cpu::{self, Cpu};
+use dcpu::device::harness::{Outcome, Report, TestHarness};
+
+const USAGE: &'static str = "
+Usage:
+  dcpu-test [--max-cycles <n>] [--no-cpp] [-I <dir>...] <file>...
+  dcpu-test (--help | --version)
+
+Assembles each file and runs it with a test harness at hardware index 0
+(see dcpu::device::harness), until the program sends DONE, halts or runs
+out of cycles. Prints the result of each test it reports, and exits with 1
+if a test failed or a program didn't finish.
+
+Options:
+  --max-cycles <n>   Cycles each program runs at most. [default: 10000000]
+  --no-cpp           Don't run the files through cpp.
+  -I <dir>           Search the .include files in this directory too.
+  -h, --help         Show this message.
+  --version          Show the version of dcpu-test.
+";
+
+#[allow(non_snake_case)]
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_max_cycles: u64,
+    flag_no_cpp: bool,
+    flag_I: Vec<String>,
+    arg_file: Vec<String>,
+}
+
+/// Why a program ended.
+enum End {
+    Done,
+    Halted,
+    OutOfCycles,
+    Failed(cpu::Error),
+}
+
+fn run(bin: &[u16], max_cycles: u64) -> (Report, End) {
+    let mut cpu = Cpu::default();
+    cpu.load(bin, 0);
+    let mut computer = Computer::new(cpu);
+    let harness = TestHarness::new();
+    let report = harness.report();
+    computer.add_device(Box::new(harness));
+    let end = run_until_end(&mut computer, &report, max_cycles);
+    let report = report.borrow().clone();
+    (report, end)
+}
+
+fn run_until_end(computer: &mut Computer, report: &RefCell<Report>, max_cycles: u64) -> End {
+    loop {
+        if report.borrow().done {
+            return End::Done;
+        }
+        if computer.current_tick() >= max_cycles {
+            return End::OutOfCycles;
+        }
+        match computer.tick() {
+            Ok(_) => (),
+            Err(cpu::Error::Halted) => return End::Halted,
+            Err(e) => return End::Failed(e),
+        }
+    }
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let loader = Loader {
+        search_paths: args.flag_I.iter().map(PathBuf::from).collect(),
+        preprocess: !args.flag_no_cpp,
+        ..Loader::default()
+    };
+    let mut success = true;
+    for file in &args.arg_file {
+        println!("{}", file);
+        let bin = match assembler::assemble_file(&loader, Path::new(file)) {
+            Ok(bin) => bin,
+            Err(e) => {
+                println!("  error: {}", e);
+                success = false;
+                continue;
+            }
+        };
+        let (report, end) = run(&bin, args.flag_max_cycles);
+        for (i, test) in report.tests.iter().enumerate() {
+            let name = if test.name.is_empty() {
+                format!("test {}", i + 1)
+            } else {
+                test.name.clone()
+            };
+            match test.outcome {
+                Outcome::Running => println!("  unfinished  {}", name),
+                Outcome::Passed => println!("  ok          {}", name),
+                Outcome::Failed(ref message) if message.is_empty() => {
+                    println!("  FAILED      {}", name)
+                }
+                Outcome::Failed(ref message) => println!("  FAILED      {}: {}", name, message),
+            }
+        }
+        let finished = match end {
+            End::Done | End::Halted => true,
+            End::OutOfCycles => {
+                println!("  still running after {} cycles", args.flag_max_cycles);
+                false
+            }
+            End::Failed(e) => {
+                println!("  {}", e);
+                false
+            }
+        };
+        let passed = report.tests.iter().filter(|t| t.outcome == Outcome::Passed).count();
+        let failed = report.failed();
+        println!("  {} passed, {} failed", passed, failed);
+        success &= finished && passed == report.tests.len();
+    }
+    if success { 0 } else { 1 }
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
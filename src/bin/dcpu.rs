@@ -0,0 +1,396 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+mod repl;
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+use docopt::Docopt;
+
+use dcpu::types::{Instruction, MASK_B, MASK_OP, SHIFT_A, SHIFT_B, Value};
+
+const USAGE: &'static str = "
+Usage:
+  dcpu new <name> [--template <template>]
+  dcpu explain <word>...
+  dcpu encode <instruction>
+  dcpu run [--no-cpp] [-I <dir>]... [(-d <device>)...] [--turbo] [--max-cycles <n>] [--trace] <source>
+  dcpu repl [--explain] [--symbols <file>] [--debug-info <file>] [--session <file>] [<file>]
+  dcpu (--help | --version)
+
+Commands:
+  new                Create a project in the directory <name>: the sources
+                     in src/, and a Makefile building them with assembler
+                     and running them with emulator.
+  explain            Decode the words, given in decimal or as 0x hexadecimal,
+                     and show the fields of each instruction.
+  encode             Show every encoding of the instruction, like
+                     \"SET A, 0x30\", with its size and cycles.
+  run                Assemble the source and run it right away, showing
+                     the source line of the instruction where the CPU
+                     fails. Exits like emulator: with 0 when the program
+                     halts, 124 after the --max-cycles and 125 when the
+                     CPU fails.
+  repl               Read instructions and commands, like :step or :break,
+                     from the standard input. Each instruction is
+                     assembled and executed immediately. Type :help for
+                     the commands.
+
+Options:
+  --template <template>
+                     Program to start from: bare, lem-game (a LEM1802 and
+                     clock game loop), os (interrupt handler, device
+                     enumeration and system calls) or scheduler (tasks
+                     preempted by the cycle timer). [default: bare]
+  --no-cpp           Don't run the source through cpp.
+  -I <dir>           Search the .include files in this directory too.
+  -d, --device <device>
+                     Attach this device, like lem1802,screen=term, instead
+                     of the default clock (see dcpu::device::registry).
+                     Can be repeated.
+  --turbo            Run as fast as possible instead of in real time.
+  --max-cycles <n>   Stop after this many cycles.
+  --trace            Log each instruction executed to stderr, with its
+                     label.
+  --explain          Explain what each instruction does before executing it.
+  --symbols <file>   Labels of the program, one \"label 0xaddr\" per line, as
+                     written by assembler --symbols.
+  --debug-info <file>
+                     Source lines of the program, as written by the
+                     assembler. The sources are shown when stepping.
+  --session <file>   Load and save the breakpoints, watches and settings from
+                     this file. They are stored using labels when possible,
+                     so they still apply after the program is reassembled.
+  <file>             Binary file to load at address 0 in the REPL.
+  -h, --help         Show this message.
+  --version          Show the version of dcpu.
+";
+
+/// `{name}` is replaced by the name of the project.
+const TEMPLATES: [(&'static str, &'static str); 4] =
+    [("bare", include_str!("templates/bare.dasm")),
+     ("lem-game", include_str!("templates/lem-game.dasm")),
+     ("os", include_str!("templates/os.dasm")),
+     ("scheduler", include_str!("templates/scheduler.dasm"))];
+
+const MAKEFILE: &'static str = "\
+ASSEMBLER ?= assembler
+EMULATOR ?= emulator
+BIN = build/{name}.bin
+
+all: $(BIN)
+
+$(BIN): $(wildcard src/*.dasm)
+\tmkdir -p build
+\t$(ASSEMBLER) --deny-warnings --symbols build/{name}.sym \\
+\t\t--regions build/{name}.regions --debug-info build/{name}.dbg \\
+\t\tsrc/main.dasm -o $(BIN)
+
+run: $(BIN)
+\t$(EMULATOR) --trap-pc-wrap --debug-info build/{name}.dbg $(BIN)
+
+test: $(BIN)
+\t$(EMULATOR) --turbo --trap-pc-wrap --regions build/{name}.regions \\
+\t\t--debug-info build/{name}.dbg $(BIN)
+
+clean:
+\trm -rf build
+
+.PHONY: all run test clean
+";
+
+#[allow(non_snake_case)]
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    cmd_new: bool,
+    cmd_explain: bool,
+    cmd_encode: bool,
+    cmd_run: bool,
+    cmd_repl: bool,
+    arg_name: String,
+    arg_word: Vec<String>,
+    arg_instruction: String,
+    arg_source: String,
+    arg_file: Option<String>,
+    flag_template: String,
+    flag_no_cpp: bool,
+    flag_I: Vec<String>,
+    flag_device: Vec<String>,
+    flag_turbo: bool,
+    flag_max_cycles: Option<u64>,
+    flag_trace: bool,
+    flag_explain: bool,
+    flag_symbols: Option<String>,
+    flag_debug_info: Option<String>,
+    flag_session: Option<String>,
+}
+
+fn new_project(name: &str, template: &str) -> io::Result<()> {
+    let root = Path::new(name);
+    let name = root.file_name().map_or(name.into(), |n| n.to_string_lossy());
+    try!(fs::create_dir(root));
+    try!(fs::create_dir(root.join("src")));
+    let files = [("src/main.dasm", template), ("Makefile", MAKEFILE), (".gitignore", "build/\n")];
+    for &(path, content) in files.iter() {
+        let mut file = try!(File::create(root.join(path)));
+        try!(file.write_all(content.replace("{name}", &name).as_bytes()));
+    }
+    Ok(())
+}
+
+fn parse_word(s: &str) -> Option<u16> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn plural_words(words: u16) -> String {
+    format!("{} word{}", words, if words == 1 { "" } else { "s" })
+}
+
+fn uses_next_word(code: u16) -> bool {
+    Value::decode(code, 0, true).map_or(false, |(used, _)| used == 1)
+}
+
+fn explain(words: &[u16]) {
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        let data = [word,
+                    words.get(i + 1).cloned().unwrap_or(0),
+                    words.get(i + 2).cloned().unwrap_or(0)];
+        let (size, instruction) = match Instruction::decode(&data) {
+            Ok(res) => res,
+            Err(e) => {
+                println!("0x{:04x}: {}", word, e);
+                i += 1;
+                continue;
+            }
+        };
+        let (a, b, op) = (word >> SHIFT_A, word >> SHIFT_B & MASK_B, word & MASK_OP);
+        println!("0x{:04x}: {:06b} {:05b} {:05b}", word, a, b, op);
+        match instruction {
+            Instruction::BasicOp(basic, _, _) => {
+                println!("  opcode {:05b}  {}: {}", op, basic.mnemonic(), basic.description());
+                println!("  b      {:05b}  {}", b, Value::form(b));
+            }
+            Instruction::SpecialOp(special, _) => {
+                println!("  opcode {:05b}  special", op);
+                println!("  b      {:05b}  {}: {}", b, special.mnemonic(), special.description());
+            }
+        }
+        println!("  a      {:06b} {}", a, Value::form(a));
+        let mut next = i + 1;
+        for &(name, code) in [("a", a), ("b", b)].iter() {
+            if uses_next_word(code) && !(name == "b" && op == 0) {
+                match words.get(next) {
+                    Some(w) => println!("  0x{:04x}        next word of {}", w, name),
+                    None => println!("  missing        next word of {}", name),
+                }
+                next += 1;
+            }
+        }
+        if next > words.len() {
+            println!("  truncated");
+        } else {
+            println!("  = {} ({}, {} cycles)",
+                     instruction,
+                     plural_words(size),
+                     instruction.cycles(size));
+        }
+        i += size as usize;
+    }
+}
+
+#[cfg(feature = "assembler")]
+fn encode(text: &str) -> Result<(), String> {
+    let instruction: Instruction = try!(text.parse().map_err(|e| format!("{:?}", e)));
+    let mut encodings: Vec<Vec<u16>> = vec![];
+    for &short_literals in [true, false].iter() {
+        let mut words = [0; 3];
+        let size = instruction.encode_with(&mut words, short_literals);
+        let words = words[..size as usize].to_vec();
+        if encodings.contains(&words) {
+            continue;
+        }
+        let hex = words.iter().map(|w| format!("0x{:04x}", w)).collect::<Vec<_>>();
+        println!("{:20} {}, {} cycles{}",
+                 hex.join(" "),
+                 plural_words(size),
+                 instruction.cycles(size),
+                 if short_literals { "" } else { ", literal in the next word" });
+        encodings.push(words);
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "assembler"))]
+fn encode(_: &str) -> Result<(), String> {
+    Err("built without the assembler feature".into())
+}
+
+/// Exit status of `run` when --max-cycles is reached.
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+const EXIT_CYCLE_LIMIT: i32 = 124;
+/// Exit status of `run` when the CPU fails instead of halting.
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+const EXIT_FAILED: i32 = 125;
+/// Ticks run between two sleeps in real time.
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+const THROTTLE_PERIOD: u64 = 1000;
+
+/// Assembles and runs `args.arg_source`, and returns the exit status.
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+fn run(args: &Args) -> Result<i32, String> {
+    use std::cmp;
+    use std::path::PathBuf;
+    use dcpu::assembler::{self, include};
+    use dcpu::computer::Computer;
+    use dcpu::cpu::{self, Cpu};
+    use dcpu::device::registry::{self, Spec};
+    use dcpu::timebase::Throttle;
+    use dcpu::trace::Tracer;
+
+    let loader = include::Loader {
+        search_paths: args.flag_I.iter().map(PathBuf::from).collect(),
+        preprocess: !args.flag_no_cpp,
+        ..include::Loader::default()
+    };
+    let (bin, info) = try!(assembler::assemble_file_with_debug_info(&loader,
+                                                                    Path::new(&args.arg_source))
+                               .map_err(|e| e.to_string()));
+    let mut cpu = Cpu::default();
+    cpu.load(&bin, 0);
+    cpu.trap_pc_wrap = true;
+    if args.flag_trace {
+        cpu.trace = Some(Box::new(Tracer::to_writer(Box::new(io::stderr()), info.symbols().clone())));
+    }
+    let mut computer = Computer::new(cpu);
+    let specs = if args.flag_device.is_empty() {
+        vec!["clock".to_string()]
+    } else {
+        args.flag_device.clone()
+    };
+    for spec in &specs {
+        let spec = try!(spec.parse::<Spec>().map_err(|e| e.to_string()));
+        let device = try!(registry::build(&spec, computer.timebase())
+                              .map_err(|e| format!("can't attach {}: {}", spec, e)));
+        computer.add_device(device);
+    }
+
+    let max_cycles = args.flag_max_cycles.unwrap_or(u64::max_value());
+    let mut throttle = Throttle::new(computer.timebase(), 0);
+    loop {
+        let left = max_cycles.saturating_sub(computer.current_tick());
+        if left == 0 {
+            println!("cycle limit reached");
+            return Ok(EXIT_CYCLE_LIMIT);
+        }
+        let pc = computer.cpu().pc;
+        let res = computer.run(cmp::min(left, THROTTLE_PERIOD))
+                          .and_then(|_| computer.skip_idle());
+        match res {
+            Ok(_) => (),
+            Err(cpu::Error::Halted) => return Ok(0),
+            Err(e) => {
+                // The instruction which failed, unless it was in a block.
+                let pc = computer.cpu().last_instruction().map_or(pc, |(addr, _)| addr);
+                println!("{} at {}", e, info.describe(pc));
+                if let Some((file, line)) = info.line_of(pc) {
+                    if let Some(text) = source_line(file, line) {
+                        println!("  {}", text.trim());
+                    }
+                }
+                return Ok(EXIT_FAILED);
+            }
+        }
+        if !args.flag_turbo {
+            throttle.wait(computer.current_tick());
+        }
+    }
+}
+
+#[cfg(not(all(feature = "assembler", feature = "emulator-core")))]
+fn run(_: &Args) -> Result<i32, String> {
+    Err("built without the assembler or emulator-core feature".into())
+}
+
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+fn run_repl(args: Args) -> i32 {
+    repl::run(repl::Args {
+        explain: args.flag_explain,
+        symbols: args.flag_symbols,
+        debug_info: args.flag_debug_info,
+        session: args.flag_session,
+        file: args.arg_file,
+    })
+}
+
+#[cfg(not(all(feature = "assembler", feature = "emulator-core")))]
+fn run_repl(_: Args) -> i32 {
+    die!(1, "Error: built without the assembler or emulator-core feature");
+}
+
+/// Line `line` of `file`, from 1.
+#[cfg(all(feature = "assembler", feature = "emulator-core"))]
+fn source_line(file: &str, line: usize) -> Option<String> {
+    use std::io::Read;
+
+    let mut text = String::new();
+    match File::open(file).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => text.lines().nth(line.saturating_sub(1)).map(String::from),
+        Err(_) => None,
+    }
+}
+
+fn main_ret() -> i32 {
+    let args: Args = Docopt::new(USAGE)
+                         .and_then(|d| d.decode())
+                         .unwrap_or_else(|e| e.exit());
+
+    if args.cmd_new {
+        let template = match TEMPLATES.iter().find(|&&(n, _)| n == args.flag_template) {
+            Some(&(_, t)) => t,
+            None => die!(1, "Unknown template {}", args.flag_template),
+        };
+        if let Err(e) = new_project(&args.arg_name, template) {
+            die!(1, "Can't create {}: {}", args.arg_name, e);
+        }
+        println!("Created {}, build it with make", args.arg_name);
+    } else if args.cmd_explain {
+        let mut words = vec![];
+        for w in args.arg_word.iter() {
+            match parse_word(w) {
+                Some(w) => words.push(w),
+                None => die!(1, "Invalid word {}", w),
+            }
+        }
+        explain(&words);
+    } else if args.cmd_encode {
+        if let Err(e) = encode(&args.arg_instruction) {
+            die!(1, "Error: {}", e);
+        }
+    } else if args.cmd_run {
+        match run(&args) {
+            Ok(status) => return status,
+            Err(e) => die!(1, "Error: {}", e),
+        }
+    } else if args.cmd_repl {
+        return run_repl(args);
+    }
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
@@ -0,0 +1,437 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+use docopt::Docopt;
+
+use dcpu::calls::CallStack;
+use dcpu::computer::Computer;
+use dcpu::cpu::{Cpu, CpuState, Watch};
+use dcpu::formats::{self, Format};
+use dcpu::mmio::AccessKind;
+use dcpu::symbols::Symbols;
+use dcpu::window::{self, History, Row};
+
+/// Maximum number of cycles run by continue, next and finish.
+const MAX_RUN: u32 = 10_000_000;
+
+/// Instructions shown before and after PC.
+const BEFORE: usize = 4;
+const AFTER: usize = 11;
+
+/// Rows of 8 words of the memory view.
+const MEMORY_ROWS: u16 = 8;
+
+/// Words shown from the top of the stack.
+const STACK_ROWS: usize = 12;
+
+/// Queued interrupt messages shown.
+const PENDING: usize = 6;
+
+/// Width of the left column.
+const LEFT: usize = 48;
+
+const USAGE: &'static str = "
+Usage:
+  debugger [--symbols <file>] <file>
+  debugger (--help | --version)
+
+Shows the registers, the disassembly around PC, the stack, the breakpoints
+and watchpoints, and the memory, refreshed after each command:
+  s, step [n]        Execute the next n instructions.
+  n, next            Execute the next instruction, and the whole routine if
+                     it is a JSR.
+  f, finish          Run until the current routine returns.
+  c, continue        Run until a breakpoint or a watchpoint is reached.
+  b, break <addr>    Add a breakpoint.
+  w, watch <addr> [r|w]
+                     Stop after the instructions reading (r) or writing (w)
+                     the word at addr, or both by default.
+  d, delete <addr>   Remove the breakpoint and the watchpoint at addr.
+  m, mem <addr>      Show the memory starting at addr.
+  dump <addr> <len> <file> [format]
+                     Write len words of memory from addr to file, in one of
+                     the formats le, be, hex or ihex, or annotated with
+                     their address and characters by default.
+  load <addr> <file> [format]
+                     Load file at addr, in one of the formats le (default),
+                     be, hex or ihex.
+  q, quit            Exit.
+An empty line repeats the previous command.
+
+Addresses are either numbers, or label or label+offset with --symbols.
+
+Options:
+  --symbols <file>   Labels of the program, one \"label 0xaddr\" per line, as
+                     written by assembler --symbols.
+  <file>             Binary file to load at address 0.
+  -h, --help         Show this message.
+  --version          Show the version of debugger.
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_symbols: Option<String>,
+    arg_file: String,
+}
+
+struct Debugger {
+    computer: Computer,
+    symbols: Symbols,
+    /// Start of the memory view.
+    memory: u16,
+    /// Addresses of the last instructions executed, to find where the
+    /// instructions before PC start.
+    history: History,
+    /// Result of the last command.
+    status: String,
+}
+
+impl Debugger {
+    fn resolve(&self, addr: Option<&str>) -> Result<u16, String> {
+        let addr = try!(addr.ok_or("missing address".to_string()));
+        self.symbols.resolve(addr).ok_or(format!("unknown address: {}", addr))
+    }
+
+    /// Adds `pc`, the address of an instruction executed, to the history.
+    fn record(&mut self, pc: u16) {
+        self.history.record(pc);
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        let pc = self.computer.cpu().pc;
+        try!(self.computer.step().map_err(|e| e.to_string()));
+        self.record(pc);
+        Ok(())
+    }
+
+    /// Describes why `step_over` or `step_out` stopped.
+    fn stopped(&self, state: Option<CpuState>) -> String {
+        match state {
+            Some(CpuState::HitBreakpoint(addr)) => {
+                format!("breakpoint at {}", self.symbols.describe(addr))
+            }
+            Some(CpuState::HitWatchpoint(access)) => {
+                format!("watchpoint at {}", self.symbols.describe(access.addr))
+            }
+            Some(_) => String::new(),
+            None => format!("stopped after {} cycles", MAX_RUN),
+        }
+    }
+
+    fn run(&mut self) -> Result<String, String> {
+        for i in 0..MAX_RUN {
+            let pc = self.computer.cpu().pc;
+            match try!(self.computer.tick().map_err(|e| e.to_string())) {
+                // Leaves the breakpoint it stopped at.
+                CpuState::HitBreakpoint(_) if i == 0 => (),
+                CpuState::HitBreakpoint(addr) => {
+                    return Ok(format!("breakpoint at {}", self.symbols.describe(addr)));
+                }
+                CpuState::HitWatchpoint(access) => {
+                    self.record(pc);
+                    while self.computer.cpu().wait != 0 {
+                        try!(self.computer.tick().map_err(|e| e.to_string()));
+                    }
+                    let kind = match access.kind {
+                        AccessKind::Read => "read",
+                        AccessKind::Write => "write",
+                    };
+                    return Ok(format!("{} of 0x{:04x} at {} by {}",
+                                      kind,
+                                      access.value,
+                                      self.symbols.describe(access.addr),
+                                      self.symbols.describe(pc)));
+                }
+                CpuState::Executing => self.record(pc),
+                CpuState::Waiting | CpuState::Sleeping => (),
+            }
+        }
+        Err(format!("stopped after {} cycles", MAX_RUN))
+    }
+
+    /// Returns false to quit.
+    fn exec_command(&mut self, cmd: &str) -> Result<bool, String> {
+        let mut args = cmd.split_whitespace();
+        self.status = match args.next() {
+            Some("s") | Some("step") => {
+                let n = match args.next() {
+                    Some(n) => try!(n.parse::<u32>().map_err(|e| format!("{}: {}", n, e))),
+                    None => 1,
+                };
+                for _ in 0..n {
+                    try!(self.step());
+                }
+                String::new()
+            }
+            Some("n") | Some("next") => {
+                let pc = self.computer.cpu().pc;
+                let state = try!(self.computer
+                                     .step_over(MAX_RUN as u64)
+                                     .map_err(|e| e.to_string()));
+                self.record(pc);
+                self.stopped(state)
+            }
+            Some("f") | Some("finish") => {
+                if self.computer.cpu().calls.as_ref().map_or(true, |c| c.frames().is_empty()) {
+                    return Err("not in a routine".into());
+                }
+                let state = try!(self.computer
+                                     .step_out(MAX_RUN as u64)
+                                     .map_err(|e| e.to_string()));
+                self.stopped(state)
+            }
+            Some("c") | Some("continue") => try!(self.run()),
+            Some("b") | Some("break") => {
+                let addr = try!(self.resolve(args.next()));
+                self.computer.cpu_mut().add_breakpoint(addr);
+                format!("breakpoint at {}", self.symbols.describe(addr))
+            }
+            Some("d") | Some("delete") => {
+                let addr = try!(self.resolve(args.next()));
+                self.computer.cpu_mut().remove_breakpoint(addr);
+                self.computer.cpu_mut().remove_watchpoint(addr);
+                String::new()
+            }
+            Some("w") | Some("watch") => {
+                let addr = try!(self.resolve(args.next()));
+                let watch = match args.next() {
+                    Some("r") => Watch::Read,
+                    Some("w") => Watch::Write,
+                    None => Watch::ReadWrite,
+                    Some(kind) => return Err(format!("unknown access: {}", kind)),
+                };
+                self.computer.cpu_mut().add_watchpoint(addr, watch);
+                String::new()
+            }
+            Some("m") | Some("mem") => {
+                self.memory = try!(self.resolve(args.next()));
+                String::new()
+            }
+            Some("dump") => {
+                let addr = try!(self.resolve(args.next()));
+                let len = try!(self.resolve(args.next()));
+                let path = try!(args.next().ok_or("missing file".to_string()));
+                let format = match args.next() {
+                    None | Some("annotated") => None,
+                    Some(name) => Some(try!(parse_format(name))),
+                };
+                let mut output = BufWriter::new(try!(File::create(path)
+                                                         .map_err(|e| e.to_string())));
+                try!(self.computer
+                         .cpu()
+                         .dump_memory(addr, len as usize, format, &mut output)
+                         .and_then(|_| output.flush())
+                         .map_err(|e| e.to_string()));
+                format!("{} words written to {}", len, path)
+            }
+            Some("load") => {
+                let addr = try!(self.resolve(args.next()));
+                let path = try!(args.next().ok_or("missing file".to_string()));
+                let format = match args.next() {
+                    Some(name) => try!(parse_format(name)),
+                    None => Format::LittleEndian,
+                };
+                let mut input = BufReader::new(try!(File::open(path).map_err(|e| e.to_string())));
+                let len = try!(self.computer
+                                   .cpu_mut()
+                                   .load_memory(&mut input, format, addr)
+                                   .map_err(|e| e.to_string()));
+                format!("{} words loaded at {}", len, self.symbols.describe(addr))
+            }
+            Some("q") | Some("quit") => return Ok(false),
+            None => String::new(),
+            _ => return Err(format!("unknown command: {}", cmd)),
+        };
+        Ok(true)
+    }
+
+    fn registers(&self) -> Vec<String> {
+        let cpu = self.computer.cpu();
+        let r = &cpu.registers;
+        let mut state = vec![];
+        if cpu.halted {
+            state.push("halted");
+        }
+        if cpu.sleeping {
+            state.push("sleeping");
+        }
+        if cpu.is_queue_enabled {
+            state.push("queueing");
+        }
+        if cpu.check_if_cascade {
+            state.push("skipped");
+        }
+        let mut lines =
+            vec![format!("A  {:04x}  B  {:04x}  C  {:04x}  PC {:04x}", r[0], r[1], r[2], cpu.pc),
+                 format!("X  {:04x}  Y  {:04x}  Z  {:04x}  SP {:04x}", r[5], r[6], r[7], cpu.sp),
+                 format!("I  {:04x}  J  {:04x}  EX {:04x}  IA {:04x}", r[3], r[4], cpu.ex, cpu.ia),
+                 format!("tick {}  {}", self.computer.current_tick(), state.join(" "))];
+        let pending = cpu.pending_interrupts();
+        if !pending.is_empty() {
+            let messages: Vec<String> = pending.iter()
+                                               .take(PENDING)
+                                               .map(|i| format!("{:04x}", i))
+                                               .collect();
+            lines.push(format!("pending {}: {}", pending.len(), messages.join(" ")));
+        }
+        lines
+    }
+
+    fn disassembly(&self) -> Vec<String> {
+        let cpu = self.computer.cpu();
+        window::window(cpu, &self.history, BEFORE, AFTER)
+            .iter()
+            .map(|row| self.instruction(cpu, row))
+            .collect()
+    }
+
+    fn instruction(&self, cpu: &Cpu, row: &Row) -> String {
+        let marker = if row.addr == cpu.pc {
+            ">"
+        } else if cpu.breakpoints.contains(&row.addr) {
+            "*"
+        } else {
+            " "
+        };
+        let label = match self.symbols.nearest(row.addr) {
+            Some((label, 0)) => format!("{}:", label),
+            _ => String::new(),
+        };
+        format!("{} {:04x} {:10} {}", marker, row.addr, label, row.text)
+    }
+
+    fn stack(&self) -> Vec<String> {
+        let cpu = self.computer.cpu();
+        let depth = cmp::min(STACK_ROWS as u32, 0x10000 - cpu.sp as u32) as u16;
+        (0..depth)
+            .map(|i| {
+                let addr = cpu.sp.wrapping_add(i);
+                format!("{:04x}: {:04x}", addr, cpu.ram[addr as usize])
+            })
+            .collect()
+    }
+
+    fn memory(&self) -> Vec<String> {
+        let ram = &self.computer.cpu().ram;
+        (0..MEMORY_ROWS)
+            .map(|row| {
+                let start = self.memory.wrapping_add(row * 8);
+                let words: Vec<String> = (0..8)
+                                             .map(|i| start.wrapping_add(i))
+                                             .map(|a| format!("{:04x}", ram[a as usize]))
+                                             .collect();
+                format!("{:04x}: {}", start, words.join(" "))
+            })
+            .collect()
+    }
+
+    /// Clears the terminal and draws all the views.
+    fn draw(&self) {
+        let mut left = self.registers();
+        left.push(String::new());
+        left.extend(self.disassembly());
+
+        let mut right = vec!["Stack".to_string()];
+        right.extend(self.stack());
+        right.push(String::new());
+        right.push("Breakpoints".to_string());
+        let cpu = self.computer.cpu();
+        right.extend(cpu.breakpoints.iter().map(|&b| self.symbols.describe(b)));
+        right.extend(cpu.watchpoints.iter().map(|(&addr, watch)| {
+            let kind = match *watch {
+                Watch::Read => "r",
+                Watch::Write => "w",
+                Watch::ReadWrite => "rw",
+            };
+            format!("{} ({})", self.symbols.describe(addr), kind)
+        }));
+
+        print!("\x1b[2J\x1b[H");
+        for i in 0..cmp::max(left.len(), right.len()) {
+            println!("{:width$} {}",
+                     left.get(i).map_or("", |l| l.as_str()),
+                     right.get(i).map_or("", |r| r.as_str()),
+                     width = LEFT);
+        }
+        println!("");
+        for line in self.memory() {
+            println!("{}", line);
+        }
+        println!("");
+        println!("{}", self.status);
+    }
+}
+
+fn parse_format(name: &str) -> Result<Format, String> {
+    name.parse().map_err(|e: formats::Error| e.to_string())
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let mut cpu = Cpu::default();
+    let rom: Vec<u16> = utils::IterU16 { input: utils::get_input(Some(args.arg_file)) }.collect();
+    cpu.load(&rom, 0);
+    cpu.calls = Some(CallStack::new());
+    let symbols = match args.flag_symbols {
+        Some(path) => {
+            let mut s = String::new();
+            utils::get_input(Some(path)).read_to_string(&mut s).unwrap();
+            match s.parse() {
+                Ok(symbols) => symbols,
+                Err(e) => die!(1, "Invalid symbols file: {:?}", e),
+            }
+        }
+        None => Symbols::new(),
+    };
+    let mut debugger = Debugger {
+        computer: Computer::new(cpu),
+        symbols: symbols,
+        memory: 0,
+        history: History::default(),
+        status: String::new(),
+    };
+
+    let stdin = io::stdin();
+    let mut previous = String::new();
+    loop {
+        debugger.draw();
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let line = match line.trim() {
+            "" => previous.clone(),
+            l => l.to_string(),
+        };
+        match debugger.exec_command(&line) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => debugger.status = format!("Error: {}", e),
+        }
+        previous = line;
+    }
+
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
@@ -9,47 +9,226 @@ extern crate simplelog;
 #[macro_use]
 mod utils;
 
-use std::io::Write;
+use std::io::{Read, Write};
 
 use docopt::Docopt;
 
-use dcpu::iterators::U16ToInstruction;
+use rustc_serialize::json;
+
+use dcpu::formats;
+use dcpu::iterators::{Style, U16ToInstruction, disassemble_reachable, disassemble_round_trip,
+                      disassemble_with_symbols};
+use dcpu::symbols::Symbols;
+use utils::OutputFormat;
 
 const USAGE: &'static str = "
 Usage:
-  disassembler [--ast] [<file>] [-o <file>]
+  disassembler [--ast] [--no-labels] [--follow | --round-trip] [-e <entry>]... [--symbols <file>] [--output <format>] [--format <format>] [--lowercase] [--hex] [--label-style <style>] [--indent <n>] [--addresses] [--words] [<file>] [-o <file>]
   disassembler (--help | --version)
 
 Options:
   --ast              Show the AST of the file.
+  --no-labels        Show the addresses of the jumps and calls instead of
+                     generating labels for them.
+  --follow           Only disassemble the code reachable from the entry
+                     points, following the jumps and calls, and show the
+                     rest as .dat. Always with labels.
+  --round-trip       Write the words which can't be disassembled exactly as
+                     .dat, so the output assembles back to the same binary,
+                     and check it does. Always with labels.
+  -e <entry>         Entry point for --follow, as an address or a label
+                     of --symbols, 0 by default.
+  --symbols <file>   Use the labels of this file for the addresses they
+                     name, as written by assembler --symbols.
+  --output <format>  Output format, text or json. With json, a list of
+                     instructions with their address and words is written.
+                     [default: text]
+  --format <format>  Format of the binary: le, be, hex or ihex (see
+                     dcpu::formats). [default: le]
+  --lowercase        Write the opcodes and registers in lowercase.
+  --hex              Write the numbers of the instructions in hexadecimal.
+  --label-style <style>
+                     suffix for label:, or prefix for :label and dat
+                     without the dot, as in Notch's examples.
+                     [default: suffix]
+  --indent <n>       Indent the instructions by this many spaces, the
+                     labels staying in the first column. [default: 0]
+  --addresses        Start each line with its address.
+  --words            Start each line with its words, after the address.
+                     The output can't be assembled anymore with these
+                     prefixes.
   <file>             File to use instead of stdin.
   -o <file>          File to use instead of stdout.
   -h, --help         Show this message.
   --version          Show the version of disassembler.
 ";
 
+#[derive(RustcEncodable)]
+struct JsonInstruction {
+    address: u16,
+    words: Vec<u16>,
+    text: String,
+}
+
 #[derive(RustcDecodable)]
 struct Args {
     flag_ast: bool,
+    flag_no_labels: bool,
+    flag_follow: bool,
+    flag_round_trip: bool,
+    flag_e: Vec<String>,
+    flag_symbols: Option<String>,
+    flag_output: utils::OutputFormat,
+    flag_format: String,
+    flag_lowercase: bool,
+    flag_hex: bool,
+    flag_label_style: String,
+    flag_indent: usize,
+    flag_addresses: bool,
+    flag_words: bool,
     arg_file: Option<String>,
     flag_o: Option<String>,
 }
 
-fn main() {
+fn main_ret() -> i32 {
     simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
 
     let args: Args = Docopt::new(USAGE)
                             .and_then(|d| d.decode())
                             .unwrap_or_else(|e| e.exit());
 
-    let input = utils::get_input(args.arg_file);
+    let format = match args.flag_format.parse() {
+        Ok(format) => format,
+        Err(e) => die!(1, "{}", e),
+    };
+    let mut input = utils::get_input(args.arg_file);
+    let words = match formats::read(&mut input, format) {
+        Ok(words) => words,
+        Err(e) => die!(1, "Invalid binary: {}", e),
+    };
+    let style = Style {
+        lowercase: args.flag_lowercase,
+        hex: args.flag_hex,
+        prefix_labels: match args.flag_label_style.as_str() {
+            "suffix" => false,
+            "prefix" => true,
+            s => die!(1, "Unknown label style {}", s),
+        },
+        indent: args.flag_indent,
+        addresses: args.flag_addresses,
+        words: args.flag_words,
+    };
     let mut output = utils::get_output(args.flag_o);
 
-    for i in U16ToInstruction::chain(utils::IterU16{input: input}) {
-        if args.flag_ast {
-            writeln!(output, "{:?}", i).unwrap();
+    let labels = !args.flag_no_labels || args.flag_follow || args.flag_round_trip;
+    if args.flag_output == OutputFormat::Text && !args.flag_ast && labels {
+        let symbols = match args.flag_symbols {
+            Some(path) => {
+                let mut text = String::new();
+                utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+                match text.parse() {
+                    Ok(symbols) => symbols,
+                    Err(e) => die!(1, "Invalid symbols {}: {:?}", path, e),
+                }
+            }
+            None => Symbols::new(),
+        };
+        if args.flag_follow {
+            let mut entries = vec![];
+            for e in args.flag_e.iter() {
+                match symbols.resolve(e) {
+                    Some(addr) => entries.push(addr),
+                    None => die!(1, "Unknown entry point {}", e),
+                }
+            }
+            if entries.is_empty() {
+                entries.push(0);
+            }
+            write!(output, "{}", disassemble_reachable(&words, &entries, &symbols, &style))
+                .unwrap();
+        } else if args.flag_round_trip {
+            // Checked without the prefixes, which don't assemble.
+            let unprefixed = Style {
+                addresses: false,
+                words: false,
+                ..style.clone()
+            };
+            let text = disassemble_round_trip(&words, &symbols, &unprefixed);
+            if let Err(e) = check_round_trip(&text, &words) {
+                die!(1, "Round trip failed: {}", e);
+            }
+            if style != unprefixed {
+                write!(output, "{}", disassemble_round_trip(&words, &symbols, &style)).unwrap();
+            } else {
+                write!(output, "{}", text).unwrap();
+            }
         } else {
-            writeln!(output, "{}", i).unwrap();
+            write!(output, "{}", disassemble_with_symbols(&words, &symbols, &style)).unwrap();
         }
+        return 0;
     }
+
+    let mut json_output = vec![];
+    let mut address = 0u16;
+    for i in U16ToInstruction::chain(words.into_iter()) {
+        let (text, ast) = match i {
+            Ok(ref i) => (style.instruction(i), format!("{:?}", i)),
+            Err(ref data) => (style.data(&[data.word]), format!("{:?}", data)),
+        };
+        let words = match i {
+            Ok(i) => {
+                let (words, size) = i.encode_to_array();
+                words[..size as usize].to_vec()
+            }
+            Err(data) => vec![data.word],
+        };
+        let size = words.len() as u16;
+        if args.flag_output == OutputFormat::Json {
+            json_output.push(JsonInstruction {
+                address: address,
+                words: words,
+                text: if args.flag_ast { ast } else { text },
+            });
+            address = address.wrapping_add(size);
+        } else if args.flag_ast {
+            writeln!(output, "{}", ast).unwrap();
+        } else {
+            write!(output, "{}", style.line(address, &words, &text)).unwrap();
+            address = address.wrapping_add(size);
+        }
+    }
+
+    if args.flag_output == OutputFormat::Json {
+        writeln!(output, "{}", json::encode(&json_output).unwrap()).unwrap();
+    }
+
+    0
+}
+
+/// Assembles `text` and compares it to `words`.
+#[cfg(feature = "assembler")]
+fn check_round_trip(text: &str, words: &[u16]) -> Result<(), String> {
+    let assembled = try!(dcpu::assemble_str(text).map_err(|e| e.to_string()));
+    match words.iter().zip(assembled.iter()).position(|(w, a)| w != a) {
+        Some(i) => {
+            Err(format!("0x{:04x} assembled to 0x{:04x} instead of 0x{:04x}",
+                        i,
+                        assembled[i],
+                        words[i]))
+        }
+        None if assembled.len() != words.len() => {
+            Err(format!("{} words assembled instead of {}", assembled.len(), words.len()))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(feature = "assembler"))]
+fn check_round_trip(_: &str, _: &[u16]) -> Result<(), String> {
+    warn!("Built without the assembler, the round trip isn't checked");
+    Ok(())
+}
+
+fn main() {
+    std::process::exit(main_ret());
 }
@@ -9,27 +9,341 @@ extern crate simplelog;
 #[macro_use]
 mod utils;
 
+use std::cell::RefCell;
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+
 use docopt::Docopt;
+use rustc_serialize::json;
 
-use dcpu::cpu::Cpu;
+use dcpu::blocks::BlockCache;
+use dcpu::cpu::{self, Cpu, OnDecodeError};
 use dcpu::computer::Computer;
+use dcpu::control::Controller;
+use dcpu::coverage::Coverage;
+use dcpu::debug_info::DebugInfo;
+#[cfg(feature = "devices-clock")]
+use dcpu::device::clock::Clock;
+#[cfg(feature = "devices-host")]
+use dcpu::device::host::HostBridge;
+#[cfg(feature = "devices-lem")]
+use dcpu::device::lem1802::{Backend as ScreenBackend, LastFrame, LEM1802, Refresh, Screen,
+                            TermBackend};
+use dcpu::device::Empty;
+use dcpu::device::registry::{self, Spec};
+#[cfg(feature = "devices-remote")]
+use dcpu::device::remote::RemoteDevice;
+#[cfg(feature = "devices-serial")]
+use dcpu::device::serial::{ConsoleBackend, Serial};
+#[cfg(feature = "devices-speaker")]
+use dcpu::device::speaker::{PcmBackend, Speaker};
+use dcpu::differential::{self, Process};
+use dcpu::formats;
+use dcpu::gdb::{End, Stub};
+use dcpu::profile::Profiler;
+use dcpu::replay::{self, Recording};
+use dcpu::script::{Script, Stop};
+use dcpu::symbols::Symbols;
+use dcpu::timebase::{self, Throttle, Timebase};
+use dcpu::trace::Tracer;
+use dcpu::types::Register;
+use utils::OutputFormat;
+
+/// Ticks between two checks for control requests.
+const CONTROL_PERIOD: u64 = 1000;
+
+/// Ticks run between two sleeps in real time.
+const THROTTLE_PERIOD: u64 = 1000;
+
+/// Ticks run between two checks of the time by --bench.
+const BENCH_PERIOD: u64 = 100000;
+
+/// Exit status when --max-cycles is reached, like timeout(1).
+const EXIT_CYCLE_LIMIT: i32 = 124;
+/// Exit status when the CPU fails instead of halting.
+const EXIT_FAILED: i32 = 125;
+
+/// Pixels of a screenshot for each pixel of the screen.
+const SCREENSHOT_SCALE: u32 = 4;
+
+/// Labels and addresses listed by --profile.
+const PROFILE_TOP: usize = 30;
+
+/// How the screen is shown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, RustcDecodable)]
+enum Display {
+    None,
+    Term,
+}
+
+/// Where the exit status is read from when the program halts.
+enum ExitCode {
+    Register(Register),
+    Memory(u16),
+}
+
+impl ExitCode {
+    fn parse(s: &str) -> Option<ExitCode> {
+        if let Ok(r) = s.parse() {
+            return Some(ExitCode::Register(r));
+        }
+        parse_number(s).map(ExitCode::Memory)
+    }
+
+    fn read(&self, cpu: &Cpu) -> i32 {
+        match *self {
+            ExitCode::Register(r) => cpu.registers[r as usize] as i32,
+            ExitCode::Memory(addr) => cpu.ram[addr as usize] as i32,
+        }
+    }
+}
+
+/// Memory written by --dump.
+struct Dump {
+    addr: u16,
+    len: u16,
+    path: String,
+}
+
+impl Dump {
+    fn parse(s: &str) -> Option<Dump> {
+        let mut parts = s.splitn(3, ':');
+        let addr = parts.next().and_then(parse_number);
+        let len = parts.next().and_then(parse_number);
+        match (addr, len, parts.next()) {
+            (Some(addr), Some(len), Some(path)) => {
+                Some(Dump {
+                    addr: addr,
+                    len: len,
+                    path: path.into(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Exits with `msg` like docopt does for an invalid argument.
+fn usage_error(msg: String) -> ! {
+    docopt::Error::Argv(msg).exit()
+}
+
+/// Parses a number, in hex with `0x`.
+fn parse_number(s: &str) -> Option<u16> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parses --invalid-opcode.
+fn parse_invalid_opcode(s: &str) -> Option<OnDecodeError> {
+    match s {
+        "skip" => Some(OnDecodeError::Continue),
+        "stop" => Some(OnDecodeError::Fail),
+        _ => parse_number(s).map(OnDecodeError::Interrupt),
+    }
+}
 
 const USAGE: &'static str = "
 Usage:
-  emulator [(-d <device>)...] [<file>]
+  emulator [(-d <device>)...] [--strict] [--frequency <hz>] [--speed <hz> | --turbo] [--headless] [--display <kind>] [--refresh-rate <hz>] [--vsync] [--screenshot <file>] [--max-cycles <n>] [--exit-code <loc>] [--trap-pc-wrap] [--invalid-opcode <action>] [--host-dir <dir>] [--audio <file>] [--console] [--remote <addr>]... [--script <file>] [--blocks] [--verbose] [--regions <file>] [--debug-info <file>] [--symbols <file>] [--trace | --trace-last <n>] [--profile <file>] [--flamegraph <file>] [--coverage <file>] [--output <format>] [--load-state <file>] [--save-state <file>] [--record <file> | --replay <file>] [--control <port>] [--gdb <port>] [--reference <command>] [--compare-every <ticks>] [--bench [--bench-time <secs>]] [--format <format>] [--load-at <spec>]... [--dump <spec>]... [<file>]
   emulator (--help | --version)
 
+A Generic Clock is attached as device 0, timed with --frequency.
+
 Options:
   <file>             The binary file to execute.
-  -d, --device       Des super devices.
+  --format <format>  Format of the file: le, be, hex or ihex (see
+                     dcpu::formats). [default: le]
+  --load-at <spec>   Also load a file at an address, given as addr:file,
+                     in the --format, for example to patch the program or
+                     fill a buffer. Can be repeated.
+  --dump <spec>      Write words of memory to a file when the computer
+                     stops, given as addr:len:file, annotated with their
+                     address and characters, like 0x8000:384:screen.txt for
+                     the text of the screen. Can be repeated.
+  -d, --device <device>
+                     Attach this device, like lem1802,screen=term or
+                     host:files, instead of the default clock (see
+                     dcpu::device::registry). Can be repeated, the
+                     devices getting the indices in order from 0.
+  --strict           Stop at the opcodes specific to this emulator (LOG,
+                     BRK, HLT and SLP), so the program runs the same on
+                     any DCPU-16 1.7.
+  --frequency <hz>   Cycles per emulated second, which the devices are
+                     timed with. [default: 100000]
+  --speed <hz>       Run this many cycles per second of real time, like
+                     100khz or 1mhz, instead of --frequency.
+  --turbo            Run as fast as possible instead of in real time.
+  --headless         Run as a batch job, for automated tests: as fast as
+                     possible, without any display.
+  --display <kind>   Attach a LEM1802 shown this way: none, or term to draw
+                     it in the terminal with ANSI escape codes, which works
+                     over SSH. [default: none]
+  --refresh-rate <hz>
+                     Frames per emulated second of the screen. [default: 60]
+  --vsync            Draw the screen at most --refresh-rate times per second
+                     of real time, skipping the frames in between, for a
+                     slow terminal or with --turbo.
+  --screenshot <file>
+                     Save the screen to this file when the computer stops,
+                     as PNG if its name ends with .png, PPM otherwise.
+                     Attaches a LEM1802 if --display doesn't.
+  --max-cycles <n>   Stop after this many cycles, with exit status 124.
+  --exit-code <loc>  Exit with the value of this register, or of the word
+                     at this address, when the program halts. The exit
+                     status is 0 without it, and 125 when the CPU fails.
+  --trap-pc-wrap     Stop when PC wraps past 0xffff.
+  --invalid-opcode <action>
+                     What to do with a word which isn't a valid
+                     instruction: skip it, stop, or skip it and trigger
+                     an interrupt with this message, like 0x13, to test
+                     the fault handler of the program. [default: skip]
+  --host-dir <dir>   Attach a host bridge giving the program access to the
+                     files under this directory (see
+                     dcpu::device::host::HostBridge).
+  --audio <file>     Attach a speaker writing its sound to this file, as
+                     raw signed 8 bit mono samples at 8 kHz, which
+                     `aplay -f S8 -r 8000 <file>` plays.
+  --console          Attach a serial port on the terminal: the program
+                     receives the keys typed, without echo nor line
+                     buffering, and the low byte of the words it sends is
+                     printed.
+  --remote <addr>    Attach the device served at this address, for example
+                     localhost:6000 (see dcpu::device::remote). Can be
+                     repeated.
+  --script <file>    Run the rules of this script, for example
+                     \"on break done: print A; stop\", with the labels
+                     of --symbols (see dcpu::script).
+  --blocks           Decode and run the code by basic blocks, calling the
+                     handler of each instruction without decoding it again.
+                     Much faster, but the interrupts and devices only see
+                     the state between blocks. Ignored with --regions.
+  --verbose          Describe the CPU, the devices and the loaded memory
+                     before starting.
+  --regions <file>   Stop when executing outside of the code regions listed
+                     in this file (see assembler --regions).
+  --debug-info <file>
+                     Show the source line and label of the failing
+                     instruction (see assembler --debug-info).
+  --symbols <file>   Labels of the program for --trace and --profile, one
+                     \"label 0xaddr\" per line, as written by assembler
+                     with --symbols, or the map written by linker --map.
+  --trace            Log each instruction executed to stderr, with its
+                     address, label, cycle and cycles.
+  --trace-last <n>   Log the last n instructions to stderr when the CPU
+                     fails.
+  --profile <file>   Write the cycles spent by label and by address to this
+                     file when the computer stops.
+  --flamegraph <file>
+                     Write the cycles spent by call stack to this file when
+                     the computer stops, in the collapsed format of the
+                     flame graph tools.
+  --coverage <file>  Write the source lines executed and never executed to
+                     this file when the computer stops. Needs --debug-info.
+  --output <format>  Format of the exit summary, text or json. [default: text]
+  --load-state <file>
+                     Resume from this state, as written by --save-state,
+                     instead of starting the program.
+  --save-state <file>
+                     Write the state of the computer to this file when it
+                     stops, to resume it or reproduce a failure.
+  --record <file>    Write the inputs of the devices, like the keys typed,
+                     and when they happened to this file when the computer
+                     stops (see dcpu::replay).
+  --replay <file>    Give the devices the inputs of this file, written
+                     with --record, so the run is the same as the one
+                     recorded with the same options.
+  --control <port>   Accept a client of the control protocol (see
+                     dcpu::control) on this local TCP port.
+  --gdb <port>       Wait for GDB on this local TCP port before starting, and
+                     let it control the computer until it detaches (see
+                     dcpu::gdb).
+  --reference <command>
+                     Run the program in this other emulator too, with
+                     the file as last argument, and stop at the first
+                     difference of their registers or memory. It must
+                     speak the control protocol on its standard input and
+                     output (see dcpu::differential).
+  --compare-every <ticks>
+                     Ticks between two comparisons with --reference.
+                     [default: 1000]
+  --bench            Run the program as fast as possible with the devices
+                     replaced by empty slots, then print the instructions
+                     and cycles emulated per second. Stops when the CPU
+                     halts, or after the --bench-time or --max-cycles.
+  --bench-time <secs>
+                     Seconds of real time --bench runs for. [default: 10]
   <file>             File to use instead of stdin.
   -h, --help         Show this message.
   --version          Show the version of disassembler.
 ";
 
+#[derive(RustcEncodable)]
+struct JsonSummary {
+    reason: String,
+    /// Source location of the failing instruction, with --debug-info.
+    location: Option<String>,
+    ticks: u64,
+    registers: Vec<u16>,
+    pc: u16,
+    sp: u16,
+    ex: u16,
+    ia: u16,
+}
+
 #[derive(Debug, RustcDecodable)]
 struct Args {
-    arg_device: Option<Vec<String>>,
+    flag_device: Vec<String>,
+    flag_strict: bool,
+    flag_frequency: u64,
+    flag_speed: Option<String>,
+    flag_turbo: bool,
+    flag_headless: bool,
+    flag_display: Display,
+    flag_refresh_rate: u64,
+    flag_vsync: bool,
+    flag_screenshot: Option<String>,
+    flag_max_cycles: Option<u64>,
+    flag_exit_code: Option<String>,
+    flag_trap_pc_wrap: bool,
+    flag_invalid_opcode: String,
+    flag_host_dir: Option<String>,
+    flag_audio: Option<String>,
+    flag_console: bool,
+    flag_remote: Vec<String>,
+    flag_script: Option<String>,
+    flag_blocks: bool,
+    flag_verbose: bool,
+    flag_regions: Option<String>,
+    flag_debug_info: Option<String>,
+    flag_symbols: Option<String>,
+    flag_trace: bool,
+    flag_trace_last: Option<usize>,
+    flag_profile: Option<String>,
+    flag_flamegraph: Option<String>,
+    flag_coverage: Option<String>,
+    flag_output: utils::OutputFormat,
+    flag_load_state: Option<String>,
+    flag_save_state: Option<String>,
+    flag_record: Option<String>,
+    flag_replay: Option<String>,
+    flag_control: Option<u16>,
+    flag_gdb: Option<u16>,
+    flag_reference: Option<String>,
+    flag_compare_every: u16,
+    flag_bench: bool,
+    flag_bench_time: u64,
+    flag_format: String,
+    flag_load_at: Vec<String>,
+    flag_dump: Vec<String>,
     arg_file: Option<String>,
 }
 
@@ -41,24 +355,551 @@ fn main() {
                             .unwrap_or_else(|e| e.exit());
 
     let rom = {
-        let input = utils::get_input(args.arg_file);
-        let mut rom = Vec::new();
-        rom.extend(utils::IterU16{input: input});
-        rom
+        let format = args.flag_format.parse().unwrap_or_else(|e| {
+            usage_error(format!("Invalid --format {}: {}", args.flag_format, e))
+        });
+        let mut input = utils::get_input(args.arg_file.clone());
+        formats::read(&mut input, format)
+            .unwrap_or_else(|e| usage_error(format!("Invalid binary: {}", e)))
     };
 
     let mut cpu = Cpu::default();
     cpu.load(&rom, 0);
+    for spec in &args.flag_load_at {
+        let mut parts = spec.splitn(2, ':');
+        let (addr, path) = match (parts.next().and_then(parse_number), parts.next()) {
+            (Some(addr), Some(path)) => (addr, path),
+            _ => usage_error(format!("Invalid --load-at {}, expected addr:file", spec)),
+        };
+        let format = args.flag_format.parse().unwrap_or_else(|e| {
+            usage_error(format!("Invalid --format {}: {}", args.flag_format, e))
+        });
+        let mut input = utils::get_input(Some(path.to_string()));
+        cpu.load_memory(&mut input, format, addr)
+           .unwrap_or_else(|e| usage_error(format!("Invalid binary {}: {}", path, e)));
+    }
+    let dumps: Vec<Dump> = args.flag_dump
+                               .iter()
+                               .map(|s| {
+                                   let msg = format!("Invalid --dump {}, expected addr:len:file",
+                                                     s);
+                                   Dump::parse(s).unwrap_or_else(|| usage_error(msg))
+                               })
+                               .collect();
+    cpu.trap_pc_wrap = args.flag_trap_pc_wrap;
+    cpu.strict = args.flag_strict;
+    cpu.on_decode_error = parse_invalid_opcode(&args.flag_invalid_opcode).unwrap_or_else(|| {
+        usage_error(format!("Invalid --invalid-opcode {}, expected skip, stop or a message",
+                            args.flag_invalid_opcode))
+    });
+    if args.flag_blocks {
+        cpu.blocks = Some(Box::new(BlockCache::new()));
+    }
+    if let Some(path) = args.flag_regions {
+        let input = BufReader::new(utils::get_input(Some(path.clone())));
+        let regions = input.lines()
+                           .enumerate()
+                           .map(|(i, l)| {
+                               let l = l.unwrap();
+                               l.parse().unwrap_or_else(|_| {
+                                   usage_error(format!("Invalid region \"{}\" at {}:{}",
+                                                       l,
+                                                       path,
+                                                       i + 1))
+                               })
+                           })
+                           .collect();
+        cpu.exec_regions = Some(regions);
+    }
+
+    let symbols = match args.flag_symbols {
+        Some(ref path) => {
+            let mut text = String::new();
+            utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+            text.parse::<Symbols>().unwrap_or_else(|e| {
+                usage_error(format!("Invalid symbols {}: {:?}", path, e))
+            })
+        }
+        None => Symbols::new(),
+    };
+    if args.flag_trace {
+        cpu.trace = Some(Box::new(Tracer::to_writer(Box::new(io::stderr()), symbols.clone())));
+    } else if let Some(n) = args.flag_trace_last {
+        cpu.trace = Some(Box::new(Tracer::buffered(n, symbols.clone())));
+    }
+    if args.flag_profile.is_some() || args.flag_flamegraph.is_some() {
+        cpu.profile = Some(Box::new(Profiler::new()));
+    }
+    if args.flag_coverage.is_some() {
+        if args.flag_debug_info.is_none() {
+            usage_error("--coverage needs --debug-info".into());
+        }
+        cpu.coverage = Some(Box::new(Coverage::new()));
+    }
+
+    let exit_code = args.flag_exit_code.as_ref().map(|s| {
+        ExitCode::parse(s).unwrap_or_else(|| {
+            usage_error(format!("Invalid --exit-code {}, expected a register or an address", s))
+        })
+    });
+    let turbo = args.flag_turbo || args.flag_headless;
+
+    let debug_info = args.flag_debug_info.map(|path| {
+        let mut text = String::new();
+        utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+        text.parse::<DebugInfo>().unwrap_or_else(|e| {
+            usage_error(format!("Invalid debug info {}: {:?}", path, e))
+        })
+    });
 
     let mut computer = Computer::new(cpu);
+    let mut timebase = Timebase::new(args.flag_frequency);
+    if let Some(ref speed) = args.flag_speed {
+        let hertz = timebase::parse_hertz(speed).unwrap_or_else(|| {
+            usage_error(format!("Invalid --speed {}, expected a frequency like 1mhz", speed))
+        });
+        timebase.speed = hertz as f64 / args.flag_frequency as f64;
+    }
+    computer.set_timebase(timebase);
+    if args.flag_bench {
+        // The slots of the devices, the default clock included.
+        for _ in 0..cmp::max(args.flag_device.len(), 1) {
+            computer.add_device(Box::new(Empty));
+        }
+        bench(&mut computer, args.flag_bench_time, args.flag_max_cycles);
+        return;
+    }
+    if args.flag_device.is_empty() {
+        add_clock(&mut computer);
+    }
+    for spec in &args.flag_device {
+        let spec = spec.parse::<Spec>().unwrap_or_else(|e| {
+            usage_error(format!("Invalid --device {}: {}", spec, e))
+        });
+        let device = registry::build(&spec, computer.timebase())
+                         .unwrap_or_else(|e| usage_error(format!("Can't attach {}: {}", spec, e)));
+        computer.add_device(device);
+    }
+    let term = args.flag_display == Display::Term && !args.flag_headless;
+    let last_screen = if term || args.flag_screenshot.is_some() {
+        Some(add_screen(&mut computer, term, args.flag_refresh_rate, args.flag_vsync))
+    } else {
+        None
+    };
+    if let Some(ref dir) = args.flag_host_dir {
+        add_host_bridge(&mut computer, dir);
+    }
+    if let Some(ref path) = args.flag_audio {
+        add_speaker(&mut computer, path);
+    }
+    for addr in &args.flag_remote {
+        add_remote(&mut computer, addr);
+    }
+    let terminal = if args.flag_console {
+        add_console(&mut computer);
+        unbuffer_terminal()
+    } else {
+        None
+    };
+    let recording = args.flag_record.as_ref().map(|_| replay::record(&mut computer));
+    if let Some(ref path) = args.flag_replay {
+        let mut text = String::new();
+        utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+        let recording = text.parse::<Recording>().unwrap_or_else(|e| {
+            usage_error(format!("Invalid recording {}: {:?}", path, e))
+        });
+        replay::replay(&mut computer, &recording);
+    }
+    if let Some(ref path) = args.flag_load_state {
+        let mut input = utils::get_input(Some(path.clone()));
+        computer.load_state(&mut input).unwrap_or_else(|e| {
+            usage_error(format!("Invalid state file {}: {}", path, e))
+        });
+    }
+    if args.flag_verbose {
+        print!("{}", computer.describe());
+    }
+    let mut script = args.flag_script.as_ref().map(|path| {
+        let mut text = String::new();
+        utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+        Script::parse(&text, &symbols).unwrap_or_else(|e| {
+            usage_error(format!("Invalid script {}: {:?}", path, e))
+        })
+    });
+    let mut stopped = match script {
+        Some(ref mut script) => script.attach(&mut computer, &mut io::stdout()).err(),
+        None => None,
+    };
+
+    if let Some(ref reference) = args.flag_reference {
+        let mut words = reference.split_whitespace();
+        let mut command = Command::new(words.next().unwrap_or(""));
+        command.args(words);
+        if let Some(ref file) = args.arg_file {
+            command.arg(file);
+        }
+        let mut process = Process::spawn(&mut command).expect("Can't start the reference");
+        let result = differential::run(&mut computer,
+                                       &mut process,
+                                       args.flag_compare_every,
+                                       u64::max_value());
+        match result {
+            Ok(Some(divergence)) => print!("{}", divergence),
+            Ok(None) => println!("no difference in {} ticks", computer.current_tick()),
+            Err(e) => println!("reference error: {}", e),
+        }
+        return;
+    }
+
+    if let Some(port) = args.flag_gdb {
+        let listener = TcpListener::bind(("127.0.0.1", port)).expect("Can't listen");
+        println!("Waiting for GDB on port {}", port);
+        let (mut stream, _) = listener.accept().expect("Can't accept GDB");
+        match Stub::new().serve(&mut computer, &mut stream) {
+            Ok(End::Detached) => (),
+            Ok(End::Killed) => return,
+            Err(e) => {
+                println!("GDB error: {}", e);
+                return;
+            }
+        }
+    }
 
+    let listener = args.flag_control.map(|port| {
+        let listener = TcpListener::bind(("127.0.0.1", port)).expect("Can't listen");
+        listener.set_nonblocking(true).unwrap();
+        listener
+    });
+    let mut client = None;
+    let mut controller = Controller::new();
+    let mut throttle = Throttle::new(computer.timebase(), computer.current_tick());
+    let mut next_throttle = computer.current_tick() + THROTTLE_PERIOD;
+
+    let (reason, location, status);
     loop {
-        match computer.tick() {
+        if let Some(stop) = stopped.take() {
+            location = None;
+            status = match stop {
+                Stop::Requested => 0,
+                Stop::Failed(_) => EXIT_FAILED,
+            };
+            reason = stop.to_string();
+            break;
+        }
+        if args.flag_max_cycles.map_or(false, |max| computer.current_tick() >= max) {
+            reason = "cycle limit reached".to_string();
+            location = None;
+            status = EXIT_CYCLE_LIMIT;
+            break;
+        }
+        if let Some(ref listener) = listener {
+            if controller.paused || computer.current_tick() % CONTROL_PERIOD == 0 {
+                poll_control(listener, &mut client, &mut controller, &mut computer);
+            }
+            if controller.paused {
+                continue;
+            }
+        }
+        if !turbo && computer.current_tick() >= next_throttle {
+            throttle.wait(computer.current_tick());
+            next_throttle = computer.current_tick() + THROTTLE_PERIOD;
+        }
+        let pc = computer.cpu().pc;
+        let res = computer.tick();
+        if let (Some(script), Ok(state)) = (script.as_mut(), res.as_ref()) {
+            stopped = script.handle(&mut computer, state, &mut io::stdout()).err();
+        }
+        // Up to the next check for control requests, where it must stop.
+        let mut limit = CONTROL_PERIOD - computer.current_tick() % CONTROL_PERIOD;
+        if let Some(max) = args.flag_max_cycles {
+            limit = cmp::min(limit, max.saturating_sub(computer.current_tick()));
+        }
+        match res.and_then(|_| {
+            computer.skip_waits(limit);
+            computer.skip_idle()
+        }) {
+            // Sleeping until a host device, like the keyboard, interrupts.
+            Ok(0) if computer.is_sleeping() => thread::sleep(Duration::from_millis(1)),
             Ok(_) => (),
             Err(e) => {
-                println!("{}", e);
+                location = debug_info.as_ref().map(|info| info.describe(pc));
+                status = match e {
+                    cpu::Error::Halted => {
+                        match script.as_mut().map(|s| s.halt(&mut computer, &mut io::stdout())) {
+                            Some(Err(Stop::Failed(e))) => {
+                                println!("script failed: {}", e);
+                                EXIT_FAILED
+                            }
+                            _ => exit_code.as_ref().map_or(0, |c| c.read(computer.cpu())),
+                        }
+                    }
+                    _ => EXIT_FAILED,
+                };
+                if status == EXIT_FAILED {
+                    if let Some(ref trace) = computer.cpu().trace {
+                        trace.dump(&mut io::stderr()).unwrap();
+                    }
+                }
+                reason = e.to_string();
                 break;
             }
         }
     }
+
+    if args.flag_output == OutputFormat::Json {
+        let cpu = computer.cpu();
+        let summary = JsonSummary {
+            reason: reason,
+            location: location,
+            ticks: computer.current_tick(),
+            registers: cpu.registers.to_vec(),
+            pc: cpu.pc,
+            sp: cpu.sp,
+            ex: cpu.ex,
+            ia: cpu.ia,
+        };
+        println!("{}", json::encode(&summary).unwrap());
+    } else if let Some(location) = location {
+        println!("{} at {}", reason, location);
+    } else {
+        println!("{}", reason);
+    }
+    if let Some(path) = args.flag_save_state {
+        let mut output = utils::get_output(Some(path));
+        computer.save_state(&mut output).expect("Can't write the state");
+    }
+    for dump in dumps {
+        let mut output = utils::get_output(Some(dump.path));
+        computer.cpu()
+                .dump_memory(dump.addr, dump.len as usize, None, &mut output)
+                .expect("Can't write the dump");
+    }
+    if let (Some(path), Some(recording)) = (args.flag_record, recording) {
+        let mut output = utils::get_output(Some(path));
+        write!(output, "{}", recording.borrow()).expect("Can't write the recording");
+    }
+    if let Some(ref profile) = computer.cpu().profile {
+        if let Some(path) = args.flag_profile {
+            let mut output = utils::get_output(Some(path));
+            profile.report(&symbols, PROFILE_TOP, &mut output).expect("Can't write the profile");
+        }
+        if let Some(path) = args.flag_flamegraph {
+            let mut output = utils::get_output(Some(path));
+            profile.write_collapsed(&symbols, &mut output).expect("Can't write the flame graph");
+        }
+    }
+    if let (Some(path), Some(last)) = (args.flag_screenshot, last_screen) {
+        save_screenshot(&last, &path);
+    }
+    if let (Some(coverage), Some(info)) = (computer.cpu().coverage.as_ref(), debug_info.as_ref()) {
+        let mut output = utils::get_output(args.flag_coverage);
+        coverage.report(info, &mut output).expect("Can't write the coverage");
+    }
+    if let Some(ref settings) = terminal {
+        restore_terminal(settings);
+    }
+    std::process::exit(status);
+}
+
+/// Runs for `seconds` of real time or `max_cycles` cycles, then prints the
+/// speed of the emulation.
+fn bench(computer: &mut Computer, seconds: u64, max_cycles: Option<u64>) {
+    let max_cycles = max_cycles.unwrap_or(u64::max_value());
+    let duration = Duration::from_secs(seconds);
+    let start = Instant::now();
+    let (start_tick, start_instructions) = (computer.current_tick(), computer.cpu().instructions);
+    let mut reason = "time limit reached".to_string();
+    while start.elapsed() < duration {
+        let left = max_cycles.saturating_sub(computer.current_tick() - start_tick);
+        if left == 0 {
+            reason = "cycle limit reached".to_string();
+            break;
+        }
+        let res = computer.run(cmp::min(left, BENCH_PERIOD))
+                          .and_then(|_| computer.skip_idle());
+        if let Err(e) = res {
+            reason = e.to_string();
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+    let cycles = computer.current_tick() - start_tick;
+    let instructions = computer.cpu().instructions - start_instructions;
+    let frequency = computer.timebase().ticks_per_second as f64;
+    println!("{}", reason);
+    println!("{} instructions, {} cycles in {:.2} s", instructions, cycles, seconds);
+    println!("{:.3} MIPS, {:.0} cycles/s, {:.1}x real time",
+             instructions as f64 / seconds / 1e6,
+             cycles as f64 / seconds,
+             cycles as f64 / seconds / frequency);
+}
+
+#[cfg(feature = "devices-clock")]
+fn add_clock(computer: &mut Computer) {
+    let clock = Clock::with_timebase(computer.timebase());
+    computer.add_device(Box::new(clock));
+}
+
+#[cfg(not(feature = "devices-clock"))]
+fn add_clock(_: &mut Computer) {}
+
+#[cfg(feature = "devices-host")]
+fn add_host_bridge(computer: &mut Computer, dir: &str) {
+    let bridge = HostBridge::new(dir).expect("Can't open the host directory");
+    computer.add_device(Box::new(bridge));
+}
+
+#[cfg(not(feature = "devices-host"))]
+fn add_host_bridge(_: &mut Computer, _: &str) {
+    panic!("--host-dir needs the devices-host feature");
+}
+
+/// Last frame of the screen.
+#[cfg(feature = "devices-lem")]
+type LastScreen = Rc<RefCell<Option<Screen>>>;
+#[cfg(not(feature = "devices-lem"))]
+type LastScreen = ();
+
+#[cfg(feature = "devices-lem")]
+fn add_screen(computer: &mut Computer, term: bool, rate: u64, vsync: bool) -> LastScreen {
+    let last = Rc::new(RefCell::new(None));
+    let inner = if term {
+        Some(Box::new(TermBackend(io::stdout())) as Box<ScreenBackend>)
+    } else {
+        None
+    };
+    let backend = Box::new(LastFrame {
+        inner: inner,
+        last: last.clone(),
+    });
+    let refresh = Refresh {
+        rate: rate,
+        vsync: vsync,
+    };
+    let lem = LEM1802::with_refresh(backend, computer.timebase(), refresh);
+    computer.add_device(Box::new(lem));
+    last
+}
+
+#[cfg(not(feature = "devices-lem"))]
+fn add_screen(_: &mut Computer, _: bool, _: u64, _: bool) -> LastScreen {
+    panic!("--display and --screenshot need the devices-lem feature");
+}
+
+#[cfg(feature = "devices-lem")]
+fn save_screenshot(last: &LastScreen, path: &str) {
+    let last = last.borrow();
+    let image = last.as_ref().expect("The screen was never mapped").image(SCREENSHOT_SCALE);
+    let mut output = utils::get_output(Some(path.to_string()));
+    let res = if path.ends_with(".png") {
+        image.write_png(&mut output)
+    } else {
+        image.write_ppm(&mut output)
+    };
+    res.expect("Can't write the screenshot");
+}
+
+#[cfg(not(feature = "devices-lem"))]
+fn save_screenshot(_: &LastScreen, _: &str) {}
+
+#[cfg(feature = "devices-serial")]
+fn add_console(computer: &mut Computer) {
+    computer.add_device(Box::new(Serial::new(Box::new(ConsoleBackend::stdio()))));
+}
+
+#[cfg(not(feature = "devices-serial"))]
+fn add_console(_: &mut Computer) {
+    panic!("--console needs the devices-serial feature");
+}
+
+#[cfg(feature = "devices-remote")]
+fn add_remote(computer: &mut Computer, addr: &str) {
+    let stream = TcpStream::connect(addr).expect("Can't connect to the remote device");
+    let device = RemoteDevice::with_timebase(stream, computer.timebase())
+        .expect("Can't query the remote device");
+    computer.add_device(Box::new(device));
+}
+
+#[cfg(not(feature = "devices-remote"))]
+fn add_remote(_: &mut Computer, _: &str) {
+    panic!("--remote needs the devices-remote feature");
+}
+
+/// Makes the terminal pass each key as it is typed, without echoing it.
+/// Returns the settings to restore, `None` if stdin is not a terminal.
+fn unbuffer_terminal() -> Option<String> {
+    let stty = |args: &[&str]| {
+        Command::new("stty")
+            .args(args)
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .and_then(|o| if o.status.success() { String::from_utf8(o.stdout).ok() } else { None })
+    };
+    let settings = match stty(&["-g"]) {
+        Some(settings) => settings.trim().to_string(),
+        None => return None,
+    };
+    stty(&["-icanon", "-echo", "min", "1"]);
+    Some(settings)
+}
+
+fn restore_terminal(settings: &str) {
+    let _ = Command::new("stty").arg(settings).stdin(Stdio::inherit()).status();
+}
+
+#[cfg(feature = "devices-speaker")]
+fn add_speaker(computer: &mut Computer, path: &str) {
+    let output = std::fs::File::create(path).expect("Can't create the audio file");
+    let backend = Box::new(PcmBackend(io::BufWriter::new(output)));
+    computer.add_device(Box::new(Speaker::with_timebase(backend, computer.timebase())));
+}
+
+#[cfg(not(feature = "devices-speaker"))]
+fn add_speaker(_: &mut Computer, _: &str) {
+    panic!("--audio needs the devices-speaker feature");
+}
+
+/// Handles the pending control requests. Blocks while the computer is
+/// paused.
+fn poll_control(listener: &TcpListener,
+                client: &mut Option<TcpStream>,
+                controller: &mut Controller,
+                computer: &mut Computer) {
+    if client.is_none() {
+        *client = listener.accept().ok().map(|(stream, _)| stream);
+    }
+    let pending = match *client {
+        Some(ref stream) => {
+            stream.set_nonblocking(!controller.paused).unwrap();
+            match stream.peek(&mut [0]) {
+                Ok(0) => Err(()),
+                Ok(_) => Ok(true),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+                Err(_) => Err(()),
+            }
+        }
+        None => Ok(false),
+    };
+    let connected = match pending {
+        Ok(true) => {
+            let stream = client.as_mut().unwrap();
+            stream.set_nonblocking(false).unwrap();
+            match controller.serve(computer, stream) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Control client error: {}", e);
+                    false
+                }
+            }
+        }
+        Ok(false) => true,
+        Err(()) => false,
+    };
+    // Don't stay paused without a way to resume.
+    if !connected {
+        *client = None;
+        controller.paused = false;
+    }
 }
@@ -0,0 +1,117 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use byteorder::WriteBytesExt;
+use docopt::Docopt;
+
+use dcpu::assembler::object::{self, Object};
+
+const USAGE: &'static str = "
+Usage:
+  linker [--hex] [--wrap <symbol>]... [--prefix <prefix=object>]... [--map <file>] <object>... [-o <file>]
+  linker (--help | --version)
+
+Options:
+  --hex         Show in hexadecimal instead of binary.
+  --wrap <symbol>
+                Send the references to this symbol to __wrap_<symbol>,
+                and the ones to __real_<symbol> to the symbol, like ld.
+  --prefix <prefix=object>
+                Prefix the global symbols of this object, and its uses of
+                them. The objects with the same prefix form a library,
+                their uses of each other's symbols are prefixed too.
+  --map <file>  Write the global symbols to this file, one
+                \"symbol 0xaddr object\" per line.
+  <object>      Object written by `assembler -c`. They are placed in the
+                binary in the given order.
+  -o <file>     File to use instead of stdout.
+  -h --help     Show this screen.
+  --version     Show version.
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_hex: bool,
+    flag_wrap: Vec<String>,
+    flag_prefix: Vec<String>,
+    flag_map: Option<String>,
+    arg_object: Vec<String>,
+    flag_o: Option<String>,
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let mut objects = vec![];
+    for path in args.arg_object {
+        let mut text = String::new();
+        utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+        match text.parse::<Object>() {
+            Ok(o) => objects.push((path, o)),
+            Err(e) => die!(1, "Invalid object {}: {:?}", path, e),
+        }
+    }
+
+    let mut libraries = BTreeMap::new();
+    for spec in args.flag_prefix.iter() {
+        let (prefix, path) = match spec.find('=') {
+            Some(i) => (&spec[..i], &spec[i + 1..]),
+            None => die!(1, "Invalid prefix {}, expected <prefix>=<object>", spec),
+        };
+        match objects.iter().position(|&(ref p, _)| p == path) {
+            Some(i) => libraries.entry(prefix).or_insert_with(Vec::new).push(i),
+            None => die!(1, "Prefix for {}, which isn't linked", path),
+        }
+    }
+    for (prefix, members) in libraries {
+        let names = object::global_symbols(members.iter().map(|&i| &objects[i].1));
+        for i in members {
+            objects[i].1.prefix(prefix, &names);
+        }
+    }
+
+    let options = object::LinkOptions { wrap: args.flag_wrap };
+    let linked = match object::link_with_options(&objects, &options) {
+        Ok(linked) => linked,
+        Err(e) => die!(1, "Error: {}", e),
+    };
+
+    if let Some(path) = args.flag_map {
+        let mut output = utils::get_output(Some(path));
+        for p in linked.symbols.iter() {
+            writeln!(output, "{} 0x{:04x} {}", p.name, p.address, p.object).unwrap();
+        }
+    }
+
+    let bin = linked.bin;
+
+    let mut output = utils::get_output(args.flag_o);
+    if args.flag_hex {
+        for n in bin {
+            writeln!(output, "0x{:x}", n).unwrap();
+        }
+    } else {
+        for n in bin {
+            output.write_u16::<byteorder::LittleEndian>(n).unwrap();
+        }
+    }
+
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
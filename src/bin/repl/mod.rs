@@ -0,0 +1,615 @@
+//! `dcpu repl`: instructions and commands typed at a prompt.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::str::FromStr;
+
+use simplelog;
+
+use dcpu::cpu::Cpu;
+use dcpu::debug_info::DebugInfo;
+use dcpu::explain::explain;
+use dcpu::symbols::Symbols;
+use dcpu::types::{Instruction, Register, SpecialOp};
+
+use utils;
+
+/// Maximum number of instructions executed by :continue, :next, :finish and
+/// :until.
+const MAX_RUN: u32 = 10_000_000;
+
+const COMMANDS: &'static str = "
+Each line is either an instruction, assembled and executed immediately, or
+a command:
+  :regs              Show the registers.
+  :mem <addr> [len]  Show len words of memory starting at addr.
+  :history           Show the executed instructions.
+  :step [n]          Execute the next n instructions in memory.
+  :next              Same as :step, but runs JSR'd routines until they return.
+  :finish            Run until the current routine returns.
+  :until <addr>      Run until PC reaches addr.
+  :line              Run until the next source line.
+  :list [n]          Show n source lines around the current one.
+  :continue          Run until a breakpoint is reached.
+  :break [<addr>]    Add a breakpoint, or list them.
+  :delete <addr>     Remove a breakpoint.
+  :watch [<addr>]    Show the word at addr after each run, or list watches.
+  :unwatch <addr>    Remove a watch.
+  :save              Write the session file.
+  :help              Show this message.
+  :quit              Exit, writing the session file.
+
+Addresses are either numbers, label or label+offset with --symbols, or
+file:line with --debug-info.
+";
+
+/// The options of `dcpu repl`.
+pub struct Args {
+    pub explain: bool,
+    pub symbols: Option<String>,
+    pub debug_info: Option<String>,
+    pub session: Option<String>,
+    pub file: Option<String>,
+}
+
+/// What is kept between two debugging sessions. Addresses are stored as
+/// written by `Symbols::describe`.
+#[derive(Debug, Default)]
+struct Session {
+    explain: bool,
+    breakpoints: Vec<String>,
+    watches: Vec<String>,
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "explain {}", self.explain));
+        for b in self.breakpoints.iter() {
+            try!(writeln!(f, "break {}", b));
+        }
+        for w in self.watches.iter() {
+            try!(writeln!(f, "watch {}", w));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Session {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Session, String> {
+        let mut session = Session::default();
+        for line in s.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let mut words = line.splitn(2, ' ');
+            match (words.next(), words.next()) {
+                (Some("explain"), Some(v)) => {
+                    session.explain = try!(v.parse().map_err(|_| format!("invalid line: {}", line)))
+                }
+                (Some("break"), Some(addr)) => session.breakpoints.push(addr.into()),
+                (Some("watch"), Some(addr)) => session.watches.push(addr.into()),
+                _ => return Err(format!("invalid line: {}", line)),
+            }
+        }
+        Ok(session)
+    }
+}
+
+/// Routine called with JSR.
+struct Frame {
+    return_addr: u16,
+    /// SP once the return address has been pushed.
+    sp: u16,
+}
+
+struct Repl {
+    cpu: Cpu,
+    history: Vec<(u16, String)>,
+    call_stack: Vec<Frame>,
+    symbols: Symbols,
+    debug_info: DebugInfo,
+    /// Lines of each file of `debug_info`.
+    sources: HashMap<String, Vec<String>>,
+    breakpoints: Vec<String>,
+    watches: Vec<String>,
+    session_path: Option<String>,
+    explain: bool,
+    /// Set when the last IF failed, so the next instruction must be skipped.
+    skipping: bool,
+}
+
+impl Repl {
+    fn new(cpu: Cpu,
+           symbols: Symbols,
+           debug_info: DebugInfo,
+           session_path: Option<String>,
+           session: Session)
+           -> Repl {
+        let mut sources = HashMap::new();
+        for file in debug_info.files() {
+            let mut s = String::new();
+            if File::open(file).and_then(|mut f| f.read_to_string(&mut s)).is_ok() {
+                sources.insert(file.clone(), s.lines().map(String::from).collect());
+            }
+        }
+        Repl {
+            cpu: cpu,
+            history: vec![],
+            call_stack: vec![],
+            symbols: symbols,
+            debug_info: debug_info,
+            sources: sources,
+            breakpoints: session.breakpoints,
+            watches: session.watches,
+            session_path: session_path,
+            explain: session.explain,
+            skipping: false,
+        }
+    }
+
+    /// See the help for the accepted formats.
+    fn lookup(&self, addr: &str) -> Option<u16> {
+        if let Some(i) = addr.rfind(':') {
+            if let Ok(line) = addr[i + 1..].parse() {
+                return self.debug_info.addresses_of(&addr[..i], line).first().cloned();
+            }
+        }
+        self.symbols.resolve(addr)
+    }
+
+    fn resolve(&self, addr: &str) -> Result<u16, String> {
+        self.lookup(addr).ok_or(format!("unknown address: {}", addr))
+    }
+
+    /// Name used to store an address in the session.
+    fn describe(&self, addr: &str) -> Result<String, String> {
+        let resolved = try!(self.resolve(addr));
+        if addr.contains(':') {
+            Ok(addr.into())
+        } else {
+            Ok(self.symbols.describe(resolved))
+        }
+    }
+
+    fn source_line(&self, file: &str, line: usize) -> Option<&str> {
+        self.sources
+            .get(file)
+            .and_then(|lines| lines.get(line.wrapping_sub(1)))
+            .map(|l| l.as_str())
+    }
+
+    fn save_session(&self) -> Result<(), String> {
+        if let Some(ref path) = self.session_path {
+            let session = Session {
+                explain: self.explain,
+                breakpoints: self.breakpoints.clone(),
+                watches: self.watches.clone(),
+            };
+            try!(File::create(path)
+                     .and_then(|mut f| write!(f, "{}", session))
+                     .map_err(|e| format!("{}: {}", path, e)));
+        }
+        Ok(())
+    }
+
+    fn exec_line(&mut self, line: &str) -> Result<(), String> {
+        let instruction: Instruction = try!(line.parse().map_err(|e| format!("{:?}", e)));
+        let pc = self.cpu.pc;
+        let (words, size) = instruction.encode_to_array();
+        let size = size as u16;
+        self.cpu.load(&words[..size as usize], pc);
+        self.history.push((pc, line.trim().into()));
+
+        if self.skipping {
+            self.cpu.pc = pc.wrapping_add(size);
+            self.skipping = instruction.is_if();
+            println!("skipped");
+            return Ok(());
+        }
+
+        if self.explain {
+            println!("{}", explain(&self.cpu, instruction));
+        }
+
+        // A failing IF skips the next instruction, which isn't typed yet.
+        // Use a placeholder and remember to skip the real one instead.
+        let next = pc.wrapping_add(size);
+        let saved = self.cpu.ram[next as usize];
+        if instruction.is_if() {
+            // SET A, A
+            self.cpu.ram[next as usize] = 0x0001;
+        }
+
+        self.cpu.check_if_cascade = false;
+        let res = self.run_instruction();
+
+        if instruction.is_if() {
+            self.cpu.ram[next as usize] = saved;
+            if self.cpu.check_if_cascade {
+                self.cpu.check_if_cascade = false;
+                self.cpu.pc = next;
+                self.skipping = true;
+            }
+        }
+        res
+    }
+
+    fn run_instruction(&mut self) -> Result<(), String> {
+        try!(self.cpu.tick(&mut []).map_err(|e| e.to_string()));
+        while self.cpu.wait != 0 {
+            try!(self.cpu.tick(&mut []).map_err(|e| e.to_string()));
+        }
+        Ok(())
+    }
+
+    fn decode_at_pc(&self) -> Option<(u16, Instruction)> {
+        let pc = self.cpu.pc;
+        let words = [self.cpu.ram[pc as usize],
+                     self.cpu.ram[pc.wrapping_add(1) as usize],
+                     self.cpu.ram[pc.wrapping_add(2) as usize]];
+        Instruction::decode(&words).ok()
+    }
+
+    /// Executes the instruction at PC and keeps track of the routine calls.
+    fn step(&mut self) -> Result<(), String> {
+        let pc = self.cpu.pc;
+        let sp = self.cpu.sp;
+        let jsr_return = match self.decode_at_pc() {
+            Some((size, Instruction::SpecialOp(SpecialOp::JSR, _))) => Some(pc.wrapping_add(size)),
+            _ => None,
+        };
+        if self.explain {
+            if let Some((_, i)) = self.decode_at_pc() {
+                println!("{}", explain(&self.cpu, i));
+            }
+        }
+
+        try!(self.run_instruction());
+
+        // The return address has been popped, either by returning or by
+        // unwinding the stack.
+        while self.call_stack
+                  .last()
+                  .map_or(false, |f| (self.cpu.sp.wrapping_sub(f.sp) as i16) > 0) {
+            self.call_stack.pop();
+        }
+        if let Some(return_addr) = jsr_return {
+            if self.cpu.sp == sp.wrapping_sub(1) &&
+               self.cpu.ram[self.cpu.sp as usize] == return_addr {
+                self.call_stack.push(Frame {
+                    return_addr: return_addr,
+                    sp: self.cpu.sp,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Also stops at breakpoints.
+    fn run_until<F: Fn(&Repl) -> bool>(&mut self, stop: F) -> Result<(), String> {
+        let breakpoints: Vec<u16> = self.breakpoints
+                                        .iter()
+                                        .filter_map(|b| self.lookup(b))
+                                        .collect();
+        for _ in 0..MAX_RUN {
+            try!(self.step());
+            if breakpoints.contains(&self.cpu.pc) {
+                println!("breakpoint at {}", self.symbols.describe(self.cpu.pc));
+                return Ok(());
+            }
+            if stop(self) {
+                return Ok(());
+            }
+        }
+        Err(format!("stopped after {} instructions", MAX_RUN))
+    }
+
+    fn next(&mut self) -> Result<(), String> {
+        let depth = self.call_stack.len();
+        try!(self.step());
+        if self.call_stack.len() > depth {
+            let return_addr = self.call_stack[depth].return_addr;
+            try!(self.run_until(|r| {
+                r.call_stack.len() <= depth && r.cpu.pc == return_addr
+            }));
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        let depth = self.call_stack.len();
+        if depth == 0 {
+            return Err("not in a routine called with JSR".into());
+        }
+        self.run_until(|r| r.call_stack.len() < depth)
+    }
+
+    /// Shows where the execution stopped.
+    fn print_stop(&self) {
+        self.print_registers();
+        for w in self.watches.iter() {
+            match self.lookup(w) {
+                Some(addr) => {
+                    println!("{} (0x{:04x}) = 0x{:04x}", w, addr, self.cpu.ram[addr as usize])
+                }
+                None => println!("{}: unknown address", w),
+            }
+        }
+        self.print_next_instruction();
+    }
+
+    /// Runs until the execution reaches the start of another source line.
+    /// Instructions expanded from the same line, e.g. by a macro, are run
+    /// together.
+    fn step_line(&mut self) -> Result<(), String> {
+        let current = self.debug_info
+                          .line_of(self.cpu.pc)
+                          .map(|(f, l)| (f.to_string(), l));
+        self.run_until(|r| match r.debug_info.line_of(r.cpu.pc) {
+            Some((f, l)) => current.as_ref().map_or(true, |c| c.0 != f || c.1 != l),
+            None => false,
+        })
+    }
+
+    fn print_source(&self, context: usize) {
+        let (file, line) = match self.debug_info.line_of(self.cpu.pc) {
+            Some(l) => l,
+            None => {
+                println!("no source line for 0x{:04x}", self.cpu.pc);
+                return;
+            }
+        };
+        let first = if line > context { line - context } else { 1 };
+        for l in first..line + context + 1 {
+            if let Some(text) = self.source_line(file, l) {
+                println!("{}{:5} {}", if l == line { ">" } else { " " }, l, text);
+            }
+        }
+    }
+
+    fn print_next_instruction(&self) {
+        if let Some((file, line)) = self.debug_info.line_of(self.cpu.pc) {
+            println!("{}:{}: {}",
+                     file,
+                     line,
+                     self.source_line(file, line).unwrap_or("").trim());
+        }
+        match self.decode_at_pc() {
+            Some((_, i)) => println!("0x{:04x}: {}", self.cpu.pc, i),
+            None => println!("0x{:04x}: invalid instruction", self.cpu.pc),
+        }
+    }
+
+    fn exec_command(&mut self, cmd: &str) -> Result<bool, String> {
+        let mut args = cmd.split_whitespace();
+        match args.next() {
+            Some("regs") => self.print_registers(),
+            Some("mem") => {
+                let addr = try!(args.next().ok_or("missing address".to_string())
+                                    .and_then(|a| self.resolve(a)));
+                let len = try!(args.next().map(parse_num).unwrap_or(Ok(8)));
+                self.print_memory(addr, len);
+            }
+            Some("history") => {
+                for &(addr, ref line) in self.history.iter() {
+                    println!("0x{:04x}: {}", addr, line);
+                }
+            }
+            Some("step") => {
+                let n = try!(args.next().map(parse_num).unwrap_or(Ok(1)));
+                for _ in 0..n {
+                    try!(self.step());
+                }
+                self.print_stop();
+            }
+            Some("next") => {
+                try!(self.next());
+                self.print_stop();
+            }
+            Some("finish") => {
+                try!(self.finish());
+                self.print_stop();
+            }
+            Some("until") => {
+                let addr = try!(args.next().ok_or("missing address".to_string())
+                                    .and_then(|a| self.resolve(a)));
+                try!(self.run_until(|r| r.cpu.pc == addr));
+                self.print_stop();
+            }
+            Some("line") => {
+                try!(self.step_line());
+                self.print_stop();
+            }
+            Some("list") => {
+                let n = try!(args.next().map(parse_num).unwrap_or(Ok(5)));
+                self.print_source(n as usize);
+            }
+            Some("continue") => {
+                try!(self.run_until(|_| false));
+                self.print_stop();
+            }
+            Some("break") => {
+                match args.next() {
+                    Some(a) => {
+                        let addr = try!(self.describe(a));
+                        if !self.breakpoints.contains(&addr) {
+                            self.breakpoints.push(addr);
+                        }
+                    }
+                    None => {
+                        for b in self.breakpoints.iter() {
+                            println!("{}", b);
+                        }
+                    }
+                }
+            }
+            Some("delete") => {
+                let addr = try!(args.next().ok_or("missing address".to_string())
+                                    .and_then(|a| self.resolve(a)));
+                let breakpoints = self.breakpoints
+                                      .iter()
+                                      .filter(|b| self.lookup(b) != Some(addr))
+                                      .cloned()
+                                      .collect();
+                self.breakpoints = breakpoints;
+            }
+            Some("watch") => {
+                match args.next() {
+                    Some(a) => {
+                        let addr = try!(self.describe(a));
+                        if !self.watches.contains(&addr) {
+                            self.watches.push(addr);
+                        }
+                    }
+                    None => {
+                        for w in self.watches.iter() {
+                            println!("{}", w);
+                        }
+                    }
+                }
+            }
+            Some("unwatch") => {
+                let addr = try!(args.next().ok_or("missing address".to_string())
+                                    .and_then(|a| self.resolve(a)));
+                let watches = self.watches
+                                  .iter()
+                                  .filter(|w| self.lookup(w) != Some(addr))
+                                  .cloned()
+                                  .collect();
+                self.watches = watches;
+            }
+            Some("save") => try!(self.save_session()),
+            Some("help") => println!("{}", COMMANDS),
+            Some("quit") => {
+                try!(self.save_session());
+                return Ok(false);
+            }
+            _ => return Err(format!("unknown command: {}", cmd)),
+        }
+        Ok(true)
+    }
+
+    fn print_registers(&self) {
+        let regs = [Register::A, Register::B, Register::C, Register::X,
+                    Register::Y, Register::Z, Register::I, Register::J];
+        for r in regs.iter() {
+            print!("{:?}=0x{:04x} ", r, self.cpu.registers[*r as usize]);
+        }
+        println!("");
+        println!("PC=0x{:04x} SP=0x{:04x} EX=0x{:04x} IA=0x{:04x}{}",
+                 self.cpu.pc,
+                 self.cpu.sp,
+                 self.cpu.ex,
+                 self.cpu.ia,
+                 if self.skipping { " (skipping next)" } else { "" });
+    }
+
+    fn print_memory(&self, addr: u16, len: u16) {
+        for i in 0..len {
+            let a = addr.wrapping_add(i);
+            if i % 8 == 0 {
+                if i != 0 {
+                    println!("");
+                }
+                print!("0x{:04x}:", a);
+            }
+            print!(" {:04x}", self.cpu.ram[a as usize]);
+        }
+        println!("");
+    }
+}
+
+fn parse_num(s: &str) -> Result<u16, String> {
+    let res = if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    res.map_err(|e| format!("{}: {}", s, e))
+}
+
+/// Runs the prompt until :quit or the end of the input, and returns the exit
+/// status.
+pub fn run(args: Args) -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let mut cpu = Cpu::default();
+    if let Some(path) = args.file {
+        let rom: Vec<u16> = utils::IterU16 { input: utils::get_input(Some(path)) }.collect();
+        cpu.load(&rom, 0);
+    }
+    let symbols = match args.symbols {
+        Some(path) => {
+            let mut s = String::new();
+            utils::get_input(Some(path)).read_to_string(&mut s).unwrap();
+            match s.parse() {
+                Ok(symbols) => symbols,
+                Err(e) => die!(1, "Invalid symbols file: {:?}", e),
+            }
+        }
+        None => Symbols::new(),
+    };
+    let debug_info = match args.debug_info {
+        Some(path) => {
+            let mut s = String::new();
+            utils::get_input(Some(path)).read_to_string(&mut s).unwrap();
+            match s.parse() {
+                Ok(info) => info,
+                Err(e) => die!(1, "Invalid debug info file: {:?}", e),
+            }
+        }
+        None => DebugInfo::new(),
+    };
+    let mut session = Session::default();
+    if let Some(ref path) = args.session {
+        if let Ok(mut f) = File::open(path) {
+            let mut s = String::new();
+            f.read_to_string(&mut s).unwrap();
+            session = match s.parse() {
+                Ok(session) => session,
+                Err(e) => die!(1, "{}: {}", path, e),
+            };
+        }
+    }
+    session.explain |= args.explain;
+    let mut repl = Repl::new(cpu, symbols, debug_info, args.session, session);
+    for addr in repl.breakpoints.iter().chain(repl.watches.iter()) {
+        if repl.lookup(addr).is_none() {
+            println!("Warning: cannot resolve {}", addr);
+        }
+    }
+
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            if let Err(e) = repl.save_session() {
+                println!("Error: {}", e);
+            }
+            break;
+        }
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if line.starts_with(':') {
+            match repl.exec_command(&line[1..]) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => println!("Error: {}", e),
+            }
+        } else {
+            match repl.exec_line(line) {
+                Ok(()) => repl.print_registers(),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+    }
+
+    0
+}
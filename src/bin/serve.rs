@@ -0,0 +1,114 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::io::Write;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Duration;
+
+use docopt::Docopt;
+
+use dcpu::computer::Computer;
+use dcpu::cpu::Cpu;
+use dcpu::device::serial::{Serial, TcpBackend};
+use dcpu::server::{Limits, Quota, Server, State};
+
+const USAGE: &'static str = "
+Usage:
+  serve [--port <port>] [--machines <n>] [--slice <ticks>] [--max-ticks <n>] [--max-host-ms <ms>] [--max-hwi <n>] [<file>]
+  serve (--help | --version)
+
+Options:
+  --port <port>     Port of the serial device of the first computer, the
+                    next ones use the following ports. [default: 16000]
+  --machines <n>    Number of computers running the program. [default: 1]
+  --slice <ticks>   Ticks run by a computer before the next one's turn.
+                    [default: 1000]
+  --max-ticks <n>   Stop the computers after this many ticks.
+  --max-host-ms <ms>
+                    Stop the computers taking more host time than this per
+                    emulated second.
+  --max-hwi <n>     Stop the computers sending more interrupts to their
+                    devices than this per emulated second.
+  <file>            The binary file to execute, instead of stdin.
+  -h, --help        Show this message.
+  --version         Show the version of serve.
+
+Each computer has a serial device, connected to the first TCP client of
+its port.
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_port: u16,
+    flag_machines: u16,
+    flag_slice: u64,
+    flag_max_ticks: Option<u64>,
+    flag_max_host_ms: Option<u64>,
+    flag_max_hwi: Option<u64>,
+    arg_file: Option<String>,
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let rom = utils::IterU16 { input: utils::get_input(args.arg_file) }.collect::<Vec<_>>();
+    let limits = Limits {
+        slice: args.flag_slice,
+        max_ticks: args.flag_max_ticks,
+        max_host_time: args.flag_max_host_ms.map(Duration::from_millis),
+        max_interrupts: args.flag_max_hwi,
+    };
+
+    let mut server = Server::new();
+    for i in 0..args.flag_machines {
+        let port = args.flag_port.wrapping_add(i);
+        let backend = match TcpListener::bind(("0.0.0.0", port))
+                                        .and_then(TcpBackend::new) {
+            Ok(backend) => backend,
+            Err(e) => die!(1, "Can't listen on port {}: {}", port, e),
+        };
+        let mut cpu = Cpu::default();
+        cpu.load(&rom, 0);
+        let mut computer = Computer::new(cpu);
+        computer.add_device(Box::new(Serial::new(Box::new(backend))));
+        server.add(computer, limits);
+    }
+
+    while server.running() != 0 {
+        let (stopped, busy) = server.round();
+        for i in stopped {
+            let ticks = server.computer(i).current_tick();
+            match *server.state(i) {
+                State::Failed(ref e) => println!("computer {}: {} after {} ticks", i, e, ticks),
+                State::OutOfTicks => println!("computer {}: out of ticks", i),
+                State::OverQuota(Quota::HostTime) => {
+                    println!("computer {}: too slow to emulate after {} ticks", i, ticks)
+                }
+                State::OverQuota(Quota::Interrupts) => {
+                    println!("computer {}: too many HWI after {} ticks", i, ticks)
+                }
+                State::Running => unreachable!(),
+            }
+        }
+        if !busy {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
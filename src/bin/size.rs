@@ -0,0 +1,113 @@
+extern crate byteorder;
+extern crate dcpu;
+extern crate docopt;
+extern crate rustc_serialize;
+extern crate simplelog;
+
+#[macro_use]
+mod utils;
+
+use std::io::{Read, Write};
+
+use docopt::Docopt;
+
+use dcpu::size;
+use dcpu::symbols::Symbols;
+use dcpu::types::{ParseError, Region};
+
+const USAGE: &'static str = "
+Usage:
+  size [--regions <file>] <symbols> <bin> [--diff <old-symbols> <old-bin>]
+  size (--help | --version)
+
+Options:
+  --regions <file>  Code regions of the binary (see assembler --regions), to
+                    also show the words of code and of data.
+  <symbols>         Symbols of the binary (see assembler --symbols).
+  <bin>             Binary written by the assembler.
+  --diff            Show the changes from a previous build instead.
+  -h --help         Show this screen.
+  --version         Show version.
+";
+
+#[derive(Debug, RustcDecodable)]
+struct Args {
+    flag_regions: Option<String>,
+    arg_symbols: String,
+    arg_bin: String,
+    flag_diff: bool,
+    arg_old_symbols: Option<String>,
+    arg_old_bin: Option<String>,
+}
+
+fn read_symbols(path: &str) -> Result<Symbols, ParseError> {
+    let mut text = String::new();
+    utils::get_input(Some(path.into())).read_to_string(&mut text).unwrap();
+    text.parse()
+}
+
+fn bin_len(path: &str) -> usize {
+    utils::IterU16 { input: utils::get_input(Some(path.into())) }.count()
+}
+
+fn main_ret() -> i32 {
+    simplelog::TermLogger::init(simplelog::LogLevelFilter::Info).unwrap();
+
+    let args: Args = Docopt::new(USAGE)
+                            .and_then(|d| d.decode())
+                            .unwrap_or_else(|e| e.exit());
+
+    let len = bin_len(&args.arg_bin);
+    let symbols = match read_symbols(&args.arg_symbols) {
+        Ok(symbols) => symbols,
+        Err(e) => die!(1, "Invalid symbols {}: {:?}", args.arg_symbols, e),
+    };
+    let sizes = size::symbol_sizes(&symbols, len);
+    let sections = match args.flag_regions {
+        Some(path) => {
+            let mut text = String::new();
+            utils::get_input(Some(path.clone())).read_to_string(&mut text).unwrap();
+            let regions = text.lines().map(|l| l.parse()).collect::<Result<Vec<Region>, _>>();
+            let regions = match regions {
+                Ok(regions) => regions,
+                Err(e) => die!(1, "Invalid regions {}: {:?}", path, e),
+            };
+            size::section_sizes(&regions, len)
+        }
+        None => vec![],
+    };
+
+    let mut output = std::io::stdout();
+    match (args.flag_diff, args.arg_old_symbols, args.arg_old_bin) {
+        (true, Some(old_symbols), Some(old_bin)) => {
+            let old_len = bin_len(&old_bin);
+            let old = match read_symbols(&old_symbols) {
+                Ok(symbols) => symbols,
+                Err(e) => die!(1, "Invalid symbols {}: {:?}", old_symbols, e),
+            };
+            let old_sizes = size::symbol_sizes(&old, old_len);
+            for (name, change) in size::diff(&old_sizes, &sizes) {
+                writeln!(output, "{:+7} {}", change, name).unwrap();
+            }
+            writeln!(output, "{:+7} total", len as i64 - old_len as i64).unwrap();
+        }
+        _ => {
+            for &(ref name, words) in sections.iter() {
+                writeln!(output, "{:7} {}", words, name).unwrap();
+            }
+            if !sections.is_empty() {
+                writeln!(output, "").unwrap();
+            }
+            for (name, words) in sizes {
+                writeln!(output, "{:7} {}", words, name).unwrap();
+            }
+            writeln!(output, "{:7} total, {} free", len, 0x10000 - len.min(0x10000)).unwrap();
+        }
+    }
+
+    0
+}
+
+fn main() {
+    std::process::exit(main_ret());
+}
@@ -4,6 +4,13 @@ use std::io::{Read, Write};
 use byteorder;
 use byteorder::ReadBytesExt;
 
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, RustcDecodable)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct IterU16<I> {
@@ -0,0 +1,302 @@
+//! Caches of decoded instructions: single instructions, see
+//! `Cpu::decode_cache`, and basic blocks, see `Cpu::blocks`.
+//!
+//! A block is a run of instructions decoded once, the first time its start is
+//! executed, and executed by `Cpu::tick` in one go. It ends with the first
+//! instruction which may jump, skip the next one, or interact with the
+//! interrupts or the devices. The words of a block are kept to check it
+//! against the memory before each run, so writes by the program or by the
+//! devices invalidate it.
+//!
+//! Each instruction of a block is decoded into a `Step` holding the handler
+//! of its opcode, see `cpu::handler`, so running a block calls the handlers
+//! one after the other without matching the opcodes again.
+
+use std::cmp;
+
+use cpu::{self, Handler};
+use types::{DecodeError, Instruction, SpecialOp, Value};
+
+/// Most instructions in a block.
+pub const MAX_BLOCK_LEN: usize = 32;
+
+/// Instruction of a block, ready to run.
+#[derive(Debug, Copy, Clone)]
+pub struct Step {
+    pub size: u16,
+    pub instruction: Instruction,
+    /// Cycles it takes, at least 1 as when run one by one.
+    pub cycles: u16,
+    /// Whether it may write to the memory, and thus to the block.
+    pub writes_memory: bool,
+    pub handler: Handler,
+    /// Operands given to `handler`: b then a, or a twice for the special
+    /// opcodes.
+    pub operands: (Value, Value),
+}
+
+impl Step {
+    pub fn new(size: u16, instruction: Instruction) -> Step {
+        let operands = match instruction {
+            Instruction::BasicOp(_, b, a) => (b, a),
+            Instruction::SpecialOp(_, a) => (a, a),
+        };
+        Step {
+            size: size,
+            instruction: instruction,
+            cycles: cmp::max(instruction.cycles(size), 1),
+            writes_memory: writes_memory(&instruction),
+            handler: cpu::handler(&instruction),
+            operands: operands,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Memory the instructions were decoded from.
+    pub words: Vec<u16>,
+    pub steps: Vec<Step>,
+}
+
+impl Block {
+    /// Decodes the block starting at `start`, `None` if its first
+    /// instruction is invalid.
+    pub fn decode(ram: &[u16; 0x10000], start: u16) -> Option<Block> {
+        let mut block = Block {
+            words: vec![],
+            steps: vec![],
+        };
+        let mut addr = start;
+        while block.steps.len() < MAX_BLOCK_LEN {
+            let words = [ram[addr as usize],
+                         ram[addr.wrapping_add(1) as usize],
+                         ram[addr.wrapping_add(2) as usize]];
+            let (size, i) = match Instruction::decode(&words) {
+                Ok(res) => res,
+                Err(_) => break,
+            };
+            // Doesn't wrap past 0xffff, so the block is contiguous.
+            if addr as usize + size as usize > 0x10000 {
+                break;
+            }
+            block.words.extend(&words[..size as usize]);
+            block.steps.push(Step::new(size, i));
+            addr = addr.wrapping_add(size);
+            if ends_block(&i) {
+                break;
+            }
+        }
+        if block.steps.is_empty() {
+            None
+        } else {
+            Some(block)
+        }
+    }
+
+    /// Whether the memory at `start` still holds the block.
+    pub fn is_valid(&self, ram: &[u16; 0x10000], start: u16) -> bool {
+        let start = start as usize;
+        ram[start..start + self.words.len()] == self.words[..]
+    }
+}
+
+/// Whether the instruction following `i` may not be the next one executed.
+pub fn ends_block(i: &Instruction) -> bool {
+    match *i {
+        Instruction::BasicOp(op, b, _) => op.is_if() || b == Value::PC,
+        Instruction::SpecialOp(SpecialOp::IAG, a) |
+        Instruction::SpecialOp(SpecialOp::HWN, a) => a == Value::PC,
+        Instruction::SpecialOp(SpecialOp::LOG, _) |
+        Instruction::SpecialOp(SpecialOp::BRK, _) => false,
+        Instruction::SpecialOp(_, _) => true,
+    }
+}
+
+/// Whether `i` may write to the memory.
+pub fn writes_memory(i: &Instruction) -> bool {
+    let target = match *i {
+        Instruction::BasicOp(op, _, _) if op.is_if() => return false,
+        Instruction::BasicOp(_, b, _) => b,
+        Instruction::SpecialOp(SpecialOp::IAG, a) |
+        Instruction::SpecialOp(SpecialOp::HWN, a) => a,
+        Instruction::SpecialOp(_, _) => return false,
+    };
+    match target {
+        Value::AtReg(_) |
+        Value::AtRegPlus(_, _) |
+        Value::Push |
+        Value::Peek |
+        Value::Pick(_) |
+        Value::AtAddr(_) => true,
+        _ => false,
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Decoded {
+    words: [u16; 3],
+    size: u16,
+    instruction: Instruction,
+}
+
+impl Decoded {
+    fn is_valid(&self, ram: &[u16; 0x10000], addr: u16) -> bool {
+        (0..self.size).all(|i| ram[addr.wrapping_add(i) as usize] == self.words[i as usize])
+    }
+}
+
+/// Instructions by address. Each one keeps the words it was decoded from,
+/// so writes to them, by the program or by the devices, invalidate it
+/// without `Cpu` having to watch the memory.
+#[derive(Debug, Default, Clone)]
+pub struct DecodeCache {
+    /// One per address once used, empty before.
+    entries: Vec<Option<Decoded>>,
+    /// Instructions taken from the cache.
+    pub hits: u64,
+    /// Instructions decoded, the first time or after an invalidation.
+    pub misses: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache::default()
+    }
+
+    /// Instruction at `addr` and its size in words, like `Cpu::decode`.
+    pub fn get(&mut self,
+               ram: &[u16; 0x10000],
+               addr: u16)
+               -> Result<(u16, Instruction), DecodeError> {
+        if self.entries.is_empty() {
+            self.entries = vec![None; 0x10000];
+        }
+        if let Some(decoded) = self.entries[addr as usize] {
+            if decoded.is_valid(ram, addr) {
+                self.hits += 1;
+                return Ok((decoded.size, decoded.instruction));
+            }
+        }
+        self.misses += 1;
+        let words = [ram[addr as usize],
+                     ram[addr.wrapping_add(1) as usize],
+                     ram[addr.wrapping_add(2) as usize]];
+        let (size, instruction) = try!(Instruction::decode(&words));
+        self.entries[addr as usize] = Some(Decoded {
+            words: words,
+            size: size,
+            instruction: instruction,
+        });
+        Ok((size, instruction))
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Blocks by start address.
+#[derive(Debug, Default, Clone)]
+pub struct BlockCache {
+    /// One per address once used, empty before, so finding the block at PC
+    /// is a single index.
+    blocks: Vec<Option<Block>>,
+    len: usize,
+    /// Blocks run from the cache.
+    pub hits: u64,
+    /// Blocks decoded, the first time or after an invalidation.
+    pub misses: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache::default()
+    }
+
+    /// Valid block starting at `start`, decoding it if needed.
+    pub fn get(&mut self, ram: &[u16; 0x10000], start: u16) -> Option<&Block> {
+        if self.blocks.is_empty() {
+            self.blocks = vec![None; 0x10000];
+        }
+        let entry = &mut self.blocks[start as usize];
+        let valid = entry.as_ref().map_or(false, |b| b.is_valid(ram, start));
+        if valid {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+            let block = Block::decode(ram, start);
+            match (entry.is_some(), block.is_some()) {
+                (false, true) => self.len += 1,
+                (true, false) => self.len -= 1,
+                _ => (),
+            }
+            *entry = block;
+        }
+        entry.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode() {
+    use encodings::*;
+    use types::{BasicOp, Register};
+
+    let mut ram = [0; 0x10000];
+    let code = [basic(BasicOp::SET, reg(Register::A), lit(3)),
+                basic(BasicOp::ADD, reg(Register::A), lit(2)),
+                basic(BasicOp::IFE, reg(Register::A), lit(5)),
+                special(SpecialOp::HLT, lit(0))];
+    ram[4..8].copy_from_slice(&code);
+
+    let mut cache = BlockCache::new();
+    assert_eq!(cache.get(&ram, 4).unwrap().steps.len(), 3);
+    assert_eq!(cache.get(&ram, 7).unwrap().steps.len(), 1);
+    cache.get(&ram, 4);
+    assert_eq!((cache.hits, cache.misses), (1, 2));
+
+    ram[5] = special(SpecialOp::HLT, lit(0));
+    assert_eq!(cache.get(&ram, 4).unwrap().steps.len(), 2);
+    assert_eq!(cache.misses, 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_cache() {
+    use encodings::*;
+    use types::{BasicOp, Register};
+
+    let mut ram = [0; 0x10000];
+    ram[0xffff] = basic(BasicOp::SET, reg(Register::A), NEXT);
+    ram[0] = 0x1234;
+
+    let mut cache = DecodeCache::new();
+    let set = Instruction::BasicOp(BasicOp::SET, Value::Reg(Register::A), Value::Litteral(0x1234));
+    assert_eq!(cache.get(&ram, 0xffff), Ok((2, set)));
+    assert_eq!(cache.get(&ram, 0xffff), Ok((2, set)));
+    assert_eq!((cache.hits, cache.misses), (1, 1));
+
+    // The next word, wrapped, is part of the instruction.
+    ram[0] = 0x4321;
+    let set = Instruction::BasicOp(BasicOp::SET, Value::Reg(Register::A), Value::Litteral(0x4321));
+    assert_eq!(cache.get(&ram, 0xffff), Ok((2, set)));
+    assert_eq!(cache.misses, 2);
+
+    ram[0xffff] = 0;
+    assert!(cache.get(&ram, 0xffff).is_err());
+    assert_eq!(cache.misses, 3);
+}
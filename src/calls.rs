@@ -0,0 +1,90 @@
+//! Routines being executed, see `Cpu::calls`.
+//!
+//! The calls follow the `JSR`s: a routine returns when SP goes above the
+//! return address its `JSR` pushed, whichever instruction pops it, so
+//! `SET PC, POP` and `ADD SP, 1` followed by a jump both return.
+
+use types::{Instruction, SpecialOp};
+
+/// Routine called by a `JSR`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub entry: u16,
+    /// Address of the return address.
+    pub sp: u16,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    /// Updates the routines after an instruction, after which PC and SP are
+    /// `new_pc` and `sp`. Returns whether they changed.
+    pub fn record(&mut self, instruction: Instruction, new_pc: u16, sp: u16) -> bool {
+        let depth = self.frames.len();
+        while self.frames.last().map_or(false, |f| sp > f.sp) {
+            self.frames.pop();
+        }
+        match instruction {
+            Instruction::SpecialOp(SpecialOp::JSR, _) => {
+                self.frames.push(Frame {
+                    entry: new_pc,
+                    sp: sp,
+                });
+                true
+            }
+            _ => self.frames.len() != depth,
+        }
+    }
+
+    /// Routines called, the outermost first.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_calls() {
+    use cpu::{Cpu, CpuState};
+    use encodings::*;
+    use types::*;
+
+    // main calls outer, which calls inner, which returns with ADD SP, 1 and
+    // a jump.
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::JSR, lit(4)),
+               special(SpecialOp::HLT, lit(0)),
+               0,
+               0,
+               special(SpecialOp::JSR, lit(7)),
+               basic(BasicOp::SET, PC, PUSH_POP),
+               0,
+               basic(BasicOp::ADD, SP, lit(1)),
+               basic(BasicOp::SET, PC, lit(5))],
+             0);
+    cpu.calls = Some(CallStack::new());
+    let mut depths = vec![];
+    while let Ok(state) = cpu.tick(&mut []) {
+        if let CpuState::Executing = state {
+            depths.push(cpu.calls.as_ref().unwrap().frames().len());
+        }
+    }
+    assert_eq!(depths, [1, 2, 1, 1, 0]);
+
+    let mut calls = CallStack::new();
+    let push = Instruction::BasicOp(BasicOp::SET, Value::Push, Value::Litteral(1));
+    assert!(calls.record(Instruction::SpecialOp(SpecialOp::JSR, Value::Litteral(4)), 4, 0xfffe));
+    assert!(!calls.record(push, 5, 0xfffd));
+    assert_eq!(calls.frames(),
+               [Frame {
+                    entry: 4,
+                    sp: 0xfffe,
+                }]);
+}
@@ -1,11 +1,159 @@
-use cpu;
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+use cpu::{self, CpuState};
 use device::*;
+use timebase::Timebase;
+use types::{Instruction, Region, SpecialOp};
+
+/// Device as seen by `HWQ`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescription {
+    /// Index given to `HWQ` and `HWI`.
+    pub index: u16,
+    pub name: String,
+    pub hardware_id: u32,
+    pub version: u16,
+    pub manufacturer: u32,
+}
+
+/// State of a computer, for the user to check its configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    pub ticks_per_second: u64,
+    pub current_tick: u64,
+    pub devices: Vec<DeviceDescription>,
+    /// Runs of memory neither 0 nor `cpu::UNINITIALIZED`, usually the
+    /// loaded program and its data.
+    pub segments: Vec<Region>,
+}
+
+impl fmt::Display for Description {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f,
+                      "DCPU-16 1.7 at {} kHz, tick {}",
+                      self.ticks_per_second / 1000,
+                      self.current_tick));
+        try!(writeln!(f, "{} devices:", self.devices.len()));
+        for d in self.devices.iter() {
+            try!(writeln!(f,
+                          "  {}: {} (id 0x{:08x}, version 0x{:04x}, manufacturer 0x{:08x})",
+                          d.index,
+                          d.name,
+                          d.hardware_id,
+                          d.version,
+                          d.manufacturer));
+        }
+        try!(writeln!(f, "{} memory segments:", self.segments.len()));
+        for s in self.segments.iter() {
+            try!(writeln!(f, "  {} ({} words)", s, s.last as u32 - s.first as u32 + 1));
+        }
+        Ok(())
+    }
+}
+
+/// Start of the files written by `Computer::save_state`.
+const STATE_MAGIC: &'static [u8; 4] = b"DCPU";
+
+/// Version of the format of `Computer::save_state`, increased when it
+/// changes.
+pub const STATE_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    /// Not a state file.
+    Format,
+    /// Version of the format, newer than `STATE_VERSION`.
+    Version(u16),
+    /// The computer doesn't have the devices of the state, or the state of
+    /// the device at this index is malformed.
+    Device(u16),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref e) => write!(f, "{}", e),
+            StateError::Format => write!(f, "not a state file"),
+            StateError::Version(v) => write!(f, "unsupported state version {}", v),
+            StateError::Device(i) => write!(f, "device {} doesn't match the state", i),
+        }
+    }
+}
+
+impl error::Error for StateError {
+    fn description(&self) -> &str {
+        match *self {
+            StateError::Io(ref e) => e.description(),
+            StateError::Format => "not a state file",
+            StateError::Version(_) => "unsupported state version",
+            StateError::Device(_) => "device not matching the state",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            StateError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> StateError {
+        StateError::Io(e)
+    }
+}
+
+fn write_words<W: Write>(w: &mut W, words: &[u16]) -> io::Result<()> {
+    let bytes = words.iter().flat_map(|&w| vec![(w >> 8) as u8, w as u8]).collect::<Vec<_>>();
+    w.write_all(&bytes)
+}
+
+fn read_words<R: Read>(r: &mut R, len: usize) -> io::Result<Vec<u16>> {
+    let mut bytes = vec![0; 2 * len];
+    try!(r.read_exact(&mut bytes));
+    Ok(bytes.chunks(2).map(|c| (c[0] as u16) << 8 | c[1] as u16).collect())
+}
+
+/// A length followed by the words.
+fn write_list<W: Write>(w: &mut W, words: &[u16]) -> io::Result<()> {
+    try!(write_words(w, &[words.len() as u16]));
+    write_words(w, words)
+}
+
+fn read_list<R: Read>(r: &mut R) -> io::Result<Vec<u16>> {
+    let len = try!(read_words(r, 1))[0];
+    read_words(r, len as usize)
+}
+
+/// Whether `d` is an `Empty` slot, or reports null ids like one.
+fn is_empty(d: &Device) -> bool {
+    d.hardware_id() == 0 && d.manufacturer() == 0
+}
 
+/// A CPU and its devices.
+///
+/// A device's hardware index, used by `HWQ` and `HWI`, is its position in
+/// the order they were added, unless set with `set_device`. Devices can be
+/// plugged and unplugged while the computer runs with `plug_device` and
+/// `unplug_device`, which update what `HWN` counts.
+///
+/// Everything runs synchronously on the thread calling `tick`, in a fixed
+/// order: the CPU first, then each device in the order they were added.
+/// Given the same program, devices and inputs, execution is thus fully
+/// deterministic and can be single-stepped from a host debugger.
 #[derive(Default)]
 pub struct Computer {
     cpu: cpu::Cpu,
     devices: Vec<Box<Device>>,
     current_tick: u64,
+    timebase: Timebase,
+    hotplug_interrupt: Option<u16>,
 }
 
 impl Computer {
@@ -16,22 +164,648 @@ impl Computer {
         }
     }
 
+    pub fn cpu(&self) -> &cpu::Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut cpu::Cpu {
+        &mut self.cpu
+    }
+
+    pub fn current_tick(&self) -> u64 {
+        self.current_tick
+    }
+
+    pub fn timebase(&self) -> Timebase {
+        self.timebase
+    }
+
+    pub fn set_timebase(&mut self, timebase: Timebase) {
+        self.timebase = timebase;
+    }
+
+    /// Adds a device after the others.
     pub fn add_device(&mut self, d: Box<Device>) {
         self.devices.push(d);
     }
 
-    pub fn tick(&mut self) -> Result<(), cpu::Error> {
-        try!(self.cpu.tick(&mut self.devices));
+    /// Puts a device at the hardware index `index`, filling the slots
+    /// before it with `Empty` if needed, and returns the previous one.
+    ///
+    /// Programs often hard-code the index of their devices, so the indices
+    /// only change when asked.
+    pub fn set_device(&mut self, index: u16, d: Box<Device>) -> Option<Box<Device>> {
+        let index = index as usize;
+        if index < self.devices.len() {
+            Some(mem::replace(&mut self.devices[index], d))
+        } else {
+            while self.devices.len() < index {
+                self.devices.push(Box::new(Empty));
+            }
+            self.devices.push(d);
+            None
+        }
+    }
+
+    /// Replaces the device at `index` by `Empty`, so the indices of the
+    /// next ones don't change.
+    pub fn remove_device(&mut self, index: u16) -> Option<Box<Device>> {
+        if (index as usize) < self.devices.len() {
+            self.set_device(index, Box::new(Empty))
+        } else {
+            None
+        }
+    }
+
+    /// Devices by hardware index.
+    pub fn devices(&self) -> &[Box<Device>] {
+        &self.devices
+    }
+
+    /// Interrupt message queued when a device is plugged or unplugged, so
+    /// the program can enumerate them again. None by default.
+    pub fn set_hotplug_interrupt(&mut self, message: Option<u16>) {
+        self.hotplug_interrupt = message;
+    }
+
+    /// Plugs a device in the first empty slot, or after the others, and
+    /// returns its hardware index.
+    pub fn plug_device(&mut self, d: Box<Device>) -> u16 {
+        let index = self.devices.iter().position(|d| is_empty(&**d)).unwrap_or(self.devices.len());
+        self.set_device(index as u16, d);
+        self.notify_hotplug();
+        index as u16
+    }
+
+    /// Unplugs the device at `index`, leaving an `Empty` slot so the indices
+    /// of the next ones don't change. The empty slots at the end are
+    /// dropped, so `HWN` counts one device less when it was the last one.
+    pub fn unplug_device(&mut self, index: u16) -> Option<Box<Device>> {
+        let device = self.remove_device(index);
+        while self.devices.last().map_or(false, |d| is_empty(&**d)) {
+            self.devices.pop();
+        }
+        if device.is_some() {
+            self.notify_hotplug();
+        }
+        device
+    }
+
+    /// The interrupt is dropped if the queue is full, the CPU catching
+    /// fire only on interrupts from the program or the devices.
+    fn notify_hotplug(&mut self) {
+        if let Some(message) = self.hotplug_interrupt {
+            let _ = self.cpu.queue_interrupt(message);
+        }
+    }
+
+    /// Runs one cycle of the CPU then ticks the devices, so the devices
+    /// see `current_tick` count the cycles of the instructions executed, at
+    /// `timebase().ticks_per_second`.
+    ///
+    /// Stopping at a breakpoint takes no tick, the devices only tick once
+    /// the instruction executes.
+    pub fn tick(&mut self) -> Result<CpuState, cpu::Error> {
+        let state = try!(self.cpu.tick(&mut self.devices));
+        if let CpuState::HitBreakpoint(_) = state {
+            return Ok(state);
+        }
 
         for device in self.devices.iter_mut() {
             match device.tick(&mut self.cpu, self.current_tick) {
                 TickResult::Nothing => (),
-                TickResult::Interrupt(msg) =>
-                    self.cpu.trigger_interrupt(msg),
+                TickResult::Interrupt(msg) => try!(self.cpu.queue_interrupt(msg)),
             }
         }
 
         self.current_tick += 1;
+        Ok(state)
+    }
+
+    /// Ticks until the CPU is done with the current instruction, waiting
+    /// for its cycles, and returns the number of ticks. A sleeping CPU
+    /// only ticks once. Breakpoints and watchpoints don't stop it.
+    pub fn step(&mut self) -> Result<u64, cpu::Error> {
+        let start = self.current_tick;
+        if let CpuState::HitBreakpoint(_) = try!(self.tick()) {
+            try!(self.tick());
+        }
+        while self.cpu.wait != 0 {
+            try!(self.tick());
+        }
+        Ok(self.current_tick - start)
+    }
+
+    /// Steps over the instruction at PC: if it is a `JSR`, also runs the
+    /// routine until it returns, SP going back above its return address.
+    ///
+    /// Returns `CpuState::Executing` once done, the state of the breakpoint
+    /// or watchpoint that stopped it in the routine, or `None` if it still
+    /// runs after `max_ticks`.
+    pub fn step_over(&mut self, max_ticks: u64) -> Result<Option<CpuState>, cpu::Error> {
+        let is_call = match self.cpu.decode(self.cpu.pc) {
+            Ok((_, Instruction::SpecialOp(SpecialOp::JSR, _))) => true,
+            _ => false,
+        };
+        let ticks = try!(self.step());
+        if !is_call {
+            return Ok(Some(CpuState::Executing));
+        }
+        let sp = self.cpu.sp;
+        self.run_until_return(sp, max_ticks.saturating_sub(ticks))
+    }
+
+    /// Runs until the innermost routine of `Cpu::calls` returns, like
+    /// `step_over`. Does nothing if no routine was called, or if the calls
+    /// are not tracked.
+    pub fn step_out(&mut self, max_ticks: u64) -> Result<Option<CpuState>, cpu::Error> {
+        match self.cpu.calls.as_ref().and_then(|c| c.frames().last()).map(|f| f.sp) {
+            Some(sp) => self.run_until_return(sp, max_ticks),
+            None => Ok(Some(CpuState::Executing)),
+        }
+    }
+
+    /// Ticks until SP is above `sp` and the CPU is done with the current
+    /// instruction. Doesn't stop at a breakpoint at PC.
+    fn run_until_return(&mut self,
+                        sp: u16,
+                        max_ticks: u64)
+                        -> Result<Option<CpuState>, cpu::Error> {
+        let start = self.current_tick;
+        let mut first = true;
+        while self.current_tick - start < max_ticks {
+            if self.cpu.sp > sp && self.cpu.wait == 0 {
+                return Ok(Some(CpuState::Executing));
+            }
+            match try!(self.tick()) {
+                CpuState::HitBreakpoint(_) if first => (),
+                state @ CpuState::HitBreakpoint(_) => return Ok(Some(state)),
+                state @ CpuState::HitWatchpoint(_) => {
+                    while self.cpu.wait != 0 {
+                        try!(self.tick());
+                    }
+                    return Ok(Some(state));
+                }
+                _ => (),
+            }
+            first = false;
+        }
+        Ok(None)
+    }
+
+    pub fn describe(&self) -> Description {
+        let devices = self.devices
+                          .iter()
+                          .enumerate()
+                          .map(|(i, d)| {
+                              DeviceDescription {
+                                  index: i as u16,
+                                  name: d.name().into(),
+                                  hardware_id: d.hardware_id(),
+                                  version: d.hardware_version(),
+                                  manufacturer: d.manufacturer(),
+                              }
+                          })
+                          .collect();
+        let mut segments: Vec<Region> = vec![];
+        for (addr, &w) in self.cpu.ram.iter().enumerate() {
+            if w == 0 || w == cpu::UNINITIALIZED {
+                continue;
+            }
+            let addr = addr as u16;
+            match segments.last_mut() {
+                Some(ref mut s) if s.last + 1 == addr => s.last = addr,
+                _ => {
+                    segments.push(Region {
+                        first: addr,
+                        last: addr,
+                    })
+                }
+            }
+        }
+        Description {
+            ticks_per_second: self.timebase.ticks_per_second,
+            current_tick: self.current_tick,
+            devices: devices,
+            segments: segments,
+        }
+    }
+
+    /// Writes the registers, the memory, the interrupt queue and the state
+    /// of the devices, see `Device::save_state`, to resume later with
+    /// `load_state`. The configuration of the CPU, like `trap_pc_wrap`, and
+    /// the timebase aren't saved.
+    ///
+    /// The format is `STATE_MAGIC`, `STATE_VERSION`, then big endian words.
+    pub fn save_state<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let cpu = &self.cpu;
+        try!(w.write_all(STATE_MAGIC));
+        try!(write_words(w, &[STATE_VERSION]));
+        let mut words = cpu.registers.to_vec();
+        words.extend(&[cpu.pc, cpu.sp, cpu.ex, cpu.ia, cpu.wait]);
+        let flags = [cpu.check_if_cascade, cpu.is_queue_enabled, cpu.halted, cpu.sleeping];
+        words.push(flags.iter().enumerate().fold(0, |acc, (i, &f)| acc | (f as u16) << i));
+        push_u64(&mut words, cpu.hardware_interrupts);
+        push_u64(&mut words, self.current_tick);
+        try!(write_words(w, &words));
+        try!(write_list(w, &cpu.interrupts_queue.iter().cloned().collect::<Vec<_>>()));
+        try!(write_list(w, &cpu.log_queue.iter().cloned().collect::<Vec<_>>()));
+        try!(write_words(w, &cpu.ram[..]));
+        try!(write_words(w, &[self.devices.len() as u16]));
+        for d in self.devices.iter() {
+            let id = d.hardware_id();
+            try!(write_words(w, &[(id >> 16) as u16, id as u16]));
+            try!(write_list(w, &d.save_state()));
+        }
+        w.flush()
+    }
+
+    /// Restores what `save_state` wrote. The computer must have the same
+    /// devices, in the same order. On error, some devices may already be
+    /// restored.
+    pub fn load_state<R: Read>(&mut self, r: &mut R) -> Result<(), StateError> {
+        let mut magic = [0; 4];
+        try!(r.read_exact(&mut magic));
+        if &magic != STATE_MAGIC {
+            return Err(StateError::Format);
+        }
+        let version = try!(read_words(r, 1))[0];
+        if version > STATE_VERSION {
+            return Err(StateError::Version(version));
+        }
+        let words = try!(read_words(r, 22));
+        let interrupts = try!(read_list(r));
+        let log = try!(read_list(r));
+        let ram = try!(read_words(r, 0x10000));
+        let devices = try!(read_words(r, 1))[0];
+        if devices as usize != self.devices.len() {
+            return Err(StateError::Device(devices));
+        }
+        for (i, d) in self.devices.iter_mut().enumerate() {
+            let id = try!(read_words(r, 2));
+            let state = try!(read_list(r));
+            if (id[0] as u32) << 16 | id[1] as u32 != d.hardware_id() ||
+               d.load_state(&state).is_err() {
+                return Err(StateError::Device(i as u16));
+            }
+        }
+
+        let cpu = &mut self.cpu;
+        cpu.registers.copy_from_slice(&words[..8]);
+        cpu.pc = words[8];
+        cpu.sp = words[9];
+        cpu.ex = words[10];
+        cpu.ia = words[11];
+        cpu.wait = words[12];
+        let flag = |i: usize| words[13] & 1 << i != 0;
+        cpu.check_if_cascade = flag(0);
+        cpu.is_queue_enabled = flag(1);
+        cpu.halted = flag(2);
+        cpu.sleeping = flag(3);
+        cpu.hardware_interrupts = read_u64(&words[14..18]);
+        self.current_tick = read_u64(&words[18..22]);
+        cpu.interrupts_queue = interrupts.into_iter().collect();
+        cpu.log_queue = log.into_iter().collect();
+        cpu.ram.copy_from_slice(&ram);
         Ok(())
     }
+
+    /// Whether the CPU sleeps, waiting for an interrupt.
+    pub fn is_sleeping(&self) -> bool {
+        self.cpu.sleeping && self.cpu.wait == 0
+    }
+
+    /// While the CPU sleeps, jumps to the first tick at which a device may
+    /// wake it up and returns the number of ticks skipped, 0 if the CPU is
+    /// awake or a device can't tell.
+    ///
+    /// Fails with `Error::Asleep` if no device will ever wake the CPU up.
+    pub fn skip_idle(&mut self) -> Result<u64, cpu::Error> {
+        self.skip_idle_max(u64::max_value())
+    }
+
+    /// Same as `skip_idle`, but skips at most `max_ticks`.
+    pub fn skip_idle_max(&mut self, max_ticks: u64) -> Result<u64, cpu::Error> {
+        if !self.is_sleeping() || !self.cpu.interrupts_queue.is_empty() {
+            return Ok(0);
+        }
+        let current_tick = self.current_tick;
+        let next = self.devices
+                       .iter()
+                       .filter_map(|d| d.next_interrupt(current_tick))
+                       .min();
+        match next {
+            Some(tick) => {
+                let skipped = cmp::min(tick - current_tick, max_ticks);
+                self.current_tick += skipped;
+                Ok(skipped)
+            }
+            None => Err(cpu::Error::Asleep),
+        }
+    }
+
+    /// While the CPU waits for the cycles of an instruction, jumps over the
+    /// ticks where no device has anything to do, see `Device::next_tick`,
+    /// and returns the number of ticks skipped, at most `max_ticks`.
+    ///
+    /// The tick before the next instruction is never skipped, so the
+    /// computer ends up in the same state as with `tick` alone.
+    pub fn skip_waits(&mut self, max_ticks: u64) -> u64 {
+        if self.cpu.wait < 2 {
+            return 0;
+        }
+        let current_tick = self.current_tick;
+        let last_wait = current_tick + self.cpu.wait as u64 - 1;
+        let next = self.devices
+                       .iter()
+                       .filter_map(|d| d.next_tick(current_tick))
+                       .min()
+                       .unwrap_or(last_wait);
+        let skipped = cmp::min(cmp::min(last_wait, next).saturating_sub(current_tick), max_ticks);
+        self.cpu.wait -= skipped as u16;
+        self.current_tick += skipped;
+        skipped
+    }
+
+    /// Runs for `max_ticks` ticks, like as many `tick` but skipping the
+    /// waits with `skip_waits`, and returns the last state of the CPU.
+    /// Stops early at a breakpoint or a watchpoint.
+    ///
+    /// This is the fast path for running a program: with `Cpu::blocks`, the
+    /// CPU runs a whole block in one tick, then waits for its cycles, which
+    /// cost nothing when the devices are idle.
+    pub fn run(&mut self, max_ticks: u64) -> Result<CpuState, cpu::Error> {
+        let end = self.current_tick + max_ticks;
+        let mut state = CpuState::Executing;
+        while self.current_tick < end {
+            let left = end - self.current_tick;
+            if self.skip_waits(left) == left {
+                break;
+            }
+            state = try!(self.tick());
+            match state {
+                CpuState::HitBreakpoint(_) |
+                CpuState::HitWatchpoint(_) => break,
+                _ => (),
+            }
+        }
+        Ok(state)
+    }
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_describe() {
+    use device::clock::Clock;
+
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&[1, 2], 0x10);
+    computer.cpu_mut().load(&[3], 0x20);
+    computer.add_device(Box::new(Clock::new()));
+    let description = computer.describe();
+    assert_eq!(description.devices[0].name, "Generic Clock");
+    assert_eq!(description.devices[0].hardware_id, 0x12d0b402);
+    assert_eq!(description.segments,
+               vec![Region {
+                        first: 0x10,
+                        last: 0x11,
+                    },
+                    Region {
+                        first: 0x20,
+                        last: 0x20,
+                    }]);
+    assert!(description.to_string().contains("  0: Generic Clock (id 0x12d0b402"));
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_device_indices() {
+    use device::clock::Clock;
+
+    let names = |c: &Computer| {
+        c.describe().devices.iter().map(|d| d.name.clone()).collect::<Vec<_>>()
+    };
+    let mut computer = Computer::default();
+    assert!(computer.set_device(2, Box::new(Clock::new())).is_none());
+    assert_eq!(names(&computer), vec!["empty slot", "empty slot", "Generic Clock"]);
+    computer.add_device(Box::new(Clock::new()));
+    assert_eq!(computer.remove_device(2).unwrap().name(), "Generic Clock");
+    assert!(computer.remove_device(4).is_none());
+    assert_eq!(names(&computer),
+               vec!["empty slot", "empty slot", "empty slot", "Generic Clock"]);
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_hotplug() {
+    use device::clock::Clock;
+    use encodings::*;
+    use types::*;
+
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&[special(SpecialOp::HWN, reg(Register::A)),
+                              basic(BasicOp::SET, PC, lit(0))],
+                            0);
+    let hwn = |computer: &mut Computer| {
+        computer.cpu_mut().pc = 0;
+        computer.step().unwrap();
+        computer.cpu().registers[0]
+    };
+    computer.set_hotplug_interrupt(Some(0x42));
+    computer.cpu_mut().ia = 0x100;
+    computer.set_device(1, Box::new(Clock::new()));
+    assert_eq!(computer.plug_device(Box::new(Clock::new())), 0);
+    assert_eq!(computer.plug_device(Box::new(Clock::new())), 2);
+    assert_eq!(computer.cpu().interrupts_queue, [0x42, 0x42]);
+    // The queued interrupts are dropped when triggered with IA 0.
+    computer.cpu_mut().ia = 0;
+    assert_eq!(hwn(&mut computer), 3);
+
+    assert!(computer.unplug_device(2).is_some());
+    assert_eq!(hwn(&mut computer), 2);
+    assert!(computer.unplug_device(0).is_some());
+    assert_eq!(hwn(&mut computer), 2);
+    assert_eq!(computer.devices()[0].name(), "empty slot");
+    assert!(computer.unplug_device(1).is_some());
+    assert_eq!(hwn(&mut computer), 0);
+    assert!(computer.unplug_device(1).is_none());
+}
+
+#[cfg(test)]
+#[test]
+fn test_step() {
+    use encodings::*;
+    use types::*;
+
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&[basic(BasicOp::SET, reg(Register::A), lit(3)),
+                              basic(BasicOp::MUL, reg(Register::A), NEXT),
+                              1000,
+                              special(SpecialOp::HLT, lit(0))],
+                            0);
+    computer.cpu_mut().add_breakpoint(1);
+    assert_eq!(computer.step().unwrap(), 1);
+    assert_eq!(computer.cpu().pc, 1);
+    // Stopping at the breakpoint takes no tick, and step goes over it.
+    match computer.tick() {
+        Ok(CpuState::HitBreakpoint(1)) => (),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(computer.current_tick(), 1);
+    assert_eq!(computer.step().unwrap(), 3);
+    assert_eq!(computer.cpu().pc, 3);
+    assert_eq!(computer.cpu().registers[0], 3000);
+    assert_eq!(computer.current_tick(), 4);
+    match computer.step() {
+        Err(cpu::Error::Halted) => (),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_step_over() {
+    use calls::CallStack;
+    use encodings::*;
+    use types::*;
+
+    // main calls double, which calls add.
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&[basic(BasicOp::SET, reg(Register::A), lit(3)),
+                              special(SpecialOp::JSR, lit(4)),
+                              special(SpecialOp::HLT, lit(0)),
+                              0,
+                              special(SpecialOp::JSR, lit(6)),
+                              basic(BasicOp::SET, PC, PUSH_POP),
+                              basic(BasicOp::ADD, reg(Register::A), reg(Register::A)),
+                              basic(BasicOp::SET, PC, PUSH_POP)],
+                            0);
+    computer.cpu_mut().calls = Some(CallStack::new());
+    let step_over = |computer: &mut Computer| match computer.step_over(100) {
+        Ok(Some(CpuState::Executing)) => computer.cpu().pc,
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(step_over(&mut computer), 1);
+    assert_eq!(step_over(&mut computer), 2);
+    assert_eq!(computer.cpu().registers[0], 6);
+    assert_eq!(computer.cpu().sp, 0xffff);
+
+    // Stops at the breakpoint in add, then finishes add and double.
+    computer.cpu_mut().pc = 1;
+    computer.cpu_mut().add_breakpoint(6);
+    match computer.step_over(100) {
+        Ok(Some(CpuState::HitBreakpoint(6))) => (),
+        r => panic!("{:?}", r),
+    }
+    computer.step_out(100).unwrap();
+    assert_eq!(computer.cpu().pc, 5);
+    computer.step_out(100).unwrap();
+    assert_eq!(computer.cpu().pc, 2);
+    assert_eq!(computer.cpu().registers[0], 12);
+    computer.step_out(100).unwrap();
+    assert_eq!(computer.cpu().pc, 2);
+
+    computer.cpu_mut().pc = 1;
+    assert!(computer.step_over(3).unwrap().is_none());
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_state() {
+    use device::clock::Clock;
+    use encodings::*;
+    use types::*;
+
+    // Sets the clock to interrupt with 7 every 1/60 s, then waits.
+    let program = [basic(BasicOp::SET, reg(Register::A), lit(2)),
+                   basic(BasicOp::SET, reg(Register::B), lit(7)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::SET, reg(Register::A), lit(0)),
+                   basic(BasicOp::SET, reg(Register::B), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::ADD, reg(Register::C), lit(1)),
+                   basic(BasicOp::SET, PC, lit(6))];
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&program, 0);
+    computer.add_device(Box::new(Clock::new()));
+    for _ in 0..1000 {
+        computer.tick().unwrap();
+    }
+    computer.cpu_mut().interrupts_queue.push_back(3);
+    let mut state = vec![];
+    computer.save_state(&mut state).unwrap();
+    assert_eq!(&state[..6], b"DCPU\0\x01");
+
+    let mut restored = Computer::default();
+    restored.add_device(Box::new(Clock::new()));
+    restored.load_state(&mut &state[..]).unwrap();
+    assert_eq!(restored.current_tick(), 1000);
+    assert_eq!(restored.cpu().registers, computer.cpu().registers);
+    assert_eq!(&restored.cpu().ram[..], &computer.cpu().ram[..]);
+    assert_eq!(restored.cpu().interrupts_queue, computer.cpu().interrupts_queue);
+    assert_eq!(restored.devices[0].save_state(), computer.devices[0].save_state());
+    for _ in 0..5000 {
+        computer.tick().unwrap();
+        restored.tick().unwrap();
+    }
+    assert_eq!(restored.cpu().registers, computer.cpu().registers);
+
+    match Computer::default().load_state(&mut &state[..]) {
+        Err(StateError::Device(1)) => (),
+        r => panic!("{:?}", r),
+    }
+    match Computer::default().load_state(&mut &b"DCPU\xff\xff"[..]) {
+        Err(StateError::Version(0xffff)) => (),
+        r => panic!("{:?}", r),
+    }
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_run() {
+    use blocks::BlockCache;
+    use device::clock::Clock;
+    use encodings::*;
+    use types::*;
+
+    // Counts the clock interrupts in X while multiplying in a loop.
+    let program = [special(SpecialOp::IAS, lit(12)),
+                   basic(BasicOp::SET, reg(Register::A), lit(2)),
+                   basic(BasicOp::SET, reg(Register::B), lit(7)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::SET, reg(Register::A), lit(0)),
+                   basic(BasicOp::SET, reg(Register::B), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::MUL, reg(Register::C), lit(3)),
+                   basic(BasicOp::DIV, reg(Register::Y), lit(3)),
+                   basic(BasicOp::ADD, reg(Register::C), lit(1)),
+                   basic(BasicOp::SET, PC, lit(7)),
+                   0,
+                   basic(BasicOp::ADD, reg(Register::X), lit(1)),
+                   special(SpecialOp::RFI, lit(0))];
+    let computer = |blocks: bool| {
+        let mut computer = Computer::default();
+        computer.cpu_mut().load(&program, 0);
+        if blocks {
+            computer.cpu_mut().blocks = Some(Box::new(BlockCache::new()));
+        }
+        computer.add_device(Box::new(Clock::new()));
+        computer
+    };
+
+    let mut ticked = computer(false);
+    for _ in 0..20000 {
+        ticked.tick().unwrap();
+    }
+    let mut run = computer(false);
+    run.run(20000).unwrap();
+    assert_eq!(run.current_tick(), 20000);
+    assert_eq!(run.cpu().registers, ticked.cpu().registers);
+    assert_eq!(run.cpu().registers[Register::X as usize], 11);
+
+    // The interrupts only come between blocks.
+    let mut blocks = computer(true);
+    blocks.run(20000).unwrap();
+    assert_eq!(blocks.current_tick(), 20000);
+    assert_eq!(blocks.cpu().registers[Register::X as usize], 11);
 }
@@ -0,0 +1,460 @@
+//! Protocol to control a running computer from another process.
+//!
+//! There is no authentication: only listen on local addresses.
+//!
+//! Each message is a frame: its length in bytes as a big endian `u32`, then
+//! a tag byte and the fields of the message, every number being a big
+//! endian `u16`. The client sends a `Request` and the computer replies with
+//! a `Response`.
+//!
+//! | Request          | Tag | Fields          | Response        |
+//! |------------------|-----|-----------------|-----------------|
+//! | `Version`        | 0   |                 | `Version`       |
+//! | `Pause`          | 1   |                 | `Ok`            |
+//! | `Resume`         | 2   |                 | `Ok`            |
+//! | `Step`           | 3   | ticks           | `Ok`            |
+//! | `Registers`      | 4   |                 | `Registers`     |
+//! | `ReadMemory`     | 5   | address, length | `Memory`        |
+//! | `WriteMemory`    | 6   | address, words  | `Ok`            |
+//! | `Interrupt`      | 7   | message         | `Ok`            |
+//! | `Describe`       | 8   |                 | `Description`   |
+//!
+//! | Response      | Tag | Fields                                 |
+//! |---------------|-----|----------------------------------------|
+//! | `Ok`          | 0   |                                        |
+//! | `Version`     | 1   | `VERSION`                              |
+//! | `Registers`   | 2   | A, B, C, I, J, X, Y, Z, PC, SP, EX, IA |
+//! | `Memory`      | 3   | words                                  |
+//! | `Error`       | 4   | UTF-8 message                          |
+//! | `Description` | 5   | UTF-8 text of `Computer::describe`     |
+//!
+//! New requests get new tags and the existing ones never change, so
+//! clients keep working with newer emulators.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use computer::Computer;
+
+/// Version of the protocol, increased when requests are added.
+pub const VERSION: u16 = 2;
+
+/// Largest frame accepted, enough for the whole memory.
+const MAX_FRAME: u32 = 0x20010;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Version,
+    /// Stops running the computer until `Resume`.
+    Pause,
+    Resume,
+    /// Runs this many ticks while paused.
+    Step(u16),
+    Registers,
+    ReadMemory(u16, u16),
+    WriteMemory(u16, Vec<u16>),
+    /// Triggers a hardware interrupt with this message.
+    Interrupt(u16),
+    Describe,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Version(u16),
+    /// In the order of `types::Register`, then PC, SP, EX and IA.
+    Registers([u16; 12]),
+    Memory(Vec<u16>),
+    Error(String),
+    Description(String),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// Malformed frame.
+    Protocol,
+    /// `Response::Error` sent by the computer.
+    Remote(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::Protocol => write!(f, "malformed message"),
+            Error::Remote(ref e) => write!(f, "computer error: {}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(ref e) => e.description(),
+            Error::Protocol => "malformed message",
+            Error::Remote(_) => "computer error",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub fn write_frame<W: Write>(w: &mut W, payload: &[u8]) -> io::Result<()> {
+    let len = payload.len() as u32;
+    try!(w.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]));
+    try!(w.write_all(payload));
+    w.flush()
+}
+
+pub fn read_frame<R: Read>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let mut len = [0; 4];
+    try!(r.read_exact(&mut len));
+    let len = len.iter().fold(0, |acc, &b| acc << 8 | b as u32);
+    if len > MAX_FRAME {
+        return Err(Error::Protocol);
+    }
+    let mut payload = vec![0; len as usize];
+    try!(r.read_exact(&mut payload));
+    Ok(payload)
+}
+
+/// Appends the words, big endian.
+pub fn push_words(bytes: &mut Vec<u8>, words: &[u16]) {
+    for &w in words {
+        bytes.push((w >> 8) as u8);
+        bytes.push(w as u8);
+    }
+}
+
+/// Reads big endian words.
+pub fn words(bytes: &[u8]) -> Result<Vec<u16>, Error> {
+    if bytes.len() % 2 != 0 {
+        return Err(Error::Protocol);
+    }
+    Ok(bytes.chunks(2).map(|c| (c[0] as u16) << 8 | c[1] as u16).collect())
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match *self {
+            Request::Version => bytes.push(0),
+            Request::Pause => bytes.push(1),
+            Request::Resume => bytes.push(2),
+            Request::Step(n) => {
+                bytes.push(3);
+                push_words(&mut bytes, &[n]);
+            }
+            Request::Registers => bytes.push(4),
+            Request::ReadMemory(addr, len) => {
+                bytes.push(5);
+                push_words(&mut bytes, &[addr, len]);
+            }
+            Request::WriteMemory(addr, ref data) => {
+                bytes.push(6);
+                push_words(&mut bytes, &[addr]);
+                push_words(&mut bytes, data);
+            }
+            Request::Interrupt(msg) => {
+                bytes.push(7);
+                push_words(&mut bytes, &[msg]);
+            }
+            Request::Describe => bytes.push(8),
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Request, Error> {
+        let (&tag, fields) = try!(bytes.split_first().ok_or(Error::Protocol));
+        let fields = try!(words(fields));
+        Ok(match (tag, fields.len()) {
+            (0, 0) => Request::Version,
+            (1, 0) => Request::Pause,
+            (2, 0) => Request::Resume,
+            (3, 1) => Request::Step(fields[0]),
+            (4, 0) => Request::Registers,
+            (5, 2) => Request::ReadMemory(fields[0], fields[1]),
+            (6, n) if n > 0 => Request::WriteMemory(fields[0], fields[1..].to_vec()),
+            (7, 1) => Request::Interrupt(fields[0]),
+            (8, 0) => Request::Describe,
+            _ => return Err(Error::Protocol),
+        })
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match *self {
+            Response::Ok => bytes.push(0),
+            Response::Version(v) => {
+                bytes.push(1);
+                push_words(&mut bytes, &[v]);
+            }
+            Response::Registers(ref regs) => {
+                bytes.push(2);
+                push_words(&mut bytes, regs);
+            }
+            Response::Memory(ref data) => {
+                bytes.push(3);
+                push_words(&mut bytes, data);
+            }
+            Response::Error(ref e) => {
+                bytes.push(4);
+                bytes.extend(e.as_bytes());
+            }
+            Response::Description(ref d) => {
+                bytes.push(5);
+                bytes.extend(d.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Response, Error> {
+        let (&tag, fields) = try!(bytes.split_first().ok_or(Error::Protocol));
+        if tag == 4 || tag == 5 {
+            let text = try!(String::from_utf8(fields.to_vec()).map_err(|_| Error::Protocol));
+            return Ok(if tag == 4 {
+                Response::Error(text)
+            } else {
+                Response::Description(text)
+            });
+        }
+        let fields = try!(words(fields));
+        Ok(match (tag, fields.len()) {
+            (0, 0) => Response::Ok,
+            (1, 1) => Response::Version(fields[0]),
+            (2, 12) => {
+                let mut regs = [0; 12];
+                regs.copy_from_slice(&fields);
+                Response::Registers(regs)
+            }
+            (3, _) => Response::Memory(fields),
+            _ => return Err(Error::Protocol),
+        })
+    }
+}
+
+/// Computer side of the protocol.
+#[derive(Debug, Default)]
+pub struct Controller {
+    pub paused: bool,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn handle(&mut self, computer: &mut Computer, request: Request) -> Response {
+        match request {
+            Request::Version => Response::Version(VERSION),
+            Request::Pause => {
+                self.paused = true;
+                Response::Ok
+            }
+            Request::Resume => {
+                self.paused = false;
+                Response::Ok
+            }
+            Request::Step(n) => {
+                for _ in 0..n {
+                    if let Err(e) = computer.tick() {
+                        return Response::Error(e.to_string());
+                    }
+                }
+                Response::Ok
+            }
+            Request::Registers => {
+                let cpu = computer.cpu();
+                let mut regs = [0; 12];
+                regs[..8].copy_from_slice(&cpu.registers);
+                regs[8..].copy_from_slice(&[cpu.pc, cpu.sp, cpu.ex, cpu.ia]);
+                Response::Registers(regs)
+            }
+            Request::ReadMemory(addr, len) => {
+                let ram = &computer.cpu().ram;
+                Response::Memory((0..len).map(|i| ram[addr.wrapping_add(i) as usize]).collect())
+            }
+            Request::WriteMemory(addr, data) => {
+                computer.cpu_mut().load(&data, addr);
+                Response::Ok
+            }
+            Request::Interrupt(msg) => {
+                computer.cpu_mut().trigger_interrupt(msg);
+                Response::Ok
+            }
+            Request::Describe => Response::Description(computer.describe().to_string()),
+        }
+    }
+
+    /// Reads a request from `stream`, handles it and writes the response.
+    pub fn serve<S: Read + Write>(&mut self,
+                                  computer: &mut Computer,
+                                  stream: &mut S)
+                                  -> Result<(), Error> {
+        let response = match read_frame(stream).and_then(|f| Request::decode(&f)) {
+            Ok(request) => self.handle(computer, request),
+            Err(Error::Protocol) => Response::Error("malformed message".into()),
+            Err(e) => return Err(e),
+        };
+        try!(write_frame(stream, &response.encode()));
+        Ok(())
+    }
+}
+
+/// Tool side of the protocol.
+#[derive(Debug)]
+pub struct Client<S> {
+    stream: S,
+}
+
+impl<S: Read + Write> Client<S> {
+    pub fn new(stream: S) -> Client<S> {
+        Client { stream: stream }
+    }
+
+    pub fn request(&mut self, request: &Request) -> Result<Response, Error> {
+        try!(write_frame(&mut self.stream, &request.encode()));
+        let frame = try!(read_frame(&mut self.stream));
+        match try!(Response::decode(&frame)) {
+            Response::Error(e) => Err(Error::Remote(e)),
+            response => Ok(response),
+        }
+    }
+
+    fn expect_ok(&mut self, request: &Request) -> Result<(), Error> {
+        match try!(self.request(request)) {
+            Response::Ok => Ok(()),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub fn version(&mut self) -> Result<u16, Error> {
+        match try!(self.request(&Request::Version)) {
+            Response::Version(v) => Ok(v),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub fn pause(&mut self) -> Result<(), Error> {
+        self.expect_ok(&Request::Pause)
+    }
+
+    pub fn resume(&mut self) -> Result<(), Error> {
+        self.expect_ok(&Request::Resume)
+    }
+
+    pub fn step(&mut self, ticks: u16) -> Result<(), Error> {
+        self.expect_ok(&Request::Step(ticks))
+    }
+
+    pub fn registers(&mut self) -> Result<[u16; 12], Error> {
+        match try!(self.request(&Request::Registers)) {
+            Response::Registers(regs) => Ok(regs),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u16>, Error> {
+        match try!(self.request(&Request::ReadMemory(addr, len))) {
+            Response::Memory(data) => Ok(data),
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    pub fn write_memory(&mut self, addr: u16, data: &[u16]) -> Result<(), Error> {
+        self.expect_ok(&Request::WriteMemory(addr, data.to_vec()))
+    }
+
+    pub fn interrupt(&mut self, msg: u16) -> Result<(), Error> {
+        self.expect_ok(&Request::Interrupt(msg))
+    }
+
+    /// Text of `Computer::describe`.
+    pub fn describe(&mut self) -> Result<String, Error> {
+        match try!(self.request(&Request::Describe)) {
+            Response::Description(d) => Ok(d),
+            _ => Err(Error::Protocol),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_protocol() {
+    use std::io::Cursor;
+
+    let requests = [Request::Version,
+                    Request::Step(3),
+                    Request::ReadMemory(0xfffe, 4),
+                    Request::WriteMemory(0x10, vec![1, 2])];
+    for r in requests.iter() {
+        assert_eq!(Request::decode(&r.encode()).unwrap(), *r);
+    }
+    let responses = [Response::Registers([7; 12]),
+                     Response::Error("é".into()),
+                     Response::Description("0 devices".into())];
+    for r in responses.iter() {
+        assert_eq!(Response::decode(&r.encode()).unwrap(), *r);
+    }
+    assert!(Request::decode(&[3, 0]).is_err());
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut input = vec![];
+    let requests = [Request::Pause,
+                    Request::WriteMemory(0xffff, vec![5, 6]),
+                    Request::ReadMemory(0, 1)];
+    for r in requests.iter() {
+        write_frame(&mut input, &r.encode()).unwrap();
+    }
+    let mut stream = Duplex {
+        input: Cursor::new(input),
+        output: vec![],
+    };
+    let mut computer = Computer::default();
+    let mut controller = Controller::new();
+    for _ in 0..3 {
+        controller.serve(&mut computer, &mut stream).unwrap();
+    }
+    assert!(controller.paused);
+    assert_eq!(computer.cpu().ram[0xffff], 5);
+    let mut output = Cursor::new(stream.output);
+    for expected in &[Response::Ok, Response::Ok, Response::Memory(vec![6])] {
+        assert_eq!(Response::decode(&read_frame(&mut output).unwrap()).unwrap(), *expected);
+    }
+}
@@ -0,0 +1,135 @@
+//! Addresses executed by a program, see `Cpu::coverage`, to find the code
+//! its tests never run.
+//!
+//! With the debug info of the assembler, the addresses map back to source
+//! lines: a line is covered once one of its instructions executed.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use debug_info::DebugInfo;
+
+pub struct Coverage {
+    /// Executions of the instruction at each address.
+    counts: Box<[u64]>,
+}
+
+/// Coverage of a source line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub line: usize,
+    /// Executions of its first instruction executed, 0 if none.
+    pub count: u64,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { counts: vec![0; 0x10000].into_boxed_slice() }
+    }
+
+    pub fn record(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1;
+    }
+
+    pub fn count(&self, addr: u16) -> u64 {
+        self.counts[addr as usize]
+    }
+
+    /// Addresses of the instructions executed, in increasing order.
+    pub fn executed(&self) -> Vec<u16> {
+        (0..0x10000)
+            .filter(|&a| self.counts[a] != 0)
+            .map(|a| a as u16)
+            .collect()
+    }
+
+    /// Lines of each file of `info` with instructions, in order.
+    pub fn lines(&self, info: &DebugInfo) -> BTreeMap<String, Vec<LineCoverage>> {
+        let mut counts = BTreeMap::new();
+        for (&addr, line) in info.lines() {
+            let count = counts.entry((line.file, line.line)).or_insert(0);
+            if *count == 0 {
+                *count = self.count(addr);
+            }
+        }
+        let mut files: BTreeMap<String, Vec<LineCoverage>> = BTreeMap::new();
+        for ((file, line), count) in counts {
+            let lines = files.entry(info.files()[file].clone()).or_insert_with(Vec::new);
+            lines.push(LineCoverage {
+                line: line,
+                count: count,
+            });
+        }
+        files
+    }
+
+    /// For each file, the number of lines covered and the ranges of lines
+    /// never executed, for example `main.dasm: 10/12 lines, missed 7-8`.
+    pub fn report<W: Write>(&self, info: &DebugInfo, w: &mut W) -> io::Result<()> {
+        for (file, lines) in self.lines(info) {
+            let covered = lines.iter().filter(|l| l.count != 0).count();
+            // Lines without instructions, like comments, don't break a range.
+            let mut missed: Vec<(usize, usize)> = vec![];
+            let mut previous_missed = false;
+            for l in &lines {
+                if l.count != 0 {
+                    previous_missed = false;
+                } else if previous_missed {
+                    missed.last_mut().unwrap().1 = l.line;
+                } else {
+                    missed.push((l.line, l.line));
+                    previous_missed = true;
+                }
+            }
+            try!(write!(w, "{}: {}/{} lines", file, covered, lines.len()));
+            if !missed.is_empty() {
+                let ranges: Vec<String> = missed.iter()
+                                                .map(|&(first, last)| if first == last {
+                                                    first.to_string()
+                                                } else {
+                                                    format!("{}-{}", first, last)
+                                                })
+                                                .collect();
+                try!(write!(w, ", missed {}", ranges.join(", ")));
+            }
+            try!(writeln!(w, ""));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_coverage() {
+    use cpu::Cpu;
+    use debug_info::Line;
+    use encodings::*;
+    use types::*;
+
+    // The second branch of the IFE never runs.
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::IFE, reg(Register::A), lit(0)),
+               basic(BasicOp::SET, PC, lit(4)),
+               basic(BasicOp::SET, reg(Register::B), lit(1)),
+               basic(BasicOp::SET, reg(Register::C), lit(2)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    cpu.coverage = Some(Box::new(Coverage::new()));
+    while cpu.tick(&mut []).is_ok() {}
+    let coverage = cpu.coverage.take().unwrap();
+    assert_eq!(coverage.executed(), [0, 1, 4]);
+
+    let mut info = DebugInfo::new();
+    let file = info.add_file("main.dasm".into());
+    for (addr, line) in [(0, 1), (1, 2), (2, 4), (3, 5), (4, 7)].iter().cloned() {
+        info.add_line(addr,
+                      Line {
+                          file: file,
+                          line: line,
+                      });
+    }
+    let mut report = vec![];
+    coverage.report(&info, &mut report).unwrap();
+    assert_eq!(String::from_utf8(report).unwrap(),
+               "main.dasm: 3/5 lines, missed 4-5\n");
+}
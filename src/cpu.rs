@@ -1,13 +1,78 @@
-use std::collections::VecDeque;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::default::Default;
 use std::fmt;
 use std::error::{self, Error as StdError};
+use std::io::{self, Read, Write};
+use std::mem;
 
+use blocks::{BlockCache, DecodeCache};
+use calls::CallStack;
+use coverage::Coverage;
 use device::Device;
+use formats::{self, Format};
+use mmio::{Access, AccessKind, Hook, HookId, Mmio};
+use profile::Profiler;
+use taint::{Location, Shadow, Source};
+use trace::Tracer;
 use types::*;
 use types::Value::*;
-use types::BasicOp::*;
-use types::SpecialOp::*;
+
+/// Content of the memory before it is written.
+pub const UNINITIALIZED: u16 = 0xbeef;
+
+/// Interrupts waiting at most, the CPU catching fire on the next one.
+pub const MAX_QUEUED_INTERRUPTS: usize = 256;
+
+/// Calls the handler of each row of an opcode table, see `opcodes`.
+macro_rules! dispatch {
+    ($cpu:ident, $op:ident, $name:ident, $args:tt;
+     $($variant:ident = $code:expr, $cycles:expr, $conditional:expr, $handler:ident, $doc:tt;)*)
+     => {
+        match $op {
+            $($name::$variant => $cpu.$handler $args),*
+        }
+    }
+}
+
+/// Calls the handler of each row of an opcode table through a function
+/// pointer, see `handler`. The parenthesized names are the arguments of the
+/// `Handler`, then those given to the method of `Cpu`.
+macro_rules! handlers {
+    ($op:ident, $name:ident, ($first:ident, $second:ident, $devices:ident) => $args:tt;
+     $($variant:ident = $code:expr, $cycles:expr, $conditional:expr, $handler:ident, $doc:tt;)*)
+     => {
+        match $op {
+            $($name::$variant => {
+                fn handler(cpu: &mut Cpu,
+                           $first: Value,
+                           $second: Value,
+                           $devices: &mut [Box<Device>])
+                           -> Result<(), Error> {
+                    cpu.$handler $args
+                }
+                handler as Handler
+            }),*
+        }
+    }
+}
+
+/// Executes an instruction given its operands, b then a, or a twice for
+/// the special opcodes. Found once per instruction with `handler`.
+pub type Handler = fn(&mut Cpu, Value, Value, &mut [Box<Device>]) -> Result<(), Error>;
+
+/// Handler of the opcode of `i`, for the instructions run repeatedly like
+/// those of `blocks::Block`, skipping the dispatch of `Cpu::tick`.
+pub fn handler(i: &Instruction) -> Handler {
+    match *i {
+        Instruction::BasicOp(op, _, _) => {
+            basic_ops!(handlers!(op, BasicOp, (b, a, _devices) => (b, a);))
+        }
+        Instruction::SpecialOp(op, _) => {
+            special_ops!(handlers!(op, SpecialOp, (a, _a, devices) => (a, devices);))
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum Error {
@@ -16,6 +81,12 @@ pub enum Error {
     InterruptError,
     InFire,
     Halted,
+    /// The CPU sleeps and no device will ever wake it up.
+    Asleep,
+    PcWrapped(u16),
+    NotExecutable(u16),
+    /// Opcode not in the specification, with `Cpu::strict`.
+    Extension(SpecialOp),
 }
 
 impl fmt::Display for Error {
@@ -23,6 +94,11 @@ impl fmt::Display for Error {
         match *self {
             Error::DecodeError(ref e) => write!(f, "instruction decoding error: {}", e),
             Error::InvalidHardwareId(ref id) => write!(f, "invalid device id: {}", id),
+            Error::PcWrapped(ref pc) =>
+                write!(f, "PC wrapped past 0xffff (instruction at 0x{:04x})", pc),
+            Error::NotExecutable(ref pc) =>
+                write!(f, "tried to execute non-executable address 0x{:04x}", pc),
+            Error::Extension(op) => write!(f, "extension opcode {} in strict mode", op.mnemonic()),
             _ => write!(f, "{}", self.description()),
         }
     }
@@ -36,6 +112,10 @@ impl error::Error for Error {
             Error::InterruptError => "invalid hardware int",
             Error::InFire => "dcpu in fire, run for your lives!",
             Error::Halted => "cpu halted",
+            Error::Asleep => "cpu asleep with nothing to wake it up",
+            Error::PcWrapped(_) => "PC wrapped past 0xffff",
+            Error::NotExecutable(_) => "tried to execute a non-executable address",
+            Error::Extension(_) => "extension opcode in strict mode",
         }
     }
 
@@ -57,12 +137,64 @@ impl From<DecodeError> for Error {
 pub enum CpuState {
     Executing,
     Waiting,
+    /// Waiting for an interrupt after `SLP`.
+    Sleeping,
+    /// Stopped before the instruction at this breakpoint, which the next
+    /// tick executes.
+    HitBreakpoint(u16),
+    /// The instruction just executed accessed a watchpoint, this being its
+    /// first access to one.
+    HitWatchpoint(Access),
 }
 
-#[derive(Debug)]
+/// Accesses a watchpoint stops at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Watch {
+    pub fn matches(&self, kind: AccessKind) -> bool {
+        match (*self, kind) {
+            (Watch::ReadWrite, _) |
+            (Watch::Read, AccessKind::Read) |
+            (Watch::Write, AccessKind::Write) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Called with the CPU, PC already past the word, the address of the word
+/// and why it can't be decoded. An error fails the tick.
+pub type DecodeErrorHandler = FnMut(&mut Cpu, u16, DecodeError) -> Result<(), Error>;
+
+/// What `tick` does with a word which isn't a valid instruction, like a
+/// reserved opcode.
 pub enum OnDecodeError {
+    /// Skips the word with a warning, as a 1 cycle no-op.
     Continue,
+    /// Fails with `Error::DecodeError`, PC left at the word.
     Fail,
+    /// Skips the word and queues an interrupt with this message, so the
+    /// fault handler of the program runs, like hardware trapping on
+    /// illegal instructions.
+    Interrupt(u16),
+    /// Skips the word and calls the handler, for example to emulate the
+    /// quirks of some hardware.
+    Handler(Box<DecodeErrorHandler>),
+}
+
+impl fmt::Debug for OnDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            OnDecodeError::Continue => write!(f, "Continue"),
+            OnDecodeError::Fail => write!(f, "Fail"),
+            OnDecodeError::Interrupt(message) => write!(f, "Interrupt({})", message),
+            OnDecodeError::Handler(_) => write!(f, "Handler"),
+        }
+    }
 }
 
 pub struct Cpu {
@@ -74,17 +206,68 @@ pub struct Cpu {
     pub ia: u16,
     pub wait: u16,
     pub on_decode_error: OnDecodeError,
+    /// Set when the last instruction executed was a failed conditional,
+    /// which skipped the next instructions.
     pub check_if_cascade: bool,
     pub is_queue_enabled: bool,
     pub interrupts_queue: VecDeque<u16>,
     pub log_queue: VecDeque<u16>,
+    /// Number of `HWI` executed.
+    pub hardware_interrupts: u64,
+    /// Number of instructions executed, skipped ones excluded.
+    pub instructions: u64,
     pub halted: bool,
+    /// Set by `SLP`, cleared by the next interrupt.
+    pub sleeping: bool,
+    /// Fail with `Error::PcWrapped` instead of wrapping PC back to 0.
+    pub trap_pc_wrap: bool,
+    /// Fail with `Error::Extension` on the opcodes of this emulator, see
+    /// `SpecialOp::is_extension`.
+    pub strict: bool,
+    /// If set, fail with `Error::NotExecutable` when PC leaves these regions.
+    pub exec_regions: Option<Vec<Region>>,
+    /// Taint tracking, disabled if `None`.
+    pub shadow: Option<Box<Shadow>>,
+    /// Hooks of the memory accesses, see `add_hook`.
+    pub mmio: Option<Mmio>,
+    /// Words written by `load` while `Some`, with their address, so
+    /// `replay::Recorder` knows what a `HWI` changed.
+    pub loads: Option<Vec<(u16, u16)>>,
+    /// Trace of the instructions executed, disabled if `None`.
+    pub trace: Option<Box<Tracer>>,
+    /// Cycles spent by address and by call stack, disabled if `None`.
+    pub profile: Option<Box<Profiler>>,
+    /// Addresses of the instructions executed, disabled if `None`.
+    pub coverage: Option<Box<Coverage>>,
+    /// Routines being executed, not tracked if `None`. Needed by
+    /// `Computer::step_out`.
+    pub calls: Option<CallStack>,
+    /// Addresses where `tick` stops, see `CpuState::HitBreakpoint`.
+    pub breakpoints: BTreeSet<u16>,
+    /// Words whose accesses make `tick` stop, see
+    /// `CpuState::HitWatchpoint`.
+    pub watchpoints: BTreeMap<u16, Watch>,
+    /// Breakpoint just reported, not to stop at it again.
+    resumed_breakpoint: Option<u16>,
+    /// First watched access of the current instruction.
+    watch_hit: Option<Access>,
+    /// Address of the last instruction executed, and the instruction.
+    last_instruction: Option<(u16, Instruction)>,
+    /// Execution by basic blocks, disabled if `None`. The interrupts are
+    /// only triggered between blocks, and the cycles of a block are waited
+    /// after all its instructions. It is not used with `exec_regions`,
+    /// `shadow`, `trace`, `profile`, `coverage`, `calls`, breakpoints or
+    /// watchpoints.
+    pub blocks: Option<Box<BlockCache>>,
+    /// Instructions already decoded, checked against the memory before each
+    /// use. Enabled by default, `None` decodes each instruction again.
+    pub decode_cache: Option<Box<DecodeCache>>,
 }
 
 impl Default for Cpu {
     fn default() -> Cpu {
         Cpu {
-            ram: [0xbeef; 0x10000],
+            ram: [UNINITIALIZED; 0x10000],
             registers: [0; 8],
             pc: 0,
             sp: 0xffff,
@@ -92,11 +275,31 @@ impl Default for Cpu {
             ia: 0,
             wait: 0,
             on_decode_error: OnDecodeError::Continue,
-            check_if_cascade: true,
+            check_if_cascade: false,
             is_queue_enabled: false,
             interrupts_queue: VecDeque::new(),
             log_queue: VecDeque::new(),
+            hardware_interrupts: 0,
+            instructions: 0,
             halted: false,
+            sleeping: false,
+            trap_pc_wrap: false,
+            strict: false,
+            exec_regions: None,
+            shadow: None,
+            mmio: None,
+            loads: None,
+            trace: None,
+            profile: None,
+            coverage: None,
+            calls: None,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            resumed_breakpoint: None,
+            watch_hit: None,
+            last_instruction: None,
+            blocks: None,
+            decode_cache: Some(Box::new(DecodeCache::new())),
         }
     }
 }
@@ -108,23 +311,194 @@ impl Cpu {
         cpu
     }
 
+    /// Copies `data` to the memory from `offset`. Devices write the memory
+    /// with it, see `loads`.
     pub fn load(&mut self, data: &[u16], offset: u16) {
         for (i, d) in data.iter().enumerate() {
             self.ram[offset.wrapping_add(i as u16) as usize] = *d;
         }
+        if let Some(ref mut loads) = self.loads {
+            loads.extend(data.iter()
+                             .enumerate()
+                             .map(|(i, &d)| (offset.wrapping_add(i as u16), d)));
+        }
     }
 
     pub fn load_ops(&mut self, ops: &[Instruction], mut offset: u16) {
         for op in ops {
-            offset += op.encode(&mut self.ram[offset as usize..]);
+            let (words, size) = op.encode_to_array();
+            self.load(&words[..size as usize], offset);
+            offset = offset.wrapping_add(size as u16);
         }
     }
 
-    fn get(&mut self, i: Value) -> u16 {
+    /// `len` words from `offset`, wrapping past 0xffff like `load`.
+    pub fn read_memory(&self, offset: u16, len: usize) -> Vec<u16> {
+        (0..len).map(|i| self.ram[offset.wrapping_add(i as u16) as usize]).collect()
+    }
+
+    /// Writes `len` words from `offset`, wrapping like `read_memory`, in
+    /// `format`, or annotated for humans without one, see
+    /// `formats::write_annotated`.
+    pub fn dump_memory<W: Write>(&self,
+                                 offset: u16,
+                                 len: usize,
+                                 format: Option<Format>,
+                                 w: &mut W)
+                                 -> io::Result<()> {
+        let words = self.read_memory(offset, len);
+        match format {
+            Some(format) => formats::write(&words, format, w),
+            None => formats::write_annotated(&words, offset, w),
+        }
+    }
+
+    /// Reads words in `format` and loads them at `offset`, like `load`, for
+    /// example to patch a running program. Returns how many were loaded.
+    pub fn load_memory<R: Read>(&mut self,
+                                r: &mut R,
+                                format: Format,
+                                offset: u16)
+                                -> Result<usize, formats::Error> {
+        let words = try!(formats::read(r, format));
+        self.load(&words, offset);
+        Ok(words.len())
+    }
+
+    pub fn register(&self, r: Register) -> u16 {
+        self.registers[r as usize]
+    }
+
+    pub fn set_register(&mut self, r: Register, value: u16) {
+        self.registers[r as usize] = value;
+    }
+
+    /// Address of the last instruction executed, and the instruction, the
+    /// failing one if `tick` failed while executing it.
+    pub fn last_instruction(&self) -> Option<(u16, Instruction)> {
+        self.last_instruction
+    }
+
+    /// Puts the registers and the interrupt state back as at power on, to
+    /// run the program again from PC 0. The memory and the configuration,
+    /// like `strict`, the hooks or the breakpoints, are kept.
+    pub fn reset(&mut self) {
+        self.registers = [0; 8];
+        self.pc = 0;
+        self.sp = 0xffff;
+        self.ex = 0;
+        self.ia = 0;
+        self.wait = 0;
+        self.check_if_cascade = false;
+        self.is_queue_enabled = false;
+        self.interrupts_queue.clear();
+        self.log_queue.clear();
+        self.halted = false;
+        self.sleeping = false;
+        self.resumed_breakpoint = None;
+        self.watch_hit = None;
+        self.last_instruction = None;
+    }
+
+    /// Records that `loc` has just been written by an input device.
+    pub fn input(&mut self, loc: Location, source: Source) {
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.input(loc, source);
+        }
+    }
+
+    /// Calls `hook` when an instruction reads or writes `region`, see
+    /// `mmio`.
+    pub fn add_hook(&mut self, region: Region, hook: Box<Hook>) -> HookId {
+        self.mmio.get_or_insert_with(Mmio::new).add(region, hook)
+    }
+
+    pub fn remove_hook(&mut self, id: HookId) -> Option<Box<Hook>> {
+        let hook = self.mmio.as_mut().and_then(|mmio| mmio.remove(id));
+        if self.mmio.as_ref().map_or(false, |mmio| mmio.is_empty()) {
+            self.mmio = None;
+        }
+        hook
+    }
+
+    /// Returns false if there already was a breakpoint at `addr`.
+    pub fn add_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Watches the word at `addr`, replacing its previous watchpoint.
+    pub fn add_watchpoint(&mut self, addr: u16, watch: Watch) {
+        self.watchpoints.insert(addr, watch);
+    }
+
+    pub fn remove_watchpoint(&mut self, addr: u16) -> bool {
+        self.watchpoints.remove(&addr).is_some()
+    }
+
+    /// Memory address of an operand if its accesses are hooked or watched.
+    fn watched_location(&self, i: Value, write: bool) -> Option<u16> {
+        if self.mmio.is_none() && self.watchpoints.is_empty() {
+            return None;
+        }
+        match self.location(i, write) {
+            Some(Location::Mem(addr)) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Calls the hooks and checks the watchpoints on an access.
+    fn access(&mut self, kind: AccessKind, addr: u16, value: u16) -> u16 {
+        let value = match (self.mmio.as_mut(), kind) {
+            (Some(mmio), AccessKind::Read) => mmio.read(addr, value),
+            (Some(mmio), AccessKind::Write) => {
+                mmio.write(addr, value);
+                value
+            }
+            (None, _) => value,
+        };
+        if self.watch_hit.is_none() &&
+           self.watchpoints.get(&addr).map_or(false, |w| w.matches(kind)) {
+            self.watch_hit = Some(Access {
+                kind: kind,
+                addr: addr,
+                value: value,
+            });
+        }
+        value
+    }
+
+    /// Resolves the register or memory address an operand refers to, given
+    /// the current state. `write` selects between `PUSH` and `POP`.
+    pub fn location(&self, i: Value, write: bool) -> Option<Location> {
         match i {
+            Reg(r) => Some(Location::Reg(r)),
+            AtReg(r) => Some(Location::Mem(self.registers[r as usize])),
+            AtRegPlus(r, off) =>
+                Some(Location::Mem(off.wrapping_add(self.registers[r as usize]))),
+            Push if write => Some(Location::Mem(self.sp.wrapping_sub(1))),
+            Push | Peek => Some(Location::Mem(self.sp)),
+            Pick(n) => Some(Location::Mem(self.sp.wrapping_add(n))),
+            AtAddr(off) => Some(Location::Mem(off)),
+            SP | PC | EX | Litteral(_) => None,
+        }
+    }
+
+    fn get(&mut self, i: Value) -> u16 {
+        if self.shadow.is_some() {
+            if let Some(loc) = self.location(i, false) {
+                self.shadow.as_mut().unwrap().read(loc);
+            }
+        }
+        let watched = self.watched_location(i, false);
+        let value = match i {
             Reg(r) => self.registers[r as usize],
             AtReg(r) => self.ram[(self.registers[r as usize]) as usize],
-            AtRegPlus(r, off) => self.ram[off.wrapping_add(self.get(Reg(r))) as usize],
+            AtRegPlus(r, off) =>
+                self.ram[off.wrapping_add(self.registers[r as usize]) as usize],
             Push => {
                 let v = self.ram[self.sp as usize];
                 self.sp = self.sp.wrapping_add(1);
@@ -137,14 +511,25 @@ impl Cpu {
             EX => self.ex,
             AtAddr(off) => self.ram[off as usize],
             Litteral(n) => n
+        };
+        match watched {
+            Some(addr) => self.access(AccessKind::Read, addr, value),
+            None => value,
         }
     }
 
     fn set(&mut self, i: Value, val: u16) {
+        if self.shadow.is_some() {
+            if let Some(loc) = self.location(i, true) {
+                self.shadow.as_mut().unwrap().write(loc);
+            }
+        }
+        let watched = self.watched_location(i, true);
         match i {
             Reg(r) => self.registers[r as usize] = val,
             AtReg(r) => self.ram[(self.registers[r as usize]) as usize] = val,
-            AtRegPlus(r, off) => self.ram[off.wrapping_add(self.get(Reg(r))) as usize] = val,
+            AtRegPlus(r, off) =>
+                self.ram[off.wrapping_add(self.registers[r as usize]) as usize] = val,
             Push => {
                 self.sp = self.sp.wrapping_sub(1);
                 self.ram[self.sp as usize] = val;
@@ -157,6 +542,9 @@ impl Cpu {
             AtAddr(off) => self.ram[off as usize] = val,
             Litteral(_) => ()
         }
+        if let Some(addr) = watched {
+            self.access(AccessKind::Write, addr, val);
+        }
     }
 
     pub fn tick(&mut self, devices: &mut [Box<Device>]) -> Result<CpuState, Error> {
@@ -168,6 +556,12 @@ impl Cpu {
             trace!("Waiting");
             return Ok(CpuState::Waiting);
         }
+        if self.sleeping {
+            if self.is_queue_enabled || self.interrupts_queue.is_empty() {
+                return Ok(CpuState::Sleeping);
+            }
+            self.sleeping = false;
+        }
 
         if !self.is_queue_enabled {
             if let Some(interrupt) = self.interrupts_queue.pop_front() {
@@ -176,45 +570,181 @@ impl Cpu {
         }
 
         let pc = self.pc;
-        let (words_used, instruction) = match self.decode(pc) {
+        if self.breakpoints.contains(&pc) && self.resumed_breakpoint != Some(pc) {
+            self.resumed_breakpoint = Some(pc);
+            return Ok(CpuState::HitBreakpoint(pc));
+        }
+        self.resumed_breakpoint = None;
+
+        if self.blocks.is_some() && self.exec_regions.is_none() && self.shadow.is_none() &&
+           self.trace.is_none() && self.profile.is_none() && self.coverage.is_none() &&
+           self.calls.is_none() && self.breakpoints.is_empty() && self.watchpoints.is_empty() {
+            let mut cache = self.blocks.take().unwrap();
+            let res = self.run_block(&mut cache, devices);
+            self.blocks = Some(cache);
+            if let Some(state) = try!(res) {
+                return Ok(state);
+            }
+        }
+
+        if let Some(ref regions) = self.exec_regions {
+            if !regions.iter().any(|r| r.contains(pc)) {
+                return Err(Error::NotExecutable(pc));
+            }
+        }
+        let decoded = match self.decode_cache {
+            Some(ref mut cache) => cache.get(&self.ram, pc),
+            None => self.decode(pc),
+        };
+        let (words_used, instruction) = match decoded {
             Ok(res) => res,
-            Err(e) => match self.on_decode_error {
-                OnDecodeError::Continue => {
-                    warn!("Instruction decoding error: {:x}", self.ram[pc as usize]);
-                    self.pc += 1;
-                    return Ok(CpuState::Executing);
-                },
-                OnDecodeError::Fail => return Err(e.into()),
+            Err(e) => {
+                try!(self.decode_error(pc, e));
+                return Ok(CpuState::Executing);
             }
         };
-        self.pc = self.pc.wrapping_add(words_used);
+        try!(self.advance_pc(words_used));
+
+        trace!("Executing {:?}", instruction);
+        if let Some(ref mut shadow) = self.shadow {
+            shadow.clear_current();
+        }
+        let cycles = instruction.cycles(words_used);
+        if let Some(ref mut trace) = self.trace {
+            trace.record(pc, instruction, cycles);
+        }
+        if let Some(ref mut coverage) = self.coverage {
+            coverage.record(pc);
+        }
+        self.wait = cycles.saturating_sub(1);
+        self.instructions += 1;
+        self.last_instruction = Some((pc, instruction));
+        let res = self.op(instruction, devices);
+        if let Some(ref mut profile) = self.profile {
+            profile.record(pc, instruction, cycles, self.pc, self.sp);
+        }
+        if let Some(ref mut calls) = self.calls {
+            calls.record(instruction, self.pc, self.sp);
+        }
+        try!(res);
 
-        if self.check_if_cascade {
-            trace!("Skipping cascade");
-            self.check_if_cascade = instruction.is_if();
-            if instruction.is_if() {
-                return Ok(CpuState::Waiting);
+        match self.watch_hit.take() {
+            Some(access) => Ok(CpuState::HitWatchpoint(access)),
+            None => Ok(CpuState::Executing),
+        }
+    }
+
+    /// Executes the block at PC, `None` if its first instruction can't be
+    /// decoded.
+    fn run_block(&mut self,
+                 cache: &mut BlockCache,
+                 devices: &mut [Box<Device>])
+                 -> Result<Option<CpuState>, Error> {
+        let start = self.pc;
+        let block = match cache.get(&self.ram, start) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        trace!("Executing the block at 0x{:04x}", start);
+        let mut cycles = 0u16;
+        for step in block.steps.iter() {
+            self.last_instruction = Some((self.pc, step.instruction));
+            try!(self.advance_pc(step.size));
+            cycles = cycles.saturating_add(step.cycles);
+            self.instructions += 1;
+            self.check_if_cascade = false;
+            try!(self.check_strict(&step.instruction));
+            let (first, second) = step.operands;
+            try!((step.handler)(self, first, second, devices));
+            if step.writes_memory && !block.is_valid(&self.ram, start) {
+                break;
             }
         }
+        self.wait += cycles.saturating_sub(1);
+        Ok(Some(CpuState::Executing))
+    }
 
-        trace!("Executing {:?}", instruction);
-        self.wait = instruction.delay() - 1;
-        try!(self.op(instruction, devices));
+    /// Handles the word at `pc` which can't be decoded, see
+    /// `on_decode_error`.
+    fn decode_error(&mut self, pc: u16, e: DecodeError) -> Result<(), Error> {
+        match self.on_decode_error {
+            OnDecodeError::Continue => {
+                warn!("Instruction decoding error: {:x}", self.ram[pc as usize]);
+                self.advance_pc(1)
+            }
+            OnDecodeError::Fail => Err(e.into()),
+            OnDecodeError::Interrupt(message) => {
+                try!(self.advance_pc(1));
+                self.queue_interrupt(message)
+            }
+            OnDecodeError::Handler(_) => {
+                try!(self.advance_pc(1));
+                // Taken out for the handler to borrow the CPU.
+                let mut on_error = mem::replace(&mut self.on_decode_error, OnDecodeError::Fail);
+                let res = match on_error {
+                    OnDecodeError::Handler(ref mut handler) => (**handler)(self, pc, e),
+                    _ => unreachable!(),
+                };
+                // Unless the handler changed it.
+                if let OnDecodeError::Fail = self.on_decode_error {
+                    self.on_decode_error = on_error;
+                }
+                res
+            }
+        }
+    }
 
-        Ok(CpuState::Executing)
+    fn advance_pc(&mut self, words: u16) -> Result<(), Error> {
+        let (new_pc, wrapped) = self.pc.overflowing_add(words);
+        if wrapped && self.trap_pc_wrap {
+            return Err(Error::PcWrapped(self.pc));
+        }
+        self.pc = new_pc;
+        Ok(())
     }
 
-    fn decode(&mut self, offset: u16) -> Result<(u16, Instruction), DecodeError> {
+    /// Instruction at `offset` and its size in words.
+    ///
+    /// Reads the memory directly, the taint of the instruction words is
+    /// cleared before execution anyway.
+    pub fn decode(&self, offset: u16) -> Result<(u16, Instruction), DecodeError> {
         let bin = [
-            self.get(AtAddr(offset)),
-            self.get(AtAddr(offset.wrapping_add(1))),
-            self.get(AtAddr(offset.wrapping_add(2)))
+            self.ram[offset as usize],
+            self.ram[offset.wrapping_add(1) as usize],
+            self.ram[offset.wrapping_add(2) as usize]
         ];
         Instruction::decode(&bin)
     }
 
+    /// Queues an interrupt from `INT` or a device, triggered before the
+    /// next instruction unless queueing is enabled.
+    ///
+    /// Ignored when IA is 0, but still wakes the CPU from `SLP`. Fails with
+    /// `Error::InFire` when `MAX_QUEUED_INTERRUPTS` are already waiting.
+    pub fn queue_interrupt(&mut self, i: u16) -> Result<(), Error> {
+        if self.ia == 0 {
+            self.sleeping = false;
+            return Ok(());
+        }
+        if self.interrupts_queue.len() >= MAX_QUEUED_INTERRUPTS {
+            return Err(Error::InFire);
+        }
+        self.interrupts_queue.push_back(i);
+        Ok(())
+    }
+
+    /// Messages of the queued interrupts, the next one first.
+    pub fn pending_interrupts(&self) -> &VecDeque<u16> {
+        &self.interrupts_queue
+    }
+
+    /// Triggers an interrupt right away, bypassing the queue.
     pub fn trigger_interrupt(&mut self, i: u16) {
+        self.sleeping = false;
         if self.ia != 0 {
+            if let Some(ref mut shadow) = self.shadow {
+                shadow.clear_current();
+            }
             self.is_queue_enabled = true;
             let pc = self.get(PC);
             self.set(Push, pc);
@@ -222,63 +752,35 @@ impl Cpu {
             self.set(Push, a);
             let ia = self.ia;
             self.set(PC, ia);
-            self.set(Reg(Register::A), i);
+            // Not through `set`, which would taint the message with the
+            // previous A.
+            self.registers[Register::A as usize] = i;
+            if let Some(ref mut shadow) = self.shadow {
+                shadow.clear_current();
+                shadow.write(Location::Reg(Register::A));
+            }
         }
     }
 
+    /// Single match over every opcode, generated from the tables of
+    /// `opcodes`, so each instruction is one jump away from its handler.
     fn op(&mut self, i: Instruction, devices: &mut [Box<Device>]) -> Result<(), Error> {
+        self.check_if_cascade = false;
+        try!(self.check_strict(&i));
         match i {
-            Instruction::BasicOp(op, b, a) => self.basic_op(op, b, a),
-            Instruction::SpecialOp(op, a) => self.special_op(op, a, devices)
-        }
-    }
-
-    fn basic_op(&mut self, op: BasicOp, b: Value, a: Value) -> Result<(), Error> {
-        match op {
-            SET => self.op_set(b, a),
-            ADD => self.op_add(b, a),
-            SUB => self.op_sub(b, a),
-            MUL => self.op_mul(b, a),
-            MLI => self.op_mli(b, a),
-            DIV => self.op_div(b, a),
-            DVI => self.op_dvi(b, a),
-            MOD => self.op_mod(b, a),
-            MDI => self.op_mdi(b, a),
-            AND => self.op_and(b, a),
-            BOR => self.op_bor(b, a),
-            XOR => self.op_xor(b, a),
-            SHR => self.op_shr(b, a),
-            ASR => self.op_asr(b, a),
-            SHL => self.op_shl(b, a),
-            IFB => self.op_ifb(b, a),
-            IFC => self.op_ifc(b, a),
-            IFE => self.op_ife(b, a),
-            IFN => self.op_ifn(b, a),
-            IFG => self.op_ifg(b, a),
-            IFA => self.op_ifa(b, a),
-            IFL => self.op_ifl(b, a),
-            IFU => self.op_ifu(b, a),
-            ADX => self.op_adx(b, a),
-            SBX => self.op_sbx(b, a),
-            STI => self.op_sti(b, a),
-            STD => self.op_std(b, a)
-        }
-    }
-
-    fn special_op(&mut self, op: SpecialOp, a: Value, devices: &mut [Box<Device>]) -> Result<(), Error> {
-        match op {
-            JSR => self.op_jsr(a),
-            INT => self.op_int(a),
-            IAG => self.op_iag(a),
-            IAS => self.op_ias(a),
-            RFI => self.op_rfi(a),
-            IAQ => self.op_iaq(a),
-            HWN => self.op_hwn(a, devices),
-            HWQ => self.op_hwq(a, devices),
-            HWI => self.op_hwi(a, devices),
-            LOG => self.op_log(a),
-            BRK => self.op_brk(a),
-            HLT => self.op_hlt(),
+            Instruction::BasicOp(op, b, a) => basic_ops!(dispatch!(self, op, BasicOp, (b, a);)),
+            Instruction::SpecialOp(op, a) => {
+                special_ops!(dispatch!(self, op, SpecialOp, (a, devices);))
+            }
+        }
+    }
+
+    fn check_strict(&self, i: &Instruction) -> Result<(), Error> {
+        match *i {
+            Instruction::SpecialOp(op, _) if self.strict && op.is_extension() => {
+                Err(Error::Extension(op))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -332,7 +834,7 @@ impl Cpu {
         } else {
             let val_b = self.get(b);
             self.set(b, val_b / val_a);
-            self.ex = ((val_b as u32) << 16 / val_a) as u16;
+            self.ex = (((val_b as u32) << 16) / val_a as u32) as u16;
         }
         Ok(())
     }
@@ -344,8 +846,8 @@ impl Cpu {
             self.ex = 0;
         } else {
             let val_b = self.get(b) as i16;
-            self.set(b, (val_b / val_a) as u16);
-            self.ex = ((val_b as i32) << 16 / val_a) as u16;
+            self.set(b, val_b.wrapping_div(val_a) as u16);
+            self.ex = (((val_b as i64) << 16) / val_a as i64) as u16;
         }
         Ok(())
     }
@@ -367,7 +869,7 @@ impl Cpu {
             self.set(b, 0);
         } else {
             let val_b = self.get(b) as i16;
-            self.set(b, (val_b % val_a) as u16);
+            self.set(b, val_b.wrapping_rem(val_a) as u16);
         }
         Ok(())
     }
@@ -394,36 +896,43 @@ impl Cpu {
     }
 
     fn op_shr(&mut self, b: Value, a: Value) -> Result<(), Error> {
-        let val_a = self.get(a);
-        let val_b = self.get(b);
-        self.set(b, val_b >> val_a);
-        self.ex = (((val_b as u32) << 16) >> val_a) as u16;
+        let shift = shift_amount(self.get(a));
+        let val_b = self.get(b) as u64;
+        self.set(b, (val_b >> shift) as u16);
+        self.ex = ((val_b << 16) >> shift) as u16;
         Ok(())
     }
 
     fn op_asr(&mut self, b: Value, a: Value) -> Result<(), Error> {
-        let val_a = self.get(a);
-        let val_b = self.get(b) as i16;
-        self.set(b, (val_b >> val_a) as u16);
-        self.ex = (((val_b as i32) << 16) >> val_a) as u16;
+        let shift = shift_amount(self.get(a));
+        let val_b = self.get(b) as i16 as i64;
+        self.set(b, (val_b >> shift) as u16);
+        self.ex = ((val_b << 16) >> shift) as u16;
         Ok(())
     }
 
     fn op_shl(&mut self, b: Value, a: Value) -> Result<(), Error> {
-        let val_a = self.get(a);
-        let val_b = self.get(b);
-        self.set(b, val_b << val_a);
-        self.ex = (((val_b as u32) << val_a) >> 16) as u16;
+        let shift = shift_amount(self.get(a));
+        let val_b = self.get(b) as u64;
+        self.set(b, (val_b << shift) as u16);
+        self.ex = ((val_b << shift) >> 16) as u16;
         Ok(())
     }
 
+    /// Skips the next instruction if `cond` fails, and the one after each
+    /// skipped conditional, one cycle each.
     fn exec_if(&mut self, cond: bool) -> Result<(), Error> {
         if !cond {
-            let next_i = self.pc;
-            let (offset, _) = try!(self.decode(next_i));
-            self.pc = self.pc.wrapping_add(offset);
             self.check_if_cascade = true;
-            self.wait += 1;
+            loop {
+                let next_i = self.pc;
+                let (offset, skipped) = try!(self.decode(next_i));
+                try!(self.advance_pc(offset));
+                self.wait += 1;
+                if !skipped.is_if() {
+                    break;
+                }
+            }
         }
         Ok(())
     }
@@ -455,25 +964,25 @@ impl Cpu {
     fn op_ifg(&mut self, b: Value, a: Value) -> Result<(), Error> {
         let val_a = self.get(a);
         let val_b = self.get(b);
-        self.exec_if(val_a > val_b)
+        self.exec_if(val_b > val_a)
     }
 
     fn op_ifa(&mut self, b: Value, a: Value) -> Result<(), Error> {
         let val_a = self.get(a) as i16;
         let val_b = self.get(b) as i16;
-        self.exec_if(val_a > val_b)
+        self.exec_if(val_b > val_a)
     }
 
     fn op_ifl(&mut self, b: Value, a: Value) -> Result<(), Error> {
         let val_a = self.get(a);
         let val_b = self.get(b);
-        self.exec_if(val_a < val_b)
+        self.exec_if(val_b < val_a)
     }
 
     fn op_ifu(&mut self, b: Value, a: Value) -> Result<(), Error> {
         let val_a = self.get(a) as i16;
         let val_b = self.get(b) as i16;
-        self.exec_if(val_a < val_b)
+        self.exec_if(val_b < val_a)
     }
 
     fn op_adx(&mut self, b: Value, a: Value) -> Result<(), Error> {
@@ -530,7 +1039,7 @@ impl Cpu {
         Ok(())
     }
 
-    fn op_jsr(&mut self, a: Value) -> Result<(), Error> {
+    fn op_jsr(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         let val_a = self.get(a);
         let new_pc = Litteral(self.pc);
         try!(self.op_set(Push, new_pc));
@@ -538,30 +1047,24 @@ impl Cpu {
         Ok(())
     }
 
-    fn op_int(&mut self, a: Value) -> Result<(), Error> {
-        if self.ia != 0 {
-            if self.interrupts_queue.len() >= 256 {
-                return Err(Error::InFire);
-            }
-            let val_a = self.get(a);
-            self.interrupts_queue.push_back(val_a);
-        }
-        Ok(())
+    fn op_int(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
+        let val_a = self.get(a);
+        self.queue_interrupt(val_a)
     }
 
-    fn op_iag(&mut self, a: Value) -> Result<(), Error> {
+    fn op_iag(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         let ia = self.ia;
         self.set(a, ia);
         Ok(())
     }
 
-    fn op_ias(&mut self, a: Value) -> Result<(), Error> {
+    fn op_ias(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         let val_a = self.get(a);
         self.ia = val_a;
         Ok(())
     }
 
-    fn op_rfi(&mut self, _: Value) -> Result<(), Error> {
+    fn op_rfi(&mut self, _: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         self.is_queue_enabled = false;
         let v1 = self.get(Push);
         self.set(Reg(Register::A), v1);
@@ -570,9 +1073,9 @@ impl Cpu {
         Ok(())
     }
 
-    fn op_iaq(&mut self, a: Value) -> Result<(), Error> {
+    fn op_iaq(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         let val_a = self.get(a);
-        self.is_queue_enabled = val_a == 0;
+        self.is_queue_enabled = val_a != 0;
         Ok(())
     }
 
@@ -605,6 +1108,7 @@ impl Cpu {
         let val_a = self.get(a) as usize;
 
         if val_a < devices.len() {
+            self.hardware_interrupts += 1;
             self.wait += try!(devices[val_a].interrupt(self).map_err(|_| Error::InterruptError));
             Ok(())
         } else {
@@ -612,18 +1116,375 @@ impl Cpu {
         }
     }
 
-    fn op_log(&mut self, a: Value) -> Result<(), Error> {
+    fn op_log(&mut self, a: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         let val_a = self.get(a);
         self.log_queue.push_back(val_a);
         Ok(())
     }
 
-    fn op_brk(&mut self, _: Value) -> Result<(), Error> {
+    fn op_brk(&mut self, _: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         Ok(())
     }
 
-    fn op_hlt(&mut self) -> Result<(), Error> {
+    fn op_hlt(&mut self, _: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
         self.halted = true;
         Err(Error::Halted)
     }
+
+    fn op_slp(&mut self, _: Value, _: &mut [Box<Device>]) -> Result<(), Error> {
+        self.sleeping = true;
+        Ok(())
+    }
+}
+
+/// Shift of the `SHR`, `ASR` and `SHL`, big enough to clear the 32 bits of
+/// `b` and `EX`.
+fn shift_amount(a: u16) -> u32 {
+    cmp::min(a, 32) as u32
+}
+
+#[cfg(test)]
+#[test]
+fn test_dispatch() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), lit(3)),
+               basic(BasicOp::ADD, reg(Register::A), lit(2)),
+               special(SpecialOp::BRK, lit(0)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    // 1 + 2 cycles, then BRK doesn't wait.
+    for _ in 0..4 {
+        cpu.tick(&mut []).unwrap();
+    }
+    assert_eq!(cpu.registers[Register::A as usize], 5);
+    assert_eq!(cpu.instructions, 3);
+    assert!(cpu.tick(&mut []).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_comparisons() {
+    use encodings::*;
+
+    // Each IF compares b to a: only the SET of B and Y run.
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), lit(1)),
+               basic(BasicOp::IFG, reg(Register::A), lit(2)),
+               basic(BasicOp::SET, reg(Register::X), lit(1)),
+               basic(BasicOp::IFL, reg(Register::A), lit(2)),
+               basic(BasicOp::SET, reg(Register::B), lit(1)),
+               basic(BasicOp::IFU, reg(Register::A), lit(-1)),
+               basic(BasicOp::SET, reg(Register::C), lit(1)),
+               basic(BasicOp::IFA, reg(Register::A), lit(-1)),
+               basic(BasicOp::SET, reg(Register::Y), lit(1)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    while cpu.tick(&mut []).is_ok() {}
+    assert_eq!(cpu.registers[Register::B as usize], 1);
+    assert_eq!(cpu.registers[Register::C as usize], 0);
+    assert_eq!(cpu.registers[Register::X as usize], 0);
+    assert_eq!(cpu.registers[Register::Y as usize], 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_sleep() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::IAS, lit(4)),
+               special(SpecialOp::SLP, lit(0)),
+               special(SpecialOp::HLT, lit(0)),
+               special(SpecialOp::HLT, lit(0)),
+               basic(BasicOp::SET, reg(Register::B), reg(Register::A))],
+             0);
+    cpu.tick(&mut []).unwrap();
+    cpu.tick(&mut []).unwrap();
+    for _ in 0..10 {
+        assert!(match cpu.tick(&mut []) {
+            Ok(CpuState::Sleeping) => true,
+            _ => false,
+        });
+    }
+    cpu.interrupts_queue.push_back(7);
+    cpu.tick(&mut []).unwrap();
+    assert!(!cpu.sleeping);
+    assert_eq!(cpu.registers[Register::B as usize], 7);
+    assert_eq!(cpu.ram[0xfffe], 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_interrupt_queue() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::IAS, lit(8)),
+               special(SpecialOp::IAQ, lit(1)),
+               special(SpecialOp::INT, lit(3)),
+               special(SpecialOp::INT, lit(4)),
+               special(SpecialOp::IAQ, lit(0)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    cpu.load(&[basic(BasicOp::ADD, reg(Register::B), reg(Register::A)),
+               special(SpecialOp::RFI, lit(0))],
+             8);
+    let step = |cpu: &mut Cpu, n| {
+        for _ in 0..n {
+            cpu.tick(&mut []).unwrap();
+            while cpu.wait != 0 {
+                cpu.tick(&mut []).unwrap();
+            }
+        }
+    };
+    step(&mut cpu, 4);
+    assert_eq!(cpu.pending_interrupts(), &[3, 4]);
+    // The handler of 3 runs with queueing enabled, then the one of 4.
+    step(&mut cpu, 2);
+    assert_eq!(cpu.pending_interrupts(), &[4]);
+    assert!(cpu.is_queue_enabled);
+    assert_eq!(cpu.registers[Register::B as usize], 3);
+    step(&mut cpu, 3);
+    assert!(cpu.pending_interrupts().is_empty());
+    assert_eq!(cpu.registers[Register::B as usize], 7);
+    assert!(cpu.tick(&mut []).is_err());
+
+    let mut cpu = Cpu::default();
+    cpu.queue_interrupt(1).unwrap();
+    assert!(cpu.pending_interrupts().is_empty());
+    cpu.ia = 0x100;
+    for i in 0..MAX_QUEUED_INTERRUPTS {
+        cpu.queue_interrupt(i as u16).unwrap();
+    }
+    assert!(match cpu.queue_interrupt(0) {
+        Err(Error::InFire) => true,
+        _ => false,
+    });
+}
+
+#[cfg(test)]
+#[test]
+fn test_breakpoints() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), lit(1)),
+               basic(BasicOp::SET, AT_NEXT, reg(Register::A)),
+               0x1000,
+               basic(BasicOp::ADD, reg(Register::B), AT_NEXT),
+               0x1000,
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    cpu.blocks = Some(Box::new(BlockCache::new()));
+    assert!(cpu.add_breakpoint(1));
+    cpu.add_watchpoint(0x1000, Watch::Read);
+    let mut states = vec![];
+    loop {
+        match cpu.tick(&mut []) {
+            Ok(CpuState::HitBreakpoint(addr)) => states.push(format!("break {}", addr)),
+            Ok(CpuState::HitWatchpoint(access)) => {
+                states.push(format!("watch {:?} {}", access.kind, access.value))
+            }
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    assert_eq!(states, ["break 1", "watch Read 1"]);
+    assert_eq!(cpu.registers[Register::B as usize], 1);
+
+    assert!(cpu.remove_breakpoint(1));
+    assert!(cpu.remove_watchpoint(0x1000));
+    assert!(!cpu.remove_watchpoint(0x1000));
+}
+
+#[cfg(test)]
+#[test]
+fn test_strict() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.strict = true;
+    cpu.load(&[special(SpecialOp::LOG, lit(1)), special(SpecialOp::HLT, lit(0))], 0);
+    assert!(match cpu.tick(&mut []) {
+        Err(Error::Extension(SpecialOp::LOG)) => true,
+        _ => false,
+    });
+}
+
+#[cfg(test)]
+#[test]
+fn test_large_operands() {
+    let run = |op, b, a| {
+        let mut cpu = Cpu::default();
+        cpu.registers[Register::A as usize] = b;
+        cpu.registers[Register::B as usize] = a;
+        cpu.op(Instruction::BasicOp(op, Reg(Register::A), Reg(Register::B)), &mut [])
+           .unwrap();
+        (cpu.registers[Register::A as usize], cpu.ex)
+    };
+    assert_eq!(run(BasicOp::SHL, 0x8001, 16), (0, 0x8001));
+    assert_eq!(run(BasicOp::SHL, 0x8001, 40), (0, 0));
+    assert_eq!(run(BasicOp::SHR, 0x8001, 16), (0, 0x8001));
+    assert_eq!(run(BasicOp::ASR, 0x8000, 20), (0xffff, 0xf800));
+    assert_eq!(run(BasicOp::DIV, 1, 2), (0, 0x8000));
+    assert_eq!(run(BasicOp::DVI, 0x8000, 0xffff), (0x8000, 0));
+    assert_eq!(run(BasicOp::MDI, 0x8000, 0xffff), (0, 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_blocks() {
+    use encodings::*;
+
+    let program = [basic(BasicOp::SET, reg(Register::A), lit(0)),
+                   basic(BasicOp::SET, reg(Register::B), lit(10)),
+                   basic(BasicOp::ADD, reg(Register::A), reg(Register::B)),
+                   basic(BasicOp::SUB, reg(Register::B), lit(1)),
+                   basic(BasicOp::IFN, reg(Register::B), lit(0)),
+                   basic(BasicOp::SET, PC, lit(2)),
+                   // Patches the next instruction, in the same block.
+                   basic(BasicOp::SET, AT_NEXT, NEXT),
+                   basic(BasicOp::SET, reg(Register::A), lit(3)),
+                   9,
+                   basic(BasicOp::SET, reg(Register::A), lit(1)),
+                   basic(BasicOp::SET, PC, lit(10))];
+    let run = |cpu: &mut Cpu| {
+        cpu.load(&program, 0);
+        let mut states = vec![];
+        for _ in 0..100 {
+            cpu.tick(&mut []).unwrap();
+            if cpu.wait == 0 {
+                states.push((cpu.pc, cpu.registers));
+            }
+        }
+        states
+    };
+
+    let mut cpu = Cpu::default();
+    let states = run(&mut cpu);
+    let mut block_cpu = Cpu::default();
+    block_cpu.blocks = Some(Box::new(BlockCache::new()));
+    let block_states = run(&mut block_cpu);
+    // Same state at the end of each block.
+    assert!(block_states.iter().all(|s| states.contains(s)));
+    assert_eq!(block_states.last(), states.last());
+    assert_eq!(cpu.registers[Register::A as usize], 3);
+    assert_eq!(cpu.pc, 10);
+    assert!(block_cpu.blocks.unwrap().hits > 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_cycles() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), NEXT),
+               0x20,
+               basic(BasicOp::IFE, reg(Register::A), lit(0)),
+               basic(BasicOp::IFN, reg(Register::B), lit(1)),
+               basic(BasicOp::SET, reg(Register::B), lit(1)),
+               basic(BasicOp::SET, reg(Register::C), lit(1)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    let step = |cpu: &mut Cpu| {
+        let mut ticks = 1;
+        cpu.tick(&mut []).unwrap();
+        while cpu.wait != 0 {
+            cpu.tick(&mut []).unwrap();
+            ticks += 1;
+        }
+        ticks
+    };
+    // One more cycle for the next word.
+    assert_eq!(step(&mut cpu), 2);
+    // The failed IFE skips the IFN, then the instruction after it.
+    assert_eq!(step(&mut cpu), 4);
+    assert!(cpu.check_if_cascade);
+    assert_eq!(cpu.pc, 5);
+    assert_eq!(step(&mut cpu), 1);
+    assert!(!cpu.check_if_cascade);
+    assert_eq!(cpu.registers[Register::B as usize], 0);
+    assert_eq!(cpu.registers[Register::C as usize], 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_inspection() {
+    use encodings::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::X), reg(Register::A)),
+               special(SpecialOp::HLT, lit(0))],
+             0xffff);
+    assert_eq!(cpu.read_memory(0xffff, 2), [cpu.ram[0xffff], cpu.ram[0]]);
+    cpu.pc = 0xffff;
+    cpu.set_register(Register::A, 7);
+    cpu.tick(&mut []).unwrap();
+    assert_eq!(cpu.register(Register::X), 7);
+    assert_eq!(cpu.last_instruction(),
+               Some((0xffff,
+                     Instruction::BasicOp(BasicOp::SET, Reg(Register::X), Reg(Register::A)))));
+    assert!(cpu.tick(&mut []).is_err());
+    assert_eq!(cpu.last_instruction().map(|(addr, _)| addr), Some(0));
+
+    cpu.reset();
+    assert!(!cpu.halted);
+    assert_eq!((cpu.pc, cpu.sp, cpu.register(Register::X)), (0, 0xffff, 0));
+    assert_eq!(cpu.last_instruction(), None);
+    assert_eq!(cpu.ram[0], special(SpecialOp::HLT, lit(0)));
+
+    let mut dump = vec![];
+    cpu.dump_memory(0xffff, 2, Some(Format::BigEndian), &mut dump).unwrap();
+    assert_eq!(cpu.load_memory(&mut &dump[..], Format::BigEndian, 0x100).unwrap(), 2);
+    assert_eq!(cpu.read_memory(0x100, 2), cpu.read_memory(0xffff, 2));
+    let mut dump = vec![];
+    cpu.dump_memory(0x100, 1, None, &mut dump).unwrap();
+    assert!(dump.starts_with(b"0100: "));
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_error() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use encodings::*;
+
+    // A reserved special opcode, then a HLT.
+    let program = [0, special(SpecialOp::HLT, lit(0))];
+    let run = |on_error: OnDecodeError| {
+        let mut cpu = Cpu::new(on_error);
+        cpu.load(&program, 0);
+        cpu.ia = 0x100;
+        let res = cpu.tick(&mut []);
+        (cpu, res)
+    };
+
+    let (cpu, res) = run(OnDecodeError::Fail);
+    assert!(match res {
+        Err(Error::DecodeError(DecodeError::SpecialOp(0))) => true,
+        _ => false,
+    });
+    assert_eq!(cpu.pc, 0);
+
+    let (cpu, res) = run(OnDecodeError::Interrupt(0x42));
+    assert!(res.is_ok());
+    assert_eq!(cpu.pc, 1);
+    assert_eq!(cpu.pending_interrupts(), &[0x42]);
+
+    let faults = Rc::new(Cell::new(0));
+    let counter = faults.clone();
+    let handler = move |cpu: &mut Cpu, addr: u16, _: DecodeError| {
+        counter.set(counter.get() + 1);
+        cpu.registers[Register::A as usize] = addr + 7;
+        Ok(())
+    };
+    let (mut cpu, res) = run(OnDecodeError::Handler(Box::new(handler)));
+    assert!(res.is_ok());
+    assert_eq!((cpu.pc, cpu.registers[Register::A as usize]), (1, 7));
+    cpu.pc = 0;
+    cpu.tick(&mut []).unwrap();
+    assert_eq!(faults.get(), 2);
 }
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, btree_map};
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use symbols::Symbols;
+use types::ParseError;
+
+/// Source line of an instruction.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// Index in `DebugInfo::files`.
+    pub file: usize,
+    pub line: usize,
+}
+
+/// Maps the address of each instruction to the source line it comes from,
+/// with the labels of the program.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    lines: BTreeMap<u16, Line>,
+    symbols: Symbols,
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo::default()
+    }
+
+    /// Returns the index to use in `Line::file`.
+    pub fn add_file(&mut self, name: String) -> usize {
+        self.files.push(name);
+        self.files.len() - 1
+    }
+
+    pub fn add_line(&mut self, addr: u16, line: Line) {
+        self.lines.insert(addr, line);
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Source line of each instruction, by increasing address.
+    pub fn lines(&self) -> btree_map::Iter<u16, Line> {
+        self.lines.iter()
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// File and line of the instruction starting at `addr`.
+    pub fn line_of(&self, addr: u16) -> Option<(&str, usize)> {
+        self.lines.get(&addr).map(|l| (self.files[l.file].as_str(), l.line))
+    }
+
+    /// Addresses of the instructions of a line, in increasing order. `file`
+    /// can be any trailing part of the path, e.g. `main.dasm` for
+    /// `src/main.dasm`.
+    pub fn addresses_of(&self, file: &str, line: usize) -> Vec<u16> {
+        self.lines
+            .iter()
+            .filter(|&(_, l)| {
+                l.line == line && Path::new(&self.files[l.file]).ends_with(file)
+            })
+            .map(|(&addr, _)| addr)
+            .collect()
+    }
+
+    /// `file:line (:label+offset)`, or the address if its line is unknown.
+    pub fn describe(&self, addr: u16) -> String {
+        let location = match self.line_of(addr) {
+            Some((file, line)) => format!("{}:{}", file, line),
+            None => format!("0x{:04x}", addr),
+        };
+        match self.symbols.nearest(addr) {
+            Some((label, 0)) => format!("{} (:{})", location, label),
+            Some((label, offset)) => format!("{} (:{}+{})", location, label, offset),
+            None => location,
+        }
+    }
+}
+
+/// `file <path>` lines declaring the files in order, then one
+/// `0xaddr file line` line per instruction and one `label <name> 0xaddr` line
+/// per label.
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for file in self.files.iter() {
+            try!(writeln!(f, "file {}", file));
+        }
+        for (addr, l) in self.lines.iter() {
+            try!(writeln!(f, "0x{:04x} {} {}", addr, l.file, l.line));
+        }
+        for line in self.symbols.to_string().lines() {
+            try!(writeln!(f, "label {}", line));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for DebugInfo {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<DebugInfo, ParseError> {
+        let mut info = DebugInfo::new();
+        let mut symbols = String::new();
+        for line in s.lines().filter(|l| !l.trim().is_empty()) {
+            if line.starts_with("file ") {
+                info.add_file(line[5..].into());
+                continue;
+            }
+            if line.starts_with("label ") {
+                symbols.push_str(&line[6..]);
+                symbols.push('\n');
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let addr = words.next()
+                            .map(|a| a.trim_left_matches("0x"))
+                            .and_then(|a| u16::from_str_radix(a, 16).ok());
+            let file = words.next().and_then(|f| f.parse().ok());
+            let l = words.next().and_then(|l| l.parse().ok());
+            match (addr, file, l, words.next()) {
+                (Some(addr), Some(file), Some(l), None) if file < info.files.len() => {
+                    info.add_line(addr,
+                                  Line {
+                                      file: file,
+                                      line: l,
+                                  })
+                }
+                _ => return Err(ParseError::DebugInfo),
+            }
+        }
+        info.symbols = try!(symbols.parse().map_err(|_| ParseError::DebugInfo));
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_debug_info() {
+    let mut info = DebugInfo::new();
+    let main = info.add_file("src/main.dasm".into());
+    let lib = info.add_file("lib.dasm".into());
+    info.add_line(0, Line { file: main, line: 3 });
+    info.add_line(2, Line { file: lib, line: 1 });
+    info.add_line(3, Line { file: main, line: 3 });
+    let mut symbols = Symbols::new();
+    symbols.insert("loop".into(), 2);
+    info.set_symbols(symbols);
+
+    assert_eq!(info.to_string().parse::<DebugInfo>(), Ok(info.clone()));
+    assert_eq!(info.line_of(2), Some(("lib.dasm", 1)));
+    assert_eq!(info.line_of(1), None);
+    assert_eq!(info.addresses_of("main.dasm", 3), vec![0, 3]);
+    assert_eq!(info.addresses_of("ain.dasm", 3), vec![]);
+    assert_eq!(info.describe(0), "src/main.dasm:3");
+    assert_eq!(info.describe(2), "lib.dasm:1 (:loop)");
+    assert_eq!(info.describe(4), "0x0004 (:loop+2)");
+}
@@ -1,7 +1,11 @@
+use std::cmp;
+
 use num::traits::FromPrimitive;
 
 use cpu::Cpu;
 use device::*;
+use device::jitter::Jitter;
+use timebase::Timebase;
 
 enum_from_primitive! {
 #[allow(non_camel_case_types)]
@@ -13,11 +17,61 @@ enum Command {
 }
 }
 
-#[derive(Debug)]
+/// Generic Clock of the specification.
+///
+/// - `SET_SPEED`: ticks 60 / B times per second from now, stopped if B is 0,
+///   and resets the tick count.
+/// - `GET_TICKS`: sets C to the ticks since the last `SET_SPEED`.
+/// - `SET_INT`: interrupts with message B at each tick, disabled if B is 0.
+#[derive(Debug, Default)]
 pub struct Clock {
     speed: u16,
     int_msg: u16,
-    last_call: u64,
+    /// Ticks of the clock since the last `SET_SPEED`.
+    ticks: u64,
+    jitter: Option<Jitter>,
+    /// Cycle of the next tick of the clock, with a jitter.
+    next_tick: u64,
+    /// Last cycle seen, `SET_SPEED` starts counting from the next one.
+    current_tick: u64,
+    timebase: Timebase,
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    /// Clock of a computer running at another speed than the default.
+    pub fn with_timebase(timebase: Timebase) -> Clock {
+        Clock {
+            timebase: timebase,
+            ..Clock::default()
+        }
+    }
+
+    /// Ticks between two interrupts, the clock ticking 60 / speed times per
+    /// second.
+    fn period(&self) -> u64 {
+        self.timebase.ticks(self.speed as u64, 60)
+    }
+
+    /// Clock whose interrupts are late or early according to `jitter`.
+    pub fn with_jitter(jitter: Jitter) -> Clock {
+        Clock {
+            jitter: Some(jitter),
+            ..Clock::default()
+        }
+    }
+
+    /// Cycles until the next tick of the clock.
+    fn next_period(&mut self) -> u64 {
+        let period = self.period();
+        match self.jitter {
+            Some(ref mut jitter) => jitter.vary(period),
+            None => period,
+        }
+    }
 }
 
 impl Device for Clock {
@@ -33,15 +87,20 @@ impl Device for Clock {
         0x1c6c8b36
     }
 
+    fn name(&self) -> &str {
+        "Generic Clock"
+    }
+
     fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
         let a = cpu.registers[0];
         let b = cpu.registers[1];
         match Command::from_u16(a) {
-            Some(Command::SET_SPEED) => self.speed = b,
-            Some(Command::GET_TICKS) => {
-                cpu.registers[2] = self.last_call as u16;
-                self.last_call = 0;
-            },
+            Some(Command::SET_SPEED) => {
+                self.speed = b;
+                self.ticks = 0;
+                self.next_tick = self.current_tick + 1 + self.next_period();
+            }
+            Some(Command::GET_TICKS) => cpu.registers[2] = self.ticks as u16,
             Some(Command::SET_INT) => self.int_msg = b,
             None => return Err(())
         }
@@ -50,13 +109,95 @@ impl Device for Clock {
     }
 
     fn tick(&mut self, _: &mut Cpu, current_tick: u64) -> TickResult {
-        if self.speed != 0 && self.int_msg != 0 {
-            if current_tick % (6000000 / self.speed as u64) == 0 {
-                self.last_call += 1;
-                return TickResult::Interrupt(self.int_msg);
-            }
+        self.current_tick = current_tick;
+        if self.speed == 0 || current_tick < self.next_tick {
+            return TickResult::Nothing;
+        }
+        // Counts the ticks skipped by `Computer::skip_idle` too, with one
+        // interrupt for all of them.
+        while current_tick >= self.next_tick {
+            self.ticks += 1;
+            self.next_tick += self.next_period();
         }
+        if self.int_msg != 0 {
+            TickResult::Interrupt(self.int_msg)
+        } else {
+            TickResult::Nothing
+        }
+    }
 
-        return TickResult::Nothing;
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        if self.speed == 0 || self.int_msg == 0 {
+            None
+        } else {
+            Some(cmp::max(self.next_tick, current_tick))
+        }
+    }
+
+    /// The ticks are counted even without an interrupt message.
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        if self.speed == 0 {
+            None
+        } else {
+            Some(cmp::max(self.next_tick, current_tick))
+        }
     }
+
+    fn save_state(&self) -> Vec<u16> {
+        let mut state = vec![self.speed, self.int_msg];
+        push_u64(&mut state, self.ticks);
+        push_u64(&mut state, self.next_tick);
+        push_u64(&mut state, self.current_tick);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 14 {
+            return Err(());
+        }
+        self.speed = state[0];
+        self.int_msg = state[1];
+        self.ticks = read_u64(&state[2..6]);
+        self.next_tick = read_u64(&state[6..10]);
+        self.current_tick = read_u64(&state[10..14]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_clock() {
+    let mut cpu = Cpu::default();
+    let mut clock = Clock::with_timebase(Timebase::new(6000));
+    let command = |clock: &mut Clock, cpu: &mut Cpu, a: Command, b| {
+        cpu.registers[..2].copy_from_slice(&[a as u16, b]);
+        clock.interrupt(cpu).unwrap();
+        cpu.registers[2]
+    };
+    // 30 times per second, every 200 cycles.
+    clock.tick(&mut cpu, 49);
+    command(&mut clock, &mut cpu, Command::SET_SPEED, 2);
+    let ticks: Vec<u64> = (50..700)
+                              .filter(|&t| match clock.tick(&mut cpu, t) {
+                                  TickResult::Interrupt(_) => true,
+                                  _ => false,
+                              })
+                              .collect();
+    // Counted without interrupts.
+    assert!(ticks.is_empty());
+    assert_eq!(command(&mut clock, &mut cpu, Command::GET_TICKS, 0), 3);
+    assert_eq!(command(&mut clock, &mut cpu, Command::GET_TICKS, 0), 3);
+
+    command(&mut clock, &mut cpu, Command::SET_INT, 9);
+    assert_eq!(clock.next_interrupt(700), Some(850));
+    assert!(match clock.tick(&mut cpu, 850) {
+        TickResult::Interrupt(9) => true,
+        _ => false,
+    });
+    // Skipped by a sleeping computer.
+    clock.tick(&mut cpu, 1460);
+    assert_eq!(command(&mut clock, &mut cpu, Command::GET_TICKS, 0), 7);
+    command(&mut clock, &mut cpu, Command::SET_SPEED, 0);
+    assert_eq!(command(&mut clock, &mut cpu, Command::GET_TICKS, 0), 0);
+    assert_eq!(clock.next_interrupt(2000), None);
 }
@@ -0,0 +1,184 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    BEGIN = 0x0,
+    PASS = 0x1,
+    FAIL = 0x2,
+    DONE = 0x3,
+}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Running,
+    Passed,
+    /// With the message of the program, empty if it gave none.
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Test {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// What the program reported.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub tests: Vec<Test>,
+    /// Whether the program sent `DONE`.
+    pub done: bool,
+}
+
+impl Report {
+    pub fn failed(&self) -> usize {
+        self.tests
+            .iter()
+            .filter(|t| match t.outcome {
+                Outcome::Failed(_) => true,
+                _ => false,
+            })
+            .count()
+    }
+}
+
+/// Device the programs report the results of their tests to, for the
+/// `dcpu-test` runner. Not part of the specification.
+///
+/// - `BEGIN`: starts the test named by the C words at B, one character per
+///   word.
+/// - `PASS`: the current test passed.
+/// - `FAIL`: the current test failed, with the message of C words at B.
+/// - `DONE`: every test ran.
+///
+/// `PASS` and `FAIL` outside of a test report an unnamed one.
+#[derive(Debug, Default)]
+pub struct TestHarness {
+    report: Rc<RefCell<Report>>,
+}
+
+impl TestHarness {
+    pub fn new() -> TestHarness {
+        TestHarness::default()
+    }
+
+    /// Filled as the program runs.
+    pub fn report(&self) -> Rc<RefCell<Report>> {
+        self.report.clone()
+    }
+
+    fn end(&mut self, outcome: Outcome) {
+        let mut report = self.report.borrow_mut();
+        match report.tests.last_mut() {
+            Some(ref mut test) if test.outcome == Outcome::Running => {
+                test.outcome = outcome;
+                return;
+            }
+            _ => (),
+        }
+        report.tests.push(Test {
+            name: String::new(),
+            outcome: outcome,
+        });
+    }
+}
+
+fn read_string(cpu: &Cpu, addr: u16, len: u16) -> String {
+    (0..len).map(|i| (cpu.ram[addr.wrapping_add(i) as usize] as u8) as char).collect()
+}
+
+impl Device for TestHarness {
+    fn hardware_id(&self) -> u32 {
+        0x7e57c0de
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Test Harness"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let b = cpu.registers[1];
+        let c = cpu.registers[2];
+        match Command::from_u16(a) {
+            Some(Command::BEGIN) => {
+                self.report.borrow_mut().tests.push(Test {
+                    name: read_string(cpu, b, c),
+                    outcome: Outcome::Running,
+                });
+            }
+            Some(Command::PASS) => self.end(Outcome::Passed),
+            Some(Command::FAIL) => self.end(Outcome::Failed(read_string(cpu, b, c))),
+            Some(Command::DONE) => self.report.borrow_mut().done = true,
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    fn next_tick(&self, _: u64) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_harness() {
+    let mut harness = TestHarness::new();
+    let report = harness.report();
+    let mut cpu = Cpu::default();
+    cpu.load(&['a' as u16, 'd' as u16, 'd' as u16], 0x100);
+    let mut command = |harness: &mut TestHarness, a: Command, b, c| {
+        cpu.registers[..3].copy_from_slice(&[a as u16, b, c]);
+        harness.interrupt(&mut cpu).unwrap();
+    };
+    command(&mut harness, Command::BEGIN, 0x100, 3);
+    assert_eq!(report.borrow().tests[0].outcome, Outcome::Running);
+    command(&mut harness, Command::PASS, 0, 0);
+    command(&mut harness, Command::BEGIN, 0x101, 1);
+    command(&mut harness, Command::FAIL, 0x100, 2);
+    command(&mut harness, Command::FAIL, 0, 0);
+    assert!(!report.borrow().done);
+    command(&mut harness, Command::DONE, 0, 0);
+
+    let report = report.borrow();
+    assert!(report.done);
+    assert_eq!(report.failed(), 2);
+    assert_eq!(report.tests,
+               [Test {
+                    name: "add".into(),
+                    outcome: Outcome::Passed,
+                },
+                Test {
+                    name: "d".into(),
+                    outcome: Outcome::Failed("ad".into()),
+                },
+                Test {
+                    name: "".into(),
+                    outcome: Outcome::Failed("".into()),
+                }]);
+}
@@ -0,0 +1,279 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+use taint::{Location, Source};
+use types::Register;
+
+/// Files open at the same time.
+const MAX_FILES: usize = 16;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    OPEN = 0x0,
+    CLOSE = 0x1,
+    READ = 0x2,
+    WRITE = 0x3,
+}
+}
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Mode {
+    READ = 0x0,
+    WRITE = 0x1,
+    APPEND = 0x2,
+}
+}
+
+/// Result of a command, set in B.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    NotFound = 1,
+    /// The path leaves the root directory, or the host refused.
+    Denied = 2,
+    BadHandle = 3,
+    Io = 4,
+    TooManyFiles = 5,
+}
+
+impl From<io::Error> for Status {
+    fn from(e: io::Error) -> Status {
+        match e.kind() {
+            io::ErrorKind::NotFound => Status::NotFound,
+            io::ErrorKind::PermissionDenied => Status::Denied,
+            _ => Status::Io,
+        }
+    }
+}
+
+/// Bridge to the files under a directory of the host, to exchange data or
+/// bootstrap a toolchain running on the DCPU without disk images. Paths are
+/// relative to the root and can't leave it.
+///
+/// - `OPEN`: opens the file whose path is the C words at B, one character
+///   per word, for reading if X is 0, writing from scratch if X is 1, or
+///   appending if X is 2. Sets C to the handle, 0 on failure.
+/// - `CLOSE`: closes the handle B.
+/// - `READ`: reads up to C words from the handle B to the memory at X, and
+///   sets C to the number of words read, 0 at the end of the file.
+/// - `WRITE`: writes the C words at X to the handle B, and sets C to the
+///   number of words written.
+///
+/// Words are stored little endian, like the binaries. All the commands set
+/// B to a `Status`.
+#[derive(Debug)]
+pub struct HostBridge {
+    root: PathBuf,
+    files: Vec<Option<File>>,
+}
+
+impl HostBridge {
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<HostBridge> {
+        Ok(HostBridge {
+            root: try!(root.as_ref().canonicalize()),
+            files: (0..MAX_FILES).map(|_| None).collect(),
+        })
+    }
+
+    /// Path of `name` under the root.
+    fn resolve(&self, name: &str) -> Result<PathBuf, Status> {
+        let relative = Path::new(name);
+        let normal = relative.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        });
+        if name.is_empty() || !normal {
+            return Err(Status::Denied);
+        }
+        let path = self.root.join(relative);
+        // Symbolic links may still point outside.
+        let real = if path.exists() {
+            path.canonicalize()
+        } else {
+            path.parent().unwrap_or(&self.root).canonicalize()
+        };
+        match real {
+            Ok(ref real) if real.starts_with(&self.root) => Ok(path),
+            Ok(_) => Err(Status::Denied),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn open(&mut self, name: &str, mode: u16) -> Result<u16, Status> {
+        let path = try!(self.resolve(name));
+        let mut options = OpenOptions::new();
+        match Mode::from_u16(mode) {
+            Some(Mode::READ) => options.read(true),
+            Some(Mode::WRITE) => options.write(true).create(true).truncate(true),
+            Some(Mode::APPEND) => options.append(true).create(true),
+            None => return Err(Status::Io),
+        };
+        let slot = try!(self.files.iter().position(|f| f.is_none()).ok_or(Status::TooManyFiles));
+        self.files[slot] = Some(try!(options.open(path)));
+        Ok(slot as u16 + 1)
+    }
+
+    fn file(&mut self, handle: u16) -> Result<&mut File, Status> {
+        match self.files.get_mut((handle as usize).wrapping_sub(1)) {
+            Some(&mut Some(ref mut file)) => Ok(file),
+            _ => Err(Status::BadHandle),
+        }
+    }
+
+    fn read(&mut self, cpu: &mut Cpu, handle: u16, addr: u16, len: u16) -> Result<u16, Status> {
+        let file = try!(self.file(handle));
+        let mut bytes = vec![0; 2 * len as usize];
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match try!(file.read(&mut bytes[filled..])) {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        let words = bytes[..filled + filled % 2]
+                        .chunks(2)
+                        .map(|pair| pair[0] as u16 | (pair[1] as u16) << 8)
+                        .collect::<Vec<_>>();
+        cpu.load(&words, addr);
+        for i in 0..words.len() {
+            cpu.input(Location::Mem(addr.wrapping_add(i as u16)), Source::Disk);
+        }
+        Ok(words.len() as u16)
+    }
+
+    fn write(&mut self, cpu: &Cpu, handle: u16, addr: u16, len: u16) -> Result<u16, Status> {
+        let file = try!(self.file(handle));
+        let mut bytes = Vec::with_capacity(2 * len as usize);
+        for i in 0..len {
+            let word = cpu.ram[addr.wrapping_add(i) as usize];
+            bytes.push(word as u8);
+            bytes.push((word >> 8) as u8);
+        }
+        try!(file.write_all(&bytes));
+        Ok(len)
+    }
+}
+
+impl Device for HostBridge {
+    fn hardware_id(&self) -> u32 {
+        0x4f57b1d9
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Host Bridge"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[Register::A as usize];
+        let b = cpu.registers[Register::B as usize];
+        let c = cpu.registers[Register::C as usize];
+        let x = cpu.registers[Register::X as usize];
+        let result = match Command::from_u16(a) {
+            Some(Command::OPEN) => {
+                let name: String = (0..c)
+                                       .map(|i| cpu.ram[b.wrapping_add(i) as usize] as u8 as char)
+                                       .collect();
+                self.open(&name, x)
+            }
+            Some(Command::CLOSE) => {
+                match self.files.get_mut((b as usize).wrapping_sub(1)) {
+                    Some(file) if file.is_some() => {
+                        *file = None;
+                        Ok(0)
+                    }
+                    _ => Err(Status::BadHandle),
+                }
+            }
+            Some(Command::READ) => self.read(cpu, b, x, c),
+            Some(Command::WRITE) => self.write(cpu, b, x, c),
+            None => return Err(()),
+        };
+        let (c, status) = match result {
+            Ok(c) => (c, Status::Ok),
+            Err(status) => (0, status),
+        };
+        cpu.registers[Register::B as usize] = status as u16;
+        cpu.registers[Register::C as usize] = c;
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    fn next_tick(&self, _: u64) -> Option<u64> {
+        None
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_host_bridge() {
+    use std::env;
+    use std::fs;
+
+    let root = env::temp_dir().join("dcpu_test_host_bridge");
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir(&root).unwrap();
+    let mut host = HostBridge::new(&root).unwrap();
+    let mut cpu = Cpu::default();
+    let command = |host: &mut HostBridge, cpu: &mut Cpu, a: Command, b, c, x| {
+        cpu.registers[..3].copy_from_slice(&[a as u16, b, c]);
+        cpu.registers[Register::X as usize] = x;
+        host.interrupt(cpu).unwrap();
+        (cpu.registers[1], cpu.registers[2])
+    };
+    let load_name = |cpu: &mut Cpu, name: &str| {
+        for (i, c) in name.bytes().enumerate() {
+            cpu.ram[0x100 + i] = c as u16;
+        }
+        name.len() as u16
+    };
+
+    let len = load_name(&mut cpu, "out.bin");
+    let (status, handle) = command(&mut host, &mut cpu, Command::OPEN, 0x100, len, 1);
+    assert_eq!((status, handle), (Status::Ok as u16, 1));
+    cpu.ram[0x200..0x203].copy_from_slice(&[0x1234, 0xabcd, 0x0042]);
+    assert_eq!(command(&mut host, &mut cpu, Command::WRITE, handle, 3, 0x200), (0, 3));
+    assert_eq!(command(&mut host, &mut cpu, Command::CLOSE, handle, 0, 0).0, 0);
+    let mut written = vec![];
+    File::open(root.join("out.bin")).unwrap().read_to_end(&mut written).unwrap();
+    assert_eq!(written, [0x34, 0x12, 0xcd, 0xab, 0x42, 0x00]);
+
+    let (_, handle) = command(&mut host, &mut cpu, Command::OPEN, 0x100, len, 0);
+    assert_eq!(command(&mut host, &mut cpu, Command::READ, handle, 8, 0x300), (0, 3));
+    assert_eq!(&cpu.ram[0x300..0x303], &[0x1234, 0xabcd, 0x0042]);
+    assert_eq!(command(&mut host, &mut cpu, Command::READ, handle, 8, 0x300), (0, 0));
+    command(&mut host, &mut cpu, Command::CLOSE, handle, 0, 0);
+    assert_eq!(command(&mut host, &mut cpu, Command::READ, handle, 8, 0x300),
+               (Status::BadHandle as u16, 0));
+
+    let len = load_name(&mut cpu, "../escape");
+    assert_eq!(command(&mut host, &mut cpu, Command::OPEN, 0x100, len, 1),
+               (Status::Denied as u16, 0));
+    let len = load_name(&mut cpu, "missing");
+    assert_eq!(command(&mut host, &mut cpu, Command::OPEN, 0x100, len, 0),
+               (Status::NotFound as u16, 0));
+    fs::remove_dir_all(&root).unwrap();
+}
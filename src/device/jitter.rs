@@ -0,0 +1,59 @@
+use std::cmp;
+
+/// Imperfect timing of a device: its periods are stretched by a constant
+/// drift and varied by a pseudo-random jitter.
+///
+/// The generator is seeded, so a run can be reproduced by reusing the seed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Jitter {
+    state: u64,
+    /// Largest random variation of a period, in percent.
+    pub percent: u8,
+    /// Constant variation of every period, in percent.
+    pub drift: i8,
+}
+
+impl Jitter {
+    pub fn new(seed: u64, percent: u8, drift: i8) -> Jitter {
+        Jitter {
+            // xorshift gets stuck on 0.
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            percent: percent,
+            drift: drift,
+        }
+    }
+
+    /// xorshift64*.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// `period` with the drift and a new jitter applied, at least 1.
+    pub fn vary(&mut self, period: u64) -> u64 {
+        let drifted = cmp::max(period as i64 + period as i64 * self.drift as i64 / 100, 0);
+        let max = drifted * self.percent as i64 / 100;
+        let varied = if max == 0 {
+            drifted
+        } else {
+            drifted + (self.next_u64() % (2 * max as u64 + 1)) as i64 - max
+        };
+        cmp::max(varied, 1) as u64
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_jitter() {
+    let mut a = Jitter::new(42, 10, 0);
+    let mut b = Jitter::new(42, 10, 0);
+    let periods = (0..100).map(|_| a.vary(1000)).collect::<Vec<_>>();
+    assert_eq!(periods, (0..100).map(|_| b.vary(1000)).collect::<Vec<_>>());
+    assert!(periods.iter().all(|&p| p >= 900 && p <= 1100));
+    assert!(periods.iter().any(|&p| p != 1000));
+
+    assert_eq!(Jitter::new(1, 0, -5).vary(1000), 950);
+    assert_eq!(Jitter::new(1, 0, -100).vary(1000), 1);
+}
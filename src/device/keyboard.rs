@@ -1,10 +1,16 @@
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::fmt::Debug;
+use std::sync::mpsc::{Receiver, TryRecvError};
 
 use num::traits::FromPrimitive;
 
 use cpu::Cpu;
 use device::*;
+use taint::{Location, Source};
+use types::Register;
+
+/// Typed keys kept before the new ones are dropped.
+const BUFFER_SIZE: usize = 64;
 
 enum_from_primitive! {
 #[allow(non_camel_case_types)]
@@ -17,11 +23,30 @@ enum Command {
 }
 }
 
+/// Generic Keyboard of the specification, reading the keys from a
+/// `Backend`, such as a `ChannelBackend` fed by the window showing the
+/// screen.
+///
+/// - `CLEAR_BUFFER`: forgets the keys typed.
+/// - `GET_NEXT`: sets C to the oldest key typed, 0 if there is none.
+/// - `CHECK_KEY`: sets C to 1 if the key B is pressed, 0 otherwise.
+/// - `SET_INT`: interrupts with message B when a key is typed, pressed or
+///   released, disabled if B is 0.
 #[derive(Debug)]
 pub struct Keyboard {
     key_buffer: VecDeque<Key>,
     int_msg: u16,
-    backend: Backend,
+    backend: Box<Backend>,
+}
+
+impl Keyboard {
+    pub fn new(backend: Box<Backend>) -> Keyboard {
+        Keyboard {
+            key_buffer: VecDeque::new(),
+            int_msg: 0,
+            backend: backend,
+        }
+    }
 }
 
 impl Device for Keyboard {
@@ -37,22 +62,29 @@ impl Device for Keyboard {
         0x1c6c8b36
     }
 
+    fn name(&self) -> &str {
+        "Generic Keyboard"
+    }
+
     fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
         let a = cpu.registers[0];
         let b = cpu.registers[1];
         match Command::from_u16(a) {
             Some(Command::CLEAR_BUFFER) => self.key_buffer.clear(),
-            Some(Command::GET_NEXT) =>
+            Some(Command::GET_NEXT) => {
                 cpu.registers[2] = self.key_buffer
                                        .pop_front()
                                        .map(Key::encode)
-                                       .unwrap_or(0),
+                                       .unwrap_or(0);
+                cpu.input(Location::Reg(Register::C), Source::Keyboard);
+            }
             Some(Command::CHECK_KEY) => {
                 let key = match Key::decode(b) {
                     Ok(k) => k,
                     Err(()) => return Err(()),
                 };
                 cpu.registers[2] = self.backend.is_key_pressed(key) as u16;
+                cpu.input(Location::Reg(Register::C), Source::Keyboard);
             },
             Some(Command::SET_INT) => self.int_msg = b,
             _ => return Err(()),
@@ -60,20 +92,105 @@ impl Device for Keyboard {
         Ok(0)
     }
 
-    fn tick(&mut self, cpu: &mut Cpu, _: u64) -> TickResult {
-        if self.backend.push_typed_keys(&mut self.key_buffer) && self.int_msg != 0 {
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        let changed = self.backend.push_typed_keys(&mut self.key_buffer);
+        while self.key_buffer.len() > BUFFER_SIZE {
+            self.key_buffer.pop_back();
+        }
+        if changed && self.int_msg != 0 {
             TickResult::Interrupt(self.int_msg)
         } else {
             TickResult::Nothing
         }
     }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        if self.int_msg != 0 {
+            Some(current_tick)
+        } else {
+            None
+        }
+    }
+
+    /// The interrupt message, then the keys typed and not read yet.
+    fn save_state(&self) -> Vec<u16> {
+        let mut state = vec![self.int_msg];
+        state.extend(self.key_buffer.iter().map(|&k| k.encode()));
+        state
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        match state.split_first() {
+            Some((&int_msg, keys)) if keys.len() <= BUFFER_SIZE => {
+                let keys = keys.iter().map(|&k| Key::decode(k));
+                self.key_buffer = try!(keys.collect::<Result<VecDeque<Key>, ()>>());
+                self.int_msg = int_msg;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
 }
 
+/// Where the keys come from.
 pub trait Backend: Debug {
     fn is_key_pressed(&mut self, key: Key) -> bool;
+    /// Appends the keys typed since the last call to `queue`, returning
+    /// whether any key was typed, pressed or released.
     fn push_typed_keys(&mut self, queue: &mut VecDeque<Key>) -> bool;
 }
 
+/// What a window, or any other frontend, sends to a `ChannelBackend`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Pressed(Key),
+    Released(Key),
+    /// Key to put in the buffer, usually a character from the text input
+    /// of the host, taking the layout and the modifiers into account.
+    Typed(Key),
+}
+
+/// Keyboard fed by another thread, like the one of a window, which can't be
+/// polled from the emulation thread.
+#[derive(Debug)]
+pub struct ChannelBackend {
+    events: Receiver<KeyEvent>,
+    pressed: HashSet<u16>,
+}
+
+impl ChannelBackend {
+    pub fn new(events: Receiver<KeyEvent>) -> ChannelBackend {
+        ChannelBackend {
+            events: events,
+            pressed: HashSet::new(),
+        }
+    }
+}
+
+impl Backend for ChannelBackend {
+    fn is_key_pressed(&mut self, key: Key) -> bool {
+        self.pressed.contains(&key.encode())
+    }
+
+    fn push_typed_keys(&mut self, queue: &mut VecDeque<Key>) -> bool {
+        let mut changed = false;
+        loop {
+            match self.events.try_recv() {
+                Ok(KeyEvent::Pressed(key)) => {
+                    self.pressed.insert(key.encode());
+                }
+                Ok(KeyEvent::Released(key)) => {
+                    self.pressed.remove(&key.encode());
+                }
+                Ok(KeyEvent::Typed(key)) => queue.push_back(key),
+                // The frontend is gone, like a closed window.
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return changed,
+            }
+            changed = true;
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Key {
     Backspace,
@@ -99,6 +216,40 @@ impl Key {
         }
     }
 
+    /// Maps the name of a key of the host, as in the `VirtualKeyCode`s of
+    /// winit and glium, like `Back`, `LShift`, `A` or `Key1`. The letters
+    /// are lowercase, as typed without shift.
+    pub fn from_host(name: &str) -> Option<Key> {
+        let key = match name {
+            "Back" => Key::Backspace,
+            "Return" | "NumpadEnter" => Key::Return,
+            "Insert" => Key::Insert,
+            "Delete" => Key::Delete,
+            "Up" => Key::Up,
+            "Down" => Key::Down,
+            "Left" => Key::Left,
+            "Right" => Key::Right,
+            "LShift" | "RShift" => Key::Shift,
+            "LControl" | "RControl" => Key::Control,
+            "Space" => Key::ASCII(0x20),
+            _ => {
+                let name = if name.starts_with("Key") || name.starts_with("Numpad") {
+                    name.trim_left_matches("Key").trim_left_matches("Numpad")
+                } else {
+                    name
+                };
+                let mut chars = name.chars();
+                return match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii_alphanumeric() => {
+                        Key::from_char(c.to_ascii_lowercase()).ok()
+                    }
+                    _ => None,
+                };
+            }
+        };
+        Some(key)
+    }
+
     pub fn encode(self) -> u16 {
         match self {
             Key::Backspace => 0x10,
@@ -132,3 +283,42 @@ impl Key {
         }
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_keyboard() {
+    use std::sync::mpsc;
+
+    let (sender, receiver) = mpsc::channel();
+    let mut keyboard = Keyboard::new(Box::new(ChannelBackend::new(receiver)));
+    let mut cpu = Cpu::default();
+    let command = |keyboard: &mut Keyboard, cpu: &mut Cpu, a: Command, b| {
+        cpu.registers[..2].copy_from_slice(&[a as u16, b]);
+        keyboard.interrupt(cpu).unwrap();
+        cpu.registers[2]
+    };
+    command(&mut keyboard, &mut cpu, Command::SET_INT, 4);
+    assert!(match keyboard.tick(&mut cpu, 0) {
+        TickResult::Nothing => true,
+        _ => false,
+    });
+
+    sender.send(KeyEvent::Pressed(Key::from_host("LShift").unwrap())).unwrap();
+    sender.send(KeyEvent::Typed(Key::from_char('A').unwrap())).unwrap();
+    sender.send(KeyEvent::Typed(Key::from_host("Return").unwrap())).unwrap();
+    assert!(match keyboard.tick(&mut cpu, 1) {
+        TickResult::Interrupt(4) => true,
+        _ => false,
+    });
+    assert_eq!(command(&mut keyboard, &mut cpu, Command::CHECK_KEY, 0x90), 1);
+    assert_eq!(command(&mut keyboard, &mut cpu, Command::GET_NEXT, 0), 0x41);
+    assert_eq!(command(&mut keyboard, &mut cpu, Command::GET_NEXT, 0), 0x11);
+    assert_eq!(command(&mut keyboard, &mut cpu, Command::GET_NEXT, 0), 0);
+
+    sender.send(KeyEvent::Released(Key::Shift)).unwrap();
+    keyboard.tick(&mut cpu, 2);
+    assert_eq!(command(&mut keyboard, &mut cpu, Command::CHECK_KEY, 0x90), 0);
+    assert_eq!(Key::from_host("Key7"), Some(Key::ASCII(0x37)));
+    assert_eq!(Key::from_host("Q"), Some(Key::ASCII(0x71)));
+    assert_eq!(Key::from_host("F1"), None);
+}
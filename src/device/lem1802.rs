@@ -1,24 +1,84 @@
-use std::fmt::Debug;
-use std::num::Wrapping;
+use std::cell::RefCell;
+use std::fmt::{Debug, Write as FmtWrite};
+use std::io::Write;
+use std::rc::Rc;
+use std::time::Duration;
 
 use num::traits::FromPrimitive;
 
 use cpu::Cpu;
 use device::*;
+use image::Image;
+use timebase::{HostClock, SystemClock, Timebase};
 
-const MASK_INDEX: u16 = 0xf;
-const SCREEN_HEIGHT: u16 = 128;
-const SCREEN_WIDTH: u16 = 96;
+/// Size of the screen in pixels, without the border.
+pub const SCREEN_WIDTH: u16 = 128;
+pub const SCREEN_HEIGHT: u16 = 96;
+/// Width of the border of `Screen::image`, in pixels.
+pub const BORDER: u16 = 4;
 const CHAR_HEIGHT: u16 = 8;
 const CHAR_WIDTH: u16 = 4;
-const NB_CHARS: u16 = (SCREEN_HEIGHT / CHAR_HEIGHT) * (SCREEN_WIDTH / CHAR_WIDTH);
 
+const MASK_INDEX: u16 = 0xf;
 const MASK_BLINKING: u16 = 1 << 7;
-const MASK_COLOR_IDX: u16 = 0xf;
 const MASK_CHAR: u16 = 0x7f;
 const SHIFT_FG: u16 = 12;
 const SHIFT_BG: u16 = 8;
 
+/// Size of the screen in characters, the video words being row by row.
+pub const COLUMNS: u16 = 32;
+pub const ROWS: u16 = 12;
+const NB_CHARS: u16 = COLUMNS * ROWS;
+/// Words of a font.
+const FONT_SIZE: u16 = 256;
+const PALETTE_SIZE: u16 = 16;
+/// Changes of the blinking characters per second, shown then hidden at
+/// 1 Hz.
+const BLINKS_PER_SECOND: u64 = 2;
+
+/// Font used when none is mapped, two words per character: the columns from
+/// the left, a byte each, the top row in the lowest bit.
+pub const DEFAULT_FONT: [u16; FONT_SIZE as usize] = [
+    0x000f, 0x0808, 0x080f, 0x0808, 0x08f8, 0x0808, 0x00ff, 0x0808,
+    0x0808, 0x0808, 0x08ff, 0x0808, 0x00ff, 0x1414, 0xff00, 0xff08,
+    0x1f10, 0x1714, 0xfc04, 0xf414, 0x1710, 0x1714, 0xf404, 0xf414,
+    0xff00, 0xf714, 0x1414, 0x1414, 0xf700, 0xf714, 0x1417, 0x1414,
+    0x0f08, 0x0f08, 0x14f4, 0x1414, 0xf808, 0xf808, 0x0f08, 0x0f08,
+    0x001f, 0x1414, 0x00fc, 0x1414, 0xf808, 0xf808, 0xff08, 0xff08,
+    0x14ff, 0x1414, 0x080f, 0x0000, 0x00f8, 0x0808, 0xffff, 0xffff,
+    0xf0f0, 0xf0f0, 0xffff, 0x0000, 0x0000, 0xffff, 0x0f0f, 0x0f0f,
+    0x0000, 0x0000, 0x005f, 0x0000, 0x0300, 0x0300, 0x3e14, 0x3e00,
+    0x266b, 0x3200, 0x611c, 0x4300, 0x3629, 0x7650, 0x0002, 0x0100,
+    0x1c22, 0x4100, 0x4122, 0x1c00, 0x1408, 0x1400, 0x081c, 0x0800,
+    0x4020, 0x0000, 0x0808, 0x0800, 0x0040, 0x0000, 0x601c, 0x0300,
+    0x3e49, 0x3e00, 0x427f, 0x4000, 0x6259, 0x4600, 0x2249, 0x3600,
+    0x0f08, 0x7f00, 0x2745, 0x3900, 0x3e49, 0x3200, 0x6119, 0x0700,
+    0x3649, 0x3600, 0x2649, 0x3e00, 0x0024, 0x0000, 0x4024, 0x0000,
+    0x0814, 0x2200, 0x1414, 0x1400, 0x2214, 0x0800, 0x0259, 0x0600,
+    0x3e59, 0x5e00, 0x7e09, 0x7e00, 0x7f49, 0x3600, 0x3e41, 0x2200,
+    0x7f41, 0x3e00, 0x7f49, 0x4100, 0x7f09, 0x0100, 0x3e41, 0x7a00,
+    0x7f08, 0x7f00, 0x417f, 0x4100, 0x2040, 0x3f00, 0x7f08, 0x7700,
+    0x7f40, 0x4000, 0x7f06, 0x7f00, 0x7f01, 0x7e00, 0x3e41, 0x3e00,
+    0x7f09, 0x0600, 0x3e61, 0x7e00, 0x7f09, 0x7600, 0x2649, 0x3200,
+    0x017f, 0x0100, 0x3f40, 0x7f00, 0x1f60, 0x1f00, 0x7f30, 0x7f00,
+    0x7708, 0x7700, 0x0778, 0x0700, 0x7149, 0x4700, 0x007f, 0x4100,
+    0x031c, 0x6000, 0x417f, 0x0000, 0x0201, 0x0200, 0x8080, 0x8000,
+    0x0001, 0x0200, 0x2454, 0x7800, 0x7f44, 0x3800, 0x3844, 0x2800,
+    0x3844, 0x7f00, 0x3854, 0x5800, 0x087e, 0x0900, 0x4854, 0x3c00,
+    0x7f04, 0x7800, 0x047d, 0x0000, 0x2040, 0x3d00, 0x7f10, 0x6c00,
+    0x017f, 0x0000, 0x7c18, 0x7c00, 0x7c04, 0x7800, 0x3844, 0x3800,
+    0x7c14, 0x0800, 0x0814, 0x7c00, 0x7c04, 0x0800, 0x4854, 0x2400,
+    0x043e, 0x4400, 0x3c40, 0x7c00, 0x1c60, 0x1c00, 0x7c30, 0x7c00,
+    0x6c10, 0x6c00, 0x4c50, 0x3c00, 0x6454, 0x4c00, 0x0836, 0x4100,
+    0x0077, 0x0000, 0x4136, 0x0800, 0x0201, 0x0201, 0x0205, 0x0200,
+];
+
+/// Palette used when none is mapped, `0x0rgb` colors.
+pub const DEFAULT_PALETTE: [u16; PALETTE_SIZE as usize] = [
+    0x000, 0x00a, 0x0a0, 0x0aa, 0xa00, 0xa0a, 0xa50, 0xaaa,
+    0x555, 0x55f, 0x5f5, 0x5ff, 0xf55, 0xf5f, 0xff5, 0xfff,
+];
+
 enum_from_primitive! {
 #[allow(non_camel_case_types)]
 #[derive(Debug)]
@@ -27,40 +87,420 @@ enum Command {
     MEM_MAP_FONT = 0x1,
     MEM_MAP_PALETTE = 0x2,
     SET_BORDER_COLOR = 0x3,
+    MEM_DUMP_FONT = 0x4,
+    MEM_DUMP_PALETTE = 0x5,
 }
 }
 
-#[derive(Default, Copy, Clone)]
+/// Color with 4 bits per component.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
 pub struct Color {
     pub r: u16,
     pub g: u16,
     pub b: u16,
-    pub blinking: bool,
 }
-pub type Screen = [Color; (SCREEN_HEIGHT * SCREEN_WIDTH) as usize];
 
 impl Color {
-    fn from_packed(c: u16) -> Color {
+    pub fn from_packed(c: u16) -> Color {
         Color {
             r: (c >> 8) & 0xf,
             g: (c >> 4) & 0xf,
             b: (c >> 0) & 0xf,
-            blinking: false,
         }
     }
+
+    /// 8 bits per component.
+    pub fn to_rgb(&self) -> [u8; 3] {
+        [(self.r * 0x11) as u8, (self.g * 0x11) as u8, (self.b * 0x11) as u8]
+    }
+}
+
+/// What the screen displays: its memory, read at the frame, and the blink
+/// phase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Screen {
+    /// Video words, row by row.
+    pub video: Vec<u16>,
+    /// The mapped font, or `DEFAULT_FONT`.
+    pub font: Vec<u16>,
+    /// The mapped palette, or `DEFAULT_PALETTE`.
+    pub palette: Vec<u16>,
+    pub border_color_index: u16,
+    /// Whether the blinking characters are shown.
+    pub blink_on: bool,
+}
+
+impl Screen {
+    pub fn color(&self, index: u16) -> Color {
+        Color::from_packed(self.palette[(index & MASK_INDEX) as usize])
+    }
+
+    pub fn border(&self) -> Color {
+        self.color(self.border_color_index)
+    }
+
+    /// Colors of the `SCREEN_WIDTH` x `SCREEN_HEIGHT` pixels, row by row,
+    /// without the border.
+    pub fn pixels(&self) -> Vec<Color> {
+        let mut pixels = vec![Color::default(); (SCREEN_WIDTH * SCREEN_HEIGHT) as usize];
+        for (offset, &word) in self.video.iter().enumerate() {
+            let word = VideoWord::from_packed(word);
+            let glyph = (self.font[2 * word.char_idx as usize] as u32) << 16 |
+                        self.font[2 * word.char_idx as usize + 1] as u32;
+            let left = offset as u16 % COLUMNS * CHAR_WIDTH;
+            let top = offset as u16 / COLUMNS * CHAR_HEIGHT;
+            for x in 0..CHAR_WIDTH {
+                let column = glyph >> (8 * (CHAR_WIDTH - 1 - x));
+                for y in 0..CHAR_HEIGHT {
+                    let lit = column >> y & 1 != 0 && (!word.blinking || self.blink_on);
+                    let index = if lit { word.fg_idx } else { word.bg_idx };
+                    pixels[((top + y) * SCREEN_WIDTH + left + x) as usize] = self.color(index);
+                }
+            }
+        }
+        pixels
+    }
+
+    /// The pixels in the border, each drawn as `scale` x `scale` pixels,
+    /// for screenshots.
+    pub fn image(&self, scale: u32) -> Image {
+        let pixels = self.pixels();
+        let border = self.border().to_rgb();
+        let width = (SCREEN_WIDTH + 2 * BORDER) as u32;
+        let height = (SCREEN_HEIGHT + 2 * BORDER) as u32;
+        let mut image = Image {
+            width: width * scale,
+            height: height * scale,
+            pixels: Vec::with_capacity((width * height * scale * scale) as usize),
+        };
+        for y in 0..height * scale {
+            for x in 0..width * scale {
+                let (x, y) = ((x / scale) as u16, (y / scale) as u16);
+                let inside = x >= BORDER && x < BORDER + SCREEN_WIDTH && y >= BORDER &&
+                             y < BORDER + SCREEN_HEIGHT;
+                image.pixels.push(if inside {
+                    pixels[((y - BORDER) * SCREEN_WIDTH + x - BORDER) as usize].to_rgb()
+                } else {
+                    border
+                });
+            }
+        }
+        image
+    }
+}
+
+/// Rectangle of the screen, in characters from the top left.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
 }
 
 pub trait Backend: Debug {
+    /// Called at each tick of the screen, which may skip those between two
+    /// frames, see `Device::next_tick`.
     fn tick(&mut self, cpu: &Cpu, tick_count: u64);
+
+    /// Called once per frame if some characters changed since the previous
+    /// one, or for the first frame, with `dirty` covering all of them.
+    ///
+    /// Lets frontends redraw or transmit only the changes. Not called while
+    /// the screen is disconnected.
+    fn frame(&mut self, _screen: &Screen, _dirty: &[Rect]) {}
 }
 
+/// Draws the screen in a terminal with ANSI escape codes, see `ansi`.
+/// Write errors are ignored.
+#[derive(Debug)]
+pub struct TermBackend<W>(pub W);
+
+impl<W: Write + Debug> Backend for TermBackend<W> {
+    fn tick(&mut self, _: &Cpu, _: u64) {}
+
+    fn frame(&mut self, screen: &Screen, dirty: &[Rect]) {
+        let _ = self.0.write_all(ansi(screen, dirty).as_bytes());
+        let _ = self.0.flush();
+    }
+}
+
+/// Keeps the last frame in `last`, for example to save a screenshot with
+/// `Screen::image`, and passes it on to `inner`.
+#[derive(Debug)]
+pub struct LastFrame {
+    pub inner: Option<Box<Backend>>,
+    pub last: Rc<RefCell<Option<Screen>>>,
+}
+
+impl Backend for LastFrame {
+    fn tick(&mut self, cpu: &Cpu, tick_count: u64) {
+        if let Some(ref mut inner) = self.inner {
+            inner.tick(cpu, tick_count);
+        }
+    }
+
+    fn frame(&mut self, screen: &Screen, dirty: &[Rect]) {
+        *self.last.borrow_mut() = Some(screen.clone());
+        if let Some(ref mut inner) = self.inner {
+            inner.frame(screen, dirty);
+        }
+    }
+}
+
+/// Escape codes drawing the `dirty` characters of `screen` in a terminal
+/// with 24-bit colors, one cell per character from the top left, in a
+/// border of one cell. The whole screen also clears the terminal and draws
+/// the border.
+///
+/// The characters are drawn as ASCII, whatever the font, and the others as
+/// blocks, or spaces if their glyph is empty. The cursor is left below the
+/// screen.
+pub fn ansi(screen: &Screen, dirty: &[Rect]) -> String {
+    let mut out = String::new();
+    let color = |out: &mut String, foreground: bool, c: Color| {
+        let _ = write!(out,
+                       "\x1b[{};2;{};{};{}m",
+                       if foreground { 38 } else { 48 },
+                       c.r * 0x11,
+                       c.g * 0x11,
+                       c.b * 0x11);
+    };
+    let whole = [Rect {
+                     x: 0,
+                     y: 0,
+                     width: COLUMNS,
+                     height: ROWS,
+                 }];
+    if dirty == whole {
+        out.push_str("\x1b[2J");
+        color(&mut out, false, screen.border());
+        for y in 0..ROWS + 2 {
+            let _ = write!(out, "\x1b[{};1H", y + 1);
+            if y == 0 || y == ROWS + 1 {
+                out.extend((0..COLUMNS + 2).map(|_| ' '));
+            } else {
+                let _ = write!(out, " \x1b[{}G ", COLUMNS + 2);
+            }
+        }
+    }
+    for rect in dirty {
+        for y in rect.y..rect.y + rect.height {
+            let _ = write!(out, "\x1b[{};{}H", y + 2, rect.x + 2);
+            for x in rect.x..rect.x + rect.width {
+                let word = VideoWord::from_packed(screen.video[(y * COLUMNS + x) as usize]);
+                let glyph = &screen.font[2 * word.char_idx as usize..][..2];
+                let c = if word.blinking && !screen.blink_on || glyph == [0, 0] {
+                    ' '
+                } else if word.char_idx > 0x20 && word.char_idx < 0x7f {
+                    word.char_idx as u8 as char
+                } else {
+                    '\u{2588}'
+                };
+                color(&mut out, true, screen.color(word.fg_idx));
+                color(&mut out, false, screen.color(word.bg_idx));
+                out.push(c);
+            }
+        }
+    }
+    let _ = write!(out, "\x1b[0m\x1b[{};1H", ROWS + 3);
+    out
+}
+
+/// Screen displayed by the last frame, to find what changed since.
+#[derive(Debug, Default, Clone)]
+pub struct DirtyTracker {
+    last: Option<Screen>,
+}
+
+impl DirtyTracker {
+    pub fn new() -> DirtyTracker {
+        DirtyTracker::default()
+    }
+
+    /// Rectangles covering the characters which changed since the previous
+    /// call, including the blinking ones when the blink phase changed. The
+    /// whole screen for the first call or if the font, the palette or the
+    /// border changed.
+    pub fn update(&mut self, screen: &Screen) -> Vec<Rect> {
+        let dirty = match self.last {
+            Some(ref last) if last.font == screen.font && last.palette == screen.palette &&
+                              last.border_color_index == screen.border_color_index => {
+                let blinked = last.blink_on != screen.blink_on;
+                last.video
+                    .iter()
+                    .zip(&screen.video)
+                    .map(|(&old, &new)| old != new || blinked && new & MASK_BLINKING != 0)
+                    .collect()
+            }
+            _ => vec![true; NB_CHARS as usize],
+        };
+        self.last = Some(screen.clone());
+        rects(&dirty)
+    }
+}
+
+/// Merges the dirty characters into rectangles: runs on each row, then runs
+/// spanning the same columns on consecutive rows.
+fn rects(dirty: &[bool]) -> Vec<Rect> {
+    let mut res: Vec<Rect> = vec![];
+    for y in 0..ROWS {
+        let mut x = 0;
+        while x < COLUMNS {
+            if !dirty[(y * COLUMNS + x) as usize] {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < COLUMNS && dirty[(y * COLUMNS + x) as usize] {
+                x += 1;
+            }
+            let above = res.iter_mut().find(|r| {
+                r.x == start && r.width == x - start && r.y + r.height == y
+            });
+            match above {
+                Some(r) => r.height += 1,
+                None => {
+                    res.push(Rect {
+                        x: start,
+                        y: y,
+                        width: x - start,
+                        height: 1,
+                    })
+                }
+            }
+        }
+    }
+    res
+}
+
+/// How often the screen is drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Refresh {
+    /// Frames per second of emulated time.
+    pub rate: u64,
+    /// Skips the frames coming less than `1 / rate` second of real time
+    /// after the previous one drawn, when the emulation runs faster than
+    /// real time or the backend is slow to draw. The changes are drawn by
+    /// the next frame.
+    pub vsync: bool,
+}
+
+impl Default for Refresh {
+    /// 60 Hz without vsync, drawing every frame whatever the speed.
+    fn default() -> Refresh {
+        Refresh {
+            rate: 60,
+            vsync: false,
+        }
+    }
+}
+
+/// LEM1802 monitor, 32x12 characters of 4x8 pixels in 16 colors.
+///
+/// - `MEM_MAP_SCREEN`: shows the 384 video words at B, disconnects the
+///   screen if B is 0.
+/// - `MEM_MAP_FONT`: uses the 256 words at B as font, the default one if B
+///   is 0.
+/// - `MEM_MAP_PALETTE`: uses the 16 words at B as palette, the default one
+///   if B is 0.
+/// - `SET_BORDER_COLOR`: sets the border to the color B of the palette.
+/// - `MEM_DUMP_FONT`: writes the default font at B, taking 256 cycles.
+/// - `MEM_DUMP_PALETTE`: writes the default palette at B, taking 16 cycles.
+///
+/// The characters with the bit 7 of their video word set blink at 1 Hz.
 #[derive(Debug)]
 pub struct LEM1802 {
-    video_map: Wrapping<u16>,
-    font_map: Wrapping<u16>,
-    palette_map: Wrapping<u16>,
+    video_map: u16,
+    font_map: u16,
+    palette_map: u16,
     border_color_index: u16,
-    backend: Backend,
+    dirty: DirtyTracker,
+    timebase: Timebase,
+    refresh: Refresh,
+    /// When the last frame was drawn, with vsync.
+    last_frame: Option<Duration>,
+    clock: Box<HostClock>,
+    skipped_frames: u64,
+    backend: Box<Backend>,
+}
+
+impl LEM1802 {
+    pub fn new(backend: Box<Backend>) -> LEM1802 {
+        LEM1802::with_timebase(backend, Timebase::default())
+    }
+
+    pub fn with_timebase(backend: Box<Backend>, timebase: Timebase) -> LEM1802 {
+        LEM1802::with_refresh(backend, timebase, Refresh::default())
+    }
+
+    pub fn with_refresh(backend: Box<Backend>, timebase: Timebase, refresh: Refresh) -> LEM1802 {
+        LEM1802 {
+            video_map: 0,
+            font_map: 0,
+            palette_map: 0,
+            border_color_index: 0,
+            dirty: DirtyTracker::new(),
+            timebase: timebase,
+            refresh: refresh,
+            last_frame: None,
+            clock: Box::new(SystemClock),
+            skipped_frames: 0,
+            backend: backend,
+        }
+    }
+
+    /// Replaces the clock timing the frames with vsync, the one of the
+    /// system by default.
+    pub fn set_clock(&mut self, clock: Box<HostClock>) {
+        self.clock = clock;
+        self.last_frame = None;
+    }
+
+    /// Frames skipped by vsync.
+    pub fn skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+
+    /// Whether vsync lets the frame be drawn now.
+    fn is_synced(&mut self) -> bool {
+        if !self.refresh.vsync || self.refresh.rate == 0 {
+            return true;
+        }
+        let interval = Duration::from_secs(1) / self.refresh.rate as u32;
+        let now = self.clock.now();
+        match self.last_frame {
+            Some(last) if now < last + interval => {
+                self.skipped_frames += 1;
+                false
+            }
+            _ => {
+                self.last_frame = Some(now);
+                true
+            }
+        }
+    }
+
+    /// What the screen displays at `tick_count`, `None` while it is
+    /// disconnected.
+    pub fn screen(&self, cpu: &Cpu, tick_count: u64) -> Option<Screen> {
+        if self.video_map == 0 {
+            return None;
+        }
+        let read = |start: u16, len: u16, default: &[u16]| if start == 0 {
+            default.to_vec()
+        } else {
+            (0..len).map(|i| cpu.ram[start.wrapping_add(i) as usize]).collect()
+        };
+        let blinks = tick_count / self.timebase.period(BLINKS_PER_SECOND);
+        Some(Screen {
+            video: read(self.video_map, NB_CHARS, &[]),
+            font: read(self.font_map, FONT_SIZE, &DEFAULT_FONT),
+            palette: read(self.palette_map, PALETTE_SIZE, &DEFAULT_PALETTE),
+            border_color_index: self.border_color_index,
+            blink_on: blinks % 2 == 0,
+        })
+    }
 }
 
 impl Device for LEM1802 {
@@ -76,15 +516,30 @@ impl Device for LEM1802 {
         0x1c6c8b36
     }
 
+    fn name(&self) -> &str {
+        "LEM1802"
+    }
+
     fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
         let a = cpu.registers[0];
         let b = cpu.registers[1];
+        let dump = |cpu: &mut Cpu, rom: &[u16]| {
+            cpu.load(rom, b);
+            rom.len() as InterruptDelay
+        };
         match Command::from_u16(a) {
-            Some(Command::MEM_MAP_SCREEN) => self.video_map = Wrapping(b),
-            Some(Command::MEM_MAP_FONT) => self.font_map = Wrapping(b),
-            Some(Command::MEM_MAP_PALETTE) => self.palette_map = Wrapping(b),
-            Some(Command::SET_BORDER_COLOR) =>
-                self.border_color_index = b & MASK_INDEX,
+            Some(Command::MEM_MAP_SCREEN) => {
+                if b == 0 {
+                    // Redraw the whole screen once reconnected.
+                    self.dirty = DirtyTracker::new();
+                }
+                self.video_map = b;
+            }
+            Some(Command::MEM_MAP_FONT) => self.font_map = b,
+            Some(Command::MEM_MAP_PALETTE) => self.palette_map = b,
+            Some(Command::SET_BORDER_COLOR) => self.border_color_index = b & MASK_INDEX,
+            Some(Command::MEM_DUMP_FONT) => return Ok(dump(cpu, &DEFAULT_FONT)),
+            Some(Command::MEM_DUMP_PALETTE) => return Ok(dump(cpu, &DEFAULT_PALETTE)),
             None => return Err(()),
         }
         Ok(0)
@@ -92,71 +547,45 @@ impl Device for LEM1802 {
 
     fn tick(&mut self, cpu: &mut Cpu, tick_count: u64) -> TickResult {
         self.backend.tick(cpu, tick_count);
+        let frame = self.timebase.period(self.refresh.rate);
+        if self.timebase.is_due(tick_count, frame) && self.video_map != 0 && self.is_synced() {
+            if let Some(screen) = self.screen(cpu, tick_count) {
+                let dirty = self.dirty.update(&screen);
+                if !dirty.is_empty() {
+                    self.backend.frame(&screen, &dirty);
+                }
+            }
+        }
         TickResult::Nothing
     }
-}
 
-impl LEM1802 {
-    pub fn get_screen(&self, cpu: &Cpu) -> Screen {
-        let mut screen = [
-            Color::default();
-            (SCREEN_HEIGHT * SCREEN_WIDTH) as usize
-        ];
-        for offset in 0..NB_CHARS {
-            self.add_char(cpu, &mut screen, offset);
-        }
-        screen
-    }
-
-    fn add_char(&self, cpu: &Cpu, screen: &mut Screen, offset: u16) {
-        let video_word = self.get_video_word(cpu, offset);
-        let font_item = self.get_font(cpu, video_word.char_idx);
-        // x and y are coordinates from top left, but the font items have a different layout so we
-        // have to correct it.
-        for x in 0..CHAR_WIDTH {
-            for y in 0..CHAR_HEIGHT {
-                let bit = font_item >> (x * 8 + 7 - y) & 1;
-                let mut color = self.get_color(cpu, if bit == 0 {
-                    video_word.bg_idx
-                } else {
-                    video_word.fg_idx
-                });
-                color.blinking = video_word.blinking;
-
-                let idx = offset + x + (8 * y);
-                screen[idx as usize] = color;
-            }
-        }
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
     }
 
-    fn get_video_word(&self, cpu: &Cpu, offset: u16) -> VideoWord {
-        if self.video_map.0 == 0 {
-            unimplemented!()
-        } else {
-            let idx = self.video_map + Wrapping(offset);
-            VideoWord::from_packed(cpu.ram[idx.0 as usize])
+    /// The next frame.
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        match self.timebase.period(self.refresh.rate) {
+            0 => Some(current_tick),
+            frame => Some(self.timebase.next_due(current_tick, frame)),
         }
     }
 
-    fn get_font(&self, cpu: &Cpu, char_idx: u16) -> u32 {
-        if self.font_map.0 == 0 {
-            unimplemented!()
-        } else {
-            let idx = self.font_map + Wrapping(char_idx * 2);
-            let w0 = cpu.ram[idx.0 as usize];
-            let w1 = cpu.ram[idx.0 as usize + 1];
-            (w0 as u32) << 16 & w1 as u32
-        }
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.video_map, self.font_map, self.palette_map, self.border_color_index]
     }
 
-    fn get_color(&self, cpu: &Cpu, color_idx: u16) -> Color {
-        if self.palette_map.0 == 0 {
-            unimplemented!()
-        } else {
-            let idx = self.palette_map + Wrapping(color_idx);
-            let color = cpu.ram[idx.0 as usize];
-            Color::from_packed(color)
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 4 {
+            return Err(());
         }
+        self.video_map = state[0];
+        self.font_map = state[1];
+        self.palette_map = state[2];
+        self.border_color_index = state[3] & MASK_INDEX;
+        // Redraw the whole screen.
+        self.dirty = DirtyTracker::new();
+        Ok(())
     }
 }
 
@@ -171,9 +600,240 @@ impl VideoWord {
     fn from_packed(w: u16) -> VideoWord {
         VideoWord {
             char_idx: w & MASK_CHAR,
-            bg_idx: (w & MASK_COLOR_IDX) >> SHIFT_BG,
-            fg_idx: (w & MASK_COLOR_IDX) >> SHIFT_FG,
+            bg_idx: (w >> SHIFT_BG) & MASK_INDEX,
+            fg_idx: (w >> SHIFT_FG) & MASK_INDEX,
             blinking: (w & MASK_BLINKING) != 0,
         }
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_dirty() {
+    let mut screen = Screen {
+        video: vec![0; NB_CHARS as usize],
+        font: DEFAULT_FONT.to_vec(),
+        palette: DEFAULT_PALETTE.to_vec(),
+        border_color_index: 0,
+        blink_on: true,
+    };
+    let mut tracker = DirtyTracker::new();
+    let all = Rect {
+        x: 0,
+        y: 0,
+        width: COLUMNS,
+        height: ROWS,
+    };
+    assert_eq!(tracker.update(&screen), vec![all]);
+    assert_eq!(tracker.update(&screen), vec![]);
+
+    // Second and third characters of the second and third rows.
+    for &i in &[33, 34, 65, 66, 100] {
+        screen.video[i] = 0xf041;
+    }
+    assert_eq!(tracker.update(&screen),
+               vec![Rect {
+                        x: 1,
+                        y: 1,
+                        width: 2,
+                        height: 2,
+                    },
+                    Rect {
+                        x: 4,
+                        y: 3,
+                        width: 1,
+                        height: 1,
+                    }]);
+
+    // Only the blinking characters change with the blink phase.
+    screen.video[100] |= MASK_BLINKING;
+    tracker.update(&screen);
+    screen.blink_on = false;
+    assert_eq!(tracker.update(&screen),
+               vec![Rect {
+                        x: 4,
+                        y: 3,
+                        width: 1,
+                        height: 1,
+                    }]);
+
+    screen.palette[1] = 0xfff;
+    assert_eq!(tracker.update(&screen), vec![all]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_lem1802() {
+    #[derive(Debug)]
+    struct Frames(Rc<RefCell<Vec<(Screen, Vec<Rect>)>>>);
+
+    impl Backend for Frames {
+        fn tick(&mut self, _: &Cpu, _: u64) {}
+
+        fn frame(&mut self, screen: &Screen, dirty: &[Rect]) {
+            self.0.borrow_mut().push((screen.clone(), dirty.to_vec()));
+        }
+    }
+
+    let frames = Rc::new(RefCell::new(vec![]));
+    let last = Rc::new(RefCell::new(None));
+    let backend = LastFrame {
+        inner: Some(Box::new(Frames(frames.clone()))),
+        last: last.clone(),
+    };
+    let mut lem = LEM1802::with_timebase(Box::new(backend), Timebase::new(60));
+    let mut cpu = Cpu::default();
+    let hwi = |lem: &mut LEM1802, cpu: &mut Cpu, command: Command, b: u16| {
+        cpu.registers[..2].copy_from_slice(&[command as u16, b]);
+        lem.interrupt(cpu).unwrap()
+    };
+
+    assert_eq!(hwi(&mut lem, &mut cpu, Command::MEM_DUMP_FONT, 0x9000), 256);
+    assert_eq!(&cpu.ram[0x9000..0x9100], &DEFAULT_FONT[..]);
+    assert_eq!(hwi(&mut lem, &mut cpu, Command::MEM_DUMP_PALETTE, 0x9100), 16);
+    assert_eq!(&cpu.ram[0x9100..0x9110], &DEFAULT_PALETTE[..]);
+
+    lem.tick(&mut cpu, 0);
+    assert!(frames.borrow().is_empty());
+
+    // A blinking white '!' on blue, in the second column, with the default
+    // font and palette.
+    for word in &mut cpu.ram[0x8000..0x8180] {
+        *word = 0;
+    }
+    cpu.ram[0x8001] = 0xf100 | MASK_BLINKING | '!' as u16;
+    hwi(&mut lem, &mut cpu, Command::MEM_MAP_SCREEN, 0x8000);
+    hwi(&mut lem, &mut cpu, Command::SET_BORDER_COLOR, 0x4);
+    for tick in 0..60 {
+        lem.tick(&mut cpu, tick);
+    }
+    let frames = frames.borrow();
+    assert_eq!(frames.len(), 2);
+    let (ref screen, _) = frames[0];
+    assert_eq!(screen.border(), Color { r: 0xa, g: 0, b: 0 });
+    let white = Color { r: 0xf, g: 0xf, b: 0xf };
+    let blue = Color { r: 0, g: 0, b: 0xa };
+    let pixels = screen.pixels();
+    let column: Vec<Color> = (0..CHAR_HEIGHT)
+                                 .map(|y| pixels[(y * SCREEN_WIDTH + 5) as usize])
+                                 .collect();
+    assert_eq!(column, [white, white, white, white, white, blue, white, blue]);
+    assert_eq!(pixels[4], blue);
+    assert_eq!(pixels[0], Color::default());
+
+    let (ref screen, ref dirty) = frames[1];
+    assert!(!screen.blink_on);
+    assert_eq!(last.borrow().as_ref(), Some(screen));
+    assert_eq!(dirty,
+               &[Rect {
+                     x: 1,
+                     y: 0,
+                     width: 1,
+                     height: 1,
+                 }]);
+    assert!(screen.pixels()[5..8].iter().all(|&p| p == blue));
+}
+
+#[cfg(test)]
+#[test]
+fn test_refresh() {
+    use std::thread;
+
+    #[derive(Debug)]
+    struct Count(Rc<RefCell<u32>>);
+
+    impl Backend for Count {
+        fn tick(&mut self, _: &Cpu, _: u64) {}
+
+        fn frame(&mut self, _: &Screen, _: &[Rect]) {
+            *self.0.borrow_mut() += 1;
+        }
+    }
+
+    // A frame every 2 ticks, the characters changing at each one, but
+    // drawn at most every 50 ms of real time.
+    let frames = Rc::new(RefCell::new(0));
+    let refresh = Refresh {
+        rate: 20,
+        vsync: true,
+    };
+    let mut lem = LEM1802::with_refresh(Box::new(Count(frames.clone())),
+                                        Timebase::new(40),
+                                        refresh);
+    let mut cpu = Cpu::default();
+    cpu.registers[..2].copy_from_slice(&[Command::MEM_MAP_SCREEN as u16, 0x8000]);
+    lem.interrupt(&mut cpu).unwrap();
+    for tick in 0..8 {
+        cpu.ram[0x8000] = tick as u16;
+        lem.tick(&mut cpu, tick);
+    }
+    assert_eq!(*frames.borrow(), 1);
+    assert_eq!(lem.skipped_frames(), 3);
+    thread::sleep(Duration::from_millis(60));
+    lem.tick(&mut cpu, 8);
+    assert_eq!(*frames.borrow(), 2);
+
+    #[derive(Debug)]
+    struct Manual(Rc<RefCell<Duration>>);
+
+    impl HostClock for Manual {
+        fn now(&self) -> Duration {
+            *self.0.borrow()
+        }
+    }
+
+    let now = Rc::new(RefCell::new(Duration::from_secs(1)));
+    lem.set_clock(Box::new(Manual(now.clone())));
+    for tick in 10..14 {
+        cpu.ram[0x8000] = tick as u16;
+        lem.tick(&mut cpu, tick);
+        *now.borrow_mut() += Duration::from_millis(25);
+    }
+    assert_eq!(*frames.borrow(), 4);
+}
+
+#[cfg(test)]
+#[test]
+fn test_render() {
+    let mut screen = Screen {
+        video: vec![0; NB_CHARS as usize],
+        font: DEFAULT_FONT.to_vec(),
+        palette: DEFAULT_PALETTE.to_vec(),
+        border_color_index: 0,
+        blink_on: true,
+    };
+    screen.video[33] = 0xf100 | 'A' as u16;
+    screen.video[34] = 0xf100 | ' ' as u16;
+    let dirty = [Rect {
+                     x: 1,
+                     y: 1,
+                     width: 2,
+                     height: 1,
+                 }];
+    let image = screen.image(2);
+    assert_eq!((image.width, image.height), (272, 208));
+    assert_eq!(image.pixels[0], [0, 0, 0]);
+    // The top of the 'A', in the second column of the second character of
+    // the second row.
+    let top = (2 * (BORDER + CHAR_HEIGHT) as u32) * image.width +
+              2 * (BORDER + CHAR_WIDTH + 1) as u32;
+    assert_eq!(image.pixels[top as usize], [0xff, 0xff, 0xff]);
+    assert_eq!(image.pixels[top as usize + 1], [0xff, 0xff, 0xff]);
+    assert_eq!(image.pixels[top as usize - 1], [0, 0, 0xaa]);
+
+    let colors = "\x1b[38;2;255;255;255m\x1b[48;2;0;0;170m";
+    assert_eq!(ansi(&screen, &dirty),
+               format!("\x1b[3;3H{}A{} \x1b[0m\x1b[15;1H", colors, colors));
+
+    let mut term = TermBackend(vec![]);
+    let whole = [Rect {
+                     x: 0,
+                     y: 0,
+                     width: COLUMNS,
+                     height: ROWS,
+                 }];
+    term.frame(&screen, &whole);
+    let out = String::from_utf8(term.0).unwrap();
+    assert!(out.starts_with("\x1b[2J\x1b[48;2;0;0;0m\x1b[1;1H  "));
+    assert!(out.contains("\x1b[2;1H \x1b[34G "));
+}
@@ -1,23 +1,200 @@
+//! Hardware connected to the CPU.
+//!
+//! Besides the devices of this crate, embedders can implement `Device` for
+//! their own hardware and attach it with `Computer::add_device`,
+//! `set_device` or `plug_device`, without changes to this crate:
+//!
+//! ```
+//! use dcpu::computer::Computer;
+//! use dcpu::cpu::Cpu;
+//! use dcpu::device::{Device, InterruptDelay, TickResult};
+//! use dcpu::encodings::*;
+//! use dcpu::types::{Register, SpecialOp};
+//!
+//! /// Sets B to the number of `HWI` received.
+//! #[derive(Debug, Default)]
+//! struct Counter {
+//!     count: u16,
+//! }
+//!
+//! impl Device for Counter {
+//!     fn hardware_id(&self) -> u32 {
+//!         0x12345678
+//!     }
+//!
+//!     fn hardware_version(&self) -> u16 {
+//!         1
+//!     }
+//!
+//!     fn manufacturer(&self) -> u32 {
+//!         0x87654321
+//!     }
+//!
+//!     fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+//!         self.count += 1;
+//!         cpu.registers[Register::B as usize] = self.count;
+//!         Ok(0)
+//!     }
+//!
+//!     fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+//!         TickResult::Nothing
+//!     }
+//! }
+//!
+//! let mut cpu = Cpu::default();
+//! cpu.load(&[special(SpecialOp::HWI, lit(0)), special(SpecialOp::HWI, lit(0))], 0);
+//! let mut computer = Computer::new(cpu);
+//! computer.add_device(Box::new(Counter::default()));
+//! computer.step().unwrap();
+//! computer.step().unwrap();
+//! assert_eq!(computer.cpu().registers[Register::B as usize], 2);
+//! ```
+//!
+//! The methods with a default implementation are optional, new methods will
+//! have one so the existing devices keep building.
+
+#[cfg(feature = "devices-clock")]
 pub mod clock;
+#[cfg(feature = "devices-harness")]
+pub mod harness;
+#[cfg(feature = "devices-host")]
+pub mod host;
+pub mod jitter;
+#[cfg(feature = "devices-keyboard")]
 pub mod keyboard;
+#[cfg(feature = "devices-lem")]
 pub mod lem1802;
+pub mod registry;
+#[cfg(feature = "devices-remote")]
+pub mod remote;
+#[cfg(feature = "devices-rng")]
+pub mod rng;
+#[cfg(feature = "devices-serial")]
+pub mod serial;
+#[cfg(feature = "devices-speaker")]
+pub mod speaker;
+#[cfg(feature = "devices-sped3")]
+pub mod sped3;
+#[cfg(feature = "devices-timer")]
+pub mod timer;
 
 use std::fmt::Debug;
 
 use cpu::Cpu;
 
+/// What a device did during a tick.
 pub enum TickResult {
     Nothing,
+    /// Queues an interrupt with this message, see `Cpu::queue_interrupt`.
     Interrupt(u16),
 }
 
+/// Cycles a `HWI` takes besides its own 4.
 pub type InterruptDelay = u16;
 
+/// Device the program finds with `HWN` and `HWQ`, and talks to with `HWI`.
 pub trait Device: Debug {
+    /// Read by `HWQ` in A and B.
     fn hardware_id(&self) -> u32;
+    /// Read by `HWQ` in C.
     fn hardware_version(&self) -> u16;
+    /// Read by `HWQ` in X and Y.
     fn manufacturer(&self) -> u32;
+    /// Shown to the user, for example by `Computer::describe`.
+    fn name(&self) -> &str {
+        "unknown device"
+    }
 
+    /// Called by `HWI` with the index of this device. The commands usually
+    /// take their arguments from the registers and the memory of the CPU,
+    /// and write their results there.
+    ///
+    /// Fails the CPU with `cpu::Error::InterruptError` on `Err`, for example
+    /// on an unknown command.
     fn interrupt(&mut self, &mut Cpu) -> Result<InterruptDelay, ()>;
+    /// Called once per `Computer::tick`, after the CPU.
+    ///
+    /// Implementations should do their work here rather than in a separate
+    /// thread so the emulation stays deterministic.
     fn tick(&mut self, &mut Cpu, current_tick: u64) -> TickResult;
+
+    /// First tick from `current_tick` on at which `tick` may raise an
+    /// interrupt if the CPU does nothing, `None` if it never will.
+    ///
+    /// Lets `Computer::skip_idle` skip the ticks where a sleeping CPU has
+    /// nothing to do. Devices driven by the host, whose interrupts can't be
+    /// predicted, return `current_tick`.
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        Some(current_tick)
+    }
+
+    /// First tick from `current_tick` on at which `tick` has something to
+    /// do, `None` if nothing until the next `HWI`. Defaults to every tick.
+    ///
+    /// Lets `Computer::skip_waits` skip the ticks where the CPU waits for
+    /// the cycles of an instruction. `tick` is still called on the tick
+    /// before each instruction, so `HWI` sees the device up to date.
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        Some(current_tick)
+    }
+
+    /// State set by the program, saved by `Computer::save_state`. The
+    /// configuration and the host side, like a backend, aren't part of it.
+    fn save_state(&self) -> Vec<u16> {
+        vec![]
+    }
+
+    /// Restores what `save_state` returned, failing if it is malformed.
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.is_empty() { Ok(()) } else { Err(()) }
+    }
+}
+
+/// Appends `n` as 4 words, most significant first, for `save_state`.
+pub fn push_u64(state: &mut Vec<u16>, n: u64) {
+    state.extend((0..4).rev().map(|i| (n >> (16 * i)) as u16));
+}
+
+/// Reads the 4 words written by `push_u64`.
+pub fn read_u64(words: &[u16]) -> u64 {
+    words[..4].iter().fold(0, |acc, &w| acc << 16 | w as u64)
+}
+
+/// Slot without a device, keeping the indices of the next ones. `HWQ`
+/// reports it with null ids and `HWI` does nothing.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Empty;
+
+impl Device for Empty {
+    fn hardware_id(&self) -> u32 {
+        0
+    }
+
+    fn hardware_version(&self) -> u16 {
+        0
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0
+    }
+
+    fn name(&self) -> &str {
+        "empty slot"
+    }
+
+    fn interrupt(&mut self, _: &mut Cpu) -> Result<InterruptDelay, ()> {
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    fn next_tick(&self, _: u64) -> Option<u64> {
+        None
+    }
 }
@@ -0,0 +1,400 @@
+//! Devices built from a spec, like the `-d` option of the emulator:
+//! `kind[:argument][,option=value...]`, for example `lem1802,screen=term`
+//! or `remote:localhost:6000`.
+//!
+//! | Kind       | Argument       | Options                                 |
+//! |------------|----------------|-----------------------------------------|
+//! | `clock`    |                |                                         |
+//! | `empty`    |                |                                         |
+//! | `harness`  |                |                                         |
+//! | `host`     | root directory |                                         |
+//! | `keyboard` |                |                                         |
+//! | `lem1802`  |                | `screen=none\|term`, `refresh=<hz>`,    |
+//! |            |                | `vsync=on\|off`                         |
+//! | `remote`   | address        |                                         |
+//! | `rng`      |                | `seed=<n>`                              |
+//! | `serial`   | TCP port       |                                         |
+//! | `speaker`  | output file    |                                         |
+//! | `sped3`    |                |                                         |
+//! | `timer`    |                |                                         |
+//!
+//! The devices are built without a frontend: nobody types on the keyboard,
+//! the SPED-3 draws nowhere and the LEM1802 only draws in the terminal with
+//! `screen=term`. `empty` keeps a slot free, so the next devices get the
+//! indices a program expects. `rng` is seeded from the time unless given
+//! a seed, which makes it ignore the seeds of the program. A kind whose feature is disabled fails with
+//! `Error::Disabled`.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use device::{Device, Empty};
+use timebase::Timebase;
+
+/// Every kind of device, for help messages.
+pub const KINDS: [&'static str; 12] = ["clock", "empty", "harness", "host", "keyboard",
+                                       "lem1802", "remote", "rng", "serial", "speaker", "sped3",
+                                       "timer"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spec {
+    pub kind: String,
+    pub argument: Option<String>,
+    pub options: Vec<(String, String)>,
+}
+
+impl Spec {
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options.iter().find(|o| o.0 == name).map(|o| &o.1[..])
+    }
+
+    fn argument(&self) -> Result<&str, Error> {
+        self.argument.as_ref().map(|a| &a[..]).ok_or(Error::MissingArgument(self.kind.clone()))
+    }
+
+    /// Fails on the options not in `known`.
+    fn check_options(&self, known: &[&str]) -> Result<(), Error> {
+        match self.options.iter().find(|o| !known.contains(&&o.0[..])) {
+            Some(o) => Err(Error::InvalidOption(o.0.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Spec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Spec, Error> {
+        let mut parts = s.split(',');
+        let mut device = parts.next().unwrap().splitn(2, ':');
+        let kind = device.next().unwrap().trim();
+        if kind.is_empty() {
+            return Err(Error::Syntax(s.into()));
+        }
+        let mut options = vec![];
+        for option in parts {
+            let mut pair = option.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => {
+                    options.push((name.trim().into(), value.trim().into()))
+                }
+                _ => return Err(Error::Syntax(s.into())),
+            }
+        }
+        Ok(Spec {
+            kind: kind.into(),
+            argument: device.next().map(|a| a.trim().into()),
+            options: options,
+        })
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.kind));
+        if let Some(ref argument) = self.argument {
+            try!(write!(f, ":{}", argument));
+        }
+        for &(ref name, ref value) in &self.options {
+            try!(write!(f, ",{}={}", name, value));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Syntax(String),
+    UnknownKind(String),
+    /// The kind exists, but its feature is disabled.
+    Disabled(String),
+    MissingArgument(String),
+    /// Unknown option, or invalid value.
+    InvalidOption(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Syntax(ref s) => write!(f, "invalid device spec \"{}\"", s),
+            Error::UnknownKind(ref k) => {
+                write!(f, "unknown device \"{}\", expected one of {}", k, KINDS.join(", "))
+            }
+            Error::Disabled(ref k) => write!(f, "the {} device isn't built in", k),
+            Error::MissingArgument(ref k) => write!(f, "the {} device needs an argument", k),
+            Error::InvalidOption(ref o) => write!(f, "invalid option \"{}\"", o),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Syntax(_) => "invalid device spec",
+            Error::UnknownKind(_) => "unknown device",
+            Error::Disabled(_) => "device not built in",
+            Error::MissingArgument(_) => "missing argument",
+            Error::InvalidOption(_) => "invalid option",
+            Error::Io(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Builds the device of `spec` for a computer running at `timebase`.
+pub fn build(spec: &Spec, timebase: Timebase) -> Result<Box<Device>, Error> {
+    if spec.kind != "lem1802" && spec.kind != "rng" {
+        try!(spec.check_options(&[]));
+    }
+    match &spec.kind[..] {
+        "clock" => clock(timebase),
+        "empty" => Ok(Box::new(Empty)),
+        "harness" => harness(),
+        "host" => host(try!(spec.argument())),
+        "keyboard" => keyboard(),
+        "lem1802" => lem1802(spec, timebase),
+        "remote" => remote(try!(spec.argument()), timebase),
+        "rng" => rng(spec),
+        "serial" => serial(try!(spec.argument())),
+        "speaker" => speaker(try!(spec.argument()), timebase),
+        "sped3" => sped3(timebase),
+        "timer" => timer(),
+        kind => Err(Error::UnknownKind(kind.into())),
+    }
+}
+
+#[cfg(feature = "devices-clock")]
+fn clock(timebase: Timebase) -> Result<Box<Device>, Error> {
+    Ok(Box::new(::device::clock::Clock::with_timebase(timebase)))
+}
+
+#[cfg(not(feature = "devices-clock"))]
+fn clock(_: Timebase) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("clock".into()))
+}
+
+#[cfg(feature = "devices-harness")]
+fn harness() -> Result<Box<Device>, Error> {
+    Ok(Box::new(::device::harness::TestHarness::new()))
+}
+
+#[cfg(not(feature = "devices-harness"))]
+fn harness() -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("harness".into()))
+}
+
+#[cfg(feature = "devices-host")]
+fn host(root: &str) -> Result<Box<Device>, Error> {
+    Ok(Box::new(try!(::device::host::HostBridge::new(root))))
+}
+
+#[cfg(not(feature = "devices-host"))]
+fn host(_: &str) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("host".into()))
+}
+
+#[cfg(feature = "devices-keyboard")]
+fn keyboard() -> Result<Box<Device>, Error> {
+    use std::sync::mpsc;
+    use device::keyboard::{ChannelBackend, Keyboard};
+
+    let (_, events) = mpsc::channel();
+    Ok(Box::new(Keyboard::new(Box::new(ChannelBackend::new(events)))))
+}
+
+#[cfg(not(feature = "devices-keyboard"))]
+fn keyboard() -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("keyboard".into()))
+}
+
+#[cfg(feature = "devices-lem")]
+fn lem1802(spec: &Spec, timebase: Timebase) -> Result<Box<Device>, Error> {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use device::lem1802::{Backend, LastFrame, LEM1802, Refresh, TermBackend};
+
+    try!(spec.check_options(&["screen", "refresh", "vsync"]));
+    let invalid = |name: &str| {
+        Error::InvalidOption(format!("{}={}", name, spec.option(name).unwrap()))
+    };
+    let backend: Box<Backend> = match spec.option("screen") {
+        None | Some("none") => {
+            Box::new(LastFrame {
+                inner: None,
+                last: Rc::new(RefCell::new(None)),
+            })
+        }
+        Some("term") => Box::new(TermBackend(io::stdout())),
+        Some(_) => return Err(invalid("screen")),
+    };
+    let mut refresh = Refresh::default();
+    if let Some(rate) = spec.option("refresh") {
+        refresh.rate = try!(rate.parse().map_err(|_| invalid("refresh")));
+    }
+    refresh.vsync = match spec.option("vsync") {
+        None | Some("off") => false,
+        Some("on") => true,
+        Some(_) => return Err(invalid("vsync")),
+    };
+    Ok(Box::new(LEM1802::with_refresh(backend, timebase, refresh)))
+}
+
+#[cfg(not(feature = "devices-lem"))]
+fn lem1802(_: &Spec, _: Timebase) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("lem1802".into()))
+}
+
+#[cfg(feature = "devices-remote")]
+fn remote(addr: &str, timebase: Timebase) -> Result<Box<Device>, Error> {
+    use std::net::TcpStream;
+    use control;
+    use device::remote::RemoteDevice;
+
+    let stream = try!(TcpStream::connect(addr));
+    match RemoteDevice::with_timebase(stream, timebase) {
+        Ok(device) => Ok(Box::new(device)),
+        Err(control::Error::Io(e)) => Err(Error::Io(e)),
+        Err(e) => Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, e.to_string()))),
+    }
+}
+
+#[cfg(not(feature = "devices-remote"))]
+fn remote(_: &str, _: Timebase) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("remote".into()))
+}
+
+#[cfg(feature = "devices-rng")]
+fn rng(spec: &Spec) -> Result<Box<Device>, Error> {
+    use device::rng::Rng;
+
+    try!(spec.check_options(&["seed"]));
+    match spec.option("seed") {
+        Some(seed) => {
+            let seed = try!(seed.parse()
+                                .map_err(|_| Error::InvalidOption(format!("seed={}", seed))));
+            Ok(Box::new(Rng::with_seed(seed)))
+        }
+        None => Ok(Box::new(Rng::new())),
+    }
+}
+
+#[cfg(not(feature = "devices-rng"))]
+fn rng(_: &Spec) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("rng".into()))
+}
+
+#[cfg(feature = "devices-serial")]
+fn serial(port: &str) -> Result<Box<Device>, Error> {
+    use std::net::TcpListener;
+    use device::serial::{Serial, TcpBackend};
+
+    let port: u16 = try!(port.parse().map_err(|_| Error::Syntax(format!("serial:{}", port))));
+    let listener = try!(TcpListener::bind(("127.0.0.1", port)));
+    Ok(Box::new(Serial::new(Box::new(try!(TcpBackend::new(listener))))))
+}
+
+#[cfg(not(feature = "devices-serial"))]
+fn serial(_: &str) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("serial".into()))
+}
+
+#[cfg(feature = "devices-speaker")]
+fn speaker(path: &str, timebase: Timebase) -> Result<Box<Device>, Error> {
+    use std::fs::File;
+    use device::speaker::{PcmBackend, Speaker};
+
+    let output = io::BufWriter::new(try!(File::create(path)));
+    Ok(Box::new(Speaker::with_timebase(Box::new(PcmBackend(output)), timebase)))
+}
+
+#[cfg(not(feature = "devices-speaker"))]
+fn speaker(_: &str, _: Timebase) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("speaker".into()))
+}
+
+#[cfg(feature = "devices-sped3")]
+fn sped3(timebase: Timebase) -> Result<Box<Device>, Error> {
+    use device::sped3::{Backend, Sped3, Vertex};
+
+    #[derive(Debug)]
+    struct Discard;
+
+    impl Backend for Discard {
+        fn frame(&mut self, _: &[Vertex], _: f32) {}
+    }
+
+    Ok(Box::new(Sped3::with_timebase(Box::new(Discard), timebase)))
+}
+
+#[cfg(not(feature = "devices-sped3"))]
+fn sped3(_: Timebase) -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("sped3".into()))
+}
+
+#[cfg(feature = "devices-timer")]
+fn timer() -> Result<Box<Device>, Error> {
+    Ok(Box::new(::device::timer::Timer::new()))
+}
+
+#[cfg(not(feature = "devices-timer"))]
+fn timer() -> Result<Box<Device>, Error> {
+    Err(Error::Disabled("timer".into()))
+}
+
+#[cfg(test)]
+#[test]
+fn test_registry() {
+    let spec: Spec = "remote:localhost:6000".parse().unwrap();
+    assert_eq!(spec.argument.as_ref().map(|a| &a[..]), Some("localhost:6000"));
+    let spec: Spec = "lem1802, screen=none, refresh=30".parse().unwrap();
+    assert_eq!(spec.option("refresh"), Some("30"));
+    assert_eq!(spec.to_string(), "lem1802,screen=none,refresh=30");
+    assert!("lem1802,vsync".parse::<Spec>().is_err());
+    assert!(":x".parse::<Spec>().is_err());
+
+    let build = |s: &str| build(&s.parse().unwrap(), Timebase::default());
+    assert_eq!(build("empty").unwrap().hardware_id(), 0);
+    assert!(match build("m35fd:disk.img") {
+        Err(Error::UnknownKind(ref k)) => k == "m35fd",
+        _ => false,
+    });
+    assert!(match build("host") {
+        Err(Error::MissingArgument(_)) => true,
+        _ => false,
+    });
+    assert!(match build("empty,speed=2") {
+        Err(Error::InvalidOption(ref o)) => o == "speed",
+        _ => false,
+    });
+    if cfg!(feature = "devices-clock") {
+        assert_eq!(build("clock").unwrap().hardware_id(), 0x12d0b402);
+    }
+    if cfg!(feature = "devices-rng") {
+        assert_eq!(build("rng,seed=42").unwrap().save_state(),
+                   build("rng,seed=42").unwrap().save_state());
+        assert!(build("rng,seed=x").is_err());
+    }
+    if cfg!(feature = "devices-lem") {
+        assert_eq!(build("lem1802,refresh=30,vsync=on").unwrap().name(), "LEM1802");
+        assert!(match build("lem1802,screen=window") {
+            Err(Error::InvalidOption(ref o)) => o == "screen=window",
+            _ => false,
+        });
+    }
+}
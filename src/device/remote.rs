@@ -0,0 +1,376 @@
+//! Device running in another process, for example a prototype written in
+//! any language, attached over a stream such as a TCP connection.
+//!
+//! The messages are framed like those of `control`: their length in bytes
+//! as a big endian `u32`, then a tag byte and the fields, every number being
+//! a big endian `u16`. The emulator sends a `ToDevice` message and the
+//! device answers with `FromDevice` messages, reading and writing the
+//! memory until it ends the exchange:
+//!
+//! | `ToDevice`  | Tag | Fields                       | Ended by               |
+//! |-------------|-----|------------------------------|------------------------|
+//! | `Query`     | 0   |                              | `Info`                 |
+//! | `Interrupt` | 1   | A, B, C, I, J, X, Y, Z       | `InterruptDone`, `Error` |
+//! | `Tick`      | 2   | tick, 4 words                | `TickDone`             |
+//! | `Memory`    | 3   | words                        |                        |
+//!
+//! | `FromDevice`    | Tag | Fields                                           |
+//! |-----------------|-----|--------------------------------------------------|
+//! | `Info`          | 0   | hardware id and version, manufacturer, UTF-8 name |
+//! | `ReadMemory`    | 1   | address, length, answered by `Memory`            |
+//! | `WriteMemory`   | 2   | address, words                                   |
+//! | `InterruptDone` | 3   | extra cycles, A, B, C, I, J, X, Y, Z             |
+//! | `TickDone`      | 4   | interrupt message, 0 for none                    |
+//! | `Error`         | 5   | UTF-8 message, failing the CPU                   |
+//!
+//! The ids are 2 words each, most significant first. `Query` is sent once
+//! when connecting, and `Tick` `TICKS_PER_SECOND` times per emulated second.
+//! After an error of the stream, the device is disconnected: its interrupts
+//! fail the CPU and it doesn't tick anymore.
+
+use std::io::{Read, Write};
+
+use control::{Error, push_words, read_frame, words, write_frame};
+use cpu::Cpu;
+use device::*;
+use timebase::Timebase;
+
+/// Exchanges of `Tick` per emulated second.
+pub const TICKS_PER_SECOND: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ToDevice {
+    Query,
+    /// `HWI`, with the registers.
+    Interrupt([u16; 8]),
+    Tick(u64),
+    /// Answers `FromDevice::ReadMemory`.
+    Memory(Vec<u16>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FromDevice {
+    Info {
+        hardware_id: u32,
+        version: u16,
+        manufacturer: u32,
+        name: String,
+    },
+    ReadMemory(u16, u16),
+    WriteMemory(u16, Vec<u16>),
+    /// The cycles the interrupt takes besides `HWI`, and the new registers.
+    InterruptDone(u16, [u16; 8]),
+    TickDone(u16),
+    Error(String),
+}
+
+impl ToDevice {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match *self {
+            ToDevice::Query => bytes.push(0),
+            ToDevice::Interrupt(ref registers) => {
+                bytes.push(1);
+                push_words(&mut bytes, registers);
+            }
+            ToDevice::Tick(tick) => {
+                bytes.push(2);
+                let mut fields = vec![];
+                push_u64(&mut fields, tick);
+                push_words(&mut bytes, &fields);
+            }
+            ToDevice::Memory(ref data) => {
+                bytes.push(3);
+                push_words(&mut bytes, data);
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<ToDevice, Error> {
+        let (&tag, fields) = try!(bytes.split_first().ok_or(Error::Protocol));
+        let fields = try!(words(fields));
+        Ok(match (tag, fields.len()) {
+            (0, 0) => ToDevice::Query,
+            (1, 8) => {
+                let mut registers = [0; 8];
+                registers.copy_from_slice(&fields);
+                ToDevice::Interrupt(registers)
+            }
+            (2, 4) => ToDevice::Tick(read_u64(&fields)),
+            (3, _) => ToDevice::Memory(fields),
+            _ => return Err(Error::Protocol),
+        })
+    }
+}
+
+impl FromDevice {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        match *self {
+            FromDevice::Info { hardware_id, version, manufacturer, ref name } => {
+                bytes.push(0);
+                push_words(&mut bytes,
+                           &[(hardware_id >> 16) as u16,
+                             hardware_id as u16,
+                             version,
+                             (manufacturer >> 16) as u16,
+                             manufacturer as u16]);
+                bytes.extend(name.as_bytes());
+            }
+            FromDevice::ReadMemory(addr, len) => {
+                bytes.push(1);
+                push_words(&mut bytes, &[addr, len]);
+            }
+            FromDevice::WriteMemory(addr, ref data) => {
+                bytes.push(2);
+                push_words(&mut bytes, &[addr]);
+                push_words(&mut bytes, data);
+            }
+            FromDevice::InterruptDone(delay, ref registers) => {
+                bytes.push(3);
+                push_words(&mut bytes, &[delay]);
+                push_words(&mut bytes, registers);
+            }
+            FromDevice::TickDone(msg) => {
+                bytes.push(4);
+                push_words(&mut bytes, &[msg]);
+            }
+            FromDevice::Error(ref e) => {
+                bytes.push(5);
+                bytes.extend(e.as_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<FromDevice, Error> {
+        let (&tag, fields) = try!(bytes.split_first().ok_or(Error::Protocol));
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| Error::Protocol);
+        match tag {
+            0 if fields.len() >= 10 => {
+                let ids = try!(words(&fields[..10]));
+                return Ok(FromDevice::Info {
+                    hardware_id: (ids[0] as u32) << 16 | ids[1] as u32,
+                    version: ids[2],
+                    manufacturer: (ids[3] as u32) << 16 | ids[4] as u32,
+                    name: try!(text(&fields[10..])),
+                });
+            }
+            5 => return Ok(FromDevice::Error(try!(text(fields)))),
+            _ => (),
+        }
+        let fields = try!(words(fields));
+        Ok(match (tag, fields.len()) {
+            (1, 2) => FromDevice::ReadMemory(fields[0], fields[1]),
+            (2, n) if n > 0 => FromDevice::WriteMemory(fields[0], fields[1..].to_vec()),
+            (3, 9) => {
+                let mut registers = [0; 8];
+                registers.copy_from_slice(&fields[1..]);
+                FromDevice::InterruptDone(fields[0], registers)
+            }
+            (4, 1) => FromDevice::TickDone(fields[0]),
+            _ => return Err(Error::Protocol),
+        })
+    }
+}
+
+/// Emulator side of the protocol.
+#[derive(Debug)]
+pub struct RemoteDevice<S> {
+    /// `None` once disconnected.
+    stream: Option<S>,
+    hardware_id: u32,
+    version: u16,
+    manufacturer: u32,
+    name: String,
+    timebase: Timebase,
+}
+
+impl<S: Read + Write> RemoteDevice<S> {
+    /// Queries the device on `stream`.
+    pub fn new(stream: S) -> Result<RemoteDevice<S>, Error> {
+        RemoteDevice::with_timebase(stream, Timebase::default())
+    }
+
+    pub fn with_timebase(mut stream: S, timebase: Timebase) -> Result<RemoteDevice<S>, Error> {
+        try!(write_frame(&mut stream, &ToDevice::Query.encode()));
+        match try!(FromDevice::decode(&try!(read_frame(&mut stream)))) {
+            FromDevice::Info { hardware_id, version, manufacturer, name } => {
+                Ok(RemoteDevice {
+                    stream: Some(stream),
+                    hardware_id: hardware_id,
+                    version: version,
+                    manufacturer: manufacturer,
+                    name: name,
+                    timebase: timebase,
+                })
+            }
+            _ => Err(Error::Protocol),
+        }
+    }
+
+    /// Sends `message`, then handles the memory requests of the device until
+    /// it ends the exchange, and returns the message ending it.
+    /// Disconnects on errors.
+    fn exchange(&mut self, cpu: &mut Cpu, message: ToDevice) -> Option<FromDevice> {
+        let res = match self.stream {
+            Some(ref mut stream) => exchange(stream, cpu, message),
+            None => return None,
+        };
+        match res {
+            Ok(end) => Some(end),
+            Err(e) => {
+                warn!("Remote device {} disconnected: {}", self.name, e);
+                self.stream = None;
+                None
+            }
+        }
+    }
+}
+
+fn exchange<S: Read + Write>(stream: &mut S,
+                             cpu: &mut Cpu,
+                             message: ToDevice)
+                             -> Result<FromDevice, Error> {
+    try!(write_frame(stream, &message.encode()));
+    loop {
+        match try!(FromDevice::decode(&try!(read_frame(stream)))) {
+            FromDevice::ReadMemory(addr, len) => {
+                let data = (0..len).map(|i| cpu.ram[addr.wrapping_add(i) as usize]).collect();
+                try!(write_frame(stream, &ToDevice::Memory(data).encode()));
+            }
+            FromDevice::WriteMemory(addr, data) => cpu.load(&data, addr),
+            FromDevice::Info { .. } => return Err(Error::Protocol),
+            end => return Ok(end),
+        }
+    }
+}
+
+impl<S: Read + Write + Debug> Device for RemoteDevice<S> {
+    fn hardware_id(&self) -> u32 {
+        self.hardware_id
+    }
+
+    fn hardware_version(&self) -> u16 {
+        self.version
+    }
+
+    fn manufacturer(&self) -> u32 {
+        self.manufacturer
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let registers = cpu.registers;
+        match self.exchange(cpu, ToDevice::Interrupt(registers)) {
+            Some(FromDevice::InterruptDone(delay, registers)) => {
+                cpu.registers = registers;
+                Ok(delay)
+            }
+            Some(FromDevice::Error(e)) => {
+                warn!("Remote device {}: {}", self.name, e);
+                Err(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn tick(&mut self, cpu: &mut Cpu, tick_count: u64) -> TickResult {
+        let period = self.timebase.period(TICKS_PER_SECOND);
+        if self.stream.is_none() || !self.timebase.is_due(tick_count, period) {
+            return TickResult::Nothing;
+        }
+        match self.exchange(cpu, ToDevice::Tick(tick_count)) {
+            Some(FromDevice::TickDone(msg)) if msg != 0 => TickResult::Interrupt(msg),
+            _ => TickResult::Nothing,
+        }
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        match self.stream {
+            Some(_) => {
+                let period = self.timebase.period(TICKS_PER_SECOND);
+                Some(self.timebase.next_due(current_tick, period))
+            }
+            None => None,
+        }
+    }
+
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        self.next_interrupt(current_tick)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_remote() {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // Adds B to the word at A, and interrupts with the sum at each tick.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let device = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let receive = |stream: &mut TcpStream| {
+            ToDevice::decode(&read_frame(stream).unwrap()).unwrap()
+        };
+        let send = |stream: &mut TcpStream, message: FromDevice| {
+            write_frame(stream, &message.encode()).unwrap();
+        };
+        assert_eq!(receive(&mut stream), ToDevice::Query);
+        send(&mut stream,
+             FromDevice::Info {
+                 hardware_id: 0x12345678,
+                 version: 2,
+                 manufacturer: 0x9abcdef0,
+                 name: "adder".into(),
+             });
+        let mut sum = 0;
+        loop {
+            match receive(&mut stream) {
+                ToDevice::Interrupt(mut registers) => {
+                    send(&mut stream, FromDevice::ReadMemory(registers[0], 1));
+                    sum = match receive(&mut stream) {
+                        ToDevice::Memory(ref data) => data[0] + registers[1],
+                        m => panic!("{:?}", m),
+                    };
+                    send(&mut stream, FromDevice::WriteMemory(registers[0], vec![sum]));
+                    registers[2] = 1;
+                    send(&mut stream, FromDevice::InterruptDone(3, registers));
+                }
+                ToDevice::Tick(_) => {
+                    send(&mut stream, FromDevice::TickDone(sum));
+                    return;
+                }
+                m => panic!("{:?}", m),
+            }
+        }
+    });
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut remote = RemoteDevice::with_timebase(stream, Timebase::new(60)).unwrap();
+    assert_eq!((remote.hardware_id(), remote.hardware_version(), remote.manufacturer()),
+               (0x12345678, 2, 0x9abcdef0));
+    assert_eq!(remote.name(), "adder");
+
+    let mut cpu = Cpu::default();
+    cpu.ram[0x1000] = 40;
+    cpu.registers[..2].copy_from_slice(&[0x1000, 2]);
+    assert_eq!(remote.interrupt(&mut cpu), Ok(3));
+    assert_eq!(cpu.ram[0x1000], 42);
+    assert_eq!(cpu.registers[2], 1);
+    assert!(match remote.tick(&mut cpu, 0) {
+        TickResult::Interrupt(42) => true,
+        _ => false,
+    });
+    device.join().unwrap();
+
+    // The device closed the connection.
+    assert_eq!(remote.interrupt(&mut cpu), Err(()));
+    assert!(remote.next_interrupt(1).is_none());
+}
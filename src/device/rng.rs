@@ -0,0 +1,166 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    SEED = 0x0,
+    READ = 0x1,
+}
+}
+
+/// Pseudo-random number generator.
+///
+/// - `SEED`: restarts the generator from the 32 bit seed B:C, B being the
+///   high word.
+/// - `READ`: sets C to the next random word.
+///
+/// Built with `with_seed`, the generator starts from a fixed seed and
+/// ignores `SEED`, so a program produces the same numbers on every run even
+/// if it seeds from the clock: a test or a bug report can be replayed
+/// exactly by reusing the seed. `new` seeds from the time and logs the
+/// seed.
+#[derive(Debug)]
+pub struct Rng {
+    state: u64,
+    /// Ignore `SEED`.
+    pub fixed: bool,
+}
+
+impl Default for Rng {
+    fn default() -> Rng {
+        Rng::new()
+    }
+}
+
+impl Rng {
+    /// Seeded from the time, the program can reseed it.
+    pub fn new() -> Rng {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH)
+                                  .unwrap_or(Duration::from_secs(0));
+        let seed = since_epoch.as_secs() ^ (since_epoch.subsec_nanos() as u64) << 32;
+        info!("RNG seeded with {}, use rng,seed={} to replay", seed, seed);
+        let mut rng = Rng::with_seed(seed);
+        rng.fixed = false;
+        rng
+    }
+
+    /// Deterministic, see `fixed`.
+    pub fn with_seed(seed: u64) -> Rng {
+        let mut rng = Rng {
+            state: 0,
+            fixed: true,
+        };
+        rng.seed(seed);
+        rng
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        // xorshift gets stuck on 0.
+        self.state = seed ^ 0x9e37_79b9_7f4a_7c15;
+    }
+
+    /// xorshift64*, keeping the high bits which are the most random.
+    pub fn next_word(&mut self) -> u16 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 48) as u16
+    }
+}
+
+impl Device for Rng {
+    fn hardware_id(&self) -> u32 {
+        0x5eed0001
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Random Number Generator"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let b = cpu.registers[1];
+        let c = cpu.registers[2];
+        match Command::from_u16(a) {
+            Some(Command::SEED) => {
+                if !self.fixed {
+                    self.seed((b as u64) << 16 | c as u64);
+                }
+            }
+            Some(Command::READ) => cpu.registers[2] = self.next_word(),
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    fn next_tick(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        let mut state = vec![];
+        push_u64(&mut state, self.state);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 4 {
+            return Err(());
+        }
+        self.state = read_u64(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rng() {
+    let mut cpu = Cpu::default();
+    let mut hwi = |rng: &mut Rng, registers: [u16; 3]| {
+        cpu.registers[..3].copy_from_slice(&registers);
+        rng.interrupt(&mut cpu).unwrap();
+        cpu.registers[2]
+    };
+    let read = |hwi: &mut FnMut(&mut Rng, [u16; 3]) -> u16, rng: &mut Rng| {
+        (0..10).map(|_| hwi(rng, [Command::READ as u16, 0, 0])).collect::<Vec<_>>()
+    };
+
+    let mut a = Rng::with_seed(42);
+    let words = read(&mut hwi, &mut a);
+    assert_eq!(words, read(&mut hwi, &mut Rng::with_seed(42)));
+    assert!(words.iter().any(|&w| w != words[0]));
+
+    // Replayed from a saved state, SEED being ignored.
+    let state = a.save_state();
+    hwi(&mut a, [Command::SEED as u16, 0, 7]);
+    let next = read(&mut hwi, &mut a);
+    let mut b = Rng::with_seed(1);
+    b.load_state(&state).unwrap();
+    assert_eq!(read(&mut hwi, &mut b), next);
+
+    b.fixed = false;
+    hwi(&mut b, [Command::SEED as u16, 0, 42]);
+    assert_eq!(read(&mut hwi, &mut b), words);
+}
@@ -0,0 +1,356 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+use taint::{Location, Source};
+use types::Register;
+
+/// Words kept in each direction before the new ones are dropped.
+const BUFFER_SIZE: usize = 256;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    SET_INT = 0x0,
+    RECEIVE = 0x1,
+    SEND = 0x2,
+}
+}
+
+/// Word oriented serial port.
+///
+/// - `SET_INT`: interrupts with message B when a word is received, disabled
+///   if B is 0.
+/// - `RECEIVE`: sets C to the oldest received word and B to 1, or both to
+///   0 if there is none.
+/// - `SEND`: sends B.
+#[derive(Debug)]
+pub struct Serial {
+    received: VecDeque<u16>,
+    int_msg: u16,
+    backend: Box<Backend>,
+}
+
+impl Serial {
+    pub fn new(backend: Box<Backend>) -> Serial {
+        Serial {
+            received: VecDeque::new(),
+            int_msg: 0,
+            backend: backend,
+        }
+    }
+}
+
+impl Device for Serial {
+    fn hardware_id(&self) -> u32 {
+        0xe57d9027
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Serial Port"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let b = cpu.registers[1];
+        match Command::from_u16(a) {
+            Some(Command::SET_INT) => self.int_msg = b,
+            Some(Command::RECEIVE) => {
+                let word = self.received.pop_front();
+                cpu.registers[1] = word.is_some() as u16;
+                cpu.registers[2] = word.unwrap_or(0);
+                cpu.input(Location::Reg(Register::C), Source::Serial);
+            }
+            Some(Command::SEND) => self.backend.send(b),
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        let mut received = false;
+        while let Some(word) = self.backend.receive() {
+            if self.received.len() < BUFFER_SIZE {
+                self.received.push_back(word);
+                received = true;
+            }
+        }
+        if received && self.int_msg != 0 {
+            TickResult::Interrupt(self.int_msg)
+        } else {
+            TickResult::Nothing
+        }
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        if self.int_msg != 0 {
+            Some(current_tick)
+        } else {
+            None
+        }
+    }
+
+    /// The interrupt message, then the words received and not read yet.
+    fn save_state(&self) -> Vec<u16> {
+        let mut state = vec![self.int_msg];
+        state.extend(self.received.iter());
+        state
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        match state.split_first() {
+            Some((&int_msg, received)) if received.len() <= BUFFER_SIZE => {
+                self.int_msg = int_msg;
+                self.received = received.iter().cloned().collect();
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+/// The other end of the port.
+pub trait Backend: Debug {
+    /// Next word sent to the computer, without blocking.
+    fn receive(&mut self) -> Option<u16>;
+    fn send(&mut self, word: u16);
+}
+
+/// Serial port exposed as a TCP server accepting one client at a time.
+///
+/// Words are little endian, like the binaries. Everything is non-blocking:
+/// the words the client doesn't read fast enough are dropped.
+#[derive(Debug)]
+pub struct TcpBackend {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    /// First byte of a word being received.
+    low: Option<u8>,
+    output: VecDeque<u8>,
+}
+
+impl TcpBackend {
+    pub fn new(listener: TcpListener) -> io::Result<TcpBackend> {
+        try!(listener.set_nonblocking(true));
+        Ok(TcpBackend {
+            listener: listener,
+            client: None,
+            low: None,
+            output: VecDeque::new(),
+        })
+    }
+
+    fn accept(&mut self) {
+        if self.client.is_none() {
+            if let Ok((stream, _)) = self.listener.accept() {
+                if stream.set_nonblocking(true).is_ok() {
+                    self.client = Some(stream);
+                    self.low = None;
+                    self.output.clear();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let written = match self.client {
+            Some(ref mut client) if !self.output.is_empty() => {
+                let (bytes, _) = self.output.as_slices();
+                client.write(bytes)
+            }
+            _ => return,
+        };
+        match written {
+            Ok(n) => {
+                self.output.drain(..n);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(_) => self.client = None,
+        }
+    }
+}
+
+impl Backend for TcpBackend {
+    fn receive(&mut self) -> Option<u16> {
+        self.accept();
+        self.flush();
+        loop {
+            let mut byte = [0];
+            let read = match self.client {
+                Some(ref mut client) => client.read(&mut byte),
+                None => return None,
+            };
+            match read {
+                Ok(1) => {
+                    match self.low.take() {
+                        Some(low) => return Some(low as u16 | (byte[0] as u16) << 8),
+                        None => self.low = Some(byte[0]),
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                // Disconnected.
+                _ => {
+                    self.client = None;
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, word: u16) {
+        if self.client.is_some() && self.output.len() < 2 * BUFFER_SIZE {
+            self.output.push_back(word as u8);
+            self.output.push_back((word >> 8) as u8);
+        }
+    }
+}
+
+/// One end of a link between two serial ports, for example of the
+/// computers of a `network::Network`, see `link`.
+#[derive(Debug)]
+pub struct LinkBackend {
+    tx: Sender<u16>,
+    rx: Receiver<u16>,
+}
+
+/// Two backends connected to each other, each receiving the words sent by
+/// the other. The words sent once the other end is dropped are lost.
+pub fn link() -> (LinkBackend, LinkBackend) {
+    let (tx_a, rx_a) = mpsc::channel();
+    let (tx_b, rx_b) = mpsc::channel();
+    (LinkBackend {
+        tx: tx_a,
+        rx: rx_b,
+    },
+     LinkBackend {
+        tx: tx_b,
+        rx: rx_a,
+    })
+}
+
+impl Backend for LinkBackend {
+    fn receive(&mut self) -> Option<u16> {
+        self.rx.try_recv().ok()
+    }
+
+    fn send(&mut self, word: u16) {
+        let _ = self.tx.send(word);
+    }
+}
+
+/// Serial port on a console: the low byte of each word sent is written to
+/// `W`, usually stdout, and each byte read from the input is received as a
+/// word. The input is read by a thread, until its end or an error.
+#[derive(Debug)]
+pub struct ConsoleBackend<W> {
+    input: Receiver<u8>,
+    output: W,
+}
+
+impl<W: Write> ConsoleBackend<W> {
+    pub fn new<R: Read + Send + 'static>(input: R, output: W) -> ConsoleBackend<W> {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in input.bytes() {
+                match byte {
+                    Ok(byte) if tx.send(byte).is_ok() => (),
+                    _ => break,
+                }
+            }
+        });
+        ConsoleBackend {
+            input: rx,
+            output: output,
+        }
+    }
+}
+
+impl ConsoleBackend<io::Stdout> {
+    /// On stdin and stdout. The terminal should not buffer the lines nor
+    /// echo the keys, for example after `stty -icanon -echo`, for the
+    /// program to get each key as it is typed.
+    pub fn stdio() -> ConsoleBackend<io::Stdout> {
+        ConsoleBackend::new(io::stdin(), io::stdout())
+    }
+}
+
+impl<W: Write + Debug> Backend for ConsoleBackend<W> {
+    fn receive(&mut self) -> Option<u16> {
+        self.input.try_recv().ok().map(|byte| byte as u16)
+    }
+
+    fn send(&mut self, word: u16) {
+        let _ = self.output.write_all(&[word as u8]);
+        let _ = self.output.flush();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_serial() {
+    #[derive(Debug)]
+    struct Loopback(VecDeque<u16>);
+
+    impl Backend for Loopback {
+        fn receive(&mut self) -> Option<u16> {
+            self.0.pop_front()
+        }
+
+        fn send(&mut self, word: u16) {
+            self.0.push_back(word + 1);
+        }
+    }
+
+    let mut cpu = Cpu::default();
+    let mut serial = Serial::new(Box::new(Loopback(VecDeque::new())));
+    cpu.registers[..2].copy_from_slice(&[Command::SET_INT as u16, 5]);
+    serial.interrupt(&mut cpu).unwrap();
+    cpu.registers[..2].copy_from_slice(&[Command::SEND as u16, 41]);
+    serial.interrupt(&mut cpu).unwrap();
+    assert!(match serial.tick(&mut cpu, 0) {
+        TickResult::Interrupt(5) => true,
+        _ => false,
+    });
+    cpu.registers[..2].copy_from_slice(&[Command::RECEIVE as u16, 0]);
+    serial.interrupt(&mut cpu).unwrap();
+    assert_eq!(&cpu.registers[1..3], &[1, 42]);
+    cpu.registers[..2].copy_from_slice(&[Command::RECEIVE as u16, 0]);
+    serial.interrupt(&mut cpu).unwrap();
+    assert_eq!(&cpu.registers[1..3], &[0, 0]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_console() {
+    use std::time::Duration;
+
+    let mut console = ConsoleBackend::new(&b"hi"[..], vec![]);
+    console.send('o' as u16);
+    console.send(0x100 | 'k' as u16);
+    assert_eq!(console.output, b"ok");
+    let mut received = vec![];
+    for _ in 0..100 {
+        match console.receive() {
+            Some(word) => received.push(word),
+            None if received.len() == 2 => break,
+            None => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+    assert_eq!(received, ['h' as u16, 'i' as u16]);
+}
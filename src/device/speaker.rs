@@ -0,0 +1,205 @@
+use std::fmt::Debug;
+use std::io::Write;
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+use timebase::Timebase;
+
+/// Samples per second of the sound.
+pub const SAMPLE_RATE: u64 = 8000;
+/// Samples given to the backend at once, 20 ms.
+const BUFFER_SIZE: usize = SAMPLE_RATE as usize / 50;
+/// Amplitude of each channel, the sum fitting in an `i8`.
+const AMPLITUDE: i8 = 63;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    SET_CHANNEL_1 = 0x0,
+    SET_CHANNEL_2 = 0x1,
+}
+}
+
+/// Plays the sound, for example through the audio output of the host.
+pub trait Backend: Debug {
+    /// Called with every `BUFFER_SIZE` samples, signed, at `SAMPLE_RATE`.
+    fn play(&mut self, samples: &[i8]);
+}
+
+/// Writes the samples to `W` as raw signed 8 bit mono, which
+/// `aplay -f S8 -r 8000` plays. Write errors are ignored, the program
+/// running the same without sound.
+#[derive(Debug)]
+pub struct PcmBackend<W>(pub W);
+
+impl<W: Write + Debug> Backend for PcmBackend<W> {
+    fn play(&mut self, samples: &[i8]) {
+        let bytes: Vec<u8> = samples.iter().map(|&s| s as u8).collect();
+        let _ = self.0.write_all(&bytes);
+    }
+}
+
+/// Sum of two square waves.
+#[derive(Debug, Default, Clone)]
+pub struct SquareWave {
+    /// In hertz, silent if 0.
+    pub frequencies: [u16; 2],
+    /// Position in the period of each channel, in `SAMPLE_RATE`ths.
+    phases: [u64; 2],
+}
+
+impl SquareWave {
+    pub fn next_sample(&mut self) -> i8 {
+        let mut sample = 0;
+        for (&frequency, phase) in self.frequencies.iter().zip(self.phases.iter_mut()) {
+            if frequency == 0 {
+                continue;
+            }
+            sample += if *phase < SAMPLE_RATE / 2 { AMPLITUDE } else { -AMPLITUDE };
+            *phase = (*phase + frequency as u64) % SAMPLE_RATE;
+        }
+        sample
+    }
+}
+
+/// Speaker with two channels playing square waves.
+///
+/// - `SET_CHANNEL_1`: plays B hertz on the first channel, nothing if B is 0.
+/// - `SET_CHANNEL_2`: same on the second channel.
+///
+/// Frequencies above 4 kHz, half the sample rate, are aliased.
+#[derive(Debug)]
+pub struct Speaker {
+    wave: SquareWave,
+    buffer: Vec<i8>,
+    timebase: Timebase,
+    backend: Box<Backend>,
+}
+
+impl Speaker {
+    pub fn new(backend: Box<Backend>) -> Speaker {
+        Speaker::with_timebase(backend, Timebase::default())
+    }
+
+    pub fn with_timebase(backend: Box<Backend>, timebase: Timebase) -> Speaker {
+        Speaker {
+            wave: SquareWave::default(),
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            timebase: timebase,
+            backend: backend,
+        }
+    }
+}
+
+impl Device for Speaker {
+    fn hardware_id(&self) -> u32 {
+        0xc0f00001
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Speaker"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let b = cpu.registers[1];
+        match Command::from_u16(a) {
+            Some(Command::SET_CHANNEL_1) => self.wave.frequencies[0] = b,
+            Some(Command::SET_CHANNEL_2) => self.wave.frequencies[1] = b,
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, tick_count: u64) -> TickResult {
+        let period = self.timebase.period(SAMPLE_RATE);
+        if self.timebase.is_due(tick_count, period) {
+            let sample = self.wave.next_sample();
+            self.buffer.push(sample);
+            if self.buffer.len() == BUFFER_SIZE {
+                self.backend.play(&self.buffer);
+                self.buffer.clear();
+            }
+        }
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    /// The next sample.
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        match self.timebase.period(SAMPLE_RATE) {
+            0 => Some(current_tick),
+            period => Some(self.timebase.next_due(current_tick, period)),
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        self.wave.frequencies.to_vec()
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 2 {
+            return Err(());
+        }
+        self.wave = SquareWave::default();
+        self.wave.frequencies.copy_from_slice(state);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_speaker() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug)]
+    struct Samples(Rc<RefCell<Vec<i8>>>);
+
+    impl Backend for Samples {
+        fn play(&mut self, samples: &[i8]) {
+            self.0.borrow_mut().extend_from_slice(samples);
+        }
+    }
+
+    let samples = Rc::new(RefCell::new(vec![]));
+    let backend = Box::new(Samples(samples.clone()));
+    let mut speaker = Speaker::with_timebase(backend, Timebase::new(2 * SAMPLE_RATE));
+    let mut cpu = Cpu::default();
+    cpu.registers[..2].copy_from_slice(&[Command::SET_CHANNEL_1 as u16, 1000]);
+    speaker.interrupt(&mut cpu).unwrap();
+    cpu.registers[..2].copy_from_slice(&[Command::SET_CHANNEL_2 as u16, 2000]);
+    speaker.interrupt(&mut cpu).unwrap();
+    // A sample every 2 ticks, the last one of the buffer at the tick 318.
+    let last = 2 * BUFFER_SIZE as u64 - 2;
+    for tick in 0..last {
+        speaker.tick(&mut cpu, tick);
+    }
+    assert!(samples.borrow().is_empty());
+    speaker.tick(&mut cpu, last);
+
+    // 8 samples per period of the first channel, 4 of the second one.
+    let samples = samples.borrow();
+    assert_eq!(samples.len(), BUFFER_SIZE);
+    assert_eq!(&samples[..8], &[126, 126, 0, 0, 0, 0, -126, -126]);
+    assert_eq!(&samples[8..16], &samples[..8]);
+
+    assert_eq!(speaker.save_state(), [1000, 2000]);
+    let mut pcm = PcmBackend(vec![]);
+    pcm.play(&[1, -1]);
+    assert_eq!(pcm.0, [1, 0xff]);
+}
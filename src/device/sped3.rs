@@ -0,0 +1,298 @@
+use std::f32::consts::PI;
+use std::fmt::Debug;
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+use timebase::Timebase;
+
+/// Vertices drawn at most.
+const MAX_VERTICES: u16 = 128;
+const FRAMES_PER_SECOND: u64 = 60;
+/// Rotation speed, in thousandths of a degree per frame: 50 degrees per
+/// second.
+const ROTATION_STEP: u32 = 50000 / FRAMES_PER_SECOND as u32;
+const FULL_TURN: u32 = 360000;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    POLL_DEVICE = 0x0,
+    MAP_REGION = 0x1,
+    ROTATE_DEVICE = 0x2,
+}
+}
+
+/// States reported by `POLL_DEVICE` in B.
+const STATE_NO_DATA: u16 = 0;
+const STATE_RUNNING: u16 = 1;
+const STATE_TURNING: u16 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VertexColor {
+    Black,
+    Red,
+    Green,
+    Blue,
+}
+
+/// Point of the model, the lines going from each vertex to the next.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Vertex {
+    pub x: u8,
+    pub y: u8,
+    pub z: u8,
+    pub color: VertexColor,
+    pub intense: bool,
+}
+
+impl Vertex {
+    /// X and Y in the low and high bytes of the first word, Z in the low
+    /// byte of the second one, then 2 bits of color and the intensity.
+    pub fn decode(first: u16, second: u16) -> Vertex {
+        Vertex {
+            x: first as u8,
+            y: (first >> 8) as u8,
+            z: second as u8,
+            color: match (second >> 8) & 0x3 {
+                0 => VertexColor::Black,
+                1 => VertexColor::Red,
+                2 => VertexColor::Green,
+                _ => VertexColor::Blue,
+            },
+            intense: second & 0x400 != 0,
+        }
+    }
+
+    /// Position on the screen, from -1 to 1 with y up, of the vertex seen
+    /// from the front once the model is rotated by `rotation` degrees
+    /// around its vertical axis, and its depth, from -1 at the front to 1
+    /// at the back.
+    pub fn project(&self, rotation: f32) -> (f32, f32, f32) {
+        let centered = |c: u8| c as f32 / 127.5 - 1.;
+        let (x, y, z) = (centered(self.x), centered(self.y), centered(self.z));
+        let (sin, cos) = (rotation * PI / 180.).sin_cos();
+        (x * cos - z * sin, y, x * sin + z * cos)
+    }
+}
+
+/// Draws the model, for example as a 3D wireframe in a window.
+pub trait Backend: Debug {
+    /// Called once per frame while a region is mapped, with the rotation in
+    /// degrees.
+    fn frame(&mut self, vertices: &[Vertex], rotation: f32);
+}
+
+/// SPED-3 suspended particle display, drawing lines between up to 128
+/// vertices and rotating them at 50 degrees per second.
+///
+/// - `POLL_DEVICE`: sets B to the state, 0 without vertices, 1 running or 2
+///   turning, and C to the last error, always 0.
+/// - `MAP_REGION`: draws the Y vertices at X, 2 words each, none if Y is 0.
+/// - `ROTATE_DEVICE`: turns to X modulo 360 degrees, the shortest way.
+#[derive(Debug)]
+pub struct Sped3 {
+    vertex_map: u16,
+    vertex_count: u16,
+    /// In thousandths of a degree.
+    rotation: u32,
+    target: u32,
+    timebase: Timebase,
+    backend: Box<Backend>,
+}
+
+impl Sped3 {
+    pub fn new(backend: Box<Backend>) -> Sped3 {
+        Sped3::with_timebase(backend, Timebase::default())
+    }
+
+    pub fn with_timebase(backend: Box<Backend>, timebase: Timebase) -> Sped3 {
+        Sped3 {
+            vertex_map: 0,
+            vertex_count: 0,
+            rotation: 0,
+            target: 0,
+            timebase: timebase,
+            backend: backend,
+        }
+    }
+
+    pub fn vertices(&self, cpu: &Cpu) -> Vec<Vertex> {
+        (0..self.vertex_count)
+            .map(|i| {
+                let addr = self.vertex_map.wrapping_add(2 * i);
+                Vertex::decode(cpu.ram[addr as usize],
+                               cpu.ram[addr.wrapping_add(1) as usize])
+            })
+            .collect()
+    }
+
+    /// Current rotation, in degrees.
+    pub fn rotation(&self) -> f32 {
+        self.rotation as f32 / 1000.
+    }
+
+    /// Moves the rotation one frame towards the target.
+    fn turn(&mut self) {
+        let forward = (self.target + FULL_TURN - self.rotation) % FULL_TURN;
+        if forward <= ROTATION_STEP || FULL_TURN - forward <= ROTATION_STEP {
+            self.rotation = self.target;
+        } else if forward <= FULL_TURN / 2 {
+            self.rotation = (self.rotation + ROTATION_STEP) % FULL_TURN;
+        } else {
+            self.rotation = (self.rotation + FULL_TURN - ROTATION_STEP) % FULL_TURN;
+        }
+    }
+}
+
+impl Device for Sped3 {
+    fn hardware_id(&self) -> u32 {
+        0x42babf3c
+    }
+
+    fn hardware_version(&self) -> u16 {
+        0x0003
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1eb37e91
+    }
+
+    fn name(&self) -> &str {
+        "SPED-3"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let x = cpu.registers[5];
+        let y = cpu.registers[6];
+        match Command::from_u16(a) {
+            Some(Command::POLL_DEVICE) => {
+                cpu.registers[1] = if self.vertex_count == 0 {
+                    STATE_NO_DATA
+                } else if self.rotation != self.target {
+                    STATE_TURNING
+                } else {
+                    STATE_RUNNING
+                };
+                cpu.registers[2] = 0;
+            }
+            Some(Command::MAP_REGION) => {
+                self.vertex_map = x;
+                self.vertex_count = if y > MAX_VERTICES { MAX_VERTICES } else { y };
+            }
+            Some(Command::ROTATE_DEVICE) => self.target = (x % 360) as u32 * 1000,
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, cpu: &mut Cpu, tick_count: u64) -> TickResult {
+        let frame = self.timebase.period(FRAMES_PER_SECOND);
+        if self.timebase.is_due(tick_count, frame) {
+            self.turn();
+            if self.vertex_count != 0 {
+                let vertices = self.vertices(cpu);
+                let rotation = self.rotation();
+                self.backend.frame(&vertices, rotation);
+            }
+        }
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+
+    /// The next frame.
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        match self.timebase.period(FRAMES_PER_SECOND) {
+            0 => Some(current_tick),
+            period => Some(self.timebase.next_due(current_tick, period)),
+        }
+    }
+
+    /// The region, then the rotation and the target in thousandths of a
+    /// degree.
+    fn save_state(&self) -> Vec<u16> {
+        vec![self.vertex_map,
+             self.vertex_count,
+             (self.rotation >> 16) as u16,
+             self.rotation as u16,
+             (self.target / 1000) as u16]
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 5 || state[1] > MAX_VERTICES {
+            return Err(());
+        }
+        let rotation = (state[2] as u32) << 16 | state[3] as u32;
+        if rotation >= FULL_TURN || state[4] >= 360 {
+            return Err(());
+        }
+        self.vertex_map = state[0];
+        self.vertex_count = state[1];
+        self.rotation = rotation;
+        self.target = state[4] as u32 * 1000;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_sped3() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug)]
+    struct Frames(Rc<RefCell<Vec<(usize, f32)>>>);
+
+    impl Backend for Frames {
+        fn frame(&mut self, vertices: &[Vertex], rotation: f32) {
+            self.0.borrow_mut().push((vertices.len(), rotation));
+        }
+    }
+
+    let frames = Rc::new(RefCell::new(vec![]));
+    let backend = Box::new(Frames(frames.clone()));
+    let mut sped = Sped3::with_timebase(backend, Timebase::new(600));
+    let mut cpu = Cpu::default();
+    let command = |sped: &mut Sped3, cpu: &mut Cpu, a: Command, x, y| {
+        cpu.registers[0] = a as u16;
+        cpu.registers[5] = x;
+        cpu.registers[6] = y;
+        sped.interrupt(cpu).unwrap();
+        cpu.registers[1]
+    };
+    assert_eq!(command(&mut sped, &mut cpu, Command::POLL_DEVICE, 0, 0), STATE_NO_DATA);
+
+    cpu.ram[0x1000..0x1004].copy_from_slice(&[0xff00, 0x0580, 0x0000, 0x02ff]);
+    command(&mut sped, &mut cpu, Command::MAP_REGION, 0x1000, 2);
+    assert_eq!(sped.vertices(&cpu)[0],
+               Vertex {
+                   x: 0,
+                   y: 0xff,
+                   z: 0x80,
+                   color: VertexColor::Red,
+                   intense: true,
+               });
+    assert_eq!(sped.vertices(&cpu)[1].color, VertexColor::Green);
+    assert_eq!(command(&mut sped, &mut cpu, Command::POLL_DEVICE, 0, 0), STATE_RUNNING);
+
+    // 350 degrees is 10 degrees backwards, 12 frames.
+    command(&mut sped, &mut cpu, Command::ROTATE_DEVICE, 710, 0);
+    assert_eq!(command(&mut sped, &mut cpu, Command::POLL_DEVICE, 0, 0), STATE_TURNING);
+    for tick in 0..200 {
+        sped.tick(&mut cpu, tick);
+    }
+    let frames = frames.borrow();
+    assert_eq!(frames.len(), 20);
+    assert!((frames[0].1 - 359.167).abs() < 0.01);
+    assert_eq!(frames[12].1, 350.);
+    assert_eq!(command(&mut sped, &mut cpu, Command::POLL_DEVICE, 0, 0), STATE_RUNNING);
+
+    let (x, y, _) = Vertex::decode(0x80ff, 0x80).project(90.);
+    assert!(x.abs() < 0.01 && (y - 0.0039).abs() < 0.01);
+}
@@ -0,0 +1,190 @@
+use std::cmp;
+
+use num::traits::FromPrimitive;
+
+use cpu::Cpu;
+use device::*;
+
+enum_from_primitive! {
+#[allow(non_camel_case_types)]
+#[derive(Debug)]
+enum Command {
+    SET_INT = 0x0,
+    SET_PERIOD = 0x1,
+    GET_REMAINING = 0x2,
+}
+}
+
+/// Timer counting cycles instead of fractions of a second, precise enough
+/// to give each task of a preemptive scheduler the same time slice.
+///
+/// - `SET_INT`: interrupts with message B, disabled if B is 0.
+/// - `SET_PERIOD`: interrupts every B cycles from the next one, stopped if B
+///   is 0.
+/// - `GET_REMAINING`: sets C to the cycles left before the next interrupt,
+///   0 if stopped.
+///
+/// With a scheduler, the interrupt handler can save the registers on the
+/// stack of the interrupted task, below the PC and A pushed by the CPU, then
+/// switch to the stack of the next task and restore them from there before
+/// `RFI`. `dcpu new --template scheduler` documents such a frame.
+#[derive(Debug, Default)]
+pub struct Timer {
+    int_msg: u16,
+    period: u16,
+    /// Tick of the next interrupt.
+    next_tick: u64,
+    /// Last tick seen, the interrupts happen between two ticks.
+    current_tick: u64,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+
+    fn is_running(&self) -> bool {
+        self.period != 0 && self.int_msg != 0
+    }
+}
+
+impl Device for Timer {
+    fn hardware_id(&self) -> u32 {
+        0x71de0001
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Cycle Timer"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let a = cpu.registers[0];
+        let b = cpu.registers[1];
+        match Command::from_u16(a) {
+            Some(Command::SET_INT) => self.int_msg = b,
+            Some(Command::SET_PERIOD) => {
+                self.period = b;
+                self.next_tick = self.current_tick + 1 + b as u64;
+            }
+            Some(Command::GET_REMAINING) => {
+                cpu.registers[2] = if self.period == 0 {
+                    0
+                } else {
+                    self.next_tick.saturating_sub(self.current_tick + 1) as u16
+                };
+            }
+            None => return Err(()),
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, current_tick: u64) -> TickResult {
+        self.current_tick = current_tick;
+        if self.period != 0 && current_tick >= self.next_tick {
+            self.next_tick = cmp::max(self.next_tick + self.period as u64, current_tick + 1);
+            if self.int_msg != 0 {
+                return TickResult::Interrupt(self.int_msg);
+            }
+        }
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        if self.is_running() {
+            Some(cmp::max(self.next_tick, current_tick))
+        } else {
+            None
+        }
+    }
+
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        if self.period != 0 {
+            Some(cmp::max(self.next_tick, current_tick))
+        } else {
+            None
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        let mut state = vec![self.int_msg, self.period];
+        push_u64(&mut state, self.next_tick);
+        push_u64(&mut state, self.current_tick);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        if state.len() != 10 {
+            return Err(());
+        }
+        self.int_msg = state[0];
+        self.period = state[1];
+        self.next_tick = read_u64(&state[2..6]);
+        self.current_tick = read_u64(&state[6..10]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_timer() {
+    let mut cpu = Cpu::default();
+    let mut timer = Timer::new();
+    cpu.registers[..2].copy_from_slice(&[Command::SET_INT as u16, 3]);
+    timer.interrupt(&mut cpu).unwrap();
+    timer.tick(&mut cpu, 9);
+    cpu.registers[..2].copy_from_slice(&[Command::SET_PERIOD as u16, 100]);
+    timer.interrupt(&mut cpu).unwrap();
+    let interrupts: Vec<u64> = (10..400)
+                                   .filter(|&t| match timer.tick(&mut cpu, t) {
+                                       TickResult::Interrupt(3) => true,
+                                       _ => false,
+                                   })
+                                   .collect();
+    assert_eq!(interrupts, [110, 210, 310]);
+    assert_eq!(timer.next_interrupt(400), Some(410));
+    cpu.registers[0] = Command::GET_REMAINING as u16;
+    timer.interrupt(&mut cpu).unwrap();
+    assert_eq!(cpu.registers[2], 10);
+}
+
+#[cfg(all(test, feature = "assembler"))]
+#[test]
+fn test_scheduler() {
+    use assembler;
+    use computer::Computer;
+
+    let source = include_str!("../bin/templates/scheduler.dasm").replace("{name}", "test");
+    let program = assembler::assemble_str(&source).unwrap();
+    let mut cpu = Cpu::default();
+    cpu.load(&program, 0);
+    let mut computer = Computer::new(cpu);
+    computer.add_device(Box::new(Timer::new()));
+    let counters = |computer: &Computer| {
+        let ram = &computer.cpu().ram;
+        (ram[program.len() - 2], ram[program.len() - 1])
+    };
+    let run = |computer: &mut Computer, ticks| {
+        for _ in 0..ticks {
+            computer.tick().unwrap();
+        }
+    };
+
+    // task_a runs first, until the end of its slice.
+    run(&mut computer, 800);
+    let (a, b) = counters(&computer);
+    assert!(a > 0 && b == 0);
+    run(&mut computer, 800);
+    let (a2, b2) = counters(&computer);
+    assert!(a2 > a && b2 > 0);
+    run(&mut computer, 4000);
+    let (a3, b3) = counters(&computer);
+    assert!(a3 > a2 && b3 > b2);
+}
@@ -0,0 +1,280 @@
+//! Differential execution: runs a computer and another implementation of the
+//! DCPU side by side and compares their states every few ticks, to find
+//! where they disagree on the specification.
+//!
+//! The other implementation is a `Reference`, such as a `Process` speaking
+//! the protocol of `control` on its standard input and output.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use computer::Computer;
+use control::{Client, Error};
+
+/// Names of the registers of `Reference::registers`.
+pub const REGISTERS: [&'static str; 12] = ["A", "B", "C", "I", "J", "X", "Y", "Z", "PC", "SP",
+                                           "EX", "IA"];
+
+/// Words read at once by `compare`.
+const CHUNK: u16 = 0x4000;
+
+/// Implementation compared to the computer, with the same program loaded.
+pub trait Reference {
+    /// Runs `ticks` ticks, failing with `Error::Remote` if the CPU stops.
+    fn step(&mut self, ticks: u16) -> Result<(), Error>;
+    /// In the order of `REGISTERS`.
+    fn registers(&mut self) -> Result<[u16; 12], Error>;
+    fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u16>, Error>;
+}
+
+impl<S: Read + Write> Reference for Client<S> {
+    fn step(&mut self, ticks: u16) -> Result<(), Error> {
+        Client::step(self, ticks)
+    }
+
+    fn registers(&mut self) -> Result<[u16; 12], Error> {
+        Client::registers(self)
+    }
+
+    fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u16>, Error> {
+        Client::read_memory(self, addr, len)
+    }
+}
+
+/// Another computer, for example with a different `Cpu` configuration.
+impl Reference for Computer {
+    fn step(&mut self, ticks: u16) -> Result<(), Error> {
+        for _ in 0..ticks {
+            try!(self.tick().map_err(|e| Error::Remote(e.to_string())));
+        }
+        Ok(())
+    }
+
+    fn registers(&mut self) -> Result<[u16; 12], Error> {
+        let cpu = self.cpu();
+        let mut regs = [0; 12];
+        regs[..8].copy_from_slice(&cpu.registers);
+        regs[8..].copy_from_slice(&[cpu.pc, cpu.sp, cpu.ex, cpu.ia]);
+        Ok(regs)
+    }
+
+    fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u16>, Error> {
+        let ram = &self.cpu().ram;
+        Ok((0..len).map(|i| ram[addr.wrapping_add(i) as usize]).collect())
+    }
+}
+
+/// Standard input and output of a child process.
+#[derive(Debug)]
+pub struct Pipes {
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Read for Pipes {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for Pipes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+/// External emulator answering the requests of `control` on its standard
+/// input and output. It is killed when dropped.
+#[derive(Debug)]
+pub struct Process {
+    child: Child,
+    client: Client<Pipes>,
+}
+
+impl Process {
+    /// Starts `command`, which must have loaded the program by its first
+    /// answer.
+    pub fn spawn(command: &mut Command) -> io::Result<Process> {
+        let mut child = try!(command.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn());
+        let pipes = Pipes {
+            stdin: child.stdin.take().unwrap(),
+            stdout: child.stdout.take().unwrap(),
+        };
+        Ok(Process {
+            child: child,
+            client: Client::new(pipes),
+        })
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl Reference for Process {
+    fn step(&mut self, ticks: u16) -> Result<(), Error> {
+        self.client.step(ticks)
+    }
+
+    fn registers(&mut self) -> Result<[u16; 12], Error> {
+        self.client.registers()
+    }
+
+    fn read_memory(&mut self, addr: u16, len: u16) -> Result<Vec<u16>, Error> {
+        self.client.read_memory(addr, len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Register of `REGISTERS`, value in the computer and in the reference.
+    Register(&'static str, u16, u16),
+    /// Address, value in the computer and in the reference.
+    Memory(u16, u16, u16),
+    /// Only one of them stopped, with this error.
+    Stopped(Option<String>, Option<String>),
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Difference::Register(r, ours, theirs) => {
+                write!(f, "{}: 0x{:04x} instead of 0x{:04x}", r, ours, theirs)
+            }
+            Difference::Memory(addr, ours, theirs) => {
+                write!(f, "[0x{:04x}]: 0x{:04x} instead of 0x{:04x}", addr, ours, theirs)
+            }
+            Difference::Stopped(Some(ref e), _) => write!(f, "stopped alone: {}", e),
+            Difference::Stopped(_, Some(ref e)) => write!(f, "reference stopped alone: {}", e),
+            Difference::Stopped(None, None) => write!(f, "stopped"),
+        }
+    }
+}
+
+/// First disagreement found by `run`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// The states were the same `period` ticks before.
+    pub tick: u64,
+    pub differences: Vec<Difference>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "diverged at tick {}:", self.tick));
+        for d in self.differences.iter() {
+            try!(writeln!(f, "  {}", d));
+        }
+        Ok(())
+    }
+}
+
+/// Differences between the states of `computer` and `reference`.
+pub fn compare<R: Reference + ?Sized>(computer: &mut Computer,
+                                      reference: &mut R)
+                                      -> Result<Vec<Difference>, Error> {
+    let mut differences = vec![];
+    let ours = try!(Reference::registers(computer));
+    let theirs = try!(reference.registers());
+    for i in 0..REGISTERS.len() {
+        if ours[i] != theirs[i] {
+            differences.push(Difference::Register(REGISTERS[i], ours[i], theirs[i]));
+        }
+    }
+    for chunk in 0..0x10000 / CHUNK as u32 {
+        let start = (chunk * CHUNK as u32) as u16;
+        let theirs = try!(reference.read_memory(start, CHUNK));
+        if theirs.len() != CHUNK as usize {
+            return Err(Error::Protocol);
+        }
+        let ours = &computer.cpu().ram[start as usize..start as usize + CHUNK as usize];
+        for (i, (&o, &t)) in ours.iter().zip(theirs.iter()).enumerate() {
+            if o != t {
+                differences.push(Difference::Memory(start + i as u16, o, t));
+            }
+        }
+    }
+    Ok(differences)
+}
+
+/// Runs `computer` and `reference` for up to `max_ticks` ticks, comparing
+/// them every `period` ticks, until they diverge or the CPU of both stops.
+/// The devices of the computer aren't known to the reference, only programs
+/// not using them can be compared.
+pub fn run<R: Reference + ?Sized>(computer: &mut Computer,
+                                  reference: &mut R,
+                                  period: u16,
+                                  max_ticks: u64)
+                                  -> Result<Option<Divergence>, Error> {
+    while computer.current_tick() < max_ticks {
+        let ours = match Reference::step(computer, period) {
+            Ok(()) => None,
+            Err(Error::Remote(e)) => Some(e),
+            Err(e) => return Err(e),
+        };
+        let theirs = match reference.step(period) {
+            Ok(()) => None,
+            Err(Error::Remote(e)) => Some(e),
+            Err(e) => return Err(e),
+        };
+        let stopped = ours.is_some() || theirs.is_some();
+        let mut differences = try!(compare(computer, reference));
+        if ours.is_some() != theirs.is_some() {
+            differences.push(Difference::Stopped(ours, theirs));
+        }
+        if !differences.is_empty() {
+            return Ok(Some(Divergence {
+                tick: computer.current_tick(),
+                differences: differences,
+            }));
+        }
+        if stopped {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+#[test]
+fn test_run() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::*;
+
+    // A counts to 100 then the program halts.
+    let program = [basic(BasicOp::ADD, reg(Register::A), lit(1)),
+                   basic(BasicOp::SET, AT_NEXT, reg(Register::A)),
+                   0x1000,
+                   basic(BasicOp::IFN, reg(Register::A), NEXT),
+                   100,
+                   basic(BasicOp::SET, PC, lit(0)),
+                   special(SpecialOp::HLT, lit(0))];
+    let computer = || {
+        let mut cpu = Cpu::default();
+        cpu.load(&program, 0);
+        Computer::new(cpu)
+    };
+
+    let mut reference = computer();
+    assert_eq!(run(&mut computer(), &mut reference, 10, 100000).unwrap(), None);
+    assert_eq!(reference.cpu().ram[0x1000], 100);
+
+    let mut other = computer();
+    other.cpu_mut().load(&[basic(BasicOp::ADD, reg(Register::A), lit(2))], 0);
+    let divergence = run(&mut computer(), &mut other, 10, 100000).unwrap().unwrap();
+    assert_eq!(divergence.tick, 10);
+    assert!(divergence.differences.contains(&Difference::Memory(0, 0x8802, 0x8c02)));
+    assert!(divergence.differences.iter().any(|d| match *d {
+        Difference::Register("A", _, _) => true,
+        _ => false,
+    }));
+}
@@ -0,0 +1,88 @@
+//! Machine words of instructions, computed at compile time.
+//!
+//! Tests can use them to get known-good binaries without going through the
+//! assembler or `Instruction::encode`:
+//!
+//! ```
+//! use dcpu::encodings::*;
+//! use dcpu::types::{BasicOp, Register};
+//!
+//! const PROGRAM: [u16; 3] = [basic(BasicOp::SET, reg(Register::A), lit(3)),
+//!                            basic(BasicOp::ADD, reg(Register::A), NEXT), 0x1000];
+//! assert_eq!(PROGRAM[0], 0x9001);
+//! ```
+
+use types::{BasicOp, Register, SpecialOp};
+
+/// `PUSH` as b, `POP` as a.
+pub const PUSH_POP: u16 = 0x18;
+pub const PEEK: u16 = 0x19;
+/// Followed by the offset.
+pub const PICK: u16 = 0x1a;
+pub const SP: u16 = 0x1b;
+pub const PC: u16 = 0x1c;
+pub const EX: u16 = 0x1d;
+/// `[next word]`.
+pub const AT_NEXT: u16 = 0x1e;
+/// Literal in the next word.
+pub const NEXT: u16 = 0x1f;
+
+/// `SET PC, POP`.
+pub const RET: u16 = basic(BasicOp::SET, PC, PUSH_POP);
+/// `SET A, A`.
+pub const NOP: u16 = basic(BasicOp::SET, 0, 0);
+
+/// Sled put at the start of the functions by `linker::Options::patch_points`,
+/// large enough for a `JSR` to a trampoline, see `patch`.
+pub const PATCH_POINT: [u16; 2] = [NOP, NOP];
+
+pub const fn basic(op: BasicOp, b: u16, a: u16) -> u16 {
+    op as u16 | b << 5 | a << 10
+}
+
+pub const fn special(op: SpecialOp, a: u16) -> u16 {
+    (op as u16) << 5 | a << 10
+}
+
+pub const fn reg(r: Register) -> u16 {
+    r as u16
+}
+
+/// `[reg]`.
+pub const fn at_reg(r: Register) -> u16 {
+    0x08 + r as u16
+}
+
+/// `[reg + next word]`.
+pub const fn at_reg_plus(r: Register) -> u16 {
+    0x10 + r as u16
+}
+
+/// Literal in the instruction word, only usable as a. `n` must be between
+/// -1 and 30.
+pub const fn lit(n: i8) -> u16 {
+    (0x21 + n as i16) as u16
+}
+
+#[cfg(test)]
+#[test]
+fn test_encodings() {
+    use types::{Instruction, Value};
+
+    let check = |i: Instruction, words: &[u16]| {
+        let mut encoded = [0; 3];
+        let size = i.encode(&mut encoded);
+        assert_eq!(&encoded[..size as usize], words);
+    };
+    check(Instruction::BasicOp(BasicOp::SET, Value::PC, Value::Push), &[RET]);
+    check(Instruction::BasicOp(BasicOp::ADD, Value::Reg(Register::B), Value::Litteral(0xffff)),
+          &[basic(BasicOp::ADD, reg(Register::B), lit(-1))]);
+    check(Instruction::BasicOp(BasicOp::IFE,
+                               Value::AtRegPlus(Register::I, 2),
+                               Value::AtAddr(0x8000)),
+          &[basic(BasicOp::IFE, at_reg_plus(Register::I), AT_NEXT), 0x8000, 2]);
+    check(Instruction::BasicOp(BasicOp::SET, Value::AtReg(Register::J), Value::Litteral(30)),
+          &[basic(BasicOp::SET, at_reg(Register::J), lit(30))]);
+    check(Instruction::SpecialOp(SpecialOp::JSR, Value::Litteral(0x1234)),
+          &[special(SpecialOp::JSR, NEXT), 0x1234]);
+}
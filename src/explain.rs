@@ -0,0 +1,126 @@
+use cpu::Cpu;
+use taint::Location;
+use types::*;
+use types::BasicOp::*;
+use types::SpecialOp::*;
+
+/// Describes in plain english what `i` will do if executed in the current
+/// state of `cpu`, for example
+/// `SET A, [B + 2]: loads the word at address B+2=0x8002 into A; 2 cycles`.
+pub fn explain(cpu: &Cpu, i: Instruction) -> String {
+    let what = match i {
+        Instruction::BasicOp(op, b, a) => {
+            // a is evaluated first, so POP has already moved SP when b is.
+            let sp_offset = if a == Value::Push { 1 } else { 0 };
+            explain_basic(op,
+                          &describe(cpu, b, true, sp_offset),
+                          &describe(cpu, a, false, 0))
+        }
+        Instruction::SpecialOp(op, a) => {
+            let write = match op {
+                IAG | HWN => true,
+                _ => false,
+            };
+            explain_special(op, &describe(cpu, a, write, 0))
+        }
+    };
+    let delay = i.delay();
+    format!("{}: {}; {} cycle{}",
+            i,
+            what,
+            delay,
+            if delay == 1 { "" } else { "s" })
+}
+
+fn describe(cpu: &Cpu, v: Value, write: bool, sp_offset: u16) -> String {
+    let addr = match (cpu.location(v, write), v) {
+        (Some(Location::Mem(addr)), Value::Push) |
+        (Some(Location::Mem(addr)), Value::Peek) |
+        (Some(Location::Mem(addr)), Value::Pick(_)) => addr.wrapping_add(sp_offset),
+        (Some(Location::Mem(addr)), _) => addr,
+        _ => 0,
+    };
+    match v {
+        Value::Reg(r) => format!("{:?}", r),
+        Value::AtReg(r) => format!("the word at address {:?}=0x{:04x}", r, addr),
+        Value::AtRegPlus(r, n) => format!("the word at address {:?}+{}=0x{:04x}", r, n, addr),
+        Value::Push if write => format!("a new word pushed on the stack at 0x{:04x}", addr),
+        Value::Push => format!("the word popped from the stack at 0x{:04x}", addr),
+        Value::Peek => format!("the word at the top of the stack at 0x{:04x}", addr),
+        Value::Pick(n) => format!("the word at address SP+{}=0x{:04x}", n, addr),
+        Value::AtAddr(_) => format!("the word at address 0x{:04x}", addr),
+        Value::SP => "SP".into(),
+        Value::PC => "PC".into(),
+        Value::EX => "EX".into(),
+        Value::Litteral(n) => format!("0x{:04x}", n),
+    }
+}
+
+fn explain_basic(op: BasicOp, b: &str, a: &str) -> String {
+    match op {
+        SET => format!("loads {} into {}", a, b),
+        ADD => format!("adds {} to {}, EX is set to the carry", a, b),
+        SUB => format!("subtracts {} from {}, EX is set to the borrow", a, b),
+        MUL => format!("multiplies {} by {} as unsigned numbers, EX is set to the high word",
+                       b, a),
+        MLI => format!("multiplies {} by {} as signed numbers, EX is set to the high word",
+                       b, a),
+        DIV => format!("divides {} by {} as unsigned numbers, EX is set to the fractional part",
+                       b, a),
+        DVI => format!("divides {} by {} as signed numbers, EX is set to the fractional part",
+                       b, a),
+        MOD => format!("sets {} to its unsigned remainder by {}", b, a),
+        MDI => format!("sets {} to its signed remainder by {}", b, a),
+        AND => format!("binary ANDs {} with {}", b, a),
+        BOR => format!("binary ORs {} with {}", b, a),
+        XOR => format!("binary XORs {} with {}", b, a),
+        SHR => format!("logically shifts {} right by {} bits, EX gets the shifted out bits",
+                       b, a),
+        ASR => format!("arithmetically shifts {} right by {} bits, EX gets the shifted out bits",
+                       b, a),
+        SHL => format!("shifts {} left by {} bits, EX gets the shifted out bits", b, a),
+        IFB => format!("executes the next instruction only if {} AND {} is not 0", b, a),
+        IFC => format!("executes the next instruction only if {} AND {} is 0", b, a),
+        IFE => format!("executes the next instruction only if {} == {}", b, a),
+        IFN => format!("executes the next instruction only if {} != {}", b, a),
+        IFG => format!("executes the next instruction only if {} > {} (unsigned)", b, a),
+        IFA => format!("executes the next instruction only if {} > {} (signed)", b, a),
+        IFL => format!("executes the next instruction only if {} < {} (unsigned)", b, a),
+        IFU => format!("executes the next instruction only if {} < {} (signed)", b, a),
+        ADX => format!("adds {} and EX to {}, EX is set to the carry", a, b),
+        SBX => format!("subtracts {} from {} and adds EX, EX is set to the carry or borrow",
+                       a, b),
+        STI => format!("loads {} into {}, then increments I and J", a, b),
+        STD => format!("loads {} into {}, then decrements I and J", a, b),
+    }
+}
+
+fn explain_special(op: SpecialOp, a: &str) -> String {
+    match op {
+        JSR => format!("pushes the address of the next instruction and jumps to {}", a),
+        INT => format!("triggers a software interrupt with message {}", a),
+        IAG => format!("loads IA into {}", a),
+        IAS => format!("loads {} into IA", a),
+        RFI => "returns from the interrupt handler, popping A then PC".into(),
+        IAQ => format!("queues the interrupts if {} is not 0, else triggers them", a),
+        HWN => format!("loads the number of connected devices into {}", a),
+        HWQ => format!("loads information about device {} into A, B, C, X and Y", a),
+        HWI => format!("sends an interrupt to device {}", a),
+        LOG => format!("logs {}", a),
+        BRK => "does nothing (breakpoint)".into(),
+        HLT => "halts the CPU".into(),
+        SLP => "sleeps until the next interrupt".into(),
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_explain() {
+    let mut cpu = Cpu::default();
+    cpu.registers[Register::B as usize] = 0x8000;
+    let i = Instruction::BasicOp(BasicOp::SET,
+                                 Value::Reg(Register::A),
+                                 Value::AtRegPlus(Register::B, 2));
+    assert_eq!(explain(&cpu, i),
+               "SET A, [B + 2]: loads the word at address B+2=0x8002 into A; 2 cycles");
+}
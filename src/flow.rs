@@ -0,0 +1,388 @@
+//! Static analysis of the control flow of a binary.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+use symbols::Symbols;
+use types::{BasicOp, Instruction, Register, SpecialOp, Value};
+
+/// Effect of an instruction on the control flow.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flow {
+    /// Continues with the next instruction.
+    Next,
+    /// Continues with the next instruction, or skips it if the condition is
+    /// false.
+    Conditional,
+    Jump(u16),
+    /// Calls the function at this address, then continues with the next
+    /// instruction.
+    Call(u16),
+    /// Call to a computed address.
+    IndirectCall,
+    /// Jump to a computed address.
+    IndirectJump,
+    Return,
+}
+
+impl Flow {
+    /// Flow of `i`, with `next` the address of the following instruction.
+    pub fn of(i: &Instruction, next: u16) -> Flow {
+        match *i {
+            Instruction::BasicOp(op, _, _) if op.is_if() => Flow::Conditional,
+            Instruction::BasicOp(BasicOp::SET, Value::PC, Value::Litteral(n)) => Flow::Jump(n),
+            Instruction::BasicOp(BasicOp::SET, Value::PC, Value::Push) => Flow::Return,
+            Instruction::BasicOp(BasicOp::ADD, Value::PC, Value::Litteral(n)) => {
+                Flow::Jump(next.wrapping_add(n))
+            }
+            Instruction::BasicOp(BasicOp::SUB, Value::PC, Value::Litteral(n)) => {
+                Flow::Jump(next.wrapping_sub(n))
+            }
+            Instruction::BasicOp(_, Value::PC, _) => Flow::IndirectJump,
+            Instruction::SpecialOp(SpecialOp::JSR, Value::Litteral(n)) => Flow::Call(n),
+            Instruction::SpecialOp(SpecialOp::JSR, _) => Flow::IndirectCall,
+            Instruction::SpecialOp(SpecialOp::RFI, _) => Flow::Return,
+            _ => Flow::Next,
+        }
+    }
+}
+
+/// Instruction at `addr`, with its size. The memory after `words` is zeroed,
+/// like in the emulator.
+pub fn decode_at(words: &[u16], addr: u16) -> Option<(u16, Instruction)> {
+    let word = |offset: u16| words.get(addr.wrapping_add(offset) as usize).cloned().unwrap_or(0);
+    Instruction::decode(&[word(0), word(1), word(2)]).ok()
+}
+
+/// Addresses which can be executed after the instruction at `addr`, of size
+/// `size`. Calls return to the next instruction.
+pub fn successors(words: &[u16], addr: u16, size: u16, flow: Flow) -> Vec<u16> {
+    let next = addr.wrapping_add(size);
+    match flow {
+        Flow::Next | Flow::Call(_) | Flow::IndirectCall => vec![next],
+        Flow::Conditional => vec![next, skip(words, next).0],
+        Flow::Jump(target) => vec![target],
+        Flow::IndirectJump | Flow::Return => vec![],
+    }
+}
+
+/// Address after the instructions skipped by a false conditional, followed
+/// by the instruction at `next`, with the number of skipped instructions. The
+/// chained conditionals are skipped too.
+fn skip(words: &[u16], next: u16) -> (u16, u64) {
+    let mut addr = next;
+    let mut skipped = 0;
+    while let Some((size, i)) = decode_at(words, addr) {
+        addr = addr.wrapping_add(size);
+        skipped += 1;
+        match i {
+            Instruction::BasicOp(op, _, _) if op.is_if() => (),
+            _ => break,
+        }
+    }
+    (addr, skipped)
+}
+
+/// Decodes the instructions reachable from `entries` without following the
+/// calls, stopping at the words which can't be decoded.
+pub fn explore(words: &[u16], entries: &[u16]) -> BTreeMap<u16, (u16, Instruction, Flow)> {
+    let mut found = BTreeMap::new();
+    let mut queue = entries.iter().cloned().collect::<VecDeque<_>>();
+    while let Some(addr) = queue.pop_front() {
+        if found.contains_key(&addr) {
+            continue;
+        }
+        if let Some((size, i)) = decode_at(words, addr) {
+            let flow = Flow::of(&i, addr.wrapping_add(size));
+            queue.extend(successors(words, addr, size, flow));
+            found.insert(addr, (size, i, flow));
+        }
+    }
+    found
+}
+
+/// Most cycles the function at `entry` can take until it returns, including
+/// its calls. `None` if it can't be known statically: loops, recursion,
+/// jumps or calls to computed addresses. The cycles taken by the devices on
+/// `HWI` aren't counted.
+pub fn max_cycles(words: &[u16], entry: u16) -> Option<u64> {
+    worst_cycles(words, entry, &mut HashMap::new(), &mut HashSet::new())
+}
+
+/// Most cycles from `addr` to the return of its function. `known` caches the
+/// result of each address, `visiting` holds the addresses being computed to
+/// detect the loops.
+fn worst_cycles(words: &[u16],
+                addr: u16,
+                known: &mut HashMap<u16, Option<u64>>,
+                visiting: &mut HashSet<u16>)
+                -> Option<u64> {
+    if let Some(&cycles) = known.get(&addr) {
+        return cycles;
+    }
+    if !visiting.insert(addr) {
+        return None;
+    }
+    let res = decode_at(words, addr).and_then(|(size, i)| {
+        let next = addr.wrapping_add(size);
+        let rest = match Flow::of(&i, next) {
+            Flow::Next => worst_cycles(words, next, known, visiting),
+            Flow::Conditional => {
+                let (after, skipped) = skip(words, next);
+                match (worst_cycles(words, next, known, visiting),
+                       worst_cycles(words, after, known, visiting)) {
+                    (Some(taken), Some(rest)) => Some(cmp::max(taken, skipped + rest)),
+                    _ => None,
+                }
+            }
+            Flow::Jump(target) => worst_cycles(words, target, known, visiting),
+            Flow::Call(target) => {
+                match (worst_cycles(words, target, known, visiting),
+                       worst_cycles(words, next, known, visiting)) {
+                    (Some(call), Some(rest)) => Some(call + rest),
+                    _ => None,
+                }
+            }
+            Flow::Return => Some(0),
+            Flow::IndirectCall | Flow::IndirectJump => None,
+        };
+        rest.map(|rest| rest + i.delay() as u64)
+    });
+    visiting.remove(&addr);
+    known.insert(addr, res);
+    res
+}
+
+/// Registers read by the function at `entry` before being written on at
+/// least one path from its start, with the addresses of the reads. `inputs`
+/// are the registers holding the arguments of the function. The calls are
+/// assumed to write every register.
+pub fn undefined_reads(words: &[u16], entry: u16, inputs: &[Register]) -> Vec<(u16, Register)> {
+    let instructions = explore(words, &[entry]);
+    // Registers written on every path to each instruction, one bit each.
+    let mut written = HashMap::new();
+    written.insert(entry, inputs.iter().fold(0u8, |w, &r| w | bit(r)));
+    let mut queue = VecDeque::new();
+    queue.push_back(entry);
+    while let Some(addr) = queue.pop_front() {
+        let (size, ref i, flow) = match instructions.get(&addr) {
+            Some(i) => *i,
+            None => continue,
+        };
+        let after = match flow {
+            Flow::Call(_) | Flow::IndirectCall => 0xff,
+            _ => written[&addr] | register_uses(i).1,
+        };
+        for next in successors(words, addr, size, flow) {
+            let merged = written.get(&next).map_or(after, |&w| w & after);
+            if written.get(&next) != Some(&merged) {
+                written.insert(next, merged);
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let mut reads = vec![];
+    for (&addr, &(_, ref i, _)) in instructions.iter() {
+        let undefined = register_uses(i).0 & !written[&addr];
+        for &r in REGISTERS.iter().filter(|&&r| undefined & bit(r) != 0) {
+            reads.push((addr, r));
+        }
+    }
+    reads
+}
+
+const REGISTERS: [Register; 8] = [Register::A, Register::B, Register::C, Register::X, Register::Y,
+                                  Register::Z, Register::I, Register::J];
+
+fn bit(r: Register) -> u8 {
+    1 << r as u8
+}
+
+/// Registers read and written by an instruction, one bit each.
+fn register_uses(i: &Instruction) -> (u8, u8) {
+    // Registers read to compute the address of a value.
+    let address = |v: &Value| {
+        match *v {
+            Value::AtReg(r) | Value::AtRegPlus(r, _) => bit(r),
+            _ => 0,
+        }
+    };
+    let direct = |v: &Value| {
+        match *v {
+            Value::Reg(r) => bit(r),
+            _ => 0,
+        }
+    };
+    match *i {
+        Instruction::BasicOp(op, b, a) => {
+            let reads = address(&a) | address(&b);
+            match op {
+                _ if op.is_if() => (reads | direct(&a) | direct(&b), 0),
+                BasicOp::SET => (reads | direct(&a), direct(&b)),
+                BasicOp::STI | BasicOp::STD => {
+                    let ij = bit(Register::I) | bit(Register::J);
+                    (reads | direct(&a) | ij, direct(&b) | ij)
+                }
+                // Clears b whatever its value.
+                BasicOp::XOR | BasicOp::SUB if a == b => (reads, direct(&b)),
+                _ => (reads | direct(&a) | direct(&b), direct(&b)),
+            }
+        }
+        Instruction::SpecialOp(op, a) => {
+            match op {
+                SpecialOp::IAG | SpecialOp::HWN => (address(&a), direct(&a)),
+                SpecialOp::HWQ => {
+                    let info = [Register::A, Register::B, Register::C, Register::X, Register::Y];
+                    (address(&a) | direct(&a), info.iter().fold(0, |w, &r| w | bit(r)))
+                }
+                _ => (address(&a) | direct(&a), 0),
+            }
+        }
+    }
+}
+
+/// Functions of a binary and the functions they call, found from the `JSR`
+/// instructions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CallGraph {
+    /// Callees of each function.
+    pub calls: BTreeMap<u16, BTreeSet<u16>>,
+    /// Functions calling computed addresses, whose callees are only known
+    /// from the extra calls.
+    pub indirect: BTreeSet<u16>,
+}
+
+impl CallGraph {
+    /// Builds the call graph of the functions reachable from `entries`.
+    /// `extra_calls` are `(caller, callee)` pairs which can't be found
+    /// statically, like the indirect calls.
+    pub fn build(words: &[u16], entries: &[u16], extra_calls: &[(u16, u16)]) -> CallGraph {
+        let mut graph = CallGraph::default();
+        let mut queue = entries.iter().cloned().collect::<VecDeque<_>>();
+        for &(caller, callee) in extra_calls {
+            graph.calls.entry(caller).or_insert_with(BTreeSet::new).insert(callee);
+            queue.push_back(caller);
+            queue.push_back(callee);
+        }
+
+        let mut done = BTreeSet::new();
+        while let Some(function) = queue.pop_front() {
+            if !done.insert(function) {
+                continue;
+            }
+            let callees = graph.calls.entry(function).or_insert_with(BTreeSet::new);
+            for (_, &(_, _, flow)) in explore(words, &[function]).iter() {
+                match flow {
+                    Flow::Call(callee) => {
+                        callees.insert(callee);
+                        queue.push_back(callee);
+                    }
+                    Flow::IndirectCall => {
+                        graph.indirect.insert(function);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        graph
+    }
+
+    /// Functions from which `target` can be called, directly or not.
+    pub fn callers(&self, target: u16) -> BTreeSet<u16> {
+        let mut found = BTreeSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(target);
+        while let Some(callee) = queue.pop_front() {
+            for (&caller, callees) in self.calls.iter() {
+                if callees.contains(&callee) && found.insert(caller) {
+                    queue.push_back(caller);
+                }
+            }
+        }
+        found
+    }
+
+    /// Graphviz graph, with the functions named after `symbols`.
+    pub fn to_dot(&self, symbols: &Symbols) -> String {
+        let mut res = String::new();
+        writeln!(res, "digraph calls {{").unwrap();
+        for (&function, callees) in self.calls.iter() {
+            let shape = if self.indirect.contains(&function) { "box" } else { "ellipse" };
+            writeln!(res, "    \"{}\" [shape={}];", symbols.describe(function), shape).unwrap();
+            for &callee in callees {
+                writeln!(res,
+                         "    \"{}\" -> \"{}\";",
+                         symbols.describe(function),
+                         symbols.describe(callee))
+                    .unwrap();
+            }
+        }
+        writeln!(res, "}}").unwrap();
+        res
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_call_graph() {
+    use encodings::*;
+    use types::Register;
+
+    let words = [
+        // 0: main
+        special(SpecialOp::JSR, NEXT), 6,
+        basic(BasicOp::IFE, reg(Register::A), lit(0)),
+        basic(BasicOp::IFE, reg(Register::B), lit(0)),
+        special(SpecialOp::JSR, reg(Register::C)),
+        basic(BasicOp::SUB, PC, lit(1)),
+        // 6: f
+        special(SpecialOp::JSR, NEXT), 9,
+        RET,
+        // 9: g
+        RET,
+    ];
+    let main = explore(&words, &[0]);
+    assert_eq!(main.keys().cloned().collect::<Vec<_>>(), vec![0, 2, 3, 4, 5]);
+    assert_eq!(main[&2].2, Flow::Conditional);
+    assert_eq!(successors(&words, 2, 1, Flow::Conditional), vec![3, 5]);
+    assert_eq!(main[&5].2, Flow::Jump(5));
+
+    let graph = CallGraph::build(&words, &[0], &[(0, 9)]);
+    assert_eq!(graph.calls[&0], [6, 9].iter().cloned().collect());
+    assert_eq!(graph.calls[&6], [9].iter().cloned().collect());
+    assert!(graph.calls[&9].is_empty());
+    assert_eq!(graph.indirect, [0].iter().cloned().collect());
+    assert_eq!(graph.callers(9), [0, 6].iter().cloned().collect());
+    assert!(graph.callers(0).is_empty());
+
+    // JSR, the RET of g then the RET of f.
+    assert_eq!(max_cycles(&words, 6), Some(3 + 1 + 1));
+    assert_eq!(max_cycles(&[basic(BasicOp::IFE, reg(Register::A), lit(0)),
+                            basic(BasicOp::IFE, reg(Register::B), lit(0)),
+                            basic(BasicOp::ADD, reg(Register::A), lit(1)),
+                            RET],
+                          0),
+               Some(2 + 2 + 2 + 1));
+    assert_eq!(max_cycles(&words, 0), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_undefined_reads() {
+    use encodings::*;
+
+    let words = [basic(BasicOp::IFE, reg(Register::A), lit(0)),
+                 basic(BasicOp::SET, reg(Register::B), lit(1)),
+                 basic(BasicOp::XOR, reg(Register::C), reg(Register::C)),
+                 basic(BasicOp::ADD, reg(Register::C), reg(Register::B)),
+                 basic(BasicOp::SET, reg(Register::A), at_reg(Register::X)),
+                 special(SpecialOp::JSR, NEXT), 0x1000,
+                 basic(BasicOp::SET, reg(Register::Y), reg(Register::Z)),
+                 RET];
+    assert_eq!(undefined_reads(&words, 0, &[Register::A, Register::X]),
+               vec![(3, Register::B)]);
+    assert_eq!(undefined_reads(&words, 0, &[]),
+               vec![(0, Register::A), (3, Register::B), (4, Register::X)]);
+}
@@ -0,0 +1,328 @@
+//! Formats of the binaries read and written by the assembler, the
+//! disassembler and the emulator.
+//!
+//! | Name   | Format                                                      |
+//! |--------|-------------------------------------------------------------|
+//! | `le`   | raw words, little-endian, the default                       |
+//! | `be`   | raw words, big-endian                                       |
+//! | `hex`  | one word per line as `0x1234`, the `0x` being optional when |
+//! |        | read                                                        |
+//! | `ihex` | Intel HEX, with the words little-endian at byte addresses   |
+//!
+//! Intel HEX files may leave holes, read as zeros.
+//!
+//! `write_annotated` writes a dump of the memory for humans, which can't be
+//! read back.
+
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Bytes per data record of the Intel HEX files written.
+const IHEX_RECORD: usize = 16;
+
+/// Words per line of `write_annotated`.
+const ANNOTATED_ROW: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    LittleEndian,
+    BigEndian,
+    Hex,
+    IntelHex,
+}
+
+/// Every format name, for help messages.
+pub const NAMES: [&'static str; 4] = ["le", "be", "hex", "ihex"];
+
+impl Default for Format {
+    fn default() -> Format {
+        Format::LittleEndian
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s {
+            "le" => Ok(Format::LittleEndian),
+            "be" => Ok(Format::BigEndian),
+            "hex" => Ok(Format::Hex),
+            "ihex" => Ok(Format::IntelHex),
+            _ => Err(Error::UnknownFormat(s.into())),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Format::LittleEndian => NAMES[0],
+            Format::BigEndian => NAMES[1],
+            Format::Hex => NAMES[2],
+            Format::IntelHex => NAMES[3],
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    UnknownFormat(String),
+    /// Invalid line of a text format, from 1.
+    Syntax(usize),
+    /// Intel HEX record with a wrong checksum, by line from 1.
+    Checksum(usize),
+    /// More words than the memory holds.
+    TooLarge,
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownFormat(ref s) => {
+                write!(f, "unknown format \"{}\", expected one of {}", s, NAMES.join(", "))
+            }
+            Error::Syntax(line) => write!(f, "invalid line {}", line),
+            Error::Checksum(line) => write!(f, "wrong checksum line {}", line),
+            Error::TooLarge => write!(f, "more than 0x10000 words"),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::UnknownFormat(_) => "unknown format",
+            Error::Syntax(_) => "invalid line",
+            Error::Checksum(_) => "wrong checksum",
+            Error::TooLarge => "too many words",
+            Error::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+pub fn write<W: Write>(words: &[u16], format: Format, w: &mut W) -> io::Result<()> {
+    match format {
+        Format::LittleEndian => {
+            w.write_all(&le_bytes(words))
+        }
+        Format::BigEndian => {
+            let bytes: Vec<u8> = words.iter().flat_map(|&n| vec![(n >> 8) as u8, n as u8]).collect();
+            w.write_all(&bytes)
+        }
+        Format::Hex => {
+            for n in words {
+                try!(writeln!(w, "0x{:x}", n));
+            }
+            Ok(())
+        }
+        Format::IntelHex => write_ihex(words, w),
+    }
+}
+
+/// Writes `words`, loaded at `start`, as rows of 8 words in hex after their
+/// address, then their low bytes as ASCII like the characters of a LEM1802,
+/// `.` when not printable:
+///
+/// ```text
+/// 8000: f048 f065 f06c f06c f06f 0000 0000 0000  Hello...
+/// ```
+pub fn write_annotated<W: Write>(words: &[u16], start: u16, w: &mut W) -> io::Result<()> {
+    for (i, row) in words.chunks(ANNOTATED_ROW).enumerate() {
+        let addr = start.wrapping_add((i * ANNOTATED_ROW) as u16);
+        try!(write!(w, "{:04x}:", addr));
+        for word in row {
+            try!(write!(w, " {:04x}", word));
+        }
+        let padding = 5 * (ANNOTATED_ROW - row.len()) + 2;
+        let text: String = row.iter()
+                              .map(|&word| match word as u8 & 0x7f {
+                                  c @ 0x20...0x7e => c as char,
+                                  _ => '.',
+                              })
+                              .collect();
+        try!(writeln!(w, "{:width$}{}", "", text, width = padding));
+    }
+    Ok(())
+}
+
+fn le_bytes(words: &[u16]) -> Vec<u8> {
+    words.iter().flat_map(|&n| vec![n as u8, (n >> 8) as u8]).collect()
+}
+
+fn write_ihex<W: Write>(words: &[u16], w: &mut W) -> io::Result<()> {
+    let bytes = le_bytes(words);
+    for (i, data) in bytes.chunks(IHEX_RECORD).enumerate() {
+        let addr = i * IHEX_RECORD;
+        // The memory is 128KiB, past the 16 bits of the data records.
+        if addr & 0xffff == 0 && addr != 0 {
+            let upper = (addr >> 16) as u16;
+            try!(write_record(w, 0, 4, &[(upper >> 8) as u8, upper as u8]));
+        }
+        try!(write_record(w, addr as u16, 0, data));
+    }
+    write_record(w, 0, 1, &[])
+}
+
+fn write_record<W: Write>(w: &mut W, addr: u16, kind: u8, data: &[u8]) -> io::Result<()> {
+    let mut record = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    record.extend_from_slice(data);
+    let checksum = record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)).wrapping_neg();
+    record.push(checksum);
+    try!(write!(w, ":"));
+    for b in record {
+        try!(write!(w, "{:02X}", b));
+    }
+    writeln!(w, "")
+}
+
+pub fn read<R: Read>(r: &mut R, format: Format) -> Result<Vec<u16>, Error> {
+    let mut bytes = vec![];
+    try!(r.read_to_end(&mut bytes));
+    let words = match format {
+        Format::LittleEndian => {
+            bytes.chunks(2)
+                 .filter(|c| c.len() == 2)
+                 .map(|c| c[0] as u16 | (c[1] as u16) << 8)
+                 .collect()
+        }
+        Format::BigEndian => {
+            bytes.chunks(2)
+                 .filter(|c| c.len() == 2)
+                 .map(|c| (c[0] as u16) << 8 | c[1] as u16)
+                 .collect()
+        }
+        Format::Hex => try!(read_hex(&String::from_utf8_lossy(&bytes))),
+        Format::IntelHex => try!(read_ihex(&String::from_utf8_lossy(&bytes))),
+    };
+    if words.len() > 0x10000 {
+        Err(Error::TooLarge)
+    } else {
+        Ok(words)
+    }
+}
+
+fn read_hex(text: &str) -> Result<Vec<u16>, Error> {
+    let mut words = vec![];
+    for (i, line) in text.lines().enumerate() {
+        for word in line.split_whitespace() {
+            let digits = word.trim_left_matches("0x");
+            match u16::from_str_radix(digits, 16) {
+                Ok(n) => words.push(n),
+                Err(_) => return Err(Error::Syntax(i + 1)),
+            }
+        }
+    }
+    Ok(words)
+}
+
+fn read_ihex(text: &str) -> Result<Vec<u16>, Error> {
+    let mut bytes = vec![];
+    let mut base = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = match parse_record(line) {
+            Some(record) => record,
+            None => return Err(Error::Syntax(i + 1)),
+        };
+        if record.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(Error::Checksum(i + 1));
+        }
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0 => {
+                let addr = base + ((record[1] as usize) << 8 | record[2] as usize);
+                if addr + data.len() > 0x20000 {
+                    return Err(Error::TooLarge);
+                }
+                if bytes.len() < addr + data.len() {
+                    bytes.resize(addr + data.len(), 0);
+                }
+                bytes[addr..addr + data.len()].copy_from_slice(data);
+            }
+            1 => break,
+            // Extended segment address.
+            2 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+            // Extended linear address.
+            4 if data.len() == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+            // Start addresses, meaningless here.
+            3 | 5 => (),
+            _ => return Err(Error::Syntax(i + 1)),
+        }
+    }
+    Ok(bytes.chunks(2).map(|c| c[0] as u16 | (*c.get(1).unwrap_or(&0) as u16) << 8).collect())
+}
+
+/// Bytes of a `:`-prefixed record, checked against its length.
+fn parse_record(line: &str) -> Option<Vec<u8>> {
+    if !line.starts_with(':') || line.len() % 2 != 1 {
+        return None;
+    }
+    let mut record = vec![];
+    for i in 0..line.len() / 2 {
+        match u8::from_str_radix(&line[1 + 2 * i..3 + 2 * i], 16) {
+            Ok(b) => record.push(b),
+            Err(_) => return None,
+        }
+    }
+    if record.len() < 5 || record.len() != record[0] as usize + 5 {
+        return None;
+    }
+    Some(record)
+}
+
+#[cfg(test)]
+#[test]
+fn test_formats() {
+    let words: Vec<u16> = (0..0x9000u32).map(|n| (n * 7) as u16).collect();
+    for name in NAMES.iter() {
+        let format: Format = name.parse().unwrap();
+        assert_eq!(format.to_string(), *name);
+        let mut bytes = vec![];
+        write(&words, format, &mut bytes).unwrap();
+        assert_eq!(read(&mut &bytes[..], format).unwrap(), words);
+    }
+    assert!("elf".parse::<Format>().is_err());
+
+    let mut bytes = vec![];
+    write(&[0x1234], Format::BigEndian, &mut bytes).unwrap();
+    assert_eq!(bytes, [0x12, 0x34]);
+    let mut bytes = vec![];
+    write(&[0x1234, 0xabcd], Format::IntelHex, &mut bytes).unwrap();
+    assert_eq!(String::from_utf8(bytes).unwrap(),
+               ":040000003412CDAB3E\n:00000001FF\n");
+
+    assert_eq!(read(&mut &b"1 0x2\n\nffff"[..], Format::Hex).unwrap(), [1, 2, 0xffff]);
+    match read(&mut &b"1\nx"[..], Format::Hex) {
+        Err(Error::Syntax(2)) => (),
+        r => panic!("{:?}", r),
+    }
+    // A hole before the data.
+    assert_eq!(read(&mut &b":020004003412B4\n:00000001FF\n"[..], Format::IntelHex).unwrap(),
+               [0, 0, 0x1234]);
+    match read(&mut &b":020004003412B5\n"[..], Format::IntelHex) {
+        Err(Error::Checksum(1)) => (),
+        r => panic!("{:?}", r),
+    }
+
+    let mut bytes = vec![];
+    let text = [0xf048, 0xf065, 0xf06c, 0xf06c, 0xf06f, 0, 0, 0, 0x7f21];
+    write_annotated(&text, 0x8000, &mut bytes).unwrap();
+    assert_eq!(String::from_utf8(bytes).unwrap(),
+               "8000: f048 f065 f06c f06c f06f 0000 0000 0000  Hello...\n\
+                8008: 7f21                                     !\n");
+}
@@ -0,0 +1,311 @@
+//! Coverage-guided fuzzing of the words a program receives on its serial
+//! port.
+//!
+//! Each input runs on a new computer, receiving the input on a serial port.
+//! Inputs reaching new edges `(previous PC, PC)` of the program are kept and
+//! mutated further. Inputs making the CPU fail, or failing an `Assertion`,
+//! are reported.
+
+use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+
+use computer::Computer;
+use cpu::{self, Cpu};
+use device::*;
+use device::jitter::Jitter;
+use device::serial::{self, Serial};
+
+/// Device failing the fuzzed program on `HWI` with A = 0, B being the id of
+/// the assertion. Other values of A do nothing, so the program can check
+/// its conditions with `IFx` before the `HWI`.
+#[derive(Debug)]
+pub struct Assertion {
+    failed: Rc<Cell<Option<u16>>>,
+}
+
+impl Device for Assertion {
+    fn hardware_id(&self) -> u32 {
+        0xa55e7100
+    }
+
+    fn hardware_version(&self) -> u16 {
+        1
+    }
+
+    fn manufacturer(&self) -> u32 {
+        0x1c6c8b36
+    }
+
+    fn name(&self) -> &str {
+        "Fuzzer Assertion"
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        if cpu.registers[0] == 0 && self.failed.get().is_none() {
+            self.failed.set(Some(cpu.registers[1]));
+        }
+        Ok(0)
+    }
+
+    fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+        TickResult::Nothing
+    }
+
+    fn next_interrupt(&self, _: u64) -> Option<u64> {
+        None
+    }
+}
+
+/// Serial backend sending an input and ignoring what the program sends.
+#[derive(Debug)]
+struct Script(VecDeque<u16>);
+
+impl serial::Backend for Script {
+    fn receive(&mut self) -> Option<u16> {
+        self.0.pop_front()
+    }
+
+    fn send(&mut self, _: u16) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// The CPU failed, with the message of its error.
+    Crash(String),
+    /// An `Assertion` failed, with its id.
+    Assertion(u16),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Crash(ref e) => write!(f, "crash: {}", e),
+            Failure::Assertion(id) => write!(f, "assertion {} failed", id),
+        }
+    }
+}
+
+/// Input making the program fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub input: Vec<u16>,
+    pub failure: Failure,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Ticks after which a run stops, for the programs waiting forever.
+    pub max_ticks: u64,
+    /// Words in an input, at most the buffer of the serial port.
+    pub max_len: usize,
+    /// Words the program compares its input to, such as the names of its
+    /// commands, inserted like the interesting values.
+    pub dictionary: Vec<u16>,
+    pub seed: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            max_ticks: 10000,
+            max_len: 64,
+            dictionary: vec![],
+            seed: 0,
+        }
+    }
+}
+
+/// Values often compared to, tried before random words.
+const INTERESTING: [u16; 8] = [0, 1, 0x7f, 0x80, 0xff, 0x7fff, 0x8000, 0xffff];
+
+pub struct Fuzzer<F: FnMut() -> Computer> {
+    /// Builds the computer to fuzz, with the program loaded. The serial port
+    /// and the `Assertion` are added after its devices.
+    setup: F,
+    options: Options,
+    rng: Jitter,
+    corpus: Vec<Vec<u16>>,
+    edges: HashSet<(u16, u16)>,
+    findings: Vec<Finding>,
+    executions: u64,
+}
+
+impl<F: FnMut() -> Computer> Fuzzer<F> {
+    pub fn new(setup: F, options: Options) -> Fuzzer<F> {
+        Fuzzer {
+            setup: setup,
+            rng: Jitter::new(options.seed, 0, 0),
+            options: options,
+            corpus: vec![],
+            edges: HashSet::new(),
+            findings: vec![],
+            executions: 0,
+        }
+    }
+
+    /// Runs `input` and keeps it as a starting point if it reaches new
+    /// edges.
+    pub fn add_seed(&mut self, input: Vec<u16>) {
+        self.try_input(input);
+    }
+
+    /// Inputs reaching new edges, in the order they were found.
+    pub fn corpus(&self) -> &[Vec<u16>] {
+        &self.corpus
+    }
+
+    /// Edges reached so far.
+    pub fn coverage(&self) -> usize {
+        self.edges.len()
+    }
+
+    /// First input found for each failure.
+    pub fn findings(&self) -> &[Finding] {
+        &self.findings
+    }
+
+    pub fn executions(&self) -> u64 {
+        self.executions
+    }
+
+    /// Runs `executions` mutated inputs.
+    pub fn run(&mut self, executions: u64) {
+        if self.corpus.is_empty() {
+            self.add_seed(vec![]);
+        }
+        for _ in 0..executions {
+            let parent = self.rng.next_u64() as usize % self.corpus.len();
+            let mut input = self.corpus[parent].clone();
+            let mutations = 1 + self.rng.next_u64() % 4;
+            for _ in 0..mutations {
+                self.mutate(&mut input);
+            }
+            input.truncate(self.options.max_len);
+            self.try_input(input);
+        }
+    }
+
+    fn try_input(&mut self, input: Vec<u16>) {
+        let (edges, failure) = self.execute(&input);
+        self.executions += 1;
+        let new = edges.difference(&self.edges).count() != 0;
+        self.edges.extend(edges);
+        if let Some(failure) = failure {
+            if self.findings.iter().all(|f| f.failure != failure) {
+                self.findings.push(Finding {
+                    input: input.clone(),
+                    failure: failure,
+                });
+            }
+        }
+        if new || self.corpus.is_empty() {
+            self.corpus.push(input);
+        }
+    }
+
+    /// Edges reached by `input` and the failure it causes.
+    pub fn execute(&mut self, input: &[u16]) -> (HashSet<(u16, u16)>, Option<Failure>) {
+        let mut computer = (self.setup)();
+        let failed = Rc::new(Cell::new(None));
+        let script = Script(input.iter().cloned().collect());
+        computer.add_device(Box::new(Serial::new(Box::new(script))));
+        computer.add_device(Box::new(Assertion { failed: failed.clone() }));
+
+        let mut edges = HashSet::new();
+        let mut previous = computer.cpu().pc;
+        let mut failure = None;
+        while computer.current_tick() < self.options.max_ticks {
+            let result = computer.tick().and_then(|_| computer.skip_idle());
+            let pc = computer.cpu().pc;
+            if pc != previous {
+                edges.insert((previous, pc));
+                previous = pc;
+            }
+            if let Some(id) = failed.get() {
+                failure = Some(Failure::Assertion(id));
+                break;
+            }
+            match result {
+                Ok(_) => (),
+                Err(cpu::Error::Halted) |
+                Err(cpu::Error::Asleep) => break,
+                Err(e) => {
+                    failure = Some(Failure::Crash(e.to_string()));
+                    break;
+                }
+            }
+        }
+        (edges, failure)
+    }
+
+    fn mutate(&mut self, input: &mut Vec<u16>) {
+        let r = self.rng.next_u64();
+        let len = input.len();
+        let pos = if len == 0 { 0 } else { (r >> 8) as usize % len };
+        let dictionary = &self.options.dictionary;
+        let word = match (r >> 40) % 4 {
+            0 => INTERESTING[(r >> 32) as usize % INTERESTING.len()],
+            1 if !dictionary.is_empty() => dictionary[(r >> 32) as usize % dictionary.len()],
+            2 => (r >> 24) as u16 & 0xff,
+            _ => (r >> 24) as u16,
+        };
+        match r % 5 {
+            0 if len != 0 => input[pos] ^= 1 << ((r >> 32) % 16),
+            1 if len != 0 => input[pos] = word,
+            2 if len != 0 => {
+                input.remove(pos);
+            }
+            3 => {
+                let other = (r >> 48) as usize % self.corpus.len();
+                let tail = self.corpus[other].clone();
+                let from = if tail.is_empty() { 0 } else { (r >> 32) as usize % tail.len() };
+                input.truncate(pos);
+                input.extend(&tail[from..]);
+            }
+            _ => input.insert((r >> 8) as usize % (len + 1), word),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_fuzz() {
+    use encodings::*;
+    use types::*;
+
+    // Fails assertion 7 after receiving 0x42 then 0x17.
+    let program = [basic(BasicOp::SET, reg(Register::A), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::IFN, reg(Register::B), lit(0)),
+                   basic(BasicOp::SET, PC, lit(5)),
+                   special(SpecialOp::HLT, lit(0)),
+                   basic(BasicOp::IFN, reg(Register::C), NEXT),
+                   0x42,
+                   basic(BasicOp::SET, PC, lit(0)),
+                   basic(BasicOp::SET, reg(Register::A), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::IFN, reg(Register::C), lit(0x17)),
+                   basic(BasicOp::SET, PC, lit(0)),
+                   basic(BasicOp::SET, reg(Register::A), lit(0)),
+                   basic(BasicOp::SET, reg(Register::B), lit(7)),
+                   special(SpecialOp::HWI, lit(1)),
+                   special(SpecialOp::HLT, lit(0))];
+    let setup = || {
+        let mut cpu = Cpu::default();
+        cpu.load(&program, 0);
+        Computer::new(cpu)
+    };
+
+    let options = Options { dictionary: vec![0x17, 0x42, 0x1234], ..Options::default() };
+    let mut fuzzer = Fuzzer::new(setup, options);
+    assert_eq!(fuzzer.execute(&[0x42, 0x17]).1, Some(Failure::Assertion(7)));
+    assert_eq!(fuzzer.execute(&[0x42, 0x18]).1, None);
+
+    fuzzer.run(5000);
+    assert!(fuzzer.corpus().len() > 1);
+    let finding = &fuzzer.findings()[0];
+    assert_eq!(finding.failure, Failure::Assertion(7));
+    assert!(finding.input.windows(2).any(|w| w == [0x42, 0x17]));
+}
@@ -0,0 +1,446 @@
+//! Stub of the GDB remote serial protocol, so debuggers speaking it can
+//! attach to a computer.
+//!
+//! GDB addresses bytes: the word at `addr` is at the byte address
+//! `2 * addr`, low byte first. The registers are A, B, C, I, J, X, Y, Z,
+//! PC, SP, EX and IA, each 2 bytes in the same order.
+//!
+//! Supported packets: `?`, `g`, `G`, `p`, `P`, `m`, `M`, `s`, `c`, `Z0`,
+//! `z0`, `Z1`, `z1`, `D` and `k`, and the `disas` monitor command (`qRcmd`)
+//! showing the instructions around PC. The others get the empty reply,
+//! meaning they aren't supported. There is no authentication: only listen
+//! on local addresses.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::str;
+
+use computer::Computer;
+use cpu;
+use window::{self, History};
+
+/// Registers sent by `g`.
+const REGISTERS: usize = 12;
+
+/// Longest `m` and `M` range in bytes: the whole memory.
+const MAX_LENGTH: u32 = 2 * 0x10000;
+
+/// Instructions run between two checks for an interruption from GDB.
+const SLICE: u32 = 10000;
+
+/// Instructions shown by `disas` before and from PC.
+const DISAS_BEFORE: usize = 4;
+const DISAS_AFTER: usize = 8;
+
+/// Data received from GDB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    /// Content of a packet, its checksum already checked.
+    Packet(String),
+    /// Ctrl-C, to stop a running computer.
+    Interrupt,
+}
+
+/// What a packet asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Reply(String),
+    Step,
+    Continue,
+    Detach,
+    Kill,
+}
+
+/// Why `Stub::serve` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    /// The computer should keep running without the debugger.
+    Detached,
+    Killed,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |acc, b| acc.wrapping_add(b))
+}
+
+/// Reads the next packet or interruption, acknowledging the packets.
+pub fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Input> {
+    let mut byte = [0];
+    loop {
+        try!(stream.read_exact(&mut byte));
+        match byte[0] {
+            b'$' => (),
+            0x03 => return Ok(Input::Interrupt),
+            // Acknowledgements and noise between packets.
+            _ => continue,
+        }
+        let mut data = vec![];
+        loop {
+            try!(stream.read_exact(&mut byte));
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        try!(stream.read_exact(&mut sum));
+        let data = String::from_utf8_lossy(&data).into_owned();
+        let expected = String::from_utf8_lossy(&sum);
+        if u8::from_str_radix(&expected, 16).ok() == Some(checksum(&data)) {
+            try!(stream.write_all(b"+"));
+            return Ok(Input::Packet(data));
+        }
+        try!(stream.write_all(b"-"));
+    }
+}
+
+/// Sends a packet until GDB acknowledges it.
+pub fn write_packet<S: Read + Write>(stream: &mut S, data: &str) -> io::Result<()> {
+    let mut ack = [0];
+    loop {
+        try!(write!(stream, "${}#{:02x}", data, checksum(data)));
+        try!(stream.flush());
+        try!(stream.read_exact(&mut ack));
+        if ack[0] == b'+' {
+            return Ok(());
+        }
+    }
+}
+
+fn hex_word(w: u16) -> String {
+    format!("{:02x}{:02x}", w & 0xff, w >> 8)
+}
+
+fn hex_bytes(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|b| str::from_utf8(b).ok().and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+fn parse_word(s: &str) -> Option<u16> {
+    parse_bytes(s).and_then(|b| if b.len() == 2 {
+        Some(b[0] as u16 | (b[1] as u16) << 8)
+    } else {
+        None
+    })
+}
+
+/// `addr,len`, the length being optional.
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, ',');
+    let addr = parts.next().and_then(parse_hex);
+    let len = parts.next().map_or(Some(0), parse_hex);
+    addr.and_then(|a| len.map(|l| (a, l)))
+}
+
+fn register(computer: &mut Computer, n: usize) -> Option<&mut u16> {
+    let cpu = computer.cpu_mut();
+    match n {
+        0...7 => Some(&mut cpu.registers[n]),
+        8 => Some(&mut cpu.pc),
+        9 => Some(&mut cpu.sp),
+        10 => Some(&mut cpu.ex),
+        11 => Some(&mut cpu.ia),
+        _ => None,
+    }
+}
+
+fn read_byte(computer: &Computer, byte: u32) -> u8 {
+    let word = computer.cpu().ram[(byte / 2) as usize % 0x10000];
+    if byte % 2 == 0 { word as u8 } else { (word >> 8) as u8 }
+}
+
+fn write_byte(computer: &mut Computer, byte: u32, val: u8) {
+    let word = &mut computer.cpu_mut().ram[(byte / 2) as usize % 0x10000];
+    *word = if byte % 2 == 0 {
+        *word & 0xff00 | val as u16
+    } else {
+        *word & 0x00ff | (val as u16) << 8
+    };
+}
+
+/// Stop reply after running the computer.
+fn stop_reply<T>(result: Result<T, cpu::Error>) -> String {
+    match result {
+        Ok(_) => "S05".into(),
+        // The program exited.
+        Err(cpu::Error::Halted) => "W00".into(),
+        // SIGILL
+        Err(_) => "S04".into(),
+    }
+}
+
+/// Debugger side state of the computer.
+#[derive(Debug, Default)]
+pub struct Stub {
+    pub breakpoints: Vec<u16>,
+    /// Instructions run by the stub, for `disas`.
+    pub history: History,
+}
+
+impl Stub {
+    pub fn new() -> Stub {
+        Stub::default()
+    }
+
+    /// Handles the requests reading or changing the state, and tells what
+    /// to do for the others.
+    pub fn handle(&mut self, computer: &mut Computer, packet: &str) -> Action {
+        if !packet.is_ascii() {
+            return Action::Reply("E01".into());
+        }
+        let (command, args) = packet.split_at(if packet.is_empty() { 0 } else { 1 });
+        let reply = match command {
+            "?" => Some("S05".into()),
+            "g" => {
+                let regs = (0..REGISTERS).map(|r| hex_word(*register(computer, r).unwrap()));
+                Some(regs.collect())
+            }
+            "G" => {
+                parse_bytes(args).and_then(|bytes| if bytes.len() == 2 * REGISTERS {
+                    for r in 0..REGISTERS {
+                        let w = bytes[2 * r] as u16 | (bytes[2 * r + 1] as u16) << 8;
+                        *register(computer, r).unwrap() = w;
+                    }
+                    Some("OK".into())
+                } else {
+                    None
+                })
+            }
+            "p" => {
+                parse_hex(args)
+                    .and_then(|r| register(computer, r as usize).map(|v| *v))
+                    .map(hex_word)
+            }
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let r = parts.next().and_then(parse_hex);
+                let w = parts.next().and_then(parse_word);
+                match (r, w) {
+                    (Some(r), Some(w)) => {
+                        register(computer, r as usize).map(|reg| {
+                            *reg = w;
+                            "OK".into()
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            "m" => {
+                parse_range(args).and_then(|(addr, len)| if len <= MAX_LENGTH {
+                    Some((addr, len))
+                } else {
+                    None
+                }).map(|(addr, len)| {
+                    (0..len)
+                        .map(|i| format!("{:02x}", read_byte(computer, addr.wrapping_add(i))))
+                        .collect()
+                })
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let bytes = parts.next().and_then(parse_bytes);
+                match (range, bytes) {
+                    (Some((addr, len)), Some(ref bytes)) if len <= MAX_LENGTH &&
+                                                            bytes.len() == len as usize => {
+                        for (i, &b) in bytes.iter().enumerate() {
+                            write_byte(computer, addr.wrapping_add(i as u32), b);
+                        }
+                        Some("OK".into())
+                    }
+                    _ => None,
+                }
+            }
+            "Z" | "z" if args.starts_with("0,") || args.starts_with("1,") => {
+                parse_range(&args[2..]).map(|(addr, _)| {
+                    let addr = (addr / 2) as u16;
+                    self.breakpoints.retain(|&b| b != addr);
+                    if command == "Z" {
+                        self.breakpoints.push(addr);
+                    }
+                    "OK".into()
+                })
+            }
+            "q" if args.starts_with("Rcmd,") => {
+                let command = parse_bytes(&args[5..]).and_then(|b| String::from_utf8(b).ok());
+                match command.as_ref().map(|c| c.trim()) {
+                    Some("disas") => Some(hex_bytes(&self.disassembly(computer))),
+                    _ => None,
+                }
+            }
+            "s" => return Action::Step,
+            "c" => return Action::Continue,
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            "H" => Some("OK".into()),
+            _ => return Action::Reply(String::new()),
+        };
+        Action::Reply(reply.unwrap_or("E01".into()))
+    }
+
+    /// Instructions around PC, one per line with its address and words.
+    fn disassembly(&self, computer: &Computer) -> String {
+        let cpu = computer.cpu();
+        window::window(cpu, &self.history, DISAS_BEFORE, DISAS_AFTER)
+            .iter()
+            .map(|row| {
+                let marker = if row.addr == cpu.pc { "=>" } else { "  " };
+                let words: Vec<String> = row.words.iter().map(|w| format!("{:04x}", w)).collect();
+                format!("{} 0x{:04x}: {:<15} {}\n", marker, row.addr, words.join(" "), row.text)
+            })
+            .collect()
+    }
+
+    /// Runs one instruction.
+    pub fn step(&mut self, computer: &mut Computer) -> String {
+        let result = computer.step();
+        self.history.update(computer.cpu());
+        stop_reply(result)
+    }
+
+    /// Runs up to `max` instructions, stopping at the breakpoints. Returns
+    /// the stop reply if the computer stopped.
+    pub fn run(&mut self, computer: &mut Computer, max: u32) -> Option<String> {
+        for _ in 0..max {
+            let result = computer.step().and_then(|_| computer.skip_idle());
+            self.history.update(computer.cpu());
+            if result.is_err() || self.breakpoints.contains(&computer.cpu().pc) {
+                return Some(stop_reply(result));
+            }
+        }
+        None
+    }
+
+    /// Runs until a breakpoint or an interruption by GDB.
+    fn resume(&mut self, computer: &mut Computer, stream: &mut TcpStream) -> io::Result<String> {
+        let mut byte = [0];
+        loop {
+            if let Some(reply) = self.run(computer, SLICE) {
+                return Ok(reply);
+            }
+            try!(stream.set_nonblocking(true));
+            let read = stream.read(&mut byte);
+            try!(stream.set_nonblocking(false));
+            match read {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "gdb left")),
+                // SIGINT
+                Ok(_) if byte[0] == 0x03 => return Ok("S02".into()),
+                Ok(_) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Answers GDB until it detaches or kills the computer.
+    pub fn serve(&mut self, computer: &mut Computer, stream: &mut TcpStream) -> io::Result<End> {
+        loop {
+            let packet = match try!(read_packet(stream)) {
+                Input::Packet(p) => p,
+                // Already stopped.
+                Input::Interrupt => continue,
+            };
+            let reply = match self.handle(computer, &packet) {
+                Action::Reply(r) => r,
+                Action::Step => self.step(computer),
+                Action::Continue => try!(self.resume(computer, stream)),
+                Action::Detach => {
+                    try!(write_packet(stream, "OK"));
+                    return Ok(End::Detached);
+                }
+                Action::Kill => return Ok(End::Killed),
+            };
+            try!(write_packet(stream, &reply));
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_gdb() {
+    use std::io::Cursor;
+
+    use encodings::*;
+    use types::*;
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut stream = Duplex {
+        input: Cursor::new(b"+$g#00$g#67\x03".to_vec()),
+        output: vec![],
+    };
+    assert_eq!(read_packet(&mut stream).unwrap(), Input::Packet("g".into()));
+    assert_eq!(read_packet(&mut stream).unwrap(), Input::Interrupt);
+    assert_eq!(stream.output, b"-+");
+    stream.input = Cursor::new(b"-+".to_vec());
+    write_packet(&mut stream, "OK").unwrap();
+    assert_eq!(&stream.output[2..], b"$OK#9a$OK#9a");
+
+    // Counts in A, the breakpoint being on the ADD.
+    let mut computer = Computer::default();
+    computer.cpu_mut().load(&[basic(BasicOp::ADD, reg(Register::A), lit(1)),
+                              basic(BasicOp::SET, PC, lit(0))],
+                            0);
+    let mut stub = Stub::new();
+    let mut reply = |c: &mut Computer, p: &str| match stub.handle(c, p) {
+        Action::Reply(r) => r,
+        a => panic!("{:?}", a),
+    };
+    assert_eq!(reply(&mut computer, "m0,4"), "02888187");
+    assert_eq!(reply(&mut computer, "M4,2:3412"), "OK");
+    assert_eq!(computer.cpu().ram[2], 0x1234);
+    assert_eq!(reply(&mut computer, "P0=0500"), "OK");
+    assert_eq!(reply(&mut computer, "p0"), "0500");
+    assert_eq!(&reply(&mut computer, "g")[32..40], "0000ffff");
+    assert_eq!(reply(&mut computer, "Z0,0,2"), "OK");
+    assert_eq!(reply(&mut computer, "vMustReplyEmpty"), "");
+    assert_eq!(reply(&mut computer, "p20"), "E01");
+    assert_eq!(reply(&mut computer, "m0,20001"), "E01");
+    assert_eq!(reply(&mut computer, "\u{e9}"), "E01");
+    assert_eq!(reply(&mut computer, "M0,1:\u{e9}"), "E01");
+    assert_eq!(parse_bytes("0\u{e9}"), None);
+    // disas
+    let disassembly = reply(&mut computer, "qRcmd,6469736173");
+    assert!(disassembly.contains(&hex_bytes("=> 0x0000: 8802")));
+    assert_eq!(reply(&mut computer, "qRcmd,6e6f7065"), "E01");
+
+    assert_eq!(stub.handle(&mut computer, "c"), Action::Continue);
+    assert_eq!(stub.run(&mut computer, 10), Some("S05".into()));
+    assert_eq!(computer.cpu().registers[0], 6);
+    assert_eq!(stub.step(&mut computer), "S05");
+    assert_eq!(computer.cpu().pc, 1);
+    assert_eq!(stub.handle(&mut computer, "z0,0,2"), Action::Reply("OK".into()));
+    assert_eq!(stub.run(&mut computer, 10), None);
+}
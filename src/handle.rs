@@ -0,0 +1,467 @@
+//! Computer running on its own thread, controlled from other threads, for
+//! frontends which don't want to own the main loop, like a GUI.
+//!
+//! The devices aren't `Send`, so the computer is built on its thread by a
+//! closure given to `ComputerHandle::spawn`. The handle then sends it
+//! commands through a channel, the queries getting their answer through
+//! another one:
+//!
+//! ```
+//! use dcpu::computer::Computer;
+//! use dcpu::cpu::Cpu;
+//! use dcpu::encodings::*;
+//! use dcpu::handle::{ComputerHandle, RunState, Until};
+//! use dcpu::types::{BasicOp, Register};
+//!
+//! let handle = ComputerHandle::spawn(|| {
+//!     let mut cpu = Cpu::default();
+//!     cpu.load(&[basic(BasicOp::ADD, reg(Register::A), lit(1)), basic(BasicOp::SET, PC, lit(0))],
+//!              0);
+//!     Computer::new(cpu)
+//! });
+//! handle.set_turbo(true).unwrap();
+//! handle.run_until(Until::Tick(1000)).unwrap();
+//! assert_eq!(handle.wait().unwrap(), RunState::Paused);
+//! assert_eq!(handle.query(|c| c.current_tick()).unwrap(), 1000);
+//! assert!(handle.query(|c| c.cpu().registers[Register::A as usize]).unwrap() > 300);
+//! ```
+
+use std::cmp;
+use std::error;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use computer::Computer;
+use cpu::{self, CpuState};
+use timebase::Throttle;
+
+/// Ticks run between two checks for commands.
+const SLICE: u64 = 1000;
+
+/// What the computer is doing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunState {
+    Running,
+    /// Waiting for a command, from the start, after `pause`, at a
+    /// breakpoint or a watchpoint, or when reaching the target of
+    /// `run_until`.
+    Paused,
+    /// The CPU failed or halted, with the message of its `cpu::Error`. It
+    /// can't run anymore, but can still be queried.
+    Stopped(String),
+}
+
+/// Target of `ComputerHandle::run_until`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Until {
+    /// PC reaches this address, the instruction there not executed yet.
+    Address(u16),
+    /// `Computer::current_tick` reaches this tick.
+    Tick(u64),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The thread of the computer is gone, after a panic.
+    Disconnected,
+    /// Can't run once stopped, see `RunState::Stopped`.
+    Stopped,
+    Cpu(cpu::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Disconnected => write!(f, "the computer thread is gone"),
+            Error::Stopped => write!(f, "the computer is stopped"),
+            Error::Cpu(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Disconnected => "computer thread gone",
+            Error::Stopped => "computer stopped",
+            Error::Cpu(ref e) => e.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Cpu(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<cpu::Error> for Error {
+    fn from(e: cpu::Error) -> Error {
+        Error::Cpu(e)
+    }
+}
+
+type Call = Box<FnMut(&mut Computer) + Send>;
+
+enum Command {
+    Pause,
+    Resume,
+    RunUntil(Until),
+    Step(u64, Sender<Result<(), Error>>),
+    Interrupt(u16),
+    SetTurbo(bool),
+    Call(Call),
+    State(Sender<RunState>),
+    /// Answered once the computer isn't running.
+    Wait(Sender<RunState>),
+    Quit,
+}
+
+/// Thread-safe handle on a computer running on its own thread, stopping it
+/// when dropped.
+#[derive(Debug)]
+pub struct ComputerHandle {
+    commands: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ComputerHandle {
+    /// Starts a thread running the computer returned by `build`, paused.
+    ///
+    /// Once resumed, it runs in real time at its timebase, see
+    /// `set_turbo`.
+    pub fn spawn<F>(build: F) -> ComputerHandle
+        where F: FnOnce() -> Computer + Send + 'static
+    {
+        let (commands, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || Runner::new(build(), receiver).run());
+        ComputerHandle {
+            commands: commands,
+            thread: Some(thread),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.commands.send(command).map_err(|_| Error::Disconnected)
+    }
+
+    pub fn pause(&self) -> Result<(), Error> {
+        self.send(Command::Pause)
+    }
+
+    /// Runs until paused, or until a breakpoint or a watchpoint.
+    pub fn resume(&self) -> Result<(), Error> {
+        self.send(Command::Resume)
+    }
+
+    /// Runs until `until`, or until paused, a breakpoint or a watchpoint.
+    pub fn run_until(&self, until: Until) -> Result<(), Error> {
+        self.send(Command::RunUntil(until))
+    }
+
+    /// Pauses, then runs `n` instructions, see `Computer::step`.
+    pub fn step(&self, n: u64) -> Result<(), Error> {
+        let (sender, receiver) = mpsc::channel();
+        try!(self.send(Command::Step(n, sender)));
+        receiver.recv().unwrap_or(Err(Error::Disconnected))
+    }
+
+    /// Queues an interrupt with this message, like a device.
+    pub fn interrupt(&self, message: u16) -> Result<(), Error> {
+        self.send(Command::Interrupt(message))
+    }
+
+    /// Runs as fast as possible instead of in real time.
+    pub fn set_turbo(&self, turbo: bool) -> Result<(), Error> {
+        self.send(Command::SetTurbo(turbo))
+    }
+
+    /// Calls `f` with the computer between two ticks, and returns its
+    /// result, to read its state or change it.
+    pub fn query<F, R>(&self, f: F) -> Result<R, Error>
+        where F: FnOnce(&mut Computer) -> R + Send + 'static,
+              R: Send + 'static
+    {
+        let (sender, receiver) = mpsc::channel();
+        let mut f = Some(f);
+        let call = move |computer: &mut Computer| {
+            if let Some(f) = f.take() {
+                let _ = sender.send(f(computer));
+            }
+        };
+        try!(self.send(Command::Call(Box::new(call))));
+        receiver.recv().map_err(|_| Error::Disconnected)
+    }
+
+    pub fn state(&self) -> Result<RunState, Error> {
+        let (sender, receiver) = mpsc::channel();
+        try!(self.send(Command::State(sender)));
+        receiver.recv().map_err(|_| Error::Disconnected)
+    }
+
+    /// Blocks until the computer is paused or stopped, and returns its
+    /// state.
+    pub fn wait(&self) -> Result<RunState, Error> {
+        let (sender, receiver) = mpsc::channel();
+        try!(self.send(Command::Wait(sender)));
+        receiver.recv().map_err(|_| Error::Disconnected)
+    }
+}
+
+impl Drop for ComputerHandle {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The loop of the thread.
+struct Runner {
+    computer: Computer,
+    commands: Receiver<Command>,
+    state: RunState,
+    until: Option<Until>,
+    /// Breakpoint added for `Until::Address`, removed when reached.
+    temporary_breakpoint: Option<u16>,
+    waiting: Vec<Sender<RunState>>,
+    turbo: bool,
+    throttle: Throttle,
+}
+
+impl Runner {
+    fn new(computer: Computer, commands: Receiver<Command>) -> Runner {
+        let throttle = Throttle::new(computer.timebase(), computer.current_tick());
+        Runner {
+            computer: computer,
+            commands: commands,
+            state: RunState::Paused,
+            until: None,
+            temporary_breakpoint: None,
+            waiting: vec![],
+            turbo: false,
+            throttle: throttle,
+        }
+    }
+
+    /// Handles the commands, running the computer in between, until the
+    /// handle is dropped.
+    fn run(mut self) {
+        loop {
+            let command = if self.state == RunState::Running {
+                match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            } else {
+                match self.commands.recv() {
+                    Ok(command) => Some(command),
+                    Err(_) => return,
+                }
+            };
+            match command {
+                Some(Command::Quit) => return,
+                Some(command) => self.handle(command),
+                None => self.run_slice(),
+            }
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        match self.state {
+            RunState::Stopped(_) => true,
+            _ => false,
+        }
+    }
+
+    fn reset_throttle(&mut self) {
+        self.throttle = Throttle::new(self.computer.timebase(), self.computer.current_tick());
+    }
+
+    fn set_state(&mut self, state: RunState) {
+        if state == RunState::Running {
+            self.reset_throttle();
+        } else {
+            self.until = None;
+            if let Some(addr) = self.temporary_breakpoint.take() {
+                self.computer.cpu_mut().remove_breakpoint(addr);
+            }
+            for waiting in self.waiting.drain(..) {
+                let _ = waiting.send(state.clone());
+            }
+        }
+        self.state = state;
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Pause if !self.is_stopped() => self.set_state(RunState::Paused),
+            Command::Resume if !self.is_stopped() => self.set_state(RunState::Running),
+            Command::RunUntil(until) if !self.is_stopped() => {
+                self.set_state(RunState::Paused);
+                if let Until::Address(addr) = until {
+                    if self.computer.cpu_mut().add_breakpoint(addr) {
+                        self.temporary_breakpoint = Some(addr);
+                    }
+                }
+                self.until = Some(until);
+                self.set_state(RunState::Running);
+            }
+            Command::Pause | Command::Resume | Command::RunUntil(_) => (),
+            Command::Step(n, result) => {
+                if self.is_stopped() {
+                    let _ = result.send(Err(Error::Stopped));
+                    return;
+                }
+                self.set_state(RunState::Paused);
+                for _ in 0..n {
+                    if let Err(e) = self.computer.step() {
+                        self.set_state(RunState::Stopped(e.to_string()));
+                        let _ = result.send(Err(e.into()));
+                        return;
+                    }
+                }
+                let _ = result.send(Ok(()));
+            }
+            Command::Interrupt(message) => {
+                if let Err(e) = self.computer.cpu_mut().queue_interrupt(message) {
+                    self.set_state(RunState::Stopped(e.to_string()));
+                }
+            }
+            Command::SetTurbo(turbo) => {
+                self.turbo = turbo;
+                self.reset_throttle();
+            }
+            Command::Call(mut call) => call(&mut self.computer),
+            Command::State(state) => {
+                let _ = state.send(self.state.clone());
+            }
+            Command::Wait(waiting) => {
+                if self.state == RunState::Running {
+                    self.waiting.push(waiting);
+                } else {
+                    let _ = waiting.send(self.state.clone());
+                }
+            }
+            Command::Quit => (),
+        }
+    }
+
+    /// Runs up to `SLICE` ticks, pausing at the target of `run_until`.
+    fn run_slice(&mut self) {
+        let mut ticks = SLICE;
+        if let Some(Until::Tick(tick)) = self.until {
+            let current_tick = self.computer.current_tick();
+            if current_tick >= tick {
+                self.set_state(RunState::Paused);
+                return;
+            }
+            ticks = cmp::min(ticks, tick - current_tick);
+        }
+        let res = self.computer.run(ticks);
+        let max_skipped = match self.until {
+            Some(Until::Tick(tick)) => tick.saturating_sub(self.computer.current_tick()),
+            _ => u64::max_value(),
+        };
+        let res = res.and_then(|state| {
+            self.computer.skip_idle_max(max_skipped).map(|skipped| (state, skipped))
+        });
+        match res {
+            Ok((CpuState::HitBreakpoint(_), _)) |
+            Ok((CpuState::HitWatchpoint(_), _)) => self.set_state(RunState::Paused),
+            // Sleeping until a host device, like the keyboard, interrupts.
+            Ok((_, 0)) if self.computer.is_sleeping() => thread::sleep(Duration::from_millis(1)),
+            Ok(_) => {
+                if !self.turbo {
+                    self.throttle.wait(self.computer.current_tick());
+                }
+            }
+            Err(e) => self.set_state(RunState::Stopped(e.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_handle() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::{BasicOp, Register, SpecialOp};
+
+    let handle = ComputerHandle::spawn(|| {
+        let mut cpu = Cpu::default();
+        cpu.load(&[basic(BasicOp::ADD, reg(Register::A), lit(1)),
+                   basic(BasicOp::IFL, reg(Register::A), lit(10)),
+                   basic(BasicOp::SET, PC, lit(0)),
+                   special(SpecialOp::HLT, lit(0))],
+                 0);
+        Computer::new(cpu)
+    });
+    let a = |handle: &ComputerHandle| {
+        handle.query(|c| c.cpu().registers[Register::A as usize]).unwrap()
+    };
+    assert_eq!(handle.state().unwrap(), RunState::Paused);
+    handle.step(4).unwrap();
+    assert_eq!(a(&handle), 2);
+
+    handle.set_turbo(true).unwrap();
+    handle.run_until(Until::Address(2)).unwrap();
+    assert_eq!(handle.wait().unwrap(), RunState::Paused);
+    assert_eq!(handle.query(|c| c.cpu().pc).unwrap(), 2);
+    assert_eq!(a(&handle), 2);
+    // The temporary breakpoint is gone.
+    assert!(handle.query(|c| c.cpu().breakpoints.is_empty()).unwrap());
+
+    handle.run_until(Until::Tick(20)).unwrap();
+    assert_eq!(handle.wait().unwrap(), RunState::Paused);
+    assert_eq!(handle.query(|c| c.current_tick()).unwrap(), 20);
+
+    handle.resume().unwrap();
+    let state = handle.wait().unwrap();
+    assert!(match state {
+        RunState::Stopped(ref e) => e == &cpu::Error::Halted.to_string(),
+        _ => false,
+    });
+    assert_eq!(a(&handle), 10);
+    assert!(match handle.step(1) {
+        Err(Error::Stopped) => true,
+        _ => false,
+    });
+}
+
+#[cfg(all(test, feature = "devices-clock"))]
+#[test]
+fn test_run_until_sleeping() {
+    use cpu::Cpu;
+    use device::clock::Clock;
+    use encodings::*;
+    use types::{BasicOp, Register, SpecialOp};
+
+    // Sleeps until the clock interrupts, 50000 ticks later.
+    let handle = ComputerHandle::spawn(|| {
+        let mut cpu = Cpu::default();
+        cpu.load(&[special(SpecialOp::IAS, lit(9)),
+                   basic(BasicOp::SET, reg(Register::A), lit(0)),
+                   basic(BasicOp::SET, reg(Register::B), lit(30)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::SET, reg(Register::A), lit(2)),
+                   basic(BasicOp::SET, reg(Register::B), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   special(SpecialOp::SLP, lit(0)),
+                   basic(BasicOp::SET, PC, lit(7)),
+                   special(SpecialOp::RFI, lit(0))],
+                 0);
+        let mut computer = Computer::new(cpu);
+        computer.add_device(Box::new(Clock::new()));
+        computer
+    });
+    handle.set_turbo(true).unwrap();
+    handle.run_until(Until::Tick(1000)).unwrap();
+    assert_eq!(handle.wait().unwrap(), RunState::Paused);
+    assert_eq!(handle.query(|c| c.current_tick()).unwrap(), 1000);
+}
@@ -0,0 +1,115 @@
+//! RGB images written as PPM or PNG, for example the screenshots of the
+//! LEM1802.
+//!
+//! The PNG files are not compressed, keeping this crate free of a deflate
+//! implementation: a screenshot is a few dozen kilobytes.
+
+use std::io::{self, Write};
+
+/// Largest block of stored data in a zlib stream.
+const MAX_STORED: usize = 0xffff;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    /// Red, green and blue of each pixel, row by row from the top left.
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// Binary PPM, `P6`.
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(write!(w, "P6\n{} {}\n255\n", self.width, self.height));
+        let bytes: Vec<u8> = self.pixels.iter().flat_map(|p| p.iter().cloned()).collect();
+        w.write_all(&bytes)
+    }
+
+    pub fn write_png<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(w.write_all(b"\x89PNG\r\n\x1a\n"));
+
+        let mut header = vec![];
+        header.extend_from_slice(&be32(self.width));
+        header.extend_from_slice(&be32(self.height));
+        // 8 bits per component, RGB, no interlacing.
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        try!(write_chunk(w, b"IHDR", &header));
+
+        // Each row starts with its filter, none.
+        let mut raw = vec![];
+        for row in self.pixels.chunks(self.width as usize) {
+            raw.push(0);
+            raw.extend(row.iter().flat_map(|p| p.iter().cloned()));
+        }
+        let mut zlib = vec![0x78, 0x01];
+        let blocks = raw.chunks(MAX_STORED).count();
+        for (i, block) in raw.chunks(MAX_STORED).enumerate() {
+            let len = block.len() as u16;
+            zlib.push(if i == blocks - 1 { 1 } else { 0 });
+            zlib.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&be32(adler32(&raw)));
+        try!(write_chunk(w, b"IDAT", &zlib));
+
+        write_chunk(w, b"IEND", &[])
+    }
+}
+
+fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    try!(w.write_all(&be32(data.len() as u32)));
+    try!(w.write_all(kind));
+    try!(w.write_all(data));
+    let crc = crc32(kind.iter().chain(data));
+    w.write_all(&be32(crc))
+}
+
+fn crc32<'a, I: Iterator<Item = &'a u8>>(bytes: I) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+#[test]
+fn test_image() {
+    assert_eq!(crc32(b"IEND".iter()), 0xae426082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+
+    let image = Image {
+        width: 2,
+        height: 1,
+        pixels: vec![[255, 0, 0], [0, 0, 255]],
+    };
+    let mut ppm = vec![];
+    image.write_ppm(&mut ppm).unwrap();
+    assert_eq!(ppm, b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff");
+
+    let mut png = vec![];
+    image.write_png(&mut png).unwrap();
+    assert_eq!(&png[..16], b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR");
+    // The row and its filter in a single stored block.
+    let idat = &png[33..];
+    assert_eq!(&idat[..8], b"\x00\x00\x00\x12IDAT");
+    assert_eq!(&idat[8..15], &[0x78, 0x01, 1, 7, 0, 0xf8, 0xff]);
+    assert_eq!(&idat[15..22], &[0, 255, 0, 0, 0, 0, 255]);
+    assert_eq!(&png[png.len() - 12..], b"\x00\x00\x00\x00IEND\xae\x42\x60\x82");
+}
@@ -1,11 +1,37 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::iter::Iterator;
 
+use flow::{self, Flow};
+use symbols::Symbols;
 use types::*;
 
+/// Word of a stream which isn't the start of an instruction, such as data
+/// between the functions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Data {
+    /// Offset of the word in the stream.
+    pub offset: u16,
+    pub word: u16,
+    /// Why it can't be decoded, `None` if the instruction it starts is cut by
+    /// the end of the stream.
+    pub error: Option<DecodeError>,
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, ".dat 0x{:04x}", self.word)
+    }
+}
+
+/// Decodes a stream of words. The words which can't be decoded are returned
+/// as `Data`, one at a time, and decoding resumes after them.
 pub struct U16ToInstruction<I> {
     it: I,
     buffer: [u16; 3],
-    len_buffer: usize
+    len_buffer: usize,
+    offset: u16,
 }
 
 impl<I: Iterator<Item=u16>> U16ToInstruction<I> {
@@ -13,15 +39,24 @@ impl<I: Iterator<Item=u16>> U16ToInstruction<I> {
         U16ToInstruction {
             it: it,
             buffer: [0; 3],
-            len_buffer: 0
+            len_buffer: 0,
+            offset: 0,
         }
     }
+
+    fn consume(&mut self, used: usize) {
+        for n in used..3 {
+            self.buffer[n - used] = self.buffer[n];
+        }
+        self.len_buffer -= used;
+        self.offset = self.offset.wrapping_add(used as u16);
+    }
 }
 
 impl<I: Iterator<Item=u16>> Iterator for U16ToInstruction<I> {
-    type Item = Instruction;
+    type Item = Result<Instruction, Data>;
 
-    fn next(&mut self) -> Option<Instruction> {
+    fn next(&mut self) -> Option<Result<Instruction, Data>> {
         while self.len_buffer < 3 {
             if let Some(u) = self.it.next() {
                 self.buffer[self.len_buffer] = u;
@@ -31,23 +66,30 @@ impl<I: Iterator<Item=u16>> Iterator for U16ToInstruction<I> {
             }
         }
 
-        let (used, i) = match Instruction::decode(&self.buffer) {
-            Ok(x) => x,
-            Err(_) => return None
-        };
-        let used = used as usize;
-        if used > self.len_buffer {
+        if self.len_buffer == 0 {
             return None;
         }
 
-        for n in used..3 {
-            self.buffer[n - used] = self.buffer[n];
-        }
-        self.len_buffer -= used;
-        Some(i)
+        let data = Data {
+            offset: self.offset,
+            word: self.buffer[0],
+            error: None,
+        };
+        let res = match Instruction::decode(&self.buffer) {
+            Ok((used, i)) if used as usize <= self.len_buffer => {
+                self.consume(used as usize);
+                return Some(Ok(i));
+            }
+            Ok(_) => data,
+            Err(e) => Data { error: Some(e), ..data },
+        };
+        self.consume(1);
+        Some(Err(res))
     }
 }
 
+/// Inverse of `U16ToInstruction`: encodes the instructions one at a time,
+/// with the short literals, without collecting them first.
 pub struct InstructionToU16<I> {
     it: I,
     buffer: [u16; 3],
@@ -70,7 +112,9 @@ impl<I: Iterator<Item=Instruction>> Iterator for InstructionToU16<I> {
     fn next(&mut self) -> Option<u16> {
         if self.len_buffer == 0 {
             if let Some(i) = self.it.next() {
-                self.len_buffer = i.encode(&mut self.buffer) as usize;
+                let (words, size) = i.encode_to_array();
+                self.buffer = words;
+                self.len_buffer = size as usize;
             } else {
                 return None;
             }
@@ -85,3 +129,470 @@ impl<I: Iterator<Item=Instruction>> Iterator for InstructionToU16<I> {
         ret
     }
 }
+
+/// Words dumped per line by `Style::words`, the size of the longest
+/// instruction. The `.dat` lines are cut to as many words.
+const DUMP_WORDS: usize = 3;
+
+/// How the disassembly is written. The default matches `Display` of
+/// `Instruction`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Style {
+    /// Lowercase opcodes and registers.
+    pub lowercase: bool,
+    /// Numbers of the instructions in hexadecimal, `.dat` always being.
+    pub hex: bool,
+    /// `:label` instead of `label:`, and `dat` instead of `.dat`, like in
+    /// Notch's examples. Every dialect of the assembler accepts them.
+    pub prefix_labels: bool,
+    /// Spaces before the instructions and `.dat`, after the prefixes, the
+    /// labels not being indented.
+    pub indent: usize,
+    /// Start each line with the address.
+    pub addresses: bool,
+    /// Start each line with the words, after the address. The output
+    /// doesn't assemble anymore with `addresses` or `words`.
+    pub words: bool,
+}
+
+impl Style {
+    fn case(&self, s: &str) -> String {
+        if self.lowercase {
+            s.to_lowercase()
+        } else {
+            s.to_string()
+        }
+    }
+
+    fn number(&self, n: u16) -> String {
+        if self.hex {
+            format!("0x{:04x}", n)
+        } else {
+            n.to_string()
+        }
+    }
+
+    fn value(&self, v: Value, is_a: bool) -> String {
+        let text = match v {
+            Value::Reg(r) => format!("{:?}", r),
+            Value::AtReg(r) => format!("[{:?}]", r),
+            Value::AtRegPlus(r, n) => format!("[{:?} + {}]", r, self.number(n)),
+            Value::Pick(n) => format!("PICK {}", self.number(n)),
+            Value::AtAddr(n) => format!("[{}]", self.number(n)),
+            Value::Litteral(n) => self.number(n),
+            Value::Push if is_a => "POP".into(),
+            Value::Push => "PUSH".into(),
+            Value::Peek => "PEEK".into(),
+            v => format!("{:?}", v),
+        };
+        self.case(&text)
+    }
+
+    /// `i` without prefixes.
+    pub fn instruction(&self, i: &Instruction) -> String {
+        match *i {
+            Instruction::BasicOp(op, b, a) => {
+                format!("{} {}, {}",
+                        self.case(&format!("{:?}", op)),
+                        self.value(b, false),
+                        self.value(a, true))
+            }
+            Instruction::SpecialOp(op, a) => {
+                format!("{} {}", self.case(&format!("{:?}", op)), self.value(a, true))
+            }
+        }
+    }
+
+    /// `.dat` directive of `words`, without prefixes.
+    pub fn data(&self, words: &[u16]) -> String {
+        let words = words.iter().map(|w| format!("0x{:04x}", w)).collect::<Vec<_>>();
+        let dat = if self.prefix_labels { "dat" } else { ".dat" };
+        format!("{} {}", dat, words.join(", "))
+    }
+
+    fn prefix(&self, addr: u16, words: &[u16]) -> String {
+        let mut res = String::new();
+        if self.addresses {
+            res.push_str(&format!("{:04x}: ", addr));
+        }
+        if self.words {
+            let dump = words.iter().map(|w| format!("{:04x}", w)).collect::<Vec<_>>();
+            res.push_str(&format!("{:1$}", dump.join(" "), DUMP_WORDS * 5));
+        }
+        res
+    }
+
+    /// Line of `text`, an instruction or a directive, at `addr` and made of
+    /// `words`, with the prefixes and the indentation.
+    pub fn line(&self, addr: u16, words: &[u16], text: &str) -> String {
+        format!("{}{:3$}{}\n", self.prefix(addr, words), "", text, self.indent)
+    }
+
+    fn label(&self, addr: u16, name: &str) -> String {
+        if self.prefix_labels {
+            format!("{}:{}\n", self.prefix(addr, &[]), name)
+        } else {
+            format!("{}{}:\n", self.prefix(addr, &[]), name)
+        }
+    }
+}
+
+/// One instruction per line, the words which can't be decoded being written
+/// as `.dat`.
+pub fn disassemble(words: &[u16]) -> String {
+    let mut res = String::new();
+    for i in U16ToInstruction::chain(words.iter().cloned()) {
+        match i {
+            Ok(i) => res.push_str(&i.to_string()),
+            Err(data) => res.push_str(&data.to_string()),
+        }
+        res.push('\n');
+    }
+    res
+}
+
+/// Same as `disassemble`, but with a `label_xxxx:` line before each
+/// instruction jumped to, called or reached by skipping a conditional, and
+/// these labels instead of the addresses in `SET PC` and `JSR`, so the output
+/// can be modified and assembled again.
+pub fn disassemble_with_labels(words: &[u16]) -> String {
+    disassemble_with_symbols(words, &Symbols::new(), &Style::default())
+}
+
+/// Same as `disassemble_with_labels`, but also with the labels of `symbols`
+/// at the start of an instruction, using their name instead of
+/// `label_xxxx`. The dots of the local labels are replaced by underscores so
+/// the output can still be assembled. The lines are written in `style`.
+pub fn disassemble_with_symbols(words: &[u16], symbols: &Symbols, style: &Style) -> String {
+    let mut instructions = vec![];
+    let mut addr = 0u16;
+    for i in U16ToInstruction::chain(words.iter().cloned()) {
+        match i {
+            Ok(i) => {
+                let size = i.encode_to_array().1 as u16;
+                instructions.push((addr, size, i));
+                addr = addr.wrapping_add(size);
+            }
+            Err(_) => addr = addr.wrapping_add(1),
+        }
+    }
+    render(words, &instructions, symbols, style, true)
+}
+
+/// Disassembles only the instructions reachable from `entries`, following
+/// the jumps, calls and conditionals, and writes the other words as `.dat`
+/// directives. Unlike a linear sweep, the data between the functions isn't
+/// decoded as instructions, so the output assembles back to `words`.
+///
+/// Jumps and calls to computed addresses can't be followed: their targets
+/// are data unless they are also in `entries`.
+pub fn disassemble_reachable(words: &[u16],
+                             entries: &[u16],
+                             symbols: &Symbols,
+                             style: &Style)
+                             -> String {
+    // `explore` doesn't follow the calls.
+    let mut entries = entries.to_vec();
+    let mut found = flow::explore(words, &entries);
+    loop {
+        let calls = found.values()
+                         .filter_map(|&(_, _, flow)| match flow {
+                             Flow::Call(t) if !entries.contains(&t) => Some(t),
+                             _ => None,
+                         })
+                         .collect::<BTreeSet<_>>();
+        if calls.is_empty() {
+            break;
+        }
+        entries.extend(calls);
+        found = flow::explore(words, &entries);
+    }
+    let mut instructions = vec![];
+    let mut end = 0;
+    for (&addr, &(size, i, _)) in found.iter() {
+        // Overlapping an instruction already kept, or past the end.
+        if (addr as usize) < end || addr as usize + size as usize > words.len() {
+            continue;
+        }
+        instructions.push((addr, size, i));
+        end = addr as usize + size as usize;
+    }
+    render(words, &instructions, symbols, style, true)
+}
+
+/// Same as `disassemble_with_symbols`, but the words which can't be decoded
+/// are written as `.dat` and decoding resumes after them, so the output
+/// assembles back to exactly `words`. So are the instructions which would be
+/// encoded differently, like a small literal stored in the next word, which
+/// the assembler would put in the instruction.
+pub fn disassemble_round_trip(words: &[u16], symbols: &Symbols, style: &Style) -> String {
+    let mut instructions = vec![];
+    let mut addr = 0;
+    while addr < words.len() {
+        let mut buffer = [0; 3];
+        let available = cmp::min(3, words.len() - addr);
+        buffer[..available].copy_from_slice(&words[addr..addr + available]);
+        let size = match Instruction::decode(&buffer) {
+            Ok((size, i)) if size as usize <= available => {
+                let (encoded, _) = i.encode_to_array();
+                if encoded[..size as usize] == buffer[..size as usize] {
+                    instructions.push((addr as u16, size, i));
+                    size as usize
+                } else {
+                    1
+                }
+            }
+            _ => 1,
+        };
+        addr += size;
+    }
+    render(words, &instructions, symbols, style, true)
+}
+
+/// Words per `.dat` line.
+const DAT_WORDS: usize = 8;
+
+/// Writes `instructions` with their labels, and the words between them as
+/// `.dat` if `data`.
+fn render(words: &[u16],
+          instructions: &[(u16, u16, Instruction)],
+          symbols: &Symbols,
+          style: &Style,
+          data: bool)
+          -> String {
+    let starts = instructions.iter().map(|&(addr, _, _)| addr).collect::<BTreeSet<_>>();
+    let labelled = |addr: u16| {
+        starts.contains(&addr) ||
+        data && (addr as usize) < words.len() &&
+        !instructions.iter().any(|&(a, s, _)| a < addr && addr < a + s)
+    };
+
+    let mut targets = BTreeSet::new();
+    for &(addr, size, ref i) in instructions.iter() {
+        match Flow::of(i, addr.wrapping_add(size)) {
+            Flow::Jump(t) | Flow::Call(t) => {
+                targets.insert(t);
+            }
+            flow @ Flow::Conditional => {
+                targets.extend(flow::successors(words, addr, size, flow).into_iter().skip(1));
+            }
+            _ => (),
+        }
+    }
+    let mut names = BTreeMap::new();
+    for (name, &addr) in symbols.labels() {
+        if labelled(addr) {
+            names.entry(addr).or_insert_with(Vec::new).push(name.replace('.', "_"));
+        }
+    }
+    let targets = targets.into_iter()
+                         .filter(|&t| labelled(t))
+                         .chain(names.keys().cloned())
+                         .collect::<BTreeSet<_>>();
+    let label = |addr: u16| {
+        names.get(&addr).map_or_else(|| format!("label_{:04x}", addr), |n| n[0].clone())
+    };
+    let push_labels = |res: &mut String, addr: u16| {
+        match names.get(&addr) {
+            Some(names) => {
+                for name in names {
+                    res.push_str(&style.label(addr, name));
+                }
+            }
+            None if targets.contains(&addr) => res.push_str(&style.label(addr, &label(addr))),
+            None => (),
+        }
+    };
+    let data_line = |start: usize, end: usize| {
+        style.line(start as u16, &words[start..end], &style.data(&words[start..end]))
+    };
+    let dat_words = if style.words { DUMP_WORDS } else { DAT_WORDS };
+    let push_data = |res: &mut String, start: usize, end: usize| {
+        let mut line_start = start;
+        for addr in start..end {
+            if targets.contains(&(addr as u16)) && addr > line_start {
+                res.push_str(&data_line(line_start, addr));
+                line_start = addr;
+            }
+            if addr == line_start {
+                push_labels(res, addr as u16);
+            }
+            if addr + 1 - line_start == dat_words || addr + 1 == end {
+                res.push_str(&data_line(line_start, addr + 1));
+                line_start = addr + 1;
+            }
+        }
+    };
+
+    let mut res = String::new();
+    let mut next = 0;
+    for &(addr, size, i) in instructions {
+        if data {
+            push_data(&mut res, next, addr as usize);
+        }
+        push_labels(&mut res, addr);
+        let text = match i {
+            Instruction::BasicOp(BasicOp::SET, Value::PC, Value::Litteral(t))
+                if targets.contains(&t) => format!("{} {}", style.case("SET PC,"), label(t)),
+            Instruction::SpecialOp(SpecialOp::JSR, Value::Litteral(t))
+                if targets.contains(&t) => format!("{} {}", style.case("JSR"), label(t)),
+            i => style.instruction(&i),
+        };
+        let end = cmp::min(addr as usize + size as usize, words.len());
+        res.push_str(&style.line(addr, &words[addr as usize..end], &text));
+        next = addr as usize + size as usize;
+    }
+    if data {
+        push_data(&mut res, next, words.len());
+    }
+    res
+}
+
+#[cfg(test)]
+#[test]
+fn test_garbage() {
+    let mut x = 0x2545u16;
+    let words = (0..10000).map(|_| {
+        // xorshift
+        x ^= x << 7;
+        x ^= x >> 9;
+        x ^= x << 8;
+        x
+    }).collect::<Vec<_>>();
+    for start in 0..16 {
+        let decoded = U16ToInstruction::chain(words[start..].iter().cloned()).count();
+        assert!(decoded <= words.len() - start);
+    }
+    assert_eq!(disassemble(&[0x8801, 0x7f81, 0x1000]), "SET A, 1\nSET PC, 4096\n");
+    assert_eq!(disassemble(&[0x03e0, 0x8801, 0x7c01]), ".dat 0x03e0\nSET A, 1\n.dat 0x7c01\n");
+    let decoded = U16ToInstruction::chain([0x03e0, 0x7c01].iter().cloned()).collect::<Vec<_>>();
+    assert_eq!(decoded,
+               vec![Err(Data {
+                        offset: 0,
+                        word: 0x03e0,
+                        error: Some(DecodeError::SpecialOp(0x1f)),
+                    }),
+                    Err(Data {
+                        offset: 1,
+                        word: 0x7c01,
+                        error: None,
+                    })]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_instruction_to_u16() {
+    use encodings::*;
+
+    let words = [basic(BasicOp::SET, reg(Register::A), NEXT),
+                 0x1234,
+                 special(SpecialOp::JSR, lit(3)),
+                 basic(BasicOp::ADD, AT_NEXT, NEXT),
+                 0x1000,
+                 0x20];
+    let instructions = U16ToInstruction::chain(words.iter().cloned())
+                           .collect::<Result<Vec<_>, _>>()
+                           .unwrap();
+    assert_eq!(instructions.len(), 3);
+    assert_eq!(InstructionToU16::chain(instructions.into_iter()).collect::<Vec<_>>(),
+               words.to_vec());
+}
+
+#[cfg(test)]
+#[test]
+fn test_labels() {
+    use encodings::*;
+
+    let words = [basic(BasicOp::IFE, reg(Register::A), lit(0)),
+                 basic(BasicOp::SET, PC, lit(0)),
+                 special(SpecialOp::JSR, lit(4)),
+                 special(SpecialOp::HLT, lit(0)),
+                 RET];
+    assert_eq!(disassemble_with_labels(&words),
+               "label_0000:\nIFE A, 0\nSET PC, label_0000\nlabel_0002:\nJSR label_0004\n\
+                HLT 0\nlabel_0004:\nSET PC, POP\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_symbols() {
+    use encodings::*;
+
+    let words = [special(SpecialOp::JSR, lit(3)),
+                 basic(BasicOp::SET, PC, lit(2)),
+                 NOP,
+                 RET];
+    let symbols = "main 0x0000\nmain.loop 0x0001\nf 0x0003\n".parse().unwrap();
+    assert_eq!(disassemble_with_symbols(&words, &symbols, &Style::default()),
+               "main:\nJSR f\nmain_loop:\nSET PC, label_0002\nlabel_0002:\nSET A, A\nf:\n\
+                SET PC, POP\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_style() {
+    use encodings::*;
+
+    let words = [basic(BasicOp::SET, reg(Register::A), NEXT),
+                 0x1234,
+                 special(SpecialOp::JSR, lit(3)),
+                 RET,
+                 0x6869];
+    let mut style = Style {
+        lowercase: true,
+        hex: true,
+        prefix_labels: true,
+        indent: 2,
+        ..Style::default()
+    };
+    assert_eq!(disassemble_round_trip(&words, &Symbols::new(), &style),
+               "  set a, 0x1234\n  jsr label_0003\n:label_0003\n  set pc, pop\n  dat 0x6869\n");
+    style.addresses = true;
+    style.words = true;
+    assert_eq!(disassemble_with_symbols(&words[..4], &Symbols::new(), &style),
+               "0000: 7c01 1234        set a, 0x1234\n0002: 9020             jsr label_0003\n\
+                0003:                :label_0003\n0003: 6381             set pc, pop\n");
+}
+
+#[cfg(test)]
+#[test]
+fn test_reachable() {
+    use encodings::*;
+
+    // The string between the call and the function would be decoded as
+    // instructions by a linear sweep.
+    let words = [special(SpecialOp::JSR, NEXT),
+                 5,
+                 basic(BasicOp::SET, PC, lit(2)),
+                 0x6869,
+                 0,
+                 RET];
+    let symbols = "message 0x0003\n".parse().unwrap();
+    assert_eq!(disassemble_reachable(&words, &[0], &symbols, &Style::default()),
+               "JSR label_0005\nlabel_0002:\nSET PC, label_0002\nmessage:\n\
+                .dat 0x6869, 0x0000\nlabel_0005:\nSET PC, POP\n");
+}
+
+#[cfg(all(test, feature = "assembler"))]
+#[test]
+fn test_round_trip() {
+    use assembler;
+    use encodings::*;
+
+    let words = [// SET A, 1 with the 1 in the next word, which is NOP.
+                 basic(BasicOp::SET, reg(Register::A), NEXT),
+                 1,
+                 // Unused special opcode 0x1f.
+                 0x03e0,
+                 basic(BasicOp::IFE, reg(Register::B), lit(3)),
+                 basic(BasicOp::SET, PC, lit(2)),
+                 special(SpecialOp::JSR, lit(6)),
+                 RET,
+                 // Next word missing.
+                 basic(BasicOp::ADD, reg(Register::A), NEXT)];
+    let text = disassemble_round_trip(&words, &Symbols::new(), &Style::default());
+    assert_eq!(text,
+               ".dat 0x7c01\nSET A, A\nlabel_0002:\n.dat 0x03e0\nIFE B, 3\nSET PC, label_0002\n\
+                label_0005:\nJSR label_0006\nlabel_0006:\nSET PC, POP\n.dat 0x7c02\n");
+    assert_eq!(assembler::assemble_str(&text).unwrap(), words.to_vec());
+}
@@ -2,16 +2,86 @@
 
 #[macro_use]
 extern crate enum_primitive;
+#[cfg(feature = "emulator-core")]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "assembler")]
 #[macro_use]
 extern crate nom;
 extern crate num;
+#[cfg(feature = "proptest")]
+#[macro_use]
+extern crate proptest;
+
+#[macro_use]
+mod opcodes;
 
+#[cfg(feature = "assembler")]
 pub mod assembler;
+#[cfg(feature = "emulator-core")]
+pub mod blocks;
+#[cfg(feature = "emulator-core")]
+pub mod calls;
+#[cfg(feature = "emulator-core")]
 pub mod computer;
+#[cfg(feature = "emulator-core")]
+pub mod control;
+#[cfg(feature = "emulator-core")]
 pub mod cpu;
+#[cfg(feature = "emulator-core")]
+pub mod coverage;
+pub mod debug_info;
+#[cfg(feature = "emulator-core")]
 pub mod device;
+#[cfg(feature = "emulator-core")]
+pub mod differential;
+pub mod encodings;
+#[cfg(feature = "emulator-core")]
+pub mod explain;
+pub mod flow;
+pub mod formats;
+#[cfg(feature = "devices-serial")]
+pub mod fuzz;
+#[cfg(feature = "emulator-core")]
+pub mod gdb;
+#[cfg(feature = "emulator-core")]
+pub mod handle;
+pub mod image;
 pub mod iterators;
+#[cfg(feature = "emulator-core")]
+pub mod mmio;
+#[cfg(feature = "devices-serial")]
+pub mod network;
+#[cfg(feature = "emulator-core")]
+pub mod patch;
+#[cfg(feature = "emulator-core")]
+pub mod profile;
+#[cfg(feature = "assembler")]
 pub mod preprocessor;
+#[cfg(feature = "emulator-core")]
+pub mod replay;
+#[cfg(feature = "emulator-core")]
+pub mod script;
+#[cfg(feature = "emulator-core")]
+pub mod server;
+pub mod size;
+#[cfg(feature = "proptest")]
+pub mod strategies;
+pub mod symbols;
+#[cfg(feature = "emulator-core")]
+pub mod taint;
+#[cfg(feature = "emulator-core")]
+pub mod testing;
+#[cfg(feature = "emulator-core")]
+pub mod timebase;
+#[cfg(feature = "emulator-core")]
+pub mod trace;
 pub mod types;
+#[cfg(feature = "web")]
+pub mod web;
+#[cfg(feature = "emulator-core")]
+pub mod window;
+
+#[cfg(feature = "assembler")]
+pub use assembler::assemble_str;
+pub use iterators::disassemble;
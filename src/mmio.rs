@@ -0,0 +1,169 @@
+//! Memory-mapped I/O: hooks called when the program reads or writes regions
+//! of the memory, to implement peripherals mapped in memory or to watch
+//! data without comparing the whole memory after each instruction.
+//!
+//! Only the operands of the instructions are hooked. The instruction words
+//! fetched by the CPU, and the memory accessed by the devices or directly
+//! through `Cpu::ram`, are not.
+
+use std::fmt::Debug;
+use std::sync::mpsc::Sender;
+
+use types::Region;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+/// Access of the program to a word of the memory, with the value read or
+/// written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Access {
+    pub kind: AccessKind,
+    pub addr: u16,
+    pub value: u16,
+}
+
+pub trait Hook: Debug {
+    /// Returns the value the program reads at `addr`, `value` being the
+    /// word in memory.
+    fn read(&mut self, _addr: u16, value: u16) -> u16 {
+        value
+    }
+
+    /// Called after the program wrote `value` at `addr`.
+    fn write(&mut self, _addr: u16, _value: u16) {}
+}
+
+/// Sends each access to a channel, for example to another thread. Nothing
+/// is sent anymore once the receiver is dropped.
+#[derive(Debug)]
+pub struct ChannelHook(pub Sender<Access>);
+
+impl Hook for ChannelHook {
+    fn read(&mut self, addr: u16, value: u16) -> u16 {
+        let _ = self.0.send(Access {
+            kind: AccessKind::Read,
+            addr: addr,
+            value: value,
+        });
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u16) {
+        let _ = self.0.send(Access {
+            kind: AccessKind::Write,
+            addr: addr,
+            value: value,
+        });
+    }
+}
+
+/// Identifies a hook to remove it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct HookId(u32);
+
+/// Hooks of the memory regions, see `Cpu::add_hook`.
+#[derive(Debug, Default)]
+pub struct Mmio {
+    hooks: Vec<(HookId, Region, Box<Hook>)>,
+    next_id: u32,
+}
+
+impl Mmio {
+    pub fn new() -> Mmio {
+        Mmio::default()
+    }
+
+    /// Calls `hook` on the accesses to `region`. The hooks of overlapping
+    /// regions are called in the order they were added, each one reading
+    /// the value returned by the previous one.
+    pub fn add(&mut self, region: Region, hook: Box<Hook>) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push((id, region, hook));
+        id
+    }
+
+    pub fn remove(&mut self, id: HookId) -> Option<Box<Hook>> {
+        self.hooks
+            .iter()
+            .position(|&(i, _, _)| i == id)
+            .map(|index| self.hooks.remove(index).2)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn read(&mut self, addr: u16, value: u16) -> u16 {
+        self.hooks
+            .iter_mut()
+            .filter(|&&mut (_, region, _)| region.contains(addr))
+            .fold(value, |value, &mut (_, _, ref mut hook)| hook.read(addr, value))
+    }
+
+    pub fn write(&mut self, addr: u16, value: u16) {
+        for &mut (_, region, ref mut hook) in &mut self.hooks {
+            if region.contains(addr) {
+                hook.write(addr, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_mmio() {
+    use std::sync::mpsc;
+
+    use cpu::{Cpu, UNINITIALIZED};
+    use encodings::*;
+    use types::*;
+
+    /// Counts up at each read, like a free-running counter register.
+    #[derive(Debug, Default)]
+    struct Counter(u16);
+
+    impl Hook for Counter {
+        fn read(&mut self, _: u16, _: u16) -> u16 {
+            self.0 += 1;
+            self.0
+        }
+    }
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), AT_NEXT),
+               0x8000,
+               basic(BasicOp::ADD, reg(Register::A), AT_NEXT),
+               0x8000,
+               basic(BasicOp::SET, AT_NEXT, reg(Register::A)),
+               0x9000],
+             0);
+    let (tx, rx) = mpsc::channel();
+    let counter = cpu.add_hook(Region {
+                                   first: 0x8000,
+                                   last: 0x8000,
+                               },
+                               Box::new(Counter::default()));
+    cpu.add_hook(Region {
+                     first: 0x8000,
+                     last: 0x9000,
+                 },
+                 Box::new(ChannelHook(tx)));
+    for _ in 0..7 {
+        cpu.tick(&mut []).unwrap();
+    }
+    assert_eq!(cpu.registers[Register::A as usize], 3);
+    assert_eq!(cpu.ram[0x8000], UNINITIALIZED);
+    let accesses: Vec<_> = rx.try_iter().map(|a| (a.kind, a.addr, a.value)).collect();
+    assert_eq!(accesses,
+               [(AccessKind::Read, 0x8000, 1),
+                (AccessKind::Read, 0x8000, 2),
+                (AccessKind::Write, 0x9000, 3)]);
+
+    assert!(cpu.remove_hook(counter).is_some());
+    assert!(cpu.remove_hook(counter).is_none());
+}
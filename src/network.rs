@@ -0,0 +1,119 @@
+//! Several computers connected by serial ports, for example the DCPUs of
+//! two ships talking to each other.
+//!
+//! The computers run in lockstep, one tick each in turn, so a run is
+//! deterministic: a word sent at a tick is received by the serial port of
+//! the other computer at its next tick.
+
+use computer::Computer;
+use cpu;
+use device::serial::{self, Serial};
+
+#[derive(Default)]
+pub struct Network {
+    computers: Vec<Computer>,
+    /// Whether each computer still runs, the others having halted or
+    /// failed.
+    running: Vec<bool>,
+}
+
+impl Network {
+    pub fn new() -> Network {
+        Network::default()
+    }
+
+    /// Returns the index of the computer in the network.
+    pub fn add(&mut self, computer: Computer) -> usize {
+        self.computers.push(computer);
+        self.running.push(true);
+        self.computers.len() - 1
+    }
+
+    /// Connects two computers with a serial port added to each one, and
+    /// returns the index of each port.
+    pub fn connect(&mut self, a: usize, b: usize) -> (u16, u16) {
+        let (end_a, end_b) = serial::link();
+        let port_a = self.computers[a].plug_device(Box::new(Serial::new(Box::new(end_a))));
+        let port_b = self.computers[b].plug_device(Box::new(Serial::new(Box::new(end_b))));
+        (port_a, port_b)
+    }
+
+    pub fn computers(&self) -> &[Computer] {
+        &self.computers
+    }
+
+    pub fn computer_mut(&mut self, index: usize) -> &mut Computer {
+        &mut self.computers[index]
+    }
+
+    pub fn is_running(&self, index: usize) -> bool {
+        self.running[index]
+    }
+
+    /// Ticks each running computer once, in order. Returns the computers
+    /// which halted or failed during this tick, which don't run anymore.
+    pub fn tick(&mut self) -> Vec<(usize, cpu::Error)> {
+        let mut stopped = vec![];
+        for (i, computer) in self.computers.iter_mut().enumerate() {
+            if !self.running[i] {
+                continue;
+            }
+            if let Err(e) = computer.tick() {
+                self.running[i] = false;
+                stopped.push((i, e));
+            }
+        }
+        stopped
+    }
+
+    /// Ticks until every computer stopped, at most `max_ticks` times, and
+    /// returns how they stopped. Those still running after `max_ticks` are
+    /// not listed.
+    pub fn run(&mut self, max_ticks: u64) -> Vec<(usize, cpu::Error)> {
+        let mut stopped = vec![];
+        for _ in 0..max_ticks {
+            if !self.running.iter().any(|&r| r) {
+                break;
+            }
+            stopped.extend(self.tick());
+        }
+        stopped
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_network() {
+    use encodings::*;
+    use types::*;
+
+    // The first computer sends 42 on its port and halts, the second one
+    // polls its port until it receives a word, then halts.
+    let mut sender = Computer::default();
+    sender.cpu_mut().load(&[basic(BasicOp::SET, reg(Register::A), lit(2)),
+                            basic(BasicOp::SET, reg(Register::B), lit(21)),
+                            basic(BasicOp::MUL, reg(Register::B), lit(2)),
+                            special(SpecialOp::HWI, lit(0)),
+                            special(SpecialOp::HLT, lit(0))],
+                          0);
+    let mut receiver = Computer::default();
+    receiver.cpu_mut().load(&[basic(BasicOp::SET, reg(Register::A), lit(1)),
+                              special(SpecialOp::HWI, lit(0)),
+                              basic(BasicOp::IFE, reg(Register::B), lit(0)),
+                              basic(BasicOp::SET, PC, lit(0)),
+                              special(SpecialOp::HLT, lit(0))],
+                            0);
+    let mut network = Network::new();
+    let sender = network.add(sender);
+    let receiver = network.add(receiver);
+    assert_eq!(network.connect(sender, receiver), (0, 0));
+
+    let stopped = network.run(1000);
+    assert_eq!(stopped.iter().map(|&(i, _)| i).collect::<Vec<_>>(), [sender, receiver]);
+    assert!(stopped.iter().all(|&(_, ref e)| match *e {
+        cpu::Error::Halted => true,
+        _ => false,
+    }));
+    assert!(!network.is_running(receiver));
+    assert_eq!(network.computers()[receiver].cpu().registers[2], 42);
+}
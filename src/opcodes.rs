@@ -0,0 +1,153 @@
+//! Tables of the opcodes, the only place where they are defined.
+//!
+//! Each table is a macro calling the macro given as argument with its extra
+//! arguments followed by one row per opcode:
+//!
+//! ```text
+//! NAME = code, cycles, conditional, handler, "description";
+//! ```
+//!
+//! `handler` is the method of `Cpu` executing the instruction, `conditional`
+//! is true for the `IF*` instructions.
+//!
+//! `declare_opcodes` generates the enums of `types`, their encoding, decoding,
+//! parsing and documentation. `Cpu` uses the same tables for its dispatch.
+
+macro_rules! basic_ops {
+    ($callback:ident!($($args:tt)*)) => {
+        $callback! {
+            $($args)*
+            SET = 0x01, 1, false, op_set, "Sets b to a.";
+            ADD = 0x02, 2, false, op_add, "Sets b to b+a, EX to the carry.";
+            SUB = 0x03, 2, false, op_sub, "Sets b to b-a, EX to 0xffff on underflow.";
+            MUL = 0x04, 2, false, op_mul,
+                "Sets b to b*a as unsigned numbers, EX to the high word.";
+            MLI = 0x05, 2, false, op_mli,
+                "Sets b to b*a as signed numbers, EX to the high word.";
+            DIV = 0x06, 3, false, op_div,
+                "Sets b to b/a as unsigned numbers, EX to the fractional part.";
+            DVI = 0x07, 3, false, op_dvi,
+                "Sets b to b/a as signed numbers, rounding towards 0.";
+            MOD = 0x08, 3, false, op_mod, "Sets b to b%a as unsigned numbers.";
+            MDI = 0x09, 3, false, op_mdi, "Sets b to b%a as signed numbers.";
+            AND = 0x0a, 1, false, op_and, "Sets b to b&a.";
+            BOR = 0x0b, 1, false, op_bor, "Sets b to b|a.";
+            XOR = 0x0c, 1, false, op_xor, "Sets b to b^a.";
+            SHR = 0x0d, 1, false, op_shr,
+                "Sets b to b>>>a (logical shift), EX to the shifted out bits.";
+            ASR = 0x0e, 1, false, op_asr,
+                "Sets b to b>>a (arithmetic shift), EX to the shifted out bits.";
+            SHL = 0x0f, 1, false, op_shl, "Sets b to b<<a, EX to the shifted out bits.";
+            IFB = 0x10, 2, true, op_ifb, "Executes the next instruction if (b&a)!=0.";
+            IFC = 0x11, 2, true, op_ifc, "Executes the next instruction if (b&a)==0.";
+            IFE = 0x12, 2, true, op_ife, "Executes the next instruction if b==a.";
+            IFN = 0x13, 2, true, op_ifn, "Executes the next instruction if b!=a.";
+            IFG = 0x14, 2, true, op_ifg,
+                "Executes the next instruction if b>a as unsigned numbers.";
+            IFA = 0x15, 2, true, op_ifa,
+                "Executes the next instruction if b>a as signed numbers.";
+            IFL = 0x16, 2, true, op_ifl,
+                "Executes the next instruction if b<a as unsigned numbers.";
+            IFU = 0x17, 2, true, op_ifu,
+                "Executes the next instruction if b<a as signed numbers.";
+            ADX = 0x1a, 3, false, op_adx, "Sets b to b+a+EX, EX to the carry.";
+            SBX = 0x1b, 3, false, op_sbx, "Sets b to b-a+EX, EX to the carry or borrow.";
+            STI = 0x1e, 2, false, op_sti, "Sets b to a, then increments I and J.";
+            STD = 0x1f, 2, false, op_std, "Sets b to a, then decrements I and J.";
+        }
+    }
+}
+
+macro_rules! special_ops {
+    ($callback:ident!($($args:tt)*)) => {
+        $callback! {
+            $($args)*
+            JSR = 0x01, 3, false, op_jsr,
+                "Pushes the address of the next instruction, then sets PC to a.";
+            INT = 0x08, 4, false, op_int, "Triggers a software interrupt with message a.";
+            IAG = 0x09, 1, false, op_iag, "Sets a to IA.";
+            IAS = 0x0a, 1, false, op_ias, "Sets IA to a.";
+            RFI = 0x0b, 3, false, op_rfi,
+                "Disables interrupt queueing, pops A then PC from the stack.";
+            IAQ = 0x0c, 2, false, op_iaq,
+                "Queues the interrupts if a is nonzero, else triggers them.";
+            HWN = 0x10, 2, false, op_hwn, "Sets a to the number of connected devices.";
+            HWQ = 0x11, 4, false, op_hwq,
+                "Sets A, B, C, X and Y to information about device a.";
+            HWI = 0x12, 4, false, op_hwi, "Sends an interrupt to device a.";
+            LOG = 0x13, 1, false, op_log, "Logs a (emulator extension).";
+            BRK = 0x14, 0, false, op_brk, "Does nothing, a breakpoint (emulator extension).";
+            HLT = 0x15, 0, false, op_hlt, "Halts the CPU (emulator extension).";
+            SLP = 0x16, 1, false, op_slp,
+                "Sleeps until the next interrupt (emulator extension).";
+        }
+    }
+}
+
+/// Declares an opcode enum with the rows of a table.
+macro_rules! declare_opcodes {
+    ($name:ident, $err:expr;
+     $($op:ident = $code:expr, $cycles:expr, $conditional:expr, $handler:ident, $doc:tt;)*) => {
+        enum_from_primitive! {
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $(#[doc = $doc] $op = $code),*
+        }
+        }
+
+        impl $name {
+            /// Every opcode, in increasing order.
+            pub fn all() -> &'static [$name] {
+                &[$($name::$op),*]
+            }
+
+            pub fn delay(&self) -> u16 {
+                match *self {
+                    $($name::$op => $cycles),*
+                }
+            }
+
+            pub fn encode(&self) -> u16 {
+                *self as u16
+            }
+
+            pub fn decode(op: u16) -> Result<$name, DecodeError> {
+                $name::from_u16(op).ok_or($err(op))
+            }
+
+            /// Whether the next instruction is skipped if the condition is
+            /// false.
+            pub fn is_if(&self) -> bool {
+                match *self {
+                    $($name::$op => $conditional),*
+                }
+            }
+
+            pub fn mnemonic(&self) -> &'static str {
+                match *self {
+                    $($name::$op => stringify!($op)),*
+                }
+            }
+
+            /// One line description, from the specification.
+            pub fn description(&self) -> &'static str {
+                match *self {
+                    $($name::$op => $doc),*
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseError;
+
+            fn from_str(s: &str) -> Result<$name, ParseError> {
+                let s = s.to_uppercase();
+                $name::all()
+                    .iter()
+                    .find(|op| op.mnemonic() == s)
+                    .cloned()
+                    .ok_or(ParseError::$name)
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Tracing of the functions of a running program through the sleds put by
+//! `linker::Options::patch_points` at their start.
+//!
+//! `patch` replaces a sled by a `JSR` to a trampoline, which does the
+//! tracing and returns to the function, and `unpatch` puts the sled back.
+//! Programs not being traced only pay the cycles of the sled.
+
+use std::error;
+use std::fmt;
+
+use cpu::Cpu;
+use encodings::*;
+use types::SpecialOp;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// Neither a sled nor a patched sled at this address.
+    NotAPatchPoint(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotAPatchPoint(addr) => write!(f, "no patch point at 0x{:04x}", addr),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotAPatchPoint(_) => "no patch point",
+        }
+    }
+}
+
+/// `JSR next word`.
+const JSR: u16 = special(SpecialOp::JSR, NEXT);
+
+fn words(cpu: &Cpu, addr: u16) -> [u16; 2] {
+    [cpu.ram[addr as usize], cpu.ram[addr.wrapping_add(1) as usize]]
+}
+
+/// Trampoline of `patch`, if the patch point at `addr` is patched.
+pub fn patched(cpu: &Cpu, addr: u16) -> Result<Option<u16>, Error> {
+    match words(cpu, addr) {
+        w if w == PATCH_POINT => Ok(None),
+        [JSR, trampoline] => Ok(Some(trampoline)),
+        _ => Err(Error::NotAPatchPoint(addr)),
+    }
+}
+
+/// Makes the function starting with the patch point at `addr` call
+/// `trampoline` first. The trampoline must keep the registers and end with
+/// `SET PC, POP`.
+pub fn patch(cpu: &mut Cpu, addr: u16, trampoline: u16) -> Result<(), Error> {
+    try!(patched(cpu, addr));
+    cpu.load(&[JSR, trampoline], addr);
+    Ok(())
+}
+
+pub fn unpatch(cpu: &mut Cpu, addr: u16) -> Result<(), Error> {
+    try!(patched(cpu, addr));
+    cpu.load(&PATCH_POINT, addr);
+    Ok(())
+}
+
+/// Trampoline doing `LOG id`, so the calls can be read from
+/// `Cpu::log_queue`.
+pub fn log_trampoline(id: u16) -> [u16; 3] {
+    [special(SpecialOp::LOG, NEXT), id, RET]
+}
+
+#[cfg(test)]
+#[test]
+fn test_patch() {
+    use types::{BasicOp, Register};
+
+    let mut cpu = Cpu::default();
+    // JSR f, HLT, f: sled, SET A, 1, SET PC, POP
+    cpu.load(&[special(SpecialOp::JSR, lit(2)),
+               special(SpecialOp::HLT, lit(0)),
+               NOP,
+               NOP,
+               basic(BasicOp::SET, reg(Register::A), lit(1)),
+               RET],
+             0);
+    cpu.load(&log_trampoline(7), 0x100);
+    assert_eq!(patch(&mut cpu, 4, 0x100), Err(Error::NotAPatchPoint(4)));
+    patch(&mut cpu, 2, 0x100).unwrap();
+    assert_eq!(patched(&cpu, 2), Ok(Some(0x100)));
+
+    while cpu.tick(&mut []).is_ok() {}
+    assert_eq!(cpu.registers[0], 1);
+    assert_eq!(cpu.log_queue.pop_front(), Some(7));
+
+    unpatch(&mut cpu, 2).unwrap();
+    assert_eq!(patched(&cpu, 2), Ok(None));
+}
@@ -1,13 +1,60 @@
 use std::io::Write;
+use std::path::Path;
 use std::process::*;
 
 pub fn preprocess(asm: &str) -> Option<String> {
-    let mut process = Command::new("cpp")
-        .arg("-Wall")
-        .args(&["-x", "assembler-with-cpp"])
-        .arg("-nostdinc")
-        .arg("-P")
-        .stdin(Stdio::piped())
+    run_cpp(asm, false, &[] as &[&Path])
+}
+
+/// Same as `preprocess`, but also returns for each line of the output the
+/// line of `asm` it comes from, or 0 if it comes from an included file.
+///
+/// `#include`s are looked up in `include_dirs`, in order.
+pub fn preprocess_with_lines<P: AsRef<Path>>(asm: &str,
+                                             include_dirs: &[P])
+                                             -> Option<(String, Vec<usize>)> {
+    let output = match run_cpp(asm, true, include_dirs) {
+        Some(o) => o,
+        None => return None,
+    };
+    let mut text = String::new();
+    let mut lines = vec![];
+    let mut line = 1;
+    let mut in_input = true;
+    for l in output.lines() {
+        // Line markers: # line "file" flags...
+        if l.starts_with("# ") {
+            let mut words = l[2..].split_whitespace();
+            if let (Some(n), Some(file)) = (words.next().and_then(|n| n.parse().ok()),
+                                            words.next()) {
+                line = n;
+                in_input = file == "\"<stdin>\"";
+                continue;
+            }
+        }
+        text.push_str(l);
+        text.push('\n');
+        lines.push(if in_input { line } else { 0 });
+        line += 1;
+    }
+    Some((text, lines))
+}
+
+fn run_cpp<P: AsRef<Path>>(asm: &str,
+                           keep_line_markers: bool,
+                           include_dirs: &[P])
+                           -> Option<String> {
+    let mut command = Command::new("cpp");
+    command.arg("-Wall")
+           .args(&["-x", "assembler-with-cpp"])
+           .arg("-nostdinc");
+    for dir in include_dirs {
+        command.arg("-I").arg(dir.as_ref());
+    }
+    if !keep_line_markers {
+        command.arg("-P");
+    }
+    let mut process = command.stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
         .spawn().unwrap_or_else(|e| panic!("failed to execute process: {}\nIs gcc installed?", e));
@@ -0,0 +1,171 @@
+//! Profiler counting the cycles spent at each address, see `Cpu::profile`.
+//!
+//! The cycles are reported by address, by label, and by call stack in the
+//! collapsed format of flame graph tools, one `main;sort;swap 1234` line per
+//! stack, the call stacks following the `JSR`s as in `calls`.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use calls::CallStack;
+use symbols::Symbols;
+use types::Instruction;
+
+pub struct Profiler {
+    cycles: Box<[u64]>,
+    /// Address of the first instruction, naming the outermost routine.
+    start: Option<u16>,
+    calls: CallStack,
+    /// Entries of the routines of each call stack seen, and their cycles.
+    stacks: Vec<(Vec<u16>, u64)>,
+    stack_ids: HashMap<Vec<u16>, usize>,
+    /// Index of the current call stack in `stacks`, `None` before the first
+    /// instruction.
+    current: Option<usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            cycles: vec![0; 0x10000].into_boxed_slice(),
+            start: None,
+            calls: CallStack::new(),
+            stacks: vec![],
+            stack_ids: HashMap::new(),
+            current: None,
+        }
+    }
+
+    /// Counts an instruction executed at `pc`, after which PC and SP are
+    /// `new_pc` and `sp`.
+    pub fn record(&mut self,
+                  pc: u16,
+                  instruction: Instruction,
+                  cycles: u16,
+                  new_pc: u16,
+                  sp: u16) {
+        self.cycles[pc as usize] += cycles as u64;
+        if self.current.is_none() {
+            self.start = Some(pc);
+            self.update_stack();
+        }
+        self.stacks[self.current.unwrap()].1 += cycles as u64;
+        if self.calls.record(instruction, new_pc, sp) {
+            self.update_stack();
+        }
+    }
+
+    fn update_stack(&mut self) {
+        let entries: Vec<u16> = self.start
+                                    .into_iter()
+                                    .chain(self.calls.frames().iter().map(|f| f.entry))
+                                    .collect();
+        let stacks = &mut self.stacks;
+        let id = *self.stack_ids.entry(entries.clone()).or_insert_with(|| {
+            stacks.push((entries, 0));
+            stacks.len() - 1
+        });
+        self.current = Some(id);
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles.iter().sum()
+    }
+
+    /// Cycles of the addresses executed, the most first.
+    pub fn by_address(&self) -> Vec<(u16, u64)> {
+        let mut counts: Vec<(u16, u64)> = self.cycles
+                                              .iter()
+                                              .enumerate()
+                                              .filter(|&(_, &c)| c != 0)
+                                              .map(|(a, &c)| (a as u16, c))
+                                              .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Cycles of the code after each label, up to the next one, the most
+    /// first. The code before the first label is counted as `?`.
+    pub fn by_label(&self, symbols: &Symbols) -> Vec<(String, u64)> {
+        let mut totals = HashMap::new();
+        for (addr, cycles) in self.by_address() {
+            let label = symbols.nearest(addr).map_or("?", |(label, _)| label);
+            *totals.entry(label.to_string()).or_insert(0) += cycles;
+        }
+        let mut totals: Vec<(String, u64)> = totals.into_iter().collect();
+        totals.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        totals
+    }
+
+    /// Cycles by label then by address, the `top` most of each, with their
+    /// share of the total.
+    pub fn report<W: Write>(&self, symbols: &Symbols, top: usize, w: &mut W) -> io::Result<()> {
+        let total = self.total_cycles();
+        let percent = |cycles| 100. * cycles as f64 / total as f64;
+        try!(writeln!(w, "{} cycles\n\nBy label:", total));
+        for (label, cycles) in self.by_label(symbols).into_iter().take(top) {
+            try!(writeln!(w, "{:>12} {:>6.2}%  {}", cycles, percent(cycles), label));
+        }
+        try!(writeln!(w, "\nBy address:"));
+        for (addr, cycles) in self.by_address().into_iter().take(top) {
+            try!(writeln!(w,
+                          "{:>12} {:>6.2}%  0x{:04x} {}",
+                          cycles,
+                          percent(cycles),
+                          addr,
+                          symbols.describe(addr)));
+        }
+        Ok(())
+    }
+
+    /// One line per call stack, its routines from the outermost separated by
+    /// `;`, then its cycles.
+    pub fn write_collapsed<W: Write>(&self, symbols: &Symbols, w: &mut W) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self.stacks
+                                                .iter()
+                                                .filter(|&&(_, cycles)| cycles != 0)
+                                                .map(|&(ref entries, cycles)| {
+                                                    let names: Vec<String> =
+                                                        entries.iter()
+                                                               .map(|&e| symbols.describe(e))
+                                                               .collect();
+                                                    (names.join(";"), cycles)
+                                                })
+                                                .collect();
+        lines.sort();
+        for (stack, cycles) in lines {
+            try!(writeln!(w, "{} {}", stack, cycles));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_profile() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::*;
+
+    let mut cpu = Cpu::default();
+    // main calls inc twice, inc calls nothing.
+    cpu.load(&[special(SpecialOp::JSR, lit(4)),
+               special(SpecialOp::JSR, lit(4)),
+               special(SpecialOp::HLT, lit(0)),
+               0,
+               basic(BasicOp::ADD, reg(Register::A), lit(1)),
+               basic(BasicOp::SET, PC, PUSH_POP)],
+             0);
+    cpu.profile = Some(Box::new(Profiler::new()));
+    while cpu.tick(&mut []).is_ok() {}
+    let profiler = cpu.profile.take().unwrap();
+    let symbols: Symbols = "main 0x0000\ninc 0x0004\n".parse().unwrap();
+
+    // JSR 3 cycles, ADD 2, SET PC, POP 1.
+    assert_eq!(profiler.by_label(&symbols),
+               [("inc".to_string(), 6), ("main".to_string(), 6)]);
+    assert_eq!(profiler.by_address(), [(4, 4), (0, 3), (1, 3), (5, 2)]);
+    let mut collapsed = vec![];
+    profiler.write_collapsed(&symbols, &mut collapsed).unwrap();
+    assert_eq!(String::from_utf8(collapsed).unwrap(), "main 6\nmain;inc 6\n");
+}
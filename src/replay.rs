@@ -0,0 +1,472 @@
+//! Record and replay of what the devices bring from outside the emulator,
+//! like the keys typed, the data received by a serial port or the time read
+//! by the host bridge, so a run depending on them can be reproduced exactly.
+//!
+//! `Recorder` wraps a device and logs the effects of its `HWI`, the
+//! registers it changes and the words of memory it writes with
+//! `Cpu::load`, and the interrupts it raises, with their ticks. `Replayer`
+//! wraps the same device in a later run and applies the logged effects
+//! instead of those of the device, which still runs so a screen or a
+//! speaker keep working. The CPU and the devices without inputs being
+//! deterministic, the run is then the same as long as the program and the
+//! devices are.
+//!
+//! A recording is written as text, one event per line, the numbers in hex:
+//!
+//! ```text
+//! <tick> <device> int <message>
+//! <tick> <device> hwi <delay|fail> <A> <B> <C> <X> <Y> <Z> <I> <J> [<addr>=<word>...]
+//! ```
+//!
+//! The memory written by a device outside of `HWI`, or other than with
+//! `Cpu::load`, isn't recorded: none of the devices of this crate does.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use computer::Computer;
+use cpu::Cpu;
+use device::{Device, InterruptDelay, TickResult};
+use types::ParseError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// The device raised an interrupt with this message.
+    Interrupt(u16),
+    /// The program sent a `HWI` to the device.
+    Hwi {
+        /// What `Device::interrupt` returned.
+        result: Result<InterruptDelay, ()>,
+        /// The registers after it.
+        registers: [u16; 8],
+        /// The words of memory it wrote, with their address.
+        writes: Vec<(u16, u16)>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub tick: u64,
+    /// Hardware index of the device.
+    pub device: u16,
+    pub action: Action,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{:x} {:x} ", self.tick, self.device));
+        match self.action {
+            Action::Interrupt(message) => write!(f, "int {:x}", message),
+            Action::Hwi { ref result, ref registers, ref writes } => {
+                match *result {
+                    Ok(delay) => try!(write!(f, "hwi {:x}", delay)),
+                    Err(()) => try!(write!(f, "hwi fail")),
+                }
+                for r in registers {
+                    try!(write!(f, " {:x}", r));
+                }
+                for &(addr, word) in writes {
+                    try!(write!(f, " {:x}={:x}", addr, word));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl FromStr for Event {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Event, ()> {
+        let mut words = s.split_whitespace();
+        let mut next = || words.next().ok_or(());
+        let tick = try!(u64::from_str_radix(try!(next()), 16).map_err(|_| ()));
+        let device = try!(hex(try!(next())));
+        let action = match try!(next()) {
+            "int" => Action::Interrupt(try!(hex(try!(next())))),
+            "hwi" => {
+                let result = match try!(next()) {
+                    "fail" => Err(()),
+                    delay => Ok(try!(hex(delay))),
+                };
+                let mut registers = [0; 8];
+                for r in registers.iter_mut() {
+                    *r = try!(hex(try!(next())));
+                }
+                let mut writes = vec![];
+                while let Ok(write) = next() {
+                    let mut pair = write.splitn(2, '=');
+                    let addr = try!(hex(pair.next().unwrap()));
+                    let word = try!(hex(try!(pair.next().ok_or(()))));
+                    writes.push((addr, word));
+                }
+                Action::Hwi {
+                    result: result,
+                    registers: registers,
+                    writes: writes,
+                }
+            }
+            _ => return Err(()),
+        };
+        if let Action::Interrupt(_) = action {
+            if next().is_ok() {
+                return Err(());
+            }
+        }
+        Ok(Event {
+            tick: tick,
+            device: device,
+            action: action,
+        })
+    }
+}
+
+fn hex(s: &str) -> Result<u16, ()> {
+    u16::from_str_radix(s, 16).map_err(|_| ())
+}
+
+/// Events of all the devices, in the order they happened.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Recording {
+    pub events: Vec<Event>,
+}
+
+impl Recording {
+    pub fn new() -> Recording {
+        Recording::default()
+    }
+}
+
+impl fmt::Display for Recording {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for event in &self.events {
+            try!(writeln!(f, "{}", event));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recording {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Recording, ParseError> {
+        let mut events = vec![];
+        for (i, line) in s.lines().enumerate().filter(|&(_, l)| !l.trim().is_empty()) {
+            events.push(try!(line.parse().map_err(|_| ParseError::Recording(i + 1))));
+        }
+        Ok(Recording { events: events })
+    }
+}
+
+/// Wraps each device of `computer` in a `Recorder`, and returns the
+/// recording they share.
+pub fn record(computer: &mut Computer) -> Rc<RefCell<Recording>> {
+    let recording = Rc::new(RefCell::new(Recording::new()));
+    for i in 0..computer.devices().len() as u16 {
+        let device = computer.remove_device(i).unwrap();
+        computer.set_device(i, Box::new(Recorder::new(device, i, recording.clone())));
+    }
+    recording
+}
+
+/// Wraps each device of `computer` in a `Replayer` of `recording`.
+pub fn replay(computer: &mut Computer, recording: &Recording) {
+    for i in 0..computer.devices().len() as u16 {
+        let device = computer.remove_device(i).unwrap();
+        computer.set_device(i, Box::new(Replayer::new(device, i, recording)));
+    }
+}
+
+/// Logs the events of a device to a recording shared with the other
+/// devices.
+#[derive(Debug)]
+pub struct Recorder {
+    inner: Box<Device>,
+    index: u16,
+    recording: Rc<RefCell<Recording>>,
+    /// Tick of the next `tick`, which a `HWI` happens before.
+    current_tick: u64,
+}
+
+impl Recorder {
+    /// Records `inner`, attached at the hardware index `index`.
+    pub fn new(inner: Box<Device>, index: u16, recording: Rc<RefCell<Recording>>) -> Recorder {
+        Recorder {
+            inner: inner,
+            index: index,
+            recording: recording,
+            current_tick: 0,
+        }
+    }
+
+    fn push(&self, tick: u64, action: Action) {
+        self.recording.borrow_mut().events.push(Event {
+            tick: tick,
+            device: self.index,
+            action: action,
+        });
+    }
+}
+
+impl Device for Recorder {
+    fn hardware_id(&self) -> u32 {
+        self.inner.hardware_id()
+    }
+
+    fn hardware_version(&self) -> u16 {
+        self.inner.hardware_version()
+    }
+
+    fn manufacturer(&self) -> u32 {
+        self.inner.manufacturer()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let outer = mem::replace(&mut cpu.loads, Some(vec![]));
+        let result = self.inner.interrupt(cpu);
+        let writes = mem::replace(&mut cpu.loads, outer).unwrap_or(vec![]);
+        let action = Action::Hwi {
+            result: result,
+            registers: cpu.registers,
+            writes: writes,
+        };
+        let tick = self.current_tick;
+        self.push(tick, action);
+        result
+    }
+
+    fn tick(&mut self, cpu: &mut Cpu, current_tick: u64) -> TickResult {
+        self.current_tick = current_tick + 1;
+        let result = self.inner.tick(cpu, current_tick);
+        if let TickResult::Interrupt(message) = result {
+            self.push(current_tick, Action::Interrupt(message));
+        }
+        result
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        self.inner.next_interrupt(current_tick)
+    }
+
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        self.inner.next_tick(current_tick)
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        self.inner.save_state()
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        self.inner.load_state(state)
+    }
+}
+
+/// Runs a device with the events recorded for it instead of its own
+/// effects: each `HWI` gets the result of the next one recorded, and the
+/// interrupts are raised at the recorded ticks.
+///
+/// A `HWI` beyond those recorded fails, the run having diverged.
+#[derive(Debug)]
+pub struct Replayer {
+    inner: Box<Device>,
+    hwis: VecDeque<Event>,
+    interrupts: VecDeque<(u64, u16)>,
+}
+
+impl Replayer {
+    /// Replays the events of `recording` for the hardware index `index`.
+    pub fn new(inner: Box<Device>, index: u16, recording: &Recording) -> Replayer {
+        let mut replayer = Replayer {
+            inner: inner,
+            hwis: VecDeque::new(),
+            interrupts: VecDeque::new(),
+        };
+        for event in recording.events.iter().filter(|e| e.device == index) {
+            match event.action {
+                Action::Interrupt(message) => replayer.interrupts.push_back((event.tick, message)),
+                Action::Hwi { .. } => replayer.hwis.push_back(event.clone()),
+            }
+        }
+        replayer
+    }
+
+    /// Whether all the events were replayed.
+    pub fn is_done(&self) -> bool {
+        self.hwis.is_empty() && self.interrupts.is_empty()
+    }
+}
+
+impl Device for Replayer {
+    fn hardware_id(&self) -> u32 {
+        self.inner.hardware_id()
+    }
+
+    fn hardware_version(&self) -> u16 {
+        self.inner.hardware_version()
+    }
+
+    fn manufacturer(&self) -> u32 {
+        self.inner.manufacturer()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        let _ = self.inner.interrupt(cpu);
+        let event = match self.hwis.pop_front() {
+            Some(event) => event,
+            None => {
+                warn!("{}: HWI not in the recording", self.inner.name());
+                return Err(());
+            }
+        };
+        match event.action {
+            Action::Hwi { result, registers, ref writes } => {
+                cpu.registers = registers;
+                for &(addr, word) in writes {
+                    cpu.ram[addr as usize] = word;
+                }
+                result
+            }
+            Action::Interrupt(_) => unreachable!(),
+        }
+    }
+
+    fn tick(&mut self, cpu: &mut Cpu, current_tick: u64) -> TickResult {
+        self.inner.tick(cpu, current_tick);
+        match self.interrupts.front() {
+            Some(&(tick, message)) if tick <= current_tick => {
+                if tick < current_tick {
+                    warn!("{}: interrupt of tick {} replayed late", self.inner.name(), tick);
+                }
+                self.interrupts.pop_front();
+                TickResult::Interrupt(message)
+            }
+            _ => TickResult::Nothing,
+        }
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        self.interrupts.front().map(|&(tick, _)| cmp::max(tick, current_tick))
+    }
+
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        let next = self.next_interrupt(current_tick);
+        match self.inner.next_tick(current_tick) {
+            Some(tick) => Some(next.map_or(tick, |n| cmp::min(n, tick))),
+            None => next,
+        }
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        self.inner.save_state()
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        self.inner.load_state(state)
+    }
+}
+
+#[cfg(all(test, feature = "devices-keyboard"))]
+#[test]
+fn test_replay() {
+    use std::sync::mpsc;
+    use device::keyboard::{ChannelBackend, Key, Keyboard, KeyEvent};
+    use encodings::*;
+    use types::{BasicOp, Register, SpecialOp};
+
+    // Adds the key read to X on each keyboard interrupt.
+    let program = [basic(BasicOp::SET, reg(Register::A), lit(3)),
+                   basic(BasicOp::SET, reg(Register::B), lit(1)),
+                   special(SpecialOp::IAS, NEXT),
+                   8,
+                   special(SpecialOp::HWI, lit(0)),
+                   special(SpecialOp::SLP, lit(0)),
+                   basic(BasicOp::SET, PC, lit(5)),
+                   0,
+                   basic(BasicOp::SET, reg(Register::A), lit(1)),
+                   special(SpecialOp::HWI, lit(0)),
+                   basic(BasicOp::ADD, reg(Register::X), reg(Register::C)),
+                   special(SpecialOp::RFI, lit(0))];
+    let computer = |events| {
+        let mut cpu = Cpu::default();
+        cpu.load(&program, 0);
+        let mut computer = Computer::new(cpu);
+        computer.add_device(Box::new(Keyboard::new(Box::new(ChannelBackend::new(events)))));
+        computer
+    };
+
+    let (keys, events) = mpsc::channel();
+    let mut recorded = computer(events);
+    let recording = record(&mut recorded);
+    for i in 0..300u16 {
+        if i % 100 == 50 {
+            keys.send(KeyEvent::Typed(Key::ASCII(0x61 + i / 100))).unwrap();
+        }
+        recorded.tick().unwrap();
+    }
+    assert_eq!(recorded.cpu().registers[Register::X as usize], 0x61 + 0x62 + 0x63);
+
+    let text = recording.borrow().to_string();
+    let recording: Recording = text.parse().unwrap();
+    assert_eq!(recording.to_string(), text);
+    // Without any key typed this time.
+    let (_keys, events) = mpsc::channel();
+    let mut replayed = computer(events);
+    replay(&mut replayed, &recording);
+    for _ in 0..300 {
+        replayed.tick().unwrap();
+    }
+    assert_eq!(replayed.cpu().registers, recorded.cpu().registers);
+    assert_eq!(replayed.current_tick(), recorded.current_tick());
+
+    assert!("0 0 int".parse::<Recording>().is_err());
+    assert_eq!("\n1f 2 hwi fail 0 0 0 0 0 0 0 0 8000=41\n".parse::<Recording>().unwrap().events[0],
+               Event {
+                   tick: 0x1f,
+                   device: 2,
+                   action: Action::Hwi {
+                       result: Err(()),
+                       registers: [0; 8],
+                       writes: vec![(0x8000, 0x41)],
+                   },
+               });
+}
+
+#[cfg(all(test, feature = "devices-lem"))]
+#[test]
+fn test_record_writes() {
+    use device::lem1802::{LastFrame, LEM1802};
+
+    let recording = Rc::new(RefCell::new(Recording::new()));
+    let backend = LastFrame {
+        inner: None,
+        last: Rc::new(RefCell::new(None)),
+    };
+    let mut recorder = Recorder::new(Box::new(LEM1802::new(Box::new(backend))),
+                                     0,
+                                     recording.clone());
+    let mut cpu = Cpu::default();
+    // MEM_DUMP_PALETTE
+    cpu.registers[..2].copy_from_slice(&[5, 0x9100]);
+    recorder.interrupt(&mut cpu).unwrap();
+    match recording.borrow().events[0].action {
+        Action::Hwi { ref writes, .. } => {
+            assert_eq!(writes.len(), 16);
+            assert_eq!(writes[1], (0x9101, cpu.ram[0x9101]));
+        }
+        ref a => panic!("{:?}", a),
+    }
+    assert!(cpu.loads.is_none());
+}
@@ -0,0 +1,397 @@
+//! Scripts automating a run: rules poke the registers and the memory, print
+//! them or check them when the program reaches a breakpoint, accesses a
+//! word or sends an interrupt to a device. For example:
+//!
+//! ```text
+//! # Feeds the routine a value and checks its result.
+//! on start: set A 5
+//! on break done: expect A 120; stop
+//! on write 0x8000: print [0x8000] PC
+//! on hwi 1: set C 0x41
+//! on halt: print A B
+//! ```
+//!
+//! The events are `start`, `halt`, `break <addr>`, `read <addr>`,
+//! `write <addr>` and `hwi <device>`, the latter after the `HWI`
+//! instruction. The actions, separated by `;`, are `set <loc> <value>`,
+//! `print <loc>...`, `expect <loc> <value>`, failing the run when the
+//! location holds another value, and `stop`. The locations are the
+//! registers, `PC`, `SP`, `EX`, `IA` and `[addr]`, the addresses and the
+//! values being numbers or labels.
+//!
+//! A small language rather than Lua, keeping the crate free of an
+//! interpreter.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+use computer::Computer;
+use cpu::{Cpu, CpuState, Watch};
+use device::{Device, InterruptDelay, TickResult};
+use mmio::AccessKind;
+use symbols::Symbols;
+use types::{ParseError, Register};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    Start,
+    Halt,
+    Break(u16),
+    Read(u16),
+    Write(u16),
+    /// `HWI` to the device at this index.
+    Hwi(u16),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+    Reg(Register),
+    Pc,
+    Sp,
+    Ex,
+    Ia,
+    Mem(u16),
+}
+
+impl Target {
+    pub fn get(&self, cpu: &Cpu) -> u16 {
+        match *self {
+            Target::Reg(r) => cpu.registers[r as usize],
+            Target::Pc => cpu.pc,
+            Target::Sp => cpu.sp,
+            Target::Ex => cpu.ex,
+            Target::Ia => cpu.ia,
+            Target::Mem(addr) => cpu.ram[addr as usize],
+        }
+    }
+
+    pub fn set(&self, cpu: &mut Cpu, value: u16) {
+        match *self {
+            Target::Reg(r) => cpu.registers[r as usize] = value,
+            Target::Pc => cpu.pc = value,
+            Target::Sp => cpu.sp = value,
+            Target::Ex => cpu.ex = value,
+            Target::Ia => cpu.ia = value,
+            Target::Mem(addr) => cpu.ram[addr as usize] = value,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Target::Reg(r) => write!(f, "{:?}", r),
+            Target::Pc => write!(f, "PC"),
+            Target::Sp => write!(f, "SP"),
+            Target::Ex => write!(f, "EX"),
+            Target::Ia => write!(f, "IA"),
+            Target::Mem(addr) => write!(f, "[0x{:04x}]", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Set(Target, u16),
+    Print(Vec<Target>),
+    Expect(Target, u16),
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub event: Event,
+    pub actions: Vec<Action>,
+}
+
+/// Why a script ended the run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// By `stop`.
+    Requested,
+    /// By a failed `expect`.
+    Failed(String),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Requested => write!(f, "stopped by the script"),
+            Stop::Failed(ref e) => write!(f, "script failed: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Script {
+    rules: Vec<Rule>,
+    /// Indices of the devices interrupted since the last `handle`.
+    interrupts: Rc<RefCell<Vec<u16>>>,
+}
+
+impl Script {
+    /// Parses a script, one rule per line, resolving the labels with
+    /// `symbols`. The error holds the number of the invalid line, from 1.
+    pub fn parse(text: &str, symbols: &Symbols) -> Result<Script, ParseError> {
+        let mut rules = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if !line.is_empty() {
+                rules.push(try!(parse_rule(line, symbols).map_err(|_| ParseError::Script(i + 1))));
+            }
+        }
+        Ok(Script {
+            rules: rules,
+            interrupts: Rc::new(RefCell::new(vec![])),
+        })
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Adds the breakpoints and watchpoints of the rules to `computer` and
+    /// observes its devices, then runs the `start` rules.
+    pub fn attach(&mut self, computer: &mut Computer, out: &mut Write) -> Result<(), Stop> {
+        for rule in &self.rules {
+            match rule.event {
+                Event::Break(addr) => {
+                    computer.cpu_mut().add_breakpoint(addr);
+                }
+                Event::Read(addr) | Event::Write(addr) => {
+                    let watch = match (computer.cpu().watchpoints.get(&addr), rule.event) {
+                        (Some(&Watch::Write), Event::Read(_)) |
+                        (Some(&Watch::Read), Event::Write(_)) |
+                        (Some(&Watch::ReadWrite), _) => Watch::ReadWrite,
+                        (_, Event::Read(_)) => Watch::Read,
+                        _ => Watch::Write,
+                    };
+                    computer.cpu_mut().add_watchpoint(addr, watch);
+                }
+                _ => (),
+            }
+        }
+        for index in 0..computer.devices().len() as u16 {
+            let inner = computer.remove_device(index).unwrap();
+            computer.set_device(index,
+                                Box::new(Observed {
+                                    inner: inner,
+                                    index: index,
+                                    interrupts: self.interrupts.clone(),
+                                }));
+        }
+        self.fire(Event::Start, computer, out)
+    }
+
+    /// Runs the rules triggered by the tick which returned `state`.
+    pub fn handle(&mut self,
+                  computer: &mut Computer,
+                  state: &CpuState,
+                  out: &mut Write)
+                  -> Result<(), Stop> {
+        match *state {
+            CpuState::HitBreakpoint(addr) => try!(self.fire(Event::Break(addr), computer, out)),
+            CpuState::HitWatchpoint(access) => {
+                let event = match access.kind {
+                    AccessKind::Read => Event::Read(access.addr),
+                    AccessKind::Write => Event::Write(access.addr),
+                };
+                try!(self.fire(event, computer, out));
+            }
+            _ => (),
+        }
+        let interrupts: Vec<u16> = self.interrupts.borrow_mut().drain(..).collect();
+        for index in interrupts {
+            try!(self.fire(Event::Hwi(index), computer, out));
+        }
+        Ok(())
+    }
+
+    /// Runs the `halt` rules, once the CPU halted.
+    pub fn halt(&mut self, computer: &mut Computer, out: &mut Write) -> Result<(), Stop> {
+        self.fire(Event::Halt, computer, out)
+    }
+
+    fn fire(&self, event: Event, computer: &mut Computer, out: &mut Write) -> Result<(), Stop> {
+        for rule in self.rules.iter().filter(|r| r.event == event) {
+            for action in &rule.actions {
+                let cpu = computer.cpu_mut();
+                match *action {
+                    Action::Set(target, value) => target.set(cpu, value),
+                    Action::Print(ref targets) => {
+                        let values: Vec<String> =
+                            targets.iter().map(|t| format!("{}=0x{:04x}", t, t.get(cpu))).collect();
+                        let _ = writeln!(out, "{}", values.join(" "));
+                    }
+                    Action::Expect(target, value) if target.get(cpu) != value => {
+                        return Err(Stop::Failed(format!("expected {} = 0x{:04x}, got 0x{:04x}",
+                                                        target,
+                                                        value,
+                                                        target.get(cpu))));
+                    }
+                    Action::Expect(..) => (),
+                    Action::Stop => return Err(Stop::Requested),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_rule(line: &str, symbols: &Symbols) -> Result<Rule, ()> {
+    let addr = |s: &str| symbols.resolve(s).ok_or(());
+    let mut parts = line.splitn(2, ':');
+    let mut words = parts.next().unwrap().split_whitespace();
+    let actions = try!(parts.next().ok_or(()));
+    if words.next() != Some("on") {
+        return Err(());
+    }
+    let event = match (words.next(), words.next(), words.next()) {
+        (Some("start"), None, None) => Event::Start,
+        (Some("halt"), None, None) => Event::Halt,
+        (Some("break"), Some(a), None) => Event::Break(try!(addr(a))),
+        (Some("read"), Some(a), None) => Event::Read(try!(addr(a))),
+        (Some("write"), Some(a), None) => Event::Write(try!(addr(a))),
+        (Some("hwi"), Some(index), None) => Event::Hwi(try!(addr(index))),
+        _ => return Err(()),
+    };
+    let mut parsed = vec![];
+    for action in actions.split(';') {
+        let words: Vec<&str> = action.split_whitespace().collect();
+        let target = |w: &str| parse_target(w, symbols).ok_or(());
+        parsed.push(match (words.first().cloned(), words.len()) {
+            (Some("set"), 3) => Action::Set(try!(target(words[1])), try!(addr(words[2]))),
+            (Some("expect"), 3) => Action::Expect(try!(target(words[1])), try!(addr(words[2]))),
+            (Some("print"), n) if n > 1 => {
+                Action::Print(try!(words[1..].iter().map(|w| target(w)).collect()))
+            }
+            (Some("stop"), 1) => Action::Stop,
+            _ => return Err(()),
+        });
+    }
+    Ok(Rule {
+        event: event,
+        actions: parsed,
+    })
+}
+
+fn parse_target(s: &str, symbols: &Symbols) -> Option<Target> {
+    if s.starts_with('[') && s.ends_with(']') {
+        return symbols.resolve(&s[1..s.len() - 1]).map(Target::Mem);
+    }
+    match s.to_uppercase().as_str() {
+        "PC" => Some(Target::Pc),
+        "SP" => Some(Target::Sp),
+        "EX" => Some(Target::Ex),
+        "IA" => Some(Target::Ia),
+        s => s.parse().ok().map(Target::Reg),
+    }
+}
+
+/// Forwards everything to `inner`, noting its interrupts for the `hwi`
+/// rules.
+#[derive(Debug)]
+struct Observed {
+    inner: Box<Device>,
+    index: u16,
+    interrupts: Rc<RefCell<Vec<u16>>>,
+}
+
+impl Device for Observed {
+    fn hardware_id(&self) -> u32 {
+        self.inner.hardware_id()
+    }
+
+    fn hardware_version(&self) -> u16 {
+        self.inner.hardware_version()
+    }
+
+    fn manufacturer(&self) -> u32 {
+        self.inner.manufacturer()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn interrupt(&mut self, cpu: &mut Cpu) -> Result<InterruptDelay, ()> {
+        self.interrupts.borrow_mut().push(self.index);
+        self.inner.interrupt(cpu)
+    }
+
+    fn tick(&mut self, cpu: &mut Cpu, current_tick: u64) -> TickResult {
+        self.inner.tick(cpu, current_tick)
+    }
+
+    fn next_interrupt(&self, current_tick: u64) -> Option<u64> {
+        self.inner.next_interrupt(current_tick)
+    }
+
+    fn next_tick(&self, current_tick: u64) -> Option<u64> {
+        self.inner.next_tick(current_tick)
+    }
+
+    fn save_state(&self) -> Vec<u16> {
+        self.inner.save_state()
+    }
+
+    fn load_state(&mut self, state: &[u16]) -> Result<(), ()> {
+        self.inner.load_state(state)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_script() {
+    use device::Empty;
+    use encodings::*;
+    use types::*;
+
+    let mut symbols = Symbols::new();
+    symbols.insert("done".into(), 4);
+    let script = "# Computes 5 + 7.
+                  on start: set A 5
+                  on hwi 0: set B 7
+                  on write 0x1000: print A [0x1000]
+                  on break done: expect [0x1000] 12; stop
+                  on halt: print A";
+    let mut script = Script::parse(script, &symbols).unwrap();
+    assert_eq!(script.rules()[3],
+               Rule {
+                   event: Event::Break(4),
+                   actions: vec![Action::Expect(Target::Mem(0x1000), 12), Action::Stop],
+               });
+    assert_eq!(Script::parse("on start: set A 1\non stop: stop", &symbols).unwrap_err(),
+               ParseError::Script(2));
+    assert_eq!(Script::parse("on break missing: stop", &symbols).unwrap_err(),
+               ParseError::Script(1));
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::HWI, lit(0)),
+               basic(BasicOp::ADD, reg(Register::A), reg(Register::B)),
+               basic(BasicOp::SET, AT_NEXT, reg(Register::A)),
+               0x1000,
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    let mut computer = Computer::new(cpu);
+    computer.add_device(Box::new(Empty));
+    let mut out = vec![];
+    script.attach(&mut computer, &mut out).unwrap();
+    // Still skipped by `Computer::skip_waits`.
+    assert_eq!(computer.devices()[0].next_tick(0), None);
+    loop {
+        let state = computer.tick().unwrap();
+        if let CpuState::HitBreakpoint(_) = state {
+            assert_eq!(script.handle(&mut computer, &state, &mut out), Err(Stop::Requested));
+            break;
+        }
+        script.handle(&mut computer, &state, &mut out).unwrap();
+    }
+    assert_eq!(String::from_utf8(out).unwrap(), "A=0x000c [0x1000]=0x000c\n");
+
+    computer.cpu_mut().ram[0x1000] = 11;
+    assert_eq!(script.handle(&mut computer, &CpuState::HitBreakpoint(4), &mut vec![]),
+               Err(Stop::Failed("expected [0x1000] = 0x000c, got 0x000b".into())));
+}
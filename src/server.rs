@@ -0,0 +1,301 @@
+//! Many independent computers sharing one thread.
+
+use std::time::{Duration, Instant};
+
+use computer::Computer;
+use cpu;
+use timebase;
+
+/// Ticks in an emulated second, the DCPU runs at 100 kHz.
+pub const TICKS_PER_SECOND: u64 = timebase::DEFAULT_TICKS_PER_SECOND;
+
+/// Resources a computer may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Ticks run in each round before the next computer's turn.
+    pub slice: u64,
+    /// Ticks after which the computer is stopped.
+    pub max_ticks: Option<u64>,
+    /// Host time the computer may take per emulated second.
+    pub max_host_time: Option<Duration>,
+    /// `HWI` the computer may execute per emulated second.
+    pub max_interrupts: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            slice: 1000,
+            max_ticks: None,
+            max_host_time: None,
+            max_interrupts: None,
+        }
+    }
+}
+
+/// Per second limit of `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quota {
+    HostTime,
+    Interrupts,
+}
+
+#[derive(Debug)]
+pub enum State {
+    Running,
+    Failed(cpu::Error),
+    /// Stopped by `Limits::max_ticks`.
+    OutOfTicks,
+    OverQuota(Quota),
+}
+
+struct Machine {
+    computer: Computer,
+    limits: Limits,
+    state: State,
+    /// Emulated second the quotas are counted on.
+    window: Window,
+}
+
+#[derive(Default)]
+struct Window {
+    start_tick: u64,
+    host_time: Duration,
+    /// `Cpu::hardware_interrupts` at the start of the window.
+    interrupts: u64,
+}
+
+/// Runs computers in turn, each for its `Limits::slice`.
+///
+/// A sleeping computer ends its turn early, so it costs nothing until a
+/// device wakes it up.
+#[derive(Default)]
+pub struct Server {
+    machines: Vec<Machine>,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server::default()
+    }
+
+    /// Adds a computer and returns its index.
+    pub fn add(&mut self, computer: Computer, limits: Limits) -> usize {
+        self.machines.push(Machine {
+            computer: computer,
+            limits: limits,
+            state: State::Running,
+            window: Window::default(),
+        });
+        self.machines.len() - 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    pub fn computer(&self, i: usize) -> &Computer {
+        &self.machines[i].computer
+    }
+
+    pub fn state(&self, i: usize) -> &State {
+        &self.machines[i].state
+    }
+
+    /// Number of computers still running.
+    pub fn running(&self) -> usize {
+        self.machines.iter().filter(|m| m.is_running()).count()
+    }
+
+    /// Gives each running computer its turn. Returns the indices of the
+    /// computers stopped during the round, and whether any computer did
+    /// something, so the caller can block when they all sleep.
+    pub fn round(&mut self) -> (Vec<usize>, bool) {
+        let mut stopped = vec![];
+        let mut busy = false;
+        for (i, machine) in self.machines.iter_mut().enumerate() {
+            if !machine.is_running() {
+                continue;
+            }
+            busy |= machine.run_slice();
+            if !machine.is_running() {
+                stopped.push(i);
+            }
+        }
+        (stopped, busy)
+    }
+}
+
+impl Machine {
+    fn is_running(&self) -> bool {
+        match self.state {
+            State::Running => true,
+            _ => false,
+        }
+    }
+
+    /// Returns false if the computer slept during the whole slice.
+    fn run_slice(&mut self) -> bool {
+        let second = self.computer.timebase().ticks_per_second;
+        if self.computer.current_tick() - self.window.start_tick >= second {
+            self.window = Window {
+                start_tick: self.computer.current_tick(),
+                host_time: Duration::from_secs(0),
+                interrupts: self.computer.cpu().hardware_interrupts,
+            };
+        }
+        let start = Instant::now();
+        let busy = self.run_ticks();
+        self.window.host_time += start.elapsed();
+        if let Some(max) = self.limits.max_host_time {
+            if self.is_running() && self.window.host_time > max {
+                self.state = State::OverQuota(Quota::HostTime);
+            }
+        }
+        busy
+    }
+
+    fn run_ticks(&mut self) -> bool {
+        for n in 0..self.limits.slice {
+            if let Some(max) = self.limits.max_ticks {
+                if self.computer.current_tick() >= max {
+                    self.state = State::OutOfTicks;
+                    return true;
+                }
+            }
+            let result = self.computer.tick().and_then(|_| self.computer.skip_idle());
+            match result {
+                Ok(0) if self.computer.is_sleeping() => return n > 0,
+                Ok(_) => (),
+                Err(e) => {
+                    self.state = State::Failed(e);
+                    return true;
+                }
+            }
+            if let Some(max) = self.limits.max_interrupts {
+                if self.computer.cpu().hardware_interrupts - self.window.interrupts > max {
+                    self.state = State::OverQuota(Quota::Interrupts);
+                    return true;
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_server() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::*;
+
+    let mut looping = Cpu::default();
+    looping.load(&[basic(BasicOp::SET, PC, lit(0))], 0);
+    let mut halting = Cpu::default();
+    halting.load(&[basic(BasicOp::ADD, reg(Register::A), lit(1)),
+                   basic(BasicOp::IFN, reg(Register::A), lit(30)),
+                   basic(BasicOp::SET, PC, lit(0)),
+                   special(SpecialOp::HLT, lit(0))],
+                 0);
+
+    let mut server = Server::new();
+    let limits = Limits {
+        slice: 10,
+        max_ticks: Some(25),
+        ..Limits::default()
+    };
+    server.add(Computer::new(looping), limits);
+    server.add(Computer::new(halting), Limits { slice: 10, ..Limits::default() });
+
+    assert_eq!(server.round(), (vec![], true));
+    assert_eq!(server.computer(0).current_tick(), 10);
+    assert_eq!(server.computer(1).current_tick(), 10);
+    while server.running() != 0 {
+        let (stopped, busy) = server.round();
+        assert!(busy);
+        assert!(stopped.len() <= 1);
+    }
+    assert_eq!(server.computer(0).current_tick(), 25);
+    assert!(match *server.state(0) {
+        State::OutOfTicks => true,
+        _ => false,
+    });
+    assert!(match *server.state(1) {
+        State::Failed(cpu::Error::Halted) => true,
+        _ => false,
+    });
+    assert_eq!(server.computer(1).cpu().registers[0], 30);
+}
+
+#[cfg(test)]
+#[test]
+fn test_quotas() {
+    use cpu::Cpu;
+    use device::*;
+    use encodings::*;
+    use types::*;
+
+    #[derive(Debug)]
+    struct Nothing;
+
+    impl Device for Nothing {
+        fn hardware_id(&self) -> u32 {
+            0
+        }
+
+        fn hardware_version(&self) -> u16 {
+            0
+        }
+
+        fn manufacturer(&self) -> u32 {
+            0
+        }
+
+        fn interrupt(&mut self, _: &mut Cpu) -> Result<InterruptDelay, ()> {
+            Ok(0)
+        }
+
+        fn tick(&mut self, _: &mut Cpu, _: u64) -> TickResult {
+            TickResult::Nothing
+        }
+    }
+
+    // 5 ticks per HWI, 20000 per emulated second.
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::HWI, lit(0)), basic(BasicOp::SET, PC, lit(0))], 0);
+    let mut server = Server::new();
+    let mut computer = Computer::new(cpu);
+    computer.add_device(Box::new(Nothing));
+    let limits = Limits {
+        slice: 10000,
+        max_interrupts: Some(20000),
+        ..Limits::default()
+    };
+    server.add(computer, limits);
+    for _ in 0..30 {
+        server.round();
+    }
+    assert!(match *server.state(0) {
+        State::Running => true,
+        _ => false,
+    });
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[special(SpecialOp::HWI, lit(0)), basic(BasicOp::SET, PC, lit(0))], 0);
+    let mut computer = Computer::new(cpu);
+    computer.add_device(Box::new(Nothing));
+    server.add(computer, Limits { max_interrupts: Some(19999), ..limits });
+    while server.running() == 2 {
+        server.round();
+    }
+    assert!(match *server.state(1) {
+        State::OverQuota(Quota::Interrupts) => true,
+        _ => false,
+    });
+    assert!(server.computer(1).current_tick() < TICKS_PER_SECOND);
+}
@@ -0,0 +1,82 @@
+//! Words used by each part of a program, to keep track of the 64K words of
+//! memory.
+
+use std::collections::HashMap;
+
+use symbols::Symbols;
+use types::Region;
+
+/// Name used for the words before the first label.
+pub const UNLABELED: &'static str = "(unlabeled)";
+
+/// Words from each global label to the next one or the end of the binary of
+/// `len` words, sorted by decreasing size. Local labels (`global.local`) are
+/// counted with their global label.
+pub fn symbol_sizes(symbols: &Symbols, len: usize) -> Vec<(String, usize)> {
+    let mut starts = symbols.labels()
+                            .filter(|&(label, &addr)| !label.contains('.') && (addr as usize) < len)
+                            .map(|(label, &addr)| (addr as usize, label.clone()))
+                            .collect::<Vec<_>>();
+    starts.sort();
+    if starts.first().map_or(len > 0, |&(addr, _)| addr > 0) {
+        starts.insert(0, (0, UNLABELED.into()));
+    }
+
+    let mut sizes = starts.iter()
+                          .enumerate()
+                          .map(|(i, &(addr, ref label))| {
+                              let end = starts.get(i + 1).map_or(len, |&(a, _)| a);
+                              (label.clone(), end - addr)
+                          })
+                          .collect::<Vec<_>>();
+    sort(&mut sizes);
+    sizes
+}
+
+/// Words in the code regions and outside of them, for a binary of `len`
+/// words.
+pub fn section_sizes(regions: &[Region], len: usize) -> Vec<(String, usize)> {
+    let code = regions.iter()
+                      .filter(|r| (r.first as usize) < len)
+                      .map(|r| (r.last as usize).min(len - 1) - r.first as usize + 1)
+                      .sum::<usize>();
+    let mut sizes = vec![("code".into(), code), ("data".into(), len - code.min(len))];
+    sort(&mut sizes);
+    sizes
+}
+
+/// Change of size of each name between two reports, sorted by decreasing
+/// change. Unchanged names are omitted.
+pub fn diff(old: &[(String, usize)], new: &[(String, usize)]) -> Vec<(String, i64)> {
+    let mut changes = HashMap::new();
+    for &(ref name, size) in old {
+        *changes.entry(name.clone()).or_insert(0) -= size as i64;
+    }
+    for &(ref name, size) in new {
+        *changes.entry(name.clone()).or_insert(0) += size as i64;
+    }
+    let mut changes = changes.into_iter().filter(|&(_, d)| d != 0).collect::<Vec<_>>();
+    changes.sort_by(|a, b| b.1.abs().cmp(&a.1.abs()).then_with(|| a.0.cmp(&b.0)));
+    changes
+}
+
+fn sort(sizes: &mut Vec<(String, usize)>) {
+    sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_sizes() {
+    let symbols: Symbols = "main 0x0002\nmain.loop 0x0004\ndata 0x0008\nafter 0x0010\n"
+                               .parse()
+                               .unwrap();
+    let sizes = symbol_sizes(&symbols, 0x000c);
+    assert_eq!(sizes,
+               vec![("main".into(), 6), ("data".into(), 4), (UNLABELED.into(), 2)]);
+    assert_eq!(section_sizes(&[Region { first: 2, last: 7 }], 0x000c),
+               vec![("code".into(), 6), ("data".into(), 6)]);
+
+    let old = vec![("main".into(), 4), ("data".into(), 4), ("gone".into(), 1)];
+    assert_eq!(diff(&old, &sizes),
+               vec![(UNLABELED.into(), 2), ("main".into(), 2), ("gone".into(), -1)]);
+}
@@ -0,0 +1,70 @@
+//! [proptest](https://github.com/AltSysrq/proptest) strategies generating
+//! valid instructions and programs, for property tests of this crate or of
+//! crates using it. Requires the `proptest` feature.
+//!
+//! ```ignore
+//! proptest! {
+//!     #[test]
+//!     fn roundtrip(i in dcpu::strategies::instruction()) {
+//!         let (words, size) = i.encode_to_array();
+//!         prop_assert_eq!(Instruction::decode(&words), Ok((size as u16, i)));
+//!     }
+//! }
+//! ```
+
+use num::FromPrimitive;
+use proptest::collection;
+use proptest::prelude::*;
+
+use iterators::InstructionToU16;
+use types::{BasicOp, Instruction, Register, SpecialOp, Value};
+
+pub fn register() -> BoxedStrategy<Register> {
+    (0u16..8).prop_map(|r| Register::from_u16(r).unwrap()).boxed()
+}
+
+pub fn basic_op() -> BoxedStrategy<BasicOp> {
+    (0..BasicOp::all().len()).prop_map(|i| BasicOp::all()[i]).boxed()
+}
+
+pub fn special_op() -> BoxedStrategy<SpecialOp> {
+    (0..SpecialOp::all().len()).prop_map(|i| SpecialOp::all()[i]).boxed()
+}
+
+/// Any word, more often one fitting in a short literal.
+pub fn word() -> BoxedStrategy<u16> {
+    prop_oneof![Just(0xffffu16), 0u16..0x1f, any::<u16>()].boxed()
+}
+
+const SPECIALS: [Value; 5] = [Value::Push, Value::Peek, Value::SP, Value::PC, Value::EX];
+
+/// Any value. `Value::Push` is `POP` when used as `a`.
+pub fn value() -> BoxedStrategy<Value> {
+    prop_oneof![register().prop_map(Value::Reg),
+                register().prop_map(Value::AtReg),
+                (register(), word()).prop_map(|(r, n)| Value::AtRegPlus(r, n)),
+                word().prop_map(Value::Pick),
+                (0..SPECIALS.len()).prop_map(|i| SPECIALS[i]),
+                word().prop_map(Value::AtAddr),
+                word().prop_map(Value::Litteral)]
+        .boxed()
+}
+
+pub fn instruction() -> BoxedStrategy<Instruction> {
+    prop_oneof![(basic_op(), value(), value())
+                    .prop_map(|(op, b, a)| Instruction::BasicOp(op, b, a)),
+                (special_op(), value()).prop_map(|(op, a)| Instruction::SpecialOp(op, a))]
+        .boxed()
+}
+
+/// Up to `max_len` instructions.
+pub fn program(max_len: usize) -> BoxedStrategy<Vec<Instruction>> {
+    collection::vec(instruction(), 1..max_len + 1).boxed()
+}
+
+/// Encoded `program`.
+pub fn binary(max_len: usize) -> BoxedStrategy<Vec<u16>> {
+    program(max_len)
+        .prop_map(|p| InstructionToU16::chain(p.into_iter()).collect())
+        .boxed()
+}
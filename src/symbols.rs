@@ -0,0 +1,112 @@
+use std::collections::{btree_map, BTreeMap};
+use std::fmt;
+use std::str::FromStr;
+
+use types::ParseError;
+
+/// Addresses of the labels of a program.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Symbols {
+    labels: BTreeMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn insert(&mut self, label: String, addr: u16) {
+        self.labels.insert(label, addr);
+    }
+
+    pub fn address(&self, label: &str) -> Option<u16> {
+        self.labels.get(label).cloned()
+    }
+
+    /// Labels and their addresses, sorted by label.
+    pub fn labels(&self) -> btree_map::Iter<String, u16> {
+        self.labels.iter()
+    }
+
+    /// Closest label at or before `addr`, with the offset from it.
+    pub fn nearest(&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels
+            .iter()
+            .filter(|&(_, &a)| a <= addr)
+            .max_by_key(|&(_, &a)| a)
+            .map(|(l, &a)| (l.as_str(), addr - a))
+    }
+
+    /// Parses `label`, `label+offset` or a number.
+    pub fn resolve(&self, s: &str) -> Option<u16> {
+        let mut parts = s.splitn(2, '+').map(|p| p.trim());
+        let base = parts.next().unwrap_or("");
+        let base = match parse_num(base).or_else(|| self.address(base)) {
+            Some(n) => n,
+            None => return None,
+        };
+        match parts.next() {
+            Some(offset) => parse_num(offset).map(|o| base.wrapping_add(o)),
+            None => Some(base),
+        }
+    }
+
+    /// Inverse of `resolve`, `label+offset` if possible.
+    pub fn describe(&self, addr: u16) -> String {
+        match self.nearest(addr) {
+            Some((label, 0)) => label.into(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => format!("0x{:04x}", addr),
+        }
+    }
+}
+
+fn parse_num(s: &str) -> Option<u16> {
+    if s.starts_with("0x") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// One `label 0xaddr` per line.
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (label, addr) in self.labels.iter() {
+            try!(writeln!(f, "{} 0x{:04x}", label, addr));
+        }
+        Ok(())
+    }
+}
+
+/// Also reads the maps of the linker, ignoring the object after the
+/// address.
+impl FromStr for Symbols {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Symbols, ParseError> {
+        let mut symbols = Symbols::new();
+        for line in s.lines().filter(|l| !l.trim().is_empty()) {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next().and_then(parse_num), words.nth(1)) {
+                (Some(label), Some(addr), None) => symbols.insert(label.into(), addr),
+                _ => return Err(ParseError::Symbols),
+            }
+        }
+        Ok(symbols)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_symbols() {
+    let symbols: Symbols = "main 0x0000\nloop 0x0004\n".parse().unwrap();
+    assert_eq!(symbols.to_string().parse::<Symbols>(), Ok(symbols.clone()));
+    assert_eq!(symbols.resolve("loop+2"), Some(6));
+    assert_eq!(symbols.resolve("0x10"), Some(0x10));
+    assert_eq!(symbols.resolve("foo"), None);
+    assert_eq!(symbols.describe(6), "loop+2");
+    assert_eq!(symbols.describe(2), "main+2");
+    let map: Symbols = "main 0x0000 main.o\nloop 0x0004 main.o\n".parse().unwrap();
+    assert_eq!(map, symbols);
+}
@@ -0,0 +1,162 @@
+use std::ops::BitOr;
+
+use types::Register;
+
+/// External input a value can come from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    Keyboard = 0b01,
+    Disk = 0b10,
+    Serial = 0b100,
+}
+
+/// Set of sources a value has been derived from.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Taint(u8);
+
+impl Taint {
+    pub fn none() -> Taint {
+        Taint(0)
+    }
+
+    pub fn all() -> Taint {
+        Taint::from(Source::Keyboard) | Taint::from(Source::Disk) | Taint::from(Source::Serial)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, s: Source) -> bool {
+        self.0 & s as u8 != 0
+    }
+}
+
+impl From<Source> for Taint {
+    fn from(s: Source) -> Taint {
+        Taint(s as u8)
+    }
+}
+
+impl BitOr for Taint {
+    type Output = Taint;
+
+    fn bitor(self, rhs: Taint) -> Taint {
+        Taint(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Location {
+    Reg(Register),
+    Mem(u16),
+}
+
+/// Shadow of the memory and general purpose registers recording where
+/// each value comes from.
+///
+/// Values read by an instruction taint the value it writes. SP, PC and EX
+/// aren't tracked.
+pub struct Shadow {
+    sources: Taint,
+    ram: Box<[Taint; 0x10000]>,
+    registers: [Taint; 8],
+    current: Taint,
+}
+
+impl Shadow {
+    /// Only inputs from `sources` will be tainted.
+    pub fn new(sources: Taint) -> Shadow {
+        Shadow {
+            sources: sources,
+            ram: Box::new([Taint::none(); 0x10000]),
+            registers: [Taint::none(); 8],
+            current: Taint::none(),
+        }
+    }
+
+    pub fn register(&self, r: Register) -> Taint {
+        self.registers[r as usize]
+    }
+
+    pub fn memory(&self, addr: u16) -> Taint {
+        self.ram[addr as usize]
+    }
+
+    /// Records that `loc` has just been written with a value from `source`.
+    pub fn input(&mut self, loc: Location, source: Source) {
+        let taint = if self.sources.contains(source) {
+            Taint::from(source)
+        } else {
+            Taint::none()
+        };
+        *self.get_mut(loc) = taint;
+    }
+
+    /// Starts tracking a new instruction.
+    pub fn clear_current(&mut self) {
+        self.current = Taint::none();
+    }
+
+    pub fn read(&mut self, loc: Location) {
+        self.current = self.current | *self.get_mut(loc);
+    }
+
+    pub fn write(&mut self, loc: Location) {
+        *self.get_mut(loc) = self.current;
+    }
+
+    fn get_mut(&mut self, loc: Location) -> &mut Taint {
+        match loc {
+            Location::Reg(r) => &mut self.registers[r as usize],
+            Location::Mem(addr) => &mut self.ram[addr as usize],
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_propagation() {
+    use cpu::Cpu;
+    use types::{Instruction, BasicOp, Value};
+
+    let mut cpu = Cpu::default();
+    cpu.shadow = Some(Box::new(Shadow::new(Source::Keyboard.into())));
+    cpu.load_ops(&[Instruction::BasicOp(BasicOp::SET,
+                                        Value::Reg(Register::A),
+                                        Value::AtAddr(0x100)),
+                   Instruction::BasicOp(BasicOp::ADD,
+                                        Value::Reg(Register::B),
+                                        Value::Reg(Register::A)),
+                   Instruction::BasicOp(BasicOp::SET,
+                                        Value::Reg(Register::A),
+                                        Value::Litteral(1))],
+                 0);
+    cpu.input(Location::Mem(0x100), Source::Keyboard);
+    cpu.input(Location::Reg(Register::C), Source::Disk);
+
+    for _ in 0..5 {
+        cpu.tick(&mut []).unwrap();
+    }
+
+    let shadow = cpu.shadow.as_ref().unwrap();
+    assert!(shadow.register(Register::A).is_clean());
+    assert!(shadow.register(Register::B).contains(Source::Keyboard));
+    assert!(shadow.register(Register::C).is_clean());
+}
+
+#[cfg(test)]
+#[test]
+fn test_interrupt() {
+    use cpu::Cpu;
+
+    let mut cpu = Cpu::default();
+    cpu.shadow = Some(Box::new(Shadow::new(Source::Keyboard.into())));
+    cpu.ia = 0x10;
+    cpu.input(Location::Reg(Register::A), Source::Keyboard);
+    cpu.trigger_interrupt(1);
+
+    let shadow = cpu.shadow.as_ref().unwrap();
+    assert!(shadow.register(Register::A).is_clean());
+    assert!(shadow.memory(cpu.sp).contains(Source::Keyboard));
+}
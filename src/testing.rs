@@ -0,0 +1,249 @@
+//! Regression tests of programs: runs them, then compares regions of their
+//! memory to golden baselines checked in next to the tests, so routines
+//! building data structures, like sorters or decompressors, are checked word
+//! by word.
+//!
+//! `assert_golden` writes the baseline when its file is missing, or when
+//! `DCPU_BLESS` is set after an intended change, and compares to it
+//! otherwise. Differences are shown with the labels of the program, for
+//! example `sorted+3: 0x0005 instead of 0x0004`.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use computer::Computer;
+use cpu;
+use symbols::Symbols;
+use types::{ParseError, Region};
+
+/// Environment variable making `assert_golden` rewrite the baselines.
+pub const BLESS_VAR: &'static str = "DCPU_BLESS";
+
+/// Words per line of a baseline file.
+const LINE: usize = 8;
+
+/// Runs `computer` until its CPU halts and returns the number of ticks.
+///
+/// Panics if the CPU fails, or is still running after `max_ticks`.
+pub fn run(computer: &mut Computer, max_ticks: u64) -> u64 {
+    while computer.current_tick() < max_ticks {
+        match computer.tick() {
+            Ok(_) => (),
+            Err(cpu::Error::Halted) => return computer.current_tick(),
+            Err(e) => panic!("{} at 0x{:04x}", e, computer.cpu().pc),
+        }
+    }
+    panic!("still running after {} ticks", max_ticks)
+}
+
+/// Parses `first..last`, both included, each being a number, a label or
+/// `label+offset`.
+pub fn region(symbols: &Symbols, s: &str) -> Option<Region> {
+    let mut bounds = s.splitn(2, "..").map(|b| symbols.resolve(b));
+    match (bounds.next(), bounds.next()) {
+        (Some(Some(first)), Some(Some(last))) if first <= last => {
+            Some(Region {
+                first: first,
+                last: last,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Words of a region of the memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Golden {
+    pub region: Region,
+    pub words: Vec<u16>,
+}
+
+impl Golden {
+    pub fn capture(ram: &[u16], region: Region) -> Golden {
+        Golden {
+            region: region,
+            words: ram[region.first as usize..region.last as usize + 1].to_vec(),
+        }
+    }
+
+    /// Words of `ram` different from the baseline.
+    pub fn diff(&self, ram: &[u16]) -> Vec<Mismatch> {
+        self.words
+            .iter()
+            .enumerate()
+            .map(|(i, &expected)| (self.region.first + i as u16, expected))
+            .filter(|&(addr, expected)| ram[addr as usize] != expected)
+            .map(|(addr, expected)| {
+                Mismatch {
+                    addr: addr,
+                    expected: expected,
+                    actual: ram[addr as usize],
+                }
+            })
+            .collect()
+    }
+}
+
+/// The region on the first line, as in `Region`'s format, then the words in
+/// hexadecimal, 8 per line after their address.
+impl fmt::Display for Golden {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "{}", self.region));
+        for (i, chunk) in self.words.chunks(LINE).enumerate() {
+            let words: Vec<String> = chunk.iter().map(|w| format!("{:04x}", w)).collect();
+            try!(writeln!(f,
+                          "0x{:04x}: {}",
+                          self.region.first as usize + i * LINE,
+                          words.join(" ")));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Golden {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Golden, ParseError> {
+        let mut lines = s.lines().filter(|l| !l.trim().is_empty());
+        let region: Region = try!(lines.next()
+                                       .ok_or(ParseError::Golden)
+                                       .and_then(|l| l.parse().map_err(|_| ParseError::Golden)));
+        let mut words = vec![];
+        for line in lines {
+            let mut parts = line.splitn(2, ':');
+            match parts.next().map(|a| a.trim_left_matches("0x")) {
+                Some(addr) if u16::from_str_radix(addr, 16).ok() ==
+                              Some(region.first.wrapping_add(words.len() as u16)) => (),
+                _ => return Err(ParseError::Golden),
+            }
+            for word in parts.next().unwrap_or("").split_whitespace() {
+                words.push(try!(u16::from_str_radix(word, 16).map_err(|_| ParseError::Golden)));
+            }
+        }
+        if words.len() != region.last as usize - region.first as usize + 1 {
+            return Err(ParseError::Golden);
+        }
+        Ok(Golden {
+            region: region,
+            words: words,
+        })
+    }
+}
+
+/// Word differing from its baseline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub addr: u16,
+    pub expected: u16,
+    pub actual: u16,
+}
+
+impl Mismatch {
+    /// Same as `Display`, with the address relative to the nearest label.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        format!("{}: 0x{:04x} instead of 0x{:04x}",
+                symbols.describe(self.addr),
+                self.actual,
+                self.expected)
+    }
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.describe(&Symbols::new()))
+    }
+}
+
+/// Compares the memory `region`, parsed by `region`, to the baseline in the
+/// file at `path`, or writes it if the file doesn't exist or `DCPU_BLESS` is
+/// set.
+///
+/// Panics listing the words that differ.
+pub fn assert_golden<P: AsRef<Path>>(computer: &Computer,
+                                     symbols: &Symbols,
+                                     region_desc: &str,
+                                     path: P) {
+    let path = path.as_ref();
+    let ram = &computer.cpu().ram;
+    let region = region(symbols, region_desc)
+                     .unwrap_or_else(|| panic!("invalid region {}", region_desc));
+    if env::var_os(BLESS_VAR).is_some() || !path.exists() {
+        let golden = Golden::capture(ram, region);
+        File::create(path)
+            .and_then(|mut f| f.write_all(golden.to_string().as_bytes()))
+            .unwrap_or_else(|e| panic!("can't write {}: {}", path.display(), e));
+        return;
+    }
+
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut text))
+        .unwrap_or_else(|e| panic!("can't read {}: {}", path.display(), e));
+    let golden: Golden = text.parse()
+                             .unwrap_or_else(|_| panic!("invalid baseline {}", path.display()));
+    if golden.region != region {
+        panic!("{} is now {}, not {} as in {}; set {} to update it",
+               region_desc,
+               region,
+               golden.region,
+               path.display(),
+               BLESS_VAR);
+    }
+    let mismatches = golden.diff(ram);
+    if !mismatches.is_empty() {
+        let lines: Vec<String> = mismatches.iter().map(|m| m.describe(symbols)).collect();
+        panic!("{} differs from {}:\n{}\nset {} to update it",
+               region_desc,
+               path.display(),
+               lines.join("\n"),
+               BLESS_VAR);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_golden() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::*;
+
+    // Writes 3, 2, 1 at `output`.
+    let program = [basic(BasicOp::SET, AT_NEXT, lit(3)),
+                   0x1000,
+                   basic(BasicOp::SET, AT_NEXT, lit(2)),
+                   0x1001,
+                   basic(BasicOp::SET, AT_NEXT, lit(1)),
+                   0x1002,
+                   special(SpecialOp::HLT, lit(0))];
+    let mut cpu = Cpu::default();
+    cpu.load(&program, 0);
+    let mut computer = Computer::new(cpu);
+    run(&mut computer, 1000);
+    let symbols: Symbols = "output 0x1000\n".parse().unwrap();
+
+    let region = region(&symbols, "output..output+2").unwrap();
+    let golden = Golden::capture(&computer.cpu().ram, region);
+    assert_eq!(golden.words, [3, 2, 1]);
+    assert_eq!(golden.to_string().parse(), Ok(golden.clone()));
+    assert!(golden.diff(&computer.cpu().ram).is_empty());
+
+    computer.cpu_mut().ram[0x1001] = 5;
+    let mismatches = golden.diff(&computer.cpu().ram);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].describe(&symbols), "output+1: 0x0005 instead of 0x0002");
+
+    let path = env::temp_dir().join("dcpu_test_golden.txt");
+    let _ = ::std::fs::remove_file(&path);
+    assert_golden(&computer, &symbols, "output..output+2", &path);
+    assert_golden(&computer, &symbols, "output..output+2", &path);
+    computer.cpu_mut().ram[0x1001] = 2;
+    let res = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        assert_golden(&computer, &symbols, "output..output+2", &path)
+    }));
+    assert!(res.is_err());
+    let _ = ::std::fs::remove_file(&path);
+}
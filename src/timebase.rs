@@ -0,0 +1,182 @@
+//! Conversions between ticks, emulated time and host time.
+
+use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// The DCPU runs at 100 kHz.
+pub const DEFAULT_TICKS_PER_SECOND: u64 = 100000;
+
+const NANOS_PER_SECOND: u64 = 1000000000;
+
+/// Lag after which a `Throttle` gives up catching up.
+const MAX_LAG_MILLIS: u64 = 100;
+
+/// Speed of a computer, for the devices and tools to convert between
+/// ticks and time instead of hard-coding the tick rate.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Timebase {
+    pub ticks_per_second: u64,
+    /// Emulated seconds per host second when the emulation is throttled, 1
+    /// to run in real time.
+    pub speed: f64,
+}
+
+impl Default for Timebase {
+    fn default() -> Timebase {
+        Timebase {
+            ticks_per_second: DEFAULT_TICKS_PER_SECOND,
+            speed: 1.,
+        }
+    }
+}
+
+impl Timebase {
+    pub fn new(ticks_per_second: u64) -> Timebase {
+        Timebase {
+            ticks_per_second: ticks_per_second,
+            ..Timebase::default()
+        }
+    }
+
+    /// Ticks in `numerator / denominator` emulated seconds.
+    pub fn ticks(&self, numerator: u64, denominator: u64) -> u64 {
+        self.ticks_per_second * numerator / denominator
+    }
+
+    /// Ticks between two events happening `hertz` times per emulated second.
+    pub fn period(&self, hertz: u64) -> u64 {
+        self.ticks(1, hertz)
+    }
+
+    /// Whether an event every `period` ticks happens at `tick`.
+    pub fn is_due(&self, tick: u64, period: u64) -> bool {
+        period != 0 && tick % period == 0
+    }
+
+    /// First tick from `tick` on at which an event every `period` ticks
+    /// happens.
+    pub fn next_due(&self, tick: u64, period: u64) -> u64 {
+        (tick + period - 1) / period * period
+    }
+
+    pub fn ticks_in(&self, emulated: Duration) -> u64 {
+        emulated.as_secs() * self.ticks_per_second +
+        emulated.subsec_nanos() as u64 * self.ticks_per_second / NANOS_PER_SECOND
+    }
+
+    pub fn emulated_time(&self, ticks: u64) -> Duration {
+        let secs = ticks / self.ticks_per_second;
+        let rest = ticks % self.ticks_per_second;
+        Duration::new(secs, (rest * NANOS_PER_SECOND / self.ticks_per_second) as u32)
+    }
+
+    /// Host time `ticks` should take at `speed`.
+    pub fn host_time(&self, ticks: u64) -> Duration {
+        let nanos = ticks as f64 * NANOS_PER_SECOND as f64 /
+                    (self.ticks_per_second as f64 * self.speed);
+        Duration::new((nanos / NANOS_PER_SECOND as f64) as u64,
+                      (nanos % NANOS_PER_SECOND as f64) as u32)
+    }
+}
+
+/// Parses a frequency like `100khz`, `1.5MHz` or `100000`.
+pub fn parse_hertz(s: &str) -> Option<u64> {
+    let s = s.trim().to_lowercase();
+    let s = s.trim_right_matches("hz");
+    let (number, unit) = if s.ends_with('k') {
+        (&s[..s.len() - 1], 1e3)
+    } else if s.ends_with('m') {
+        (&s[..s.len() - 1], 1e6)
+    } else {
+        (s, 1.)
+    };
+    match number.trim().parse::<f64>() {
+        Ok(n) if n > 0. => Some((n * unit) as u64),
+        _ => None,
+    }
+}
+
+/// Paces the ticks of a computer to the host time, `timebase.speed`
+/// emulated seconds per host second.
+///
+/// `wait` is meant to be called every few hundred ticks: it sleeps when the
+/// emulation is ahead. When it is too late, because the host is too slow or
+/// the computer was paused, it starts over from the current tick instead of
+/// running flat out to catch up.
+#[derive(Debug, Copy, Clone)]
+pub struct Throttle {
+    timebase: Timebase,
+    start: Instant,
+    start_tick: u64,
+}
+
+impl Throttle {
+    pub fn new(timebase: Timebase, current_tick: u64) -> Throttle {
+        Throttle {
+            timebase: timebase,
+            start: Instant::now(),
+            start_tick: current_tick,
+        }
+    }
+
+    pub fn wait(&mut self, current_tick: u64) {
+        let target = self.timebase.host_time(current_tick.saturating_sub(self.start_tick));
+        let elapsed = self.start.elapsed();
+        if target > elapsed {
+            thread::sleep(target - elapsed);
+        } else if elapsed - target > Duration::from_millis(MAX_LAG_MILLIS) {
+            *self = Throttle::new(self.timebase, current_tick);
+        }
+    }
+}
+
+/// Source of host time for the devices pacing themselves on the host rather
+/// than on the ticks, like the LEM1802 with vsync. Pages embedding the
+/// emulator in WebAssembly, where the standard library has no clock,
+/// provide their own.
+pub trait HostClock: Debug {
+    /// Time since an arbitrary origin.
+    fn now(&self) -> Duration;
+}
+
+/// Clock of the operating system.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl HostClock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_timebase() {
+    let timebase = Timebase::default();
+    assert_eq!(timebase.period(60), 1666);
+    assert_eq!(timebase.ticks(3, 60), 5000);
+    assert!(timebase.is_due(3332, 1666));
+    assert!(!timebase.is_due(3332, 0));
+    assert_eq!(timebase.next_due(3333, 1666), 4998);
+    assert_eq!(timebase.ticks_in(Duration::from_millis(2500)), 250000);
+    assert_eq!(timebase.emulated_time(150000), Duration::from_millis(1500));
+
+    let fast = Timebase { speed: 2., ..timebase };
+    assert_eq!(fast.host_time(150000), Duration::from_millis(750));
+}
+
+#[cfg(test)]
+#[test]
+fn test_throttle() {
+    assert_eq!(parse_hertz("100khz"), Some(100000));
+    assert_eq!(parse_hertz("1.5MHz"), Some(1500000));
+    assert_eq!(parse_hertz("2000"), Some(2000));
+    assert_eq!(parse_hertz("fast"), None);
+    assert_eq!(parse_hertz("0hz"), None);
+
+    let mut throttle = Throttle::new(Timebase::new(1000), 0);
+    let start = Instant::now();
+    throttle.wait(50);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+}
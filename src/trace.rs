@@ -0,0 +1,147 @@
+//! Trace of the instructions executed, see `Cpu::trace`.
+//!
+//! Each instruction is logged with its address, the nearest label before it
+//! if there are symbols, its disassembly and its cycles, either to a writer
+//! as it executes, or to a ring buffer keeping the last ones, to see what
+//! led to a crash.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use symbols::Symbols;
+use types::Instruction;
+
+/// Instruction executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub pc: u16,
+    pub instruction: Instruction,
+    /// Cycles of the instruction.
+    pub cycles: u16,
+    /// Cycles of the instructions executed before, since the start of the
+    /// trace.
+    pub start_cycle: u64,
+}
+
+impl Entry {
+    /// Start cycle, address, label and offset, instruction and its cycles,
+    /// in columns, for example `12 0x0003 main+2 SET A, 3 (1)`.
+    pub fn describe(&self, symbols: &Symbols) -> String {
+        let label = match symbols.nearest(self.pc) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => String::new(),
+        };
+        format!("{:>8} 0x{:04x} {:<16} {:<24} ({})",
+                self.start_cycle,
+                self.pc,
+                label,
+                self.instruction.to_string(),
+                self.cycles)
+    }
+}
+
+enum Sink {
+    Writer(Box<Write>),
+    Buffer(VecDeque<Entry>, usize),
+}
+
+pub struct Tracer {
+    symbols: Symbols,
+    sink: Sink,
+    cycles: u64,
+}
+
+impl Tracer {
+    /// Writes each entry to `writer`, one per line. Stops writing after an
+    /// error.
+    pub fn to_writer(writer: Box<Write>, symbols: Symbols) -> Tracer {
+        Tracer {
+            symbols: symbols,
+            sink: Sink::Writer(writer),
+            cycles: 0,
+        }
+    }
+
+    /// Keeps the last `capacity` entries, see `entries`.
+    pub fn buffered(capacity: usize, symbols: Symbols) -> Tracer {
+        Tracer {
+            symbols: symbols,
+            sink: Sink::Buffer(VecDeque::with_capacity(capacity), capacity),
+            cycles: 0,
+        }
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn record(&mut self, pc: u16, instruction: Instruction, cycles: u16) {
+        let entry = Entry {
+            pc: pc,
+            instruction: instruction,
+            cycles: cycles,
+            start_cycle: self.cycles,
+        };
+        self.cycles += cycles as u64;
+        let failed = match self.sink {
+            Sink::Writer(ref mut w) => {
+                writeln!(w, "{}", entry.describe(&self.symbols)).is_err()
+            }
+            Sink::Buffer(ref mut entries, capacity) => {
+                if entries.len() == capacity {
+                    entries.pop_front();
+                }
+                if capacity != 0 {
+                    entries.push_back(entry);
+                }
+                false
+            }
+        };
+        if failed {
+            self.sink = Sink::Buffer(VecDeque::new(), 0);
+        }
+    }
+
+    /// Last entries, the oldest first, empty when writing to a writer.
+    pub fn entries(&self) -> Vec<Entry> {
+        match self.sink {
+            Sink::Writer(_) => vec![],
+            Sink::Buffer(ref entries, _) => entries.iter().cloned().collect(),
+        }
+    }
+
+    /// Writes the last entries, one per line.
+    pub fn dump<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for entry in self.entries() {
+            try!(writeln!(w, "{}", entry.describe(&self.symbols)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_trace() {
+    use cpu::Cpu;
+    use encodings::*;
+    use types::*;
+
+    let mut cpu = Cpu::default();
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), lit(3)),
+               basic(BasicOp::ADD, reg(Register::A), NEXT),
+               1000,
+               basic(BasicOp::SUB, reg(Register::A), lit(1)),
+               special(SpecialOp::HLT, lit(0))],
+             0);
+    let symbols: Symbols = "main 0x0000\nloop 0x0003\n".parse().unwrap();
+    cpu.trace = Some(Box::new(Tracer::buffered(3, symbols)));
+    while cpu.tick(&mut []).is_ok() {}
+
+    let tracer = cpu.trace.unwrap();
+    let entries = tracer.entries();
+    assert_eq!(entries.iter().map(|e| (e.pc, e.start_cycle)).collect::<Vec<_>>(),
+               [(1, 1), (3, 4), (4, 6)]);
+    assert_eq!(entries[0].describe(tracer.symbols()),
+               format!("{:>8} 0x0001 {:<16} {:<24} (3)", 1, "main+1", "ADD A, 1000"));
+}
@@ -9,10 +9,11 @@ pub const SHIFT_A: u16 = 10;
 pub const SHIFT_B: u16 = 5;
 pub const MASK_B: u16 = 0b11111;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum DecodeError {
     BasicOp(u16),
-    SpecialOp(u16)
+    SpecialOp(u16),
+    Value(u16)
 }
 
 impl fmt::Display for DecodeError {
@@ -20,6 +21,7 @@ impl fmt::Display for DecodeError {
         match *self {
             DecodeError::BasicOp(ref e) => write!(f, "invalid basic opcode: {:x}", e),
             DecodeError::SpecialOp(ref e) => write!(f, "invalid special opcode: {:x}", e),
+            DecodeError::Value(ref e) => write!(f, "invalid value: {:x}", e),
         }
     }
 }
@@ -28,15 +30,73 @@ impl Error for DecodeError {
     fn description(&self) -> &str {
         match *self {
             DecodeError::BasicOp(_) => "invalid basic opcode",
-            DecodeError::SpecialOp(_) => "invalid special opcode"
+            DecodeError::SpecialOp(_) => "invalid special opcode",
+            DecodeError::Value(_) => "invalid value"
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParseError {
     BasicOp,
     SpecialOp,
-    Register
+    Register,
+    Region,
+    Symbols,
+    DebugInfo,
+    Object,
+    Instruction,
+    Golden,
+    UnknownLabel(String),
+    /// Invalid line of a script, numbered from 1.
+    Script(usize),
+    /// Invalid line of a recording, numbered from 1.
+    Recording(usize),
+}
+
+/// Inclusive range of memory addresses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl Region {
+    pub fn contains(&self, addr: u16) -> bool {
+        self.first <= addr && addr <= self.last
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:04x} 0x{:04x}", self.first, self.last)
+    }
+}
+
+impl FromStr for Region {
+    type Err = ParseError;
+
+    /// Parses the `Display` format, two hex addresses separated by spaces.
+    fn from_str(s: &str) -> Result<Region, ParseError> {
+        let mut bounds = s.split_whitespace().map(|n| {
+            let n = n.trim_left_matches("0x");
+            u16::from_str_radix(n, 16).map_err(|_| ParseError::Region)
+        });
+        match (bounds.next(), bounds.next(), bounds.next()) {
+            (Some(first), Some(last), None) => {
+                let (first, last) = (try!(first), try!(last));
+                if first <= last {
+                    Ok(Region {
+                        first: first,
+                        last: last,
+                    })
+                } else {
+                    Err(ParseError::Region)
+                }
+            }
+            _ => Err(ParseError::Region),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -61,6 +121,16 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    /// Cycles of the instruction encoded in `size` words: those of its
+    /// opcode, plus one for each next word read.
+    pub fn cycles(&self, size: u16) -> u16 {
+        let op = match *self {
+            Instruction::BasicOp(op, _, _) => op.delay(),
+            Instruction::SpecialOp(op, _) => op.delay(),
+        };
+        op + size.saturating_sub(1)
+    }
+
     pub fn delay(&self) -> u16 {
         match *self {
             Instruction::BasicOp(op, b, a) => op.delay() + a.delay(true) + b.delay(false),
@@ -69,12 +139,26 @@ impl Instruction {
     }
 
     pub fn encode(&self, output: &mut [u16]) -> u16 {
+        self.encode_with(output, true)
+    }
+
+    /// Same as `encode`, without a buffer. Returns the words and how many of
+    /// them are used.
+    pub fn encode_to_array(&self) -> ([u16; 3], u8) {
+        let mut words = [0; 3];
+        let size = self.encode(&mut words);
+        (words, size as u8)
+    }
+
+    /// With `short_literals` false, the literals are always stored in the
+    /// next word, even the small ones that could fit in a.
+    pub fn encode_with(&self, output: &mut [u16], short_literals: bool) -> u16 {
         match *self {
             Instruction::BasicOp(op, b, a) => {
                 let mut size = 1;
                 output[0] = op.encode();
 
-                let (val, next) = a.encode(true);
+                let (val, next) = a.encode(short_literals);
                 output[0] |= val << SHIFT_A;
                 if let Some(n) = next {
                     output[1] = n;
@@ -84,14 +168,14 @@ impl Instruction {
                 let (val, next) = b.encode(false);
                 output[0] |= val << SHIFT_B;
                 if let Some(n) = next {
-                    output[2] = n;
+                    output[size as usize] = n;
                     size += 1;
                 }
 
                 size
             },
             Instruction::SpecialOp(op, v) => {
-                let (a_bin, next) = v.encode(true);
+                let (a_bin, next) = v.encode(short_literals);
                 output[0] = op.encode() << SHIFT_B | (a_bin) << SHIFT_A;
                 if let Some(n) = next {
                     output[1] = n;
@@ -103,6 +187,7 @@ impl Instruction {
         }
     }
 
+    /// Never panics, whatever the content of `data`.
     pub fn decode(data: &[u16; 3]) -> Result<(u16, Instruction), DecodeError> {
         let op_bin = data[0] & MASK_OP;
         let a_bin = data[0] >> SHIFT_A;
@@ -110,12 +195,12 @@ impl Instruction {
 
         if op_bin == 0 {
             let op = try!(SpecialOp::decode(b_bin));
-            let (used, a) = Value::decode(a_bin, data[1], true);
+            let (used, a) = try!(Value::decode(a_bin, data[1], true));
             Ok((1 + used, Instruction::SpecialOp(op, a)))
         } else {
             let op = try!(BasicOp::decode(op_bin));
-            let (used_a, a) = Value::decode(a_bin, data[1], true);
-            let (used_b, b) = Value::decode(b_bin, data[(1 + used_a) as usize], false);
+            let (used_a, a) = try!(Value::decode(a_bin, data[1], true));
+            let (used_b, b) = try!(Value::decode(b_bin, data[(1 + used_a) as usize], false));
             Ok((1 + used_a + used_b, Instruction::BasicOp(op, b, a)))
         }
     }
@@ -228,10 +313,29 @@ impl Value {
         }
     }
 
-    pub fn decode(val: u16, next: u16, is_a: bool) -> (u16, Value) {
-        match val {
+    /// What the 6 bits `code` of an operand stand for.
+    pub fn form(code: u16) -> &'static str {
+        match code {
+            0x00...0x07 => "register",
+            0x08...0x0f => "[register]",
+            0x10...0x17 => "[register + next word]",
+            0x18 => "PUSH (b) or POP (a)",
+            0x19 => "PEEK",
+            0x1a => "PICK next word",
+            0x1b => "SP",
+            0x1c => "PC",
+            0x1d => "EX",
+            0x1e => "[next word]",
+            0x1f => "next word (literal)",
+            _ => "literal -1 to 30, a only",
+        }
+    }
+
+    /// Returns the number of words used after the instruction word, 0 or 1.
+    pub fn decode(val: u16, next: u16, is_a: bool) -> Result<(u16, Value), DecodeError> {
+        Ok(match val {
             x if x <= 0x17 => {
-                let reg = Register::from_u16(x % 0x8).unwrap();
+                let reg = try!(Register::from_u16(x % 0x8).ok_or(DecodeError::Value(x)));
                 if x <= 0x07 {
                     (0, Value::Reg(reg))
                 } else if x <= 0x0f {
@@ -251,8 +355,8 @@ impl Value {
             x if is_a &&
                  x >= 0x20 &&
                  x <= 0x3f => (0, Value::Litteral(x.wrapping_sub(0x21))),
-            _ => unreachable!()
-        }
+            x => return Err(DecodeError::Value(x))
+        })
     }
 }
 
@@ -266,6 +370,7 @@ impl fmt::Binary for Value {
             Value::AtAddr(v) => write!(f, "[{}]", v),
             Value::Litteral(v) => write!(f, "{}", v),
             Value::Push => write!(f, "PUSH"),
+            Value::Peek => write!(f, "PEEK"),
             x => write!(f, "{:?}", x)
         }
     }
@@ -281,166 +386,65 @@ impl fmt::Octal for Value {
             Value::AtAddr(v) => write!(f, "[{}]", v),
             Value::Litteral(v) => write!(f, "{}", v),
             Value::Push => write!(f, "POP"),
+            Value::Peek => write!(f, "PEEK"),
             x => write!(f, "{:?}", x)
         }
     }
 }
 
-enum_from_primitive! {
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub enum BasicOp {
-    SET = 0x01,
-    ADD = 0x02,
-    SUB = 0x03,
-    MUL = 0x04,
-    MLI = 0x05,
-    DIV = 0x06,
-    DVI = 0x07,
-    MOD = 0x08,
-    MDI = 0x09,
-    AND = 0x0a,
-    BOR = 0x0b,
-    XOR = 0x0c,
-    SHR = 0x0d,
-    ASR = 0x0e,
-    SHL = 0x0f,
-    IFB = 0x10,
-    IFC = 0x11,
-    IFE = 0x12,
-    IFN = 0x13,
-    IFG = 0x14,
-    IFA = 0x15,
-    IFL = 0x16,
-    IFU = 0x17,
-    ADX = 0x1a,
-    SBX = 0x1b,
-    STI = 0x1e,
-    STD = 0x1f
-}
-}
-
-impl BasicOp {
-    pub fn delay(&self) -> u16 {
-        match *self {
-            BasicOp::SET | BasicOp::AND | BasicOp::BOR | BasicOp::XOR |
-            BasicOp::SHL | BasicOp::SHR | BasicOp::ASR => 1,
-            BasicOp::DVI | BasicOp::DIV | BasicOp::MOD | BasicOp::MDI |
-            BasicOp::ADX | BasicOp::SBX => 3,
-            _ => 2
-        }
-    }
-
-    pub fn encode(&self) -> u16 {
-        *self as u16
-    }
+basic_ops!(declare_opcodes!(BasicOp, DecodeError::BasicOp;));
 
-    pub fn decode(op: u16) -> Result<BasicOp, DecodeError> {
-        BasicOp::from_u16(op).ok_or(DecodeError::BasicOp(op))
-    }
+special_ops!(declare_opcodes!(SpecialOp, DecodeError::SpecialOp;));
 
-    pub fn is_if(&self) -> bool {
+impl SpecialOp {
+    /// Opcodes of this emulator, not in the specification.
+    pub fn is_extension(&self) -> bool {
         match *self {
-            BasicOp::IFB | BasicOp::IFC | BasicOp::IFE | BasicOp::IFN |
-            BasicOp::IFG | BasicOp::IFA | BasicOp::IFL |
-            BasicOp::IFU => true,
-            _ => false
+            SpecialOp::LOG | SpecialOp::BRK | SpecialOp::HLT | SpecialOp::SLP => true,
+            _ => false,
         }
     }
 }
 
-impl FromStr for BasicOp {
-    type Err = ParseError;
-    fn from_str(s: &str) -> Result<BasicOp, ParseError> {
-        match s.to_uppercase().as_str() {
-            "SET" => Ok(BasicOp::SET),
-            "ADD" => Ok(BasicOp::ADD),
-            "SUB" => Ok(BasicOp::SUB),
-            "MUL" => Ok(BasicOp::MUL),
-            "MLI" => Ok(BasicOp::MLI),
-            "DIV" => Ok(BasicOp::DIV),
-            "DVI" => Ok(BasicOp::DVI),
-            "MOD" => Ok(BasicOp::MOD),
-            "MDI" => Ok(BasicOp::MDI),
-            "AND" => Ok(BasicOp::AND),
-            "BOR" => Ok(BasicOp::BOR),
-            "XOR" => Ok(BasicOp::XOR),
-            "SHR" => Ok(BasicOp::SHR),
-            "ASR" => Ok(BasicOp::ASR),
-            "SHL" => Ok(BasicOp::SHL),
-            "IFB" => Ok(BasicOp::IFB),
-            "IFC" => Ok(BasicOp::IFC),
-            "IFE" => Ok(BasicOp::IFE),
-            "IFN" => Ok(BasicOp::IFN),
-            "IFG" => Ok(BasicOp::IFG),
-            "IFA" => Ok(BasicOp::IFA),
-            "IFL" => Ok(BasicOp::IFL),
-            "IFU" => Ok(BasicOp::IFU),
-            "ADX" => Ok(BasicOp::ADX),
-            "SBX" => Ok(BasicOp::SBX),
-            "STI" => Ok(BasicOp::STI),
-            "STD" => Ok(BasicOp::STD),
-            _     => Err(ParseError::BasicOp)
+#[cfg(test)]
+#[test]
+fn test_decode_all_words() {
+    for w in 0..0x10000u32 {
+        for &next in [0, 0x1e, 0x1f, 0xffff].iter() {
+            let data = [w as u16, next, next ^ 0xffff];
+            if let Ok((size, i)) = Instruction::decode(&data) {
+                assert!(size >= 1 && size <= 3);
+                let (encoded, encoded_size) = i.encode_to_array();
+                let encoded_size = encoded_size as u16;
+                assert!(encoded_size <= size);
+                assert_eq!(Instruction::decode(&encoded), Ok((encoded_size, i)));
+            }
         }
     }
 }
 
-enum_from_primitive! {
-#[allow(dead_code)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-pub enum SpecialOp {
-    JSR = 0x01,
-    INT = 0x08,
-    IAG = 0x09,
-    IAS = 0x0a,
-    RFI = 0x0b,
-    IAQ = 0x0c,
-    HWN = 0x10,
-    HWQ = 0x11,
-    HWI = 0x12,
-    LOG = 0x13,
-    BRK = 0x14,
-    HLT = 0x15,
-}
-}
-
-impl SpecialOp {
-    pub fn delay(&self) -> u16 {
-        match *self {
-            SpecialOp::BRK | SpecialOp::HLT => 0,
-            SpecialOp::IAG | SpecialOp::IAS | SpecialOp::LOG => 1,
-            SpecialOp::IAQ | SpecialOp::HWN => 2,
-            SpecialOp::JSR | SpecialOp::RFI => 3,
-            SpecialOp::INT | SpecialOp::HWQ | SpecialOp::HWI => 4
-        }
-    }
-
-    pub fn encode(&self) -> u16 {
-        *self as u16
+#[cfg(test)]
+#[test]
+fn test_opcode_table() {
+    for &op in BasicOp::all() {
+        assert_eq!(op.mnemonic().to_lowercase().parse(), Ok(op));
+        assert_eq!(BasicOp::decode(op.encode()), Ok(op));
     }
-
-    pub fn decode(op: u16) -> Result<SpecialOp, DecodeError> {
-        SpecialOp::from_u16(op).ok_or(DecodeError::SpecialOp(op))
+    for &op in SpecialOp::all() {
+        assert_eq!(op.mnemonic().parse(), Ok(op));
+        assert_eq!(SpecialOp::decode(op.encode()), Ok(op));
     }
+    assert!(BasicOp::IFU.is_if() && !BasicOp::SET.is_if());
+    assert_eq!("NOP".parse::<BasicOp>(), Err(ParseError::BasicOp));
 }
 
-impl FromStr for SpecialOp {
-    type Err = ParseError;
-
-    fn from_str(s: &str) -> Result<SpecialOp, ParseError> {
-        match s.to_uppercase().as_str() {
-            "JSR" => Ok(SpecialOp::JSR),
-            "INT" => Ok(SpecialOp::INT),
-            "IAG" => Ok(SpecialOp::IAG),
-            "IAS" => Ok(SpecialOp::IAS),
-            "RFI" => Ok(SpecialOp::RFI),
-            "IAQ" => Ok(SpecialOp::IAQ),
-            "HWN" => Ok(SpecialOp::HWN),
-            "HWQ" => Ok(SpecialOp::HWQ),
-            "HWI" => Ok(SpecialOp::HWI),
-            "LOG" => Ok(SpecialOp::LOG),
-            "BRK" => Ok(SpecialOp::BRK),
-            "HLT" => Ok(SpecialOp::HLT),
-            _ => Err(ParseError::SpecialOp)
-        }
+#[cfg(test)]
+#[test]
+fn test_value_form() {
+    for code in 0..0x40 {
+        let (used, _) = Value::decode(code, 0, true).unwrap();
+        assert_eq!(used == 1, Value::form(code).contains("next word"), "{:x}", code);
     }
+    assert_eq!(Value::form(0x0a), "[register]");
+    assert_eq!(Value::form(0x3f), "literal -1 to 30, a only");
 }
@@ -0,0 +1,197 @@
+//! Computer for web pages, with the crate compiled to
+//! `wasm32-unknown-unknown`: a LEM1802 at index 0 and a keyboard at index 1,
+//! driven by the page.
+//!
+//! Nothing here uses threads nor the clock of the system: the page calls
+//! `dcpu_step` with the cycles elapsed since its last frame, reads the
+//! pixels of the screen and forwards the keys. The functions take the
+//! computer created by `dcpu_new`:
+//!
+//! | Function                 | Use                                             |
+//! |--------------------------|-------------------------------------------------|
+//! | `dcpu_alloc(words)`      | buffer to copy a ROM into, then free it with    |
+//! |                          | `dcpu_dealloc(ptr, words)`                      |
+//! | `dcpu_new(rom, words)`   | computer with the ROM loaded at 0               |
+//! | `dcpu_free(c)`           |                                                 |
+//! | `dcpu_step(c, cycles)`   | 0 if still running, 1 if halted, -1 on an error |
+//! | `dcpu_screen(c)`         | RGBA pixels, `SCREEN_WIDTH` x `SCREEN_HEIGHT`,  |
+//! |                          | border included, ready for an `ImageData`       |
+//! | `dcpu_key(c, kind, key)` | `kind` 0 to press, 1 to release and 2 to type   |
+//! |                          | the key encoded as by the keyboard, -1 if it    |
+//! |                          | is invalid                                      |
+
+use std::cell::RefCell;
+use std::mem;
+use std::rc::Rc;
+use std::slice;
+use std::sync::mpsc::{self, Sender};
+
+use computer::Computer;
+use cpu::{self, Cpu};
+use device::keyboard::{ChannelBackend, Key, KeyEvent, Keyboard};
+use device::lem1802::{self, LastFrame, LEM1802, Screen};
+
+/// Width of the screen with its border.
+pub const SCREEN_WIDTH: u32 = (lem1802::SCREEN_WIDTH + 2 * lem1802::BORDER) as u32;
+/// Height of the screen with its border.
+pub const SCREEN_HEIGHT: u32 = (lem1802::SCREEN_HEIGHT + 2 * lem1802::BORDER) as u32;
+
+pub struct WebComputer {
+    computer: Computer,
+    keys: Sender<KeyEvent>,
+    screen: Rc<RefCell<Option<Screen>>>,
+    rgba: Vec<u8>,
+}
+
+impl WebComputer {
+    pub fn new(rom: &[u16]) -> WebComputer {
+        let mut cpu = Cpu::default();
+        cpu.load(rom, 0);
+        let mut computer = Computer::new(cpu);
+        let screen = Rc::new(RefCell::new(None));
+        let frames = LastFrame {
+            inner: None,
+            last: screen.clone(),
+        };
+        computer.add_device(Box::new(LEM1802::with_timebase(Box::new(frames),
+                                                            computer.timebase())));
+        let (keys, events) = mpsc::channel();
+        computer.add_device(Box::new(Keyboard::new(Box::new(ChannelBackend::new(events)))));
+        WebComputer {
+            computer: computer,
+            keys: keys,
+            screen: screen,
+            rgba: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize],
+        }
+    }
+
+    pub fn computer(&self) -> &Computer {
+        &self.computer
+    }
+
+    /// Runs `cycles` ticks, stopping early if the CPU fails.
+    pub fn step(&mut self, cycles: u64) -> Result<(), cpu::Error> {
+        for _ in 0..cycles {
+            try!(self.computer.tick());
+        }
+        Ok(())
+    }
+
+    /// RGBA pixels of the last frame, black while the screen is
+    /// disconnected.
+    pub fn screen(&mut self) -> &[u8] {
+        match *self.screen.borrow() {
+            Some(ref screen) => {
+                let pixels = screen.image(1).pixels;
+                for (rgba, rgb) in self.rgba.chunks_mut(4).zip(pixels) {
+                    rgba[..3].copy_from_slice(&rgb);
+                    rgba[3] = 0xff;
+                }
+            }
+            None => {
+                for rgba in self.rgba.chunks_mut(4) {
+                    rgba.copy_from_slice(&[0, 0, 0, 0xff]);
+                }
+            }
+        }
+        &self.rgba
+    }
+
+    pub fn key(&self, event: KeyEvent) {
+        // The keyboard, holding the receiver, lives as long as the computer.
+        let _ = self.keys.send(event);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn dcpu_alloc(words: usize) -> *mut u16 {
+    let mut buffer = vec![0u16; words];
+    let ptr = buffer.as_mut_ptr();
+    mem::forget(buffer);
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_dealloc(ptr: *mut u16, words: usize) {
+    drop(Vec::from_raw_parts(ptr, words, words));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_new(rom: *const u16, words: usize) -> *mut WebComputer {
+    let rom = if words == 0 { &[] } else { slice::from_raw_parts(rom, words) };
+    Box::into_raw(Box::new(WebComputer::new(rom)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_free(computer: *mut WebComputer) {
+    drop(Box::from_raw(computer));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_step(computer: *mut WebComputer, cycles: u32) -> i32 {
+    match (*computer).step(cycles as u64) {
+        Ok(()) => 0,
+        Err(cpu::Error::Halted) => 1,
+        Err(_) => -1,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_screen(computer: *mut WebComputer) -> *const u8 {
+    (*computer).screen().as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcpu_key(computer: *mut WebComputer, kind: u32, key: u16) -> i32 {
+    let key = match Key::decode(key) {
+        Ok(key) => key,
+        Err(()) => return -1,
+    };
+    let event = match kind {
+        0 => KeyEvent::Pressed(key),
+        1 => KeyEvent::Released(key),
+        2 => KeyEvent::Typed(key),
+        _ => return -1,
+    };
+    (*computer).key(event);
+    0
+}
+
+#[cfg(test)]
+#[test]
+fn test_web() {
+    use encodings::*;
+    use types::*;
+
+    // Maps the screen at 0x8000, then copies each key typed to it.
+    let words = [basic(BasicOp::SET, reg(Register::B), NEXT),
+                 0x8000,
+                 special(SpecialOp::HWI, lit(0)),
+                 basic(BasicOp::SET, reg(Register::A), lit(1)),
+                 special(SpecialOp::HWI, lit(1)),
+                 basic(BasicOp::IFE, reg(Register::C), lit(0)),
+                 basic(BasicOp::SET, PC, lit(4)),
+                 basic(BasicOp::BOR, reg(Register::C), NEXT),
+                 0xf000,
+                 basic(BasicOp::SET, AT_NEXT, reg(Register::C)),
+                 0x8000,
+                 basic(BasicOp::SET, PC, lit(4))];
+    unsafe {
+        let ptr = dcpu_alloc(words.len());
+        slice::from_raw_parts_mut(ptr, words.len()).copy_from_slice(&words);
+        let computer = dcpu_new(ptr, words.len());
+        dcpu_dealloc(ptr, words.len());
+
+        assert_eq!(dcpu_key(computer, 2, 'A' as u16), 0);
+        assert_eq!(dcpu_key(computer, 2, 0xffff), -1);
+        assert_eq!(dcpu_step(computer, 200000), 0);
+        let screen = slice::from_raw_parts(dcpu_screen(computer),
+                                           (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize);
+        // The border, black by default, and a white pixel of the 'A'.
+        assert_eq!(&screen[..4], &[0, 0, 0, 0xff]);
+        let (x, y) = (lem1802::BORDER as u32 + 1, lem1802::BORDER as u32);
+        let offset = ((y * SCREEN_WIDTH + x) * 4) as usize;
+        assert_eq!(&screen[offset..offset + 4], &[0xff, 0xff, 0xff, 0xff]);
+        dcpu_free(computer);
+    }
+}
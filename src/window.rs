@@ -0,0 +1,202 @@
+//! Disassembly of the instructions around PC, for the debugger and the GDB
+//! stub.
+//!
+//! The instructions after PC are decoded from it. Those before it can't be
+//! decoded backwards, a word being either an instruction or the operand of
+//! the previous one: the addresses recently executed, kept in a `History`,
+//! are known to start instructions, so the window starts from the one which
+//! leads to PC through the most of them.
+
+use std::collections::VecDeque;
+
+use cpu::Cpu;
+use types::Instruction;
+
+/// Instructions recorded by a `History` by default.
+pub const HISTORY_LEN: usize = 64;
+
+/// Addresses of the last instructions executed.
+#[derive(Debug, Clone)]
+pub struct History {
+    addrs: VecDeque<u16>,
+    capacity: usize,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(HISTORY_LEN)
+    }
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            addrs: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+        }
+    }
+
+    /// Adds `addr`, the address of an instruction executed, unless it is
+    /// the last one added.
+    pub fn record(&mut self, addr: u16) {
+        if self.addrs.back() == Some(&addr) || self.capacity == 0 {
+            return;
+        }
+        if self.addrs.len() == self.capacity {
+            self.addrs.pop_front();
+        }
+        self.addrs.push_back(addr);
+    }
+
+    /// Records the last instruction executed by `cpu`, see
+    /// `Cpu::last_instruction`. Meant to be called after each step.
+    pub fn update(&mut self, cpu: &Cpu) {
+        if let Some((addr, _)) = cpu.last_instruction() {
+            self.record(addr);
+        }
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        self.addrs.contains(&addr)
+    }
+
+    /// The addresses, the oldest first.
+    pub fn addrs(&self) -> &VecDeque<u16> {
+        &self.addrs
+    }
+
+    pub fn clear(&mut self) {
+        self.addrs.clear();
+    }
+}
+
+/// Instruction of a window, or a word which can't be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub addr: u16,
+    pub words: Vec<u16>,
+    /// Disassembly, `.dat 0x...` for a word which can't be decoded.
+    pub text: String,
+}
+
+impl Row {
+    fn decode(cpu: &Cpu, addr: u16) -> (Row, Option<Instruction>) {
+        let (size, instruction, text) = match cpu.decode(addr) {
+            Ok((size, i)) => (size, Some(i), i.to_string()),
+            Err(_) => (1, None, format!(".dat 0x{:04x}", cpu.ram[addr as usize])),
+        };
+        let row = Row {
+            addr: addr,
+            words: cpu.read_memory(addr, size as usize),
+            text: text,
+        };
+        (row, instruction)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.words.len() as u16
+    }
+}
+
+/// Rows decoded from `start` while before `end`, `None` unless the last
+/// one ends right at `end`. Also returns how many start at addresses of
+/// `history`, and how many can't be decoded.
+fn run_to(cpu: &Cpu,
+          history: &History,
+          start: u16,
+          end: u16)
+          -> Option<(Vec<Row>, usize, usize)> {
+    let (mut rows, mut known, mut invalid) = (vec![], 0, 0);
+    let mut addr = start;
+    while addr != end {
+        let (row, instruction) = Row::decode(cpu, addr);
+        // Past `end`, wrapping around the memory.
+        if row.size() > end.wrapping_sub(addr) {
+            return None;
+        }
+        if history.contains(addr) {
+            known += 1;
+        }
+        if instruction.is_none() {
+            invalid += 1;
+        }
+        addr = addr.wrapping_add(row.size());
+        rows.push(row);
+    }
+    Some((rows, known, invalid))
+}
+
+/// `before` rows ending right before PC, as far as they can be found, then
+/// `after` rows starting at PC.
+///
+/// The rows before PC are decoded from the address, up to 3 words per row
+/// before PC, whose rows lead to PC through the most addresses of
+/// `history`, then the fewest words which can't be decoded, then the most
+/// rows.
+pub fn window(cpu: &Cpu, history: &History, before: usize, after: usize) -> Vec<Row> {
+    let pc = cpu.pc;
+    let mut best: Option<(Vec<Row>, usize, usize)> = None;
+    for distance in 1..(3 * before as u32 + 1) {
+        let start = pc.wrapping_sub(distance as u16);
+        if let Some(run) = run_to(cpu, history, start, pc) {
+            let better = match best {
+                Some((ref rows, known, invalid)) => {
+                    (run.1, invalid, run.0.len()) > (known, run.2, rows.len())
+                }
+                None => true,
+            };
+            if better {
+                best = Some(run);
+            }
+        }
+    }
+    let mut rows = best.map_or(vec![], |(rows, _, _)| rows);
+    let skip = rows.len().saturating_sub(before);
+    rows.drain(..skip);
+
+    let mut addr = pc;
+    for _ in 0..after {
+        let (row, _) = Row::decode(cpu, addr);
+        addr = addr.wrapping_add(row.size());
+        rows.push(row);
+    }
+    rows
+}
+
+#[cfg(test)]
+#[test]
+fn test_window() {
+    use encodings::*;
+    use types::{BasicOp, Register, SpecialOp};
+
+    // The literal of the SET at 0x11 also decodes as SET Z, [Z].
+    let set_b = basic(BasicOp::SET, reg(Register::B), NEXT);
+    let set_z = basic(BasicOp::SET, reg(Register::Z), at_reg(Register::Z));
+    let hlt = special(SpecialOp::HLT, lit(0));
+    let mut cpu = Cpu::default();
+    cpu.ram = [0; 0x10000];
+    cpu.load(&[basic(BasicOp::SET, reg(Register::A), lit(1)), set_b, set_z, hlt], 0x10);
+    cpu.pc = 0x13;
+
+    let addrs = |rows: &[Row]| rows.iter().map(|r| r.addr).collect::<Vec<_>>();
+    let mut history = History::new(4);
+    // Without history, the most rows, the 0 before being invalid.
+    let rows = window(&cpu, &history, 3, 1);
+    assert_eq!(addrs(&rows), [0x10, 0x11, 0x13]);
+    assert_eq!(rows[1].words, [set_b, set_z]);
+    assert_eq!(rows[2].words, [hlt]);
+    assert!(rows[2].text.starts_with("HLT"));
+
+    // Once the program jumped to 0x12.
+    history.record(0x12);
+    assert_eq!(addrs(&window(&cpu, &history, 3, 1)), [0x12, 0x13]);
+    let rows = window(&cpu, &history, 0, 2);
+    assert_eq!(addrs(&rows), [0x13, 0x14]);
+    assert_eq!(rows[1].text, ".dat 0x0000");
+
+    for addr in 0..6 {
+        history.record(addr);
+    }
+    history.record(5);
+    assert_eq!(history.addrs().iter().cloned().collect::<Vec<_>>(), [2, 3, 4, 5]);
+}
@@ -0,0 +1,172 @@
+//! Assembles each `corpus/*.dasm` and compares the result with the binary
+//! (`.bin`, little endian words) and the listing (`.lst`) checked in next to
+//! it.
+//!
+//! Run with `DCPU_BLESS=1` to write the expected files instead, e.g. after an
+//! intended change of the output.
+
+extern crate dcpu;
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use dcpu::assembler::{conditionals, include, linker, listing, macros};
+
+/// Differing words reported for a binary.
+const MAX_DIFFS: usize = 10;
+
+struct Output {
+    bin: Vec<u16>,
+    listing: String,
+}
+
+fn assemble(path: &Path) -> Output {
+    let program = include::Loader::new()
+                      .load(path)
+                      .unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+    let (ast, positions) = macros::expand_with_positions(&program.items, &program.positions)
+                               .unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+    let (ast, positions) = conditionals::evaluate_with_positions(&ast,
+                                                                 &positions,
+                                                                 &HashMap::new())
+                               .unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+    let linked = linker::link_with_options(&ast, &linker::Options::default())
+                     .unwrap_or_else(|e| panic!("{}: {:?}", path.display(), e));
+
+    // Only the file names, so the listing doesn't depend on the checkout.
+    let sources = program.files
+                         .iter()
+                         .map(|f| {
+                             let name = f.file_name().unwrap().to_string_lossy().into_owned();
+                             (name, read_string(f))
+                         })
+                         .collect::<Vec<_>>();
+    let listing = listing::listing(&sources, &ast, &positions, &linked);
+    Output {
+        bin: linked.bin,
+        listing: listing,
+    }
+}
+
+fn read_string(path: &Path) -> String {
+    let mut s = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    s
+}
+
+fn read_bin(path: &Path) -> Vec<u16> {
+    let mut bytes = vec![];
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    bytes.chunks(2).map(|w| w[0] as u16 | (*w.get(1).unwrap_or(&0) as u16) << 8).collect()
+}
+
+fn write_file(path: &Path, content: &[u8]) {
+    File::create(path)
+        .and_then(|mut f| f.write_all(content))
+        .unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+}
+
+fn diff_bin(expected: &[u16], got: &[u16]) -> Option<String> {
+    let mut report = String::new();
+    if expected.len() != got.len() {
+        writeln!(report,
+                 "  {} words instead of {}",
+                 got.len(),
+                 expected.len())
+            .unwrap();
+    }
+    let diffs = (0..expected.len().max(got.len()))
+                    .filter(|&i| expected.get(i) != got.get(i))
+                    .collect::<Vec<_>>();
+    for &i in diffs.iter().take(MAX_DIFFS) {
+        let word = |w: Option<&u16>| w.map_or("----".into(), |w| format!("{:04x}", w));
+        writeln!(report,
+                 "  0x{:04x}: expected {}, got {}",
+                 i,
+                 word(expected.get(i)),
+                 word(got.get(i)))
+            .unwrap();
+    }
+    if diffs.len() > MAX_DIFFS {
+        writeln!(report, "  ... and {} more", diffs.len() - MAX_DIFFS).unwrap();
+    }
+    if report.is_empty() {
+        None
+    } else {
+        Some(report)
+    }
+}
+
+fn diff_listing(expected: &str, got: &str) -> Option<String> {
+    let mut expected_lines = expected.lines();
+    let mut got_lines = got.lines();
+    let mut n = 1;
+    loop {
+        match (expected_lines.next(), got_lines.next()) {
+            (None, None) => return None,
+            (e, g) if e == g => n += 1,
+            (e, g) => {
+                return Some(format!("  line {}:\n  expected: {}\n  got:      {}\n",
+                                    n,
+                                    e.unwrap_or("<end>"),
+                                    g.unwrap_or("<end>")))
+            }
+        }
+    }
+}
+
+fn corpus() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+    let mut files = fs::read_dir(&dir)
+                        .unwrap()
+                        .map(|e| e.unwrap().path())
+                        .filter(|p| p.extension().map_or(false, |e| e == "dasm"))
+                        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+#[test]
+fn test_corpus() {
+    let bless = env::var_os("DCPU_BLESS").is_some();
+    let mut failures = String::new();
+    let files = corpus();
+    assert!(!files.is_empty());
+
+    for path in files {
+        let output = assemble(&path);
+        let bin_path = path.with_extension("bin");
+        let lst_path = path.with_extension("lst");
+
+        if bless {
+            let bytes = output.bin
+                              .iter()
+                              .flat_map(|w| vec![*w as u8, (w >> 8) as u8])
+                              .collect::<Vec<_>>();
+            write_file(&bin_path, &bytes);
+            write_file(&lst_path, output.listing.as_bytes());
+            continue;
+        }
+
+        if let Some(d) = diff_bin(&read_bin(&bin_path), &output.bin) {
+            write!(failures, "{}:\n{}", bin_path.display(), d).unwrap();
+        }
+        if let Some(d) = diff_listing(&read_string(&lst_path), &output.listing) {
+            write!(failures, "{}:\n{}", lst_path.display(), d).unwrap();
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!("output differs from the corpus, rerun with DCPU_BLESS=1 if \
+                intended\n{}",
+               failures);
+    }
+}
@@ -0,0 +1,49 @@
+//! Property tests of the encoding, the assembler and the interpreter, run
+//! with `cargo test --features proptest --test properties`.
+
+extern crate dcpu;
+#[macro_use]
+extern crate proptest;
+
+use dcpu::cpu::Cpu;
+use dcpu::device::Device;
+use dcpu::strategies;
+use dcpu::types::{Instruction, SpecialOp, Value};
+
+/// Whether executing `i` may set PC to something else than the next
+/// instruction, with no interrupt handler.
+fn writes_pc(i: &Instruction) -> bool {
+    match *i {
+        Instruction::BasicOp(op, Value::PC, _) => !op.is_if(),
+        Instruction::SpecialOp(SpecialOp::JSR, _) |
+        Instruction::SpecialOp(SpecialOp::RFI, _) |
+        Instruction::SpecialOp(SpecialOp::IAG, Value::PC) |
+        Instruction::SpecialOp(SpecialOp::HWN, Value::PC) => true,
+        _ => false,
+    }
+}
+
+proptest! {
+    #[test]
+    fn encode_decode(i in strategies::instruction()) {
+        let (words, size) = i.encode_to_array();
+        prop_assert_eq!(Instruction::decode(&words), Ok((size as u16, i)));
+    }
+
+    #[test]
+    fn disassemble_reassemble(bin in strategies::binary(32)) {
+        let asm = dcpu::disassemble(&bin);
+        prop_assert_eq!(dcpu::assemble_str(&asm).unwrap(), bin);
+    }
+
+    #[test]
+    fn pc_advances(i in strategies::instruction()) {
+        let mut cpu = Cpu::default();
+        cpu.load_ops(&[i], 0);
+        let mut devices: Vec<Box<Device>> = vec![];
+        // Errors, like interrupting a missing device, are fine.
+        if cpu.tick(&mut devices).is_ok() && !writes_pc(&i) {
+            prop_assert_eq!(cpu.pc, i.encode_to_array().1 as u16);
+        }
+    }
+}
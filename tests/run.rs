@@ -0,0 +1,48 @@
+//! Runs sources with `dcpu run` and checks its exit status.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{self, Command, Output};
+
+/// Writes `source` to a temporary file named after `name` and runs it.
+fn run(name: &str, source: &str, args: &[&str]) -> Output {
+    let path: PathBuf = env::temp_dir().join(format!("dcpu-run-{}-{}.dasm", name, process::id()));
+    File::create(&path).and_then(|mut f| f.write_all(source.as_bytes())).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_dcpu"))
+                     .arg("run")
+                     .arg("--no-cpp")
+                     .arg("--turbo")
+                     .args(args)
+                     .arg(&path)
+                     .output()
+                     .unwrap();
+    let _ = fs::remove_file(&path);
+    output
+}
+
+#[test]
+fn test_halt() {
+    let output = run("halt", "SET A, 1\nHLT 0\n", &[]);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+}
+
+#[test]
+fn test_cycle_limit() {
+    let output = run("loop", "loop:\nSET PC, loop\n", &["--max-cycles", "100"]);
+    assert_eq!(output.status.code(), Some(124), "{:?}", output);
+}
+
+#[test]
+fn test_failure() {
+    // Executing the empty memory after the program wraps PC around.
+    let output = run("fail", "SET A, 1\nADD A, 2\n", &["--max-cycles", "1000000"]);
+    assert_eq!(output.status.code(), Some(125), "{:?}", output);
+}
+
+#[test]
+fn test_usage() {
+    let output = Command::new(env!("CARGO_BIN_EXE_dcpu")).arg("--help").output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+}